@@ -27,6 +27,16 @@ pub struct TexturedRect {
     pub i_clip_rect: u32,
     pub border_radius: f32,
     pub base_color: ColorU32,
+    pub filter: TextureFilter,
+    pub padding: [u32; 3],
+}
+
+// Nearest keeps the texels sharp when magnified, for pixel art and texture previews
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TextureFilter {
+    Nearest = 0,
+    Linear = 1,
 }
 
 #[derive(Clone, Copy)]
@@ -115,6 +125,12 @@ impl<'a> Drawer<'a> {
         self.index_offset
     }
 
+    // Forgets the glyphs of the previous atlas image
+    pub fn reset_glyph_atlas(&mut self, glyph_atlas_descriptor: u32) {
+        self.glyph_cache = GlyphCache::new(self.glyph_cache.get_size());
+        self.glyph_atlas_descriptor = glyph_atlas_descriptor;
+    }
+
     pub fn glyph_cache(&self) -> &GlyphCache {
         &self.glyph_cache
     }
@@ -615,6 +631,8 @@ impl TexturedRect {
             i_clip_rect: !0u32,
             border_radius: 0.0,
             base_color: ColorU32::greyscale(0xFF),
+            filter: TextureFilter::Linear,
+            padding: [0; 3],
         }
     }
 
@@ -642,4 +660,91 @@ impl TexturedRect {
         self.base_color = base_color;
         self
     }
+
+    pub fn filter(mut self, filter: TextureFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: f32, y: f32) -> Rect {
+        Rect {
+            pos: [x, y],
+            size: [10.0, 10.0],
+        }
+    }
+
+    #[test]
+    fn primitive_index_bitfields() {
+        let index = PrimitiveIndex::new()
+            .index(INDX_MAX as usize)
+            .corner(3)
+            .i_type(PrimitiveType::SdfCircle);
+        assert_eq!(index.get_index(), INDX_MAX);
+        assert_eq!(index.get_corner(), 3);
+        assert_eq!(index.get_i_type(), PrimitiveType::SdfCircle as u32);
+
+        let index = index.corner(1).index(42);
+        assert_eq!(index.get_index(), 42);
+        assert_eq!(index.get_corner(), 1);
+        assert_eq!(index.get_i_type(), PrimitiveType::SdfCircle as u32);
+    }
+
+    #[test]
+    fn rects_are_indexed_by_corner() {
+        let mut vertices = vec![0u8; 1024];
+        let mut indices = vec![0u32; 64];
+        let mut drawer = Drawer::new(&mut vertices, &mut indices, [64, 64], 7);
+
+        drawer.draw_colored_rects(&[
+            ColoredRect::new(rect(0.0, 0.0)),
+            ColoredRect::new(rect(10.0, 0.0)),
+        ]);
+        assert_eq!(drawer.get_vertices().len(), 2 * size_of::<ColoredRect>());
+        assert_eq!(drawer.get_index_offset(), 12);
+
+        let indices: Vec<PrimitiveIndex> = drawer
+            .get_indices()
+            .iter()
+            .map(|bits| PrimitiveIndex(*bits))
+            .collect();
+        let corners: Vec<u32> = indices.iter().map(PrimitiveIndex::get_corner).collect();
+        assert_eq!(corners, [0, 1, 2, 0, 2, 3, 0, 1, 2, 0, 2, 3]);
+        assert!(indices[..6].iter().all(|index| index.get_index() == 0));
+        assert!(indices[6..].iter().all(|index| index.get_index() == 1));
+        assert!(indices
+            .iter()
+            .all(|index| index.get_i_type() == PrimitiveType::ColorRect as u32));
+    }
+
+    #[test]
+    fn mixed_primitives_are_aligned_to_their_size() {
+        let mut vertices = vec![0u8; 1024];
+        let mut indices = vec![0u32; 64];
+        let mut drawer = Drawer::new(&mut vertices, &mut indices, [64, 64], 7);
+
+        // One colored rect doesn't fill a textured rect slot, the textured rect goes in the next one
+        drawer.draw_colored_rect(ColoredRect::new(rect(0.0, 0.0)));
+        drawer.draw_textured_rect(TexturedRect::new(rect(10.0, 0.0)));
+        assert_eq!(drawer.get_vertices().len(), 2 * size_of::<TexturedRect>());
+
+        let textured_index = PrimitiveIndex(drawer.get_indices()[6]);
+        assert_eq!(textured_index.get_index(), 1);
+        assert_eq!(
+            textured_index.get_i_type(),
+            PrimitiveType::TexturedRect as u32
+        );
+
+        // The colored rect after it is indexed in units of colored rects
+        drawer.draw_colored_rect(ColoredRect::new(rect(20.0, 0.0)));
+        assert_eq!(PrimitiveIndex(drawer.get_indices()[12]).get_index(), 4);
+
+        drawer.clear();
+        assert!(drawer.get_vertices().is_empty());
+        assert!(drawer.get_indices().is_empty());
+    }
 }
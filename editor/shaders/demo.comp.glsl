@@ -283,6 +283,11 @@ void main()
 		R = float3(visibility * (N * 0.5 + 0.5));
 	}
 
+#ifdef DEBUG
+	// Heatmap of the steps taken by the ray march, misses included
+	R = TurboColormap(inter_steps / 200.0);
+#endif
+
 	float4 output_color = float4(1.0);
 	output_color.rgb = float3(R);
 	output_color.a = 1.0;
@@ -11,6 +11,8 @@ layout(set = SHADER_UNIFORM_SET, binding = 0) uniform Options {
 	u32 vertices_descriptor_index;
 	u32 primitive_bytes_offset;
 	u32 glyph_atlas_descriptor;
+	u32 nearest_sampler_descriptor;
+	u32 linear_sampler_descriptor;
 };
 
 bool is_in_rect(float2 pos, Rect rect)
@@ -50,7 +52,8 @@ float4 textured_rect(u32 i_primitive, u32 corner, float2 uv)
 	}
 	else
 	{
-		color = texture(global_textures[nonuniformEXT(rect.texture_descriptor)], uv);
+		u32 i_sampler = rect.texture_filter == TextureFilter_Nearest ? nearest_sampler_descriptor : linear_sampler_descriptor;
+		color = texture(sampler2D(global_sampled_images[nonuniformEXT(rect.texture_descriptor)], global_samplers[i_sampler]), uv);
 	}
 
 	return color;
@@ -77,4 +80,10 @@ void main()
 	{
 		o_color = float4(1, 0, 1, 1);
 	}
+
+#ifdef DEBUG
+	// Tint the whole quad of each primitive to see the transparent parts and the overdraw
+	float4 debug_color = primitive_type == RectType_Color ? float4(0.0, 0.1, 0.0, 0.1) : float4(0.0, 0.0, 0.1, 0.1);
+	o_color = o_color * (1.0 - debug_color.a) + debug_color;
+#endif
 }
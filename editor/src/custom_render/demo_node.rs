@@ -1,22 +1,47 @@
-use exo::pool::Handle;
-use render::{bindings, render_graph::graph::*, shader_path, vulkan};
+use exo::{pod_struct, pool::Handle};
+use render::{bindings, render_graph::graph::*, shader_path, vk, vulkan};
 use std::{cell::RefCell, rc::Rc};
 
 pub struct DemoNode {
     program: Handle<vulkan::ComputeProgram>,
     resolved_output_descriptor: u32,
+    // Shows the number of ray marching steps
+    pub debug: bool,
+}
+
+const DEMO_FEATURES: vulkan::ShaderFeatures = &["DEBUG"];
+const DEMO_FEATURE_DEBUG: usize = 0;
+
+pod_struct! {
+    struct DemoOptions {
+        pub storage_output_frame: u32,
+        pub i_frame: u32,
+        pub dt: f32,
+        pub t: f32,
+    }
 }
 
 impl DemoNode {
     pub fn new(device: &mut vulkan::Device) -> vulkan::VulkanResult<DemoNode> {
-        let shader_handle = device.create_shader(shader_path!("demo.comp.spv"))?;
+        let shader_handle = device.create_shader(shader_path!("demo.comp.glsl"))?;
         let node = DemoNode {
-            program: device.create_compute_program(String::from("demo"), shader_handle)?,
+            program: device.create_compute_program(
+                String::from("demo"),
+                shader_handle,
+                DEMO_FEATURES,
+            )?,
             resolved_output_descriptor: 1,
+            debug: false,
         };
         Ok(node)
     }
 
+    pub fn destroy(&self, device: &mut vulkan::Device) {
+        let shader = device.compute_programs.get(self.program).shader;
+        device.destroy_compute_program(self.program);
+        device.destroy_shader(shader);
+    }
+
     pub fn output_descriptor(&self) -> u32 {
         self.resolved_output_descriptor
     }
@@ -29,9 +54,17 @@ impl DemoNode {
         t: f32,
     ) {
         let demo_program = pass.borrow().program;
+        let demo_variant =
+            vulkan::ShaderVariant::default().with_feature(DEMO_FEATURE_DEBUG, pass.borrow().debug);
         let pass = Rc::clone(pass);
 
-        graph.raw_pass(
+        graph.use_texture(
+            output,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+        );
+
+        graph.async_compute_pass(
+            &[output],
             move |graph: &mut RenderGraph,
                   api: &mut PassApi,
                   ctx: &mut vulkan::ComputeContext|
@@ -45,19 +78,11 @@ impl DemoNode {
                         api.device.images.get(output_image).full_view.sampled_idx;
                     pass.borrow_mut().resolved_output_descriptor = output_sampled_descriptor;
 
-                    #[repr(C, packed)]
-                    struct Options {
-                        pub storage_output_frame: u32,
-                        pub i_frame: u32,
-                        pub dt: f32,
-                        pub t: f32,
-                    }
-
                     bindings::bind_and_copy_shader_options(
                         api.device,
                         api.uniform_buffer,
                         &ctx,
-                        Options {
+                        DemoOptions {
                             storage_output_frame: output_descriptor,
                             i_frame: graph.i_frame() as u32,
                             dt,
@@ -65,7 +90,12 @@ impl DemoNode {
                         },
                     )?;
 
-                    ctx.bind_compute_pipeline(api.device, demo_program);
+                    ctx.base_context().barrier(
+                        api.device,
+                        output_image,
+                        vulkan::ImageState::ComputeShaderReadWrite,
+                    );
+                    ctx.bind_compute_variant(api.device, demo_program, demo_variant)?;
 
                     let output_size = graph.resources.texture_desc_handle_size(output);
                     let size = [
@@ -73,11 +103,17 @@ impl DemoNode {
                         ((output_size[1] as u32) / 16) + 1,
                         1,
                     ];
-                    ctx.dispatch(api.device, size);
+                    ctx.dispatch(api.device, size)?;
+                    ctx.base_context().barrier(
+                        api.device,
+                        output_image,
+                        vulkan::ImageState::ComputeShaderRead,
+                    );
                 }
 
                 Ok(())
             },
         );
+        graph.set_pass_name("demo");
     }
 }
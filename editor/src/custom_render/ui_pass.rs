@@ -1,11 +1,29 @@
 use drawer2d::drawer::Drawer;
-use exo::{dynamic_array::DynamicArray, pool::Handle};
+use exo::{dynamic_array::DynamicArray, pod_struct, pool::Handle};
 use render::{bindings, render_graph::graph::*, shader_path, vk, vulkan};
-use std::{mem::size_of, rc::Rc};
+use std::rc::Rc;
 
 pub struct UiPass {
     pub glyph_atlas: Handle<vulkan::Image>,
+    // Tints every primitive to show the overdraw
+    pub debug: bool,
     ui_program: Handle<vulkan::GraphicsProgram>,
+    samples: vk::SampleCountFlagBits,
+}
+
+const UI_FEATURES: vulkan::ShaderFeatures = &["DEBUG"];
+const UI_FEATURE_DEBUG: usize = 0;
+
+pod_struct! {
+    struct UiOptions {
+        pub scale: [f32; 2],
+        pub translation: [f32; 2],
+        pub vertices_descriptor_index: u32,
+        pub primitive_bytes_offset: u32,
+        pub glyph_atlas_descriptor: u32,
+        pub nearest_sampler_descriptor: u32,
+        pub linear_sampler_descriptor: u32,
+    }
 }
 
 impl UiPass {
@@ -13,32 +31,29 @@ impl UiPass {
         device: &mut vulkan::Device,
         glyph_atlas_size: [i32; 2],
     ) -> vulkan::VulkanResult<Self> {
+        // Multisampling keeps the edges of the ui shapes smooth
+        let samples = device.supported_sample_count(vk::SampleCountFlagBits::_4);
+        let vertex_shader = device.create_shader(shader_path!("ui.vert.glsl"))?;
+        let fragment_shader = device.create_shader(shader_path!("ui.frag.glsl"))?;
+
         let ui_gfx_state = vulkan::GraphicsState {
-            vertex_shader: device.create_shader(shader_path!("ui.vert.spv")).unwrap(),
-            fragment_shader: device.create_shader(shader_path!("ui.frag.spv")).unwrap(),
+            vertex_shader,
+            fragment_shader,
             attachments_format: vulkan::FramebufferFormat {
                 attachment_formats: DynamicArray::from([vk::Format::R8G8B8A8_UNORM]),
+                samples,
+                resolve: samples != vk::SampleCountFlagBits::_1,
                 ..Default::default()
             },
+            features: UI_FEATURES,
         };
 
         let ui_program = device.create_graphics_program(ui_gfx_state)?;
         device.compile_graphics_program(
             ui_program,
             vulkan::RenderState {
-                depth: vulkan::DepthState {
-                    test: None,
-                    enable_write: false,
-                    bias: 0.0,
-                },
-                rasterization: vulkan::RasterizationState {
-                    enable_conservative_rasterization: false,
-                    culling: false,
-                },
-                input_assembly: vulkan::InputAssemblyState {
-                    topology: vulkan::PrimitiveTopology::TriangleList,
-                },
-                alpha_blending: true,
+                blend_states: DynamicArray::from([vulkan::BlendState::premultiplied_alpha()]),
+                ..Default::default()
             },
         )?;
 
@@ -56,68 +71,79 @@ impl UiPass {
 
         Ok(Self {
             glyph_atlas,
+            debug: false,
             ui_program,
+            samples,
         })
     }
 
+    pub fn destroy(&self, device: &mut vulkan::Device) {
+        let graphics_state = &device.graphics_programs.get(self.ui_program).graphics_state;
+        let shaders = [graphics_state.vertex_shader, graphics_state.fragment_shader];
+        device.destroy_program(self.ui_program);
+        for shader in shaders {
+            device.destroy_shader(shader);
+        }
+        device.destroy_image(self.glyph_atlas);
+    }
+
     pub fn register_graph(
         &self,
         graph: &mut RenderGraph,
+        device: &vulkan::Device,
         output: Handle<TextureDesc>,
+        // Textures drawn by the UI, like the output of an async pass
+        sampled_textures: &[Handle<TextureDesc>],
         drawer: &Rc<Drawer<'static>>,
     ) {
         let glyph_atlas = self.glyph_atlas;
+        let glyph_atlas_desc = graph.import_image(device, glyph_atlas);
         let ui_program = self.ui_program;
+        let ui_variant =
+            vulkan::ShaderVariant::default().with_feature(UI_FEATURE_DEBUG, self.debug);
         let drawer = Rc::clone(drawer);
         let drawer2 = Rc::clone(&drawer);
 
-        let execute = move |_graph: &mut RenderGraph,
+        let execute = move |graph: &mut RenderGraph,
                             api: &mut PassApi,
                             ctx: &mut vulkan::ComputeContext|
               -> vulkan::VulkanResult<()> {
             use drawer2d::glyph_cache::GlyphEvent;
             let drawer = Rc::clone(&drawer);
+            let glyph_atlas = graph
+                .resources
+                .resolve_image(api.device, glyph_atlas_desc)?;
 
-            let mut glyphs_to_upload: Vec<vulkan::BufferImageCopy> = Vec::with_capacity(32);
+            let mut rejected_size = None;
             drawer
                 .glyph_cache()
                 .process_events(|cache_event, glyph_image, glyph_atlas_pos| {
-                    // Copy new glyphs to the upload buffer
+                    // Stage the new glyphs, they are copied in a single batch
                     if let GlyphEvent::New(_, _) = cache_event {
                         if let Some(atlas_pos) = glyph_atlas_pos {
                             let image = glyph_image.unwrap();
-                            let (slice, offset) = api.upload_buffer.allocate(image.data.len(), 256);
-                            unsafe {
-                                (*slice).copy_from_slice(&image.data);
+                            if !api.upload_manager.upload_image_region(
+                                api.device,
+                                glyph_atlas,
+                                [atlas_pos[0], atlas_pos[1], 0],
+                                [image.placement.width, image.placement.height, 1],
+                                &image.data,
+                            ) {
+                                rejected_size.get_or_insert(image.data.len());
                             }
-
-                            let image_offset = [atlas_pos[0], atlas_pos[1], 0];
-
-                            glyphs_to_upload.push(vulkan::BufferImageCopy {
-                                buffer_offset: offset as u64,
-                                buffer_size: image.data.len() as u32,
-                                image_offset,
-                                image_extent: [
-                                    image.placement.width as u32,
-                                    image.placement.height as u32,
-                                    1,
-                                ],
-                            });
                         }
                     }
                 });
-            if !glyphs_to_upload.is_empty() {
-                ctx.base_context().barrier(
-                    api.device,
-                    glyph_atlas,
-                    vulkan::ImageState::TransferDst,
-                );
-                ctx.transfer_mut().copy_buffer_to_image(
-                    api.device,
-                    api.upload_buffer.buffer,
-                    glyph_atlas,
-                    &glyphs_to_upload,
-                );
+            if let Some(requested) = rejected_size {
+                return Err(vulkan::VulkanError::RingBufferFull {
+                    requested,
+                    capacity: api.upload_manager.capacity(),
+                });
+            }
+
+            if api.upload_manager.has_pending_uploads() {
+                api.upload_manager
+                    .record_uploads(api.device, ctx.transfer_mut());
                 ctx.base_context().barrier(
                     api.device,
                     glyph_atlas,
@@ -127,81 +153,87 @@ impl UiPass {
 
             Ok(())
         };
+        graph.use_texture(glyph_atlas_desc, vk::ImageUsageFlags::TRANSFER_DST);
         graph.raw_pass(execute);
+        graph.set_pass_name("upload glyphs");
 
         let drawer = drawer2;
         let execute = move |graph: &mut RenderGraph,
                             api: &mut PassApi,
-                            ctx: &mut vulkan::GraphicsContext| {
+                            ctx: &mut vulkan::GraphicsContext|
+              -> vulkan::VulkanResult<()> {
             let vertices = drawer.get_vertices();
-            let (slice, vertices_offset) = api
-                .dynamic_vertex_buffer
-                .allocate(vertices.len(), Drawer::get_primitive_alignment());
-            unsafe {
-                (*slice).copy_from_slice(vertices);
-            }
+            let vertices_allocation = api.dynamic_vertex_buffer.allocate_slice_aligned::<u8>(
+                api.device,
+                vertices.len(),
+                Drawer::get_primitive_alignment(),
+            )?;
+            vertices_allocation.data.copy_from_slice(vertices);
+            let (vertices_buffer, vertices_offset) =
+                (vertices_allocation.buffer, vertices_allocation.offset);
+
             let indices = drawer.get_indices();
-            let indices_byte_length = indices.len() * size_of::<u32>();
-            let (slice, indices_offset) = api
+            let indices_allocation = api
                 .dynamic_index_buffer
-                .allocate(indices_byte_length, size_of::<u32>());
-            unsafe {
-                let gpu_indices = std::slice::from_raw_parts_mut(
-                    (*slice).as_mut_ptr() as *mut u32,
-                    (*slice).len() / size_of::<u32>(),
-                );
-                gpu_indices.copy_from_slice(indices);
-            }
-            #[repr(C, packed)]
-            struct Options {
-                pub scale: [f32; 2],
-                pub translation: [f32; 2],
-                pub vertices_descriptor_index: u32,
-                pub primitive_bytes_offset: u32,
-                pub glyph_atlas_descriptor: u32,
-            }
-
-            let options = bindings::bind_shader_options(
-                api.device,
-                api.uniform_buffer,
-                &ctx,
-                size_of::<Options>(),
-            )
-            .unwrap();
+                .allocate_slice::<u32>(api.device, indices.len())?;
+            indices_allocation.data.copy_from_slice(indices);
+            let (indices_buffer, indices_offset) =
+                (indices_allocation.buffer, indices_allocation.offset);
 
             let output_size = graph.image_size(output);
             let glyph_atlas_descriptor = api.device.images.get(glyph_atlas).full_view.sampled_idx;
-            unsafe {
-                let p_options =
-                    std::slice::from_raw_parts_mut((*options).as_ptr() as *mut Options, 1);
-                p_options[0] = Options {
-                    scale: [2.0 / (output_size[0] as f32), 2.0 / (output_size[1] as f32)],
-                    translation: [-1.0, -1.0],
-                    vertices_descriptor_index: api
-                        .device
-                        .buffers
-                        .get(api.dynamic_vertex_buffer.buffer)
-                        .storage_idx,
-                    primitive_bytes_offset: vertices_offset,
-                    glyph_atlas_descriptor,
-                };
-            }
+            let nearest_sampler_descriptor = api
+                .device
+                .get_or_create_sampler(vulkan::SamplerSpec::nearest())?;
+            let linear_sampler_descriptor = api
+                .device
+                .get_or_create_sampler(vulkan::SamplerSpec::linear())?;
+            let options = UiOptions {
+                scale: [2.0 / (output_size[0] as f32), 2.0 / (output_size[1] as f32)],
+                translation: [-1.0, -1.0],
+                vertices_descriptor_index: api.device.buffers.get(vertices_buffer).storage_idx,
+                primitive_bytes_offset: vertices_offset,
+                glyph_atlas_descriptor,
+                nearest_sampler_descriptor,
+                linear_sampler_descriptor,
+            };
+            bindings::bind_and_copy_shader_options(api.device, api.uniform_buffer, &ctx, options)?;
+
             ctx.bind_index_buffer(
                 api.device,
-                api.dynamic_index_buffer.buffer,
+                indices_buffer,
                 vk::IndexType::UINT32,
                 indices_offset as usize,
             );
-            ctx.bind_graphics_pipeline(api.device, ui_program, 0);
+            ctx.bind_graphics_variant(api.device, ui_program, 0, ui_variant)?;
             ctx.draw_indexed(
                 api.device,
                 vulkan::DrawIndexedOptions {
                     vertex_count: indices.len() as u32,
                     ..Default::default()
                 },
-            );
+            )?;
+
+            Ok(())
         };
 
-        graph.graphics_pass(output, execute);
+        graph.use_texture(glyph_atlas_desc, vk::ImageUsageFlags::SAMPLED);
+        for texture in sampled_textures {
+            graph.use_texture(*texture, vk::ImageUsageFlags::SAMPLED);
+        }
+        let attachment = if self.samples != vk::SampleCountFlagBits::_1 {
+            let multisampled_output = graph.output_image(
+                TextureDesc::new(
+                    String::from("ui multisampled"),
+                    TextureSize::Absolute(graph.image_size(output)),
+                )
+                .samples(self.samples),
+            );
+            Attachment::new(multisampled_output).resolve(output)
+        } else {
+            Attachment::new(output)
+        };
+        graph.graphics_pass(attachment, execute);
+        graph.set_pass_name("ui");
     }
 }
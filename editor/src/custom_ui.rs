@@ -24,6 +24,8 @@ impl Default for FpsHistogram {
 
 pub mod widgets {
     use drawer2d::{drawer::*, rect::*};
+    use exo::pool::Handle;
+    use render::{render_graph::graph::TextureDesc, vulkan::Shader};
 
     pub struct FpsHistogram<'a> {
         pub histogram: &'a super::FpsHistogram,
@@ -109,4 +111,144 @@ pub mod widgets {
             ColorU32::greyscale(255),
         );
     }
+
+    pub struct RenderGraphDebug<'a> {
+        pub dump: &'a render::render_graph::debug::GraphDump,
+        // The selected texture is blitted to this image at the end of every frame
+        pub preview_descriptor: u32,
+        pub rect: Rect,
+    }
+
+    fn draw_left_label(ui: &ui::Ui, drawer: &mut Drawer, label: &str, rect: Rect, color: ColorU32) {
+        let (label_run, label_layout) = drawer.shape_and_layout_text(&ui.theme.face(), label);
+        let pos = [rect.pos[0], Rect::center(rect, label_layout.size()).pos[1]];
+        drawer.draw_text_run(&label_run, &label_layout, pos, 0, color);
+    }
+
+    // Lists the passes of the last frame with the textures they use, clicking a texture previews it
+    pub fn render_graph_debug(
+        ui: &mut ui::Ui,
+        drawer: &mut Drawer,
+        widget: RenderGraphDebug,
+        selected_texture: &mut Option<Handle<TextureDesc>>,
+    ) {
+        let em = ui.em();
+        let mut list_rect = widget.rect.inset(0.5 * em);
+        let mut preview_rect = list_rect.split_right(0.5 * list_rect.size[0]);
+        let line_height = 1.5 * em;
+
+        'passes: for (i_pass, pass) in widget.dump.passes.iter().enumerate() {
+            if list_rect.size[1] < line_height {
+                break;
+            }
+            let line = list_rect.split_top(line_height);
+            draw_left_label(
+                ui,
+                drawer,
+                &format!("{}: {} ({})", i_pass, pass.name, pass.queue),
+                line,
+                ColorU32::greyscale(255),
+            );
+
+            let uses = pass
+                .reads
+                .iter()
+                .map(|i_texture| ("<-", *i_texture))
+                .chain(pass.writes.iter().map(|i_texture| ("->", *i_texture)));
+            for (direction, i_texture) in uses {
+                if list_rect.size[1] < line_height {
+                    break 'passes;
+                }
+                let mut line = list_rect.split_top(line_height);
+                let _indent = line.split_left(2.0 * em);
+                let texture = &widget.dump.textures[i_texture];
+                let label = format!("{} {}", direction, texture.name);
+                if ui.button(
+                    drawer,
+                    ui::Button::with_label(&label)
+                        .rect(line.inset(0.1 * em))
+                        .enabled(texture.previewable),
+                ) {
+                    *selected_texture = Some(texture.handle);
+                }
+            }
+        }
+
+        let selected = selected_texture.and_then(|handle| {
+            widget
+                .dump
+                .textures
+                .iter()
+                .find(|texture| texture.handle == handle)
+        });
+
+        if let Some(texture) = selected {
+            let title_rect = preview_rect.split_top(line_height);
+            draw_left_label(
+                ui,
+                drawer,
+                &format!(
+                    "{} {}x{} {:?} ({})",
+                    texture.name, texture.size[0], texture.size[1], texture.format, texture.origin
+                ),
+                title_rect,
+                ColorU32::greyscale(255),
+            );
+
+            if texture.previewable {
+                // Keep the aspect ratio of the texture
+                let scale = (preview_rect.size[0] / texture.size[0] as f32)
+                    .min(preview_rect.size[1] / texture.size[1] as f32);
+                let size = [
+                    texture.size[0] as f32 * scale,
+                    texture.size[1] as f32 * scale,
+                ];
+                let rect = Rect {
+                    pos: preview_rect.pos,
+                    size,
+                };
+                // Magnified textures are sampled without filtering to see the texels
+                let filter = if scale > 1.0 {
+                    TextureFilter::Nearest
+                } else {
+                    TextureFilter::Linear
+                };
+                drawer.draw_textured_rect(
+                    TexturedRect::new(rect)
+                        .texture_descriptor(widget.preview_descriptor)
+                        .filter(filter),
+                );
+                ui.state.add_rect_to_last_container(rect);
+            }
+        }
+    }
+
+    pub struct ShaderErrors<'a> {
+        pub errors: &'a [(Handle<Shader>, String)],
+        pub rect: Rect,
+    }
+
+    // One line per line of the compiler logs, the lines that don't fit are dropped
+    pub fn shader_errors(ui: &mut ui::Ui, drawer: &mut Drawer, widget: ShaderErrors) {
+        let em = ui.em();
+        let line_height = 1.5 * em;
+
+        drawer.draw_colored_rect(
+            ColoredRect::new(widget.rect).color(ColorU32::from_f32(0.3, 0.0, 0.0, 0.8)),
+        );
+        ui.state.add_rect_to_last_container(widget.rect);
+
+        let mut lines_rect = widget.rect.inset(0.5 * em);
+        for line in widget
+            .errors
+            .iter()
+            .flat_map(|(_shader, error)| error.lines())
+        {
+            if lines_rect.size[1] < line_height {
+                break;
+            }
+            let line_rect = lines_rect.split_top(line_height);
+            draw_left_label(ui, drawer, line, line_rect, ColorU32::greyscale(255));
+        }
+    }
 }
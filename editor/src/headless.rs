@@ -0,0 +1,480 @@
+use crate::App;
+use anyhow::Context;
+use std::path::Path;
+
+pub const HEADLESS_RESOLUTION: [i32; 2] = [1280, 720];
+// The first frames lay out the docking and upload the glyphs
+pub const HEADLESS_FRAME_COUNT: usize = 3;
+const HEADLESS_DT: f32 = 1.0 / 60.0;
+
+// Renders a few frames without a window and writes the last one to a PNG
+pub fn run_headless(output_path: &str) -> anyhow::Result<()> {
+    let mut app = App::new_headless(HEADLESS_RESOLUTION)?;
+
+    for _ in 0..HEADLESS_FRAME_COUNT {
+        app.update(HEADLESS_DT)?;
+    }
+    let pixels = app.renderer.base.read_pixels()?;
+    write_png(Path::new(output_path), HEADLESS_RESOLUTION, &pixels)?;
+
+    app.renderer.destroy();
+    Ok(())
+}
+
+pub fn write_png(path: &Path, size: [i32; 2], rgba8_pixels: &[u8]) -> anyhow::Result<()> {
+    let file = std::io::BufWriter::new(
+        std::fs::File::create(path).with_context(|| format!("creating {:?}", path))?,
+    );
+    let mut encoder = png::Encoder::new(file, size[0] as u32, size[1] as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgba8_pixels)?;
+    Ok(())
+}
+
+// Comparison of rendered frames against checked-in reference images
+#[cfg(test)]
+mod golden {
+    use super::*;
+    use anyhow::bail;
+    use std::path::PathBuf;
+
+    pub fn read_png(path: &Path) -> anyhow::Result<([i32; 2], Vec<u8>)> {
+        let file = std::fs::File::open(path).with_context(|| format!("opening {:?}", path))?;
+        let mut reader = png::Decoder::new(file).read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels)?;
+
+        if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+            bail!("{:?} is not a RGBA8 image", path);
+        }
+        pixels.truncate(info.buffer_size());
+
+        Ok(([info.width as i32, info.height as i32], pixels))
+    }
+
+    // Software drivers and GPUs rasterize slightly differently, a few pixels are allowed to differ
+    #[derive(Clone, Copy)]
+    pub struct Tolerance {
+        pub max_channel_difference: u8,
+        pub max_different_pixels_ratio: f32,
+    }
+
+    impl Default for Tolerance {
+        fn default() -> Self {
+            Self {
+                max_channel_difference: 8,
+                max_different_pixels_ratio: 0.001,
+            }
+        }
+    }
+
+    fn golden_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("golden")
+    }
+
+    fn output_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("target")
+            .join("golden")
+    }
+
+    pub struct PixelComparison {
+        pub different_pixels: usize,
+        // Differing pixels in red over a dimmed version of the frame
+        pub diff: Vec<u8>,
+    }
+
+    impl Tolerance {
+        pub fn accepts(&self, comparison: &PixelComparison, pixel_count: usize) -> bool {
+            comparison.different_pixels as f32
+                <= self.max_different_pixels_ratio * pixel_count as f32
+        }
+    }
+
+    pub fn compare_pixels(actual: &[u8], expected: &[u8], tolerance: Tolerance) -> PixelComparison {
+        assert_eq!(actual.len(), expected.len());
+
+        let mut diff = Vec::with_capacity(actual.len());
+        let mut different_pixels = 0;
+        for (actual, expected) in actual.chunks_exact(4).zip(expected.chunks_exact(4)) {
+            let difference = actual
+                .iter()
+                .zip(expected)
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+
+            if difference > tolerance.max_channel_difference {
+                different_pixels += 1;
+                diff.extend_from_slice(&[255, 0, 0, 255]);
+            } else {
+                // Keep a dimmed version of the frame to locate the differences
+                let luminance =
+                    ((actual[0] as u32 + actual[1] as u32 + actual[2] as u32) / 12) as u8;
+                diff.extend_from_slice(&[luminance, luminance, luminance, 255]);
+            }
+        }
+
+        PixelComparison {
+            different_pixels,
+            diff,
+        }
+    }
+
+    // Compares a frame against `tests/golden/<name>.png`. On failure the frame and a diff image are written to
+    // `target/golden`, setting `UPDATE_GOLDEN=1` overwrites the reference instead.
+    pub fn compare_with_reference(
+        name: &str,
+        size: [i32; 2],
+        pixels: &[u8],
+        tolerance: Tolerance,
+    ) -> anyhow::Result<()> {
+        compare_in_dirs(
+            &golden_dir(),
+            &output_dir(),
+            std::env::var_os("UPDATE_GOLDEN").is_some(),
+            name,
+            size,
+            pixels,
+            tolerance,
+        )
+    }
+
+    fn compare_in_dirs(
+        golden_dir: &Path,
+        output_dir: &Path,
+        update: bool,
+        name: &str,
+        size: [i32; 2],
+        pixels: &[u8],
+        tolerance: Tolerance,
+    ) -> anyhow::Result<()> {
+        let reference_path = golden_dir.join(format!("{}.png", name));
+
+        if update {
+            std::fs::create_dir_all(golden_dir)?;
+            return write_png(&reference_path, size, pixels);
+        }
+
+        std::fs::create_dir_all(output_dir)?;
+        let actual_path = output_dir.join(format!("{}.actual.png", name));
+
+        if !reference_path.exists() {
+            write_png(&actual_path, size, pixels)?;
+            bail!(
+                "missing reference {:?}, the frame was written to {:?}. Run with UPDATE_GOLDEN=1 to create it.",
+                reference_path,
+                actual_path
+            );
+        }
+
+        let (reference_size, reference) = read_png(&reference_path)?;
+        if reference_size != size {
+            write_png(&actual_path, size, pixels)?;
+            bail!(
+                "{}: the frame is {:?} but the reference is {:?}",
+                name,
+                size,
+                reference_size
+            );
+        }
+
+        let comparison = compare_pixels(pixels, &reference, tolerance);
+        if !tolerance.accepts(&comparison, (size[0] * size[1]) as usize) {
+            let diff_path = output_dir.join(format!("{}.diff.png", name));
+            write_png(&actual_path, size, pixels)?;
+            write_png(&diff_path, size, &comparison.diff)?;
+            bail!(
+                "{}: {} pixels differ from the reference, see {:?} and {:?}",
+                name,
+                comparison.different_pixels,
+                actual_path,
+                diff_path
+            );
+        }
+
+        Ok(())
+    }
+
+    mod tests {
+        use super::*;
+
+        fn solid(size: [i32; 2], color: [u8; 4]) -> Vec<u8> {
+            color.repeat((size[0] * size[1]) as usize)
+        }
+
+        // A directory per test, the tests run in parallel
+        fn scratch_dir(name: &str) -> PathBuf {
+            let dir = output_dir().join("scratch").join(name);
+            let _ = std::fs::remove_dir_all(&dir);
+            dir
+        }
+
+        #[test]
+        fn small_channel_differences_are_tolerated() {
+            let tolerance = Tolerance::default();
+            let expected = solid([4, 4], [100, 100, 100, 255]);
+            let actual = solid([4, 4], [108, 92, 100, 255]);
+            let comparison = compare_pixels(&actual, &expected, tolerance);
+            assert_eq!(comparison.different_pixels, 0);
+
+            let actual = solid([4, 4], [109, 100, 100, 255]);
+            let comparison = compare_pixels(&actual, &expected, tolerance);
+            assert_eq!(comparison.different_pixels, 16);
+        }
+
+        #[test]
+        fn ratio_of_different_pixels_is_tolerated() {
+            let tolerance = Tolerance {
+                max_channel_difference: 0,
+                max_different_pixels_ratio: 0.25,
+            };
+            let expected = solid([4, 4], [0, 0, 0, 255]);
+            let mut actual = expected.clone();
+            for pixel in actual.chunks_exact_mut(4).take(4) {
+                pixel[1] = 255;
+            }
+            let comparison = compare_pixels(&actual, &expected, tolerance);
+            assert_eq!(comparison.different_pixels, 4);
+            assert!(tolerance.accepts(&comparison, 16));
+
+            actual[4 * 4 + 2] = 255;
+            let comparison = compare_pixels(&actual, &expected, tolerance);
+            assert_eq!(comparison.different_pixels, 5);
+            assert!(!tolerance.accepts(&comparison, 16));
+        }
+
+        #[test]
+        fn diff_marks_different_pixels_in_red() {
+            let expected = solid([2, 1], [120, 120, 120, 255]);
+            let actual = [120, 120, 120, 255, 0, 0, 0, 255];
+            let comparison = compare_pixels(&actual, &expected, Tolerance::default());
+            assert_eq!(comparison.diff, [30, 30, 30, 255, 255, 0, 0, 255]);
+        }
+
+        #[test]
+        fn png_round_trip() {
+            let dir = scratch_dir("png_round_trip");
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("image.png");
+            let pixels: Vec<u8> = (0..2 * 3 * 4).map(|i| (i * 10) as u8).collect();
+
+            write_png(&path, [2, 3], &pixels).unwrap();
+            let (size, read) = read_png(&path).unwrap();
+            assert_eq!(size, [2, 3]);
+            assert_eq!(read, pixels);
+        }
+
+        #[test]
+        fn reference_workflow() {
+            let golden = scratch_dir("reference_workflow_golden");
+            let output = scratch_dir("reference_workflow_output");
+            let tolerance = Tolerance::default();
+            let frame = solid([8, 8], [10, 20, 30, 255]);
+            let compare = |update, size, pixels: &[u8]| {
+                compare_in_dirs(&golden, &output, update, "frame", size, pixels, tolerance)
+            };
+
+            // A missing reference fails and keeps the frame to inspect it
+            assert!(compare(false, [8, 8], &frame).is_err());
+            assert!(output.join("frame.actual.png").exists());
+
+            compare(true, [8, 8], &frame).unwrap();
+            compare(false, [8, 8], &frame).unwrap();
+
+            assert!(compare(false, [4, 16], &frame).is_err());
+
+            let other = solid([8, 8], [200, 20, 30, 255]);
+            assert!(compare(false, [8, 8], &other).is_err());
+            let (_, diff) = read_png(&output.join("frame.diff.png")).unwrap();
+            assert_eq!(&diff[..4], &[255, 0, 0, 255]);
+        }
+    }
+}
+
+// These tests need a Vulkan driver and are skipped without one, a software implementation like lavapipe works.
+// The references in `tests/golden` are created with `UPDATE_GOLDEN=1 cargo test -p editor`.
+#[cfg(test)]
+mod tests {
+    use super::golden::*;
+    use super::*;
+    use drawer2d::{drawer::*, rect::*};
+    use render::vulkan;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+
+    // The drawer memory is static, only one app can exist at a time
+    static APP_LOCK: Mutex<()> = Mutex::new(());
+
+    // None when there is no Vulkan driver
+    fn new_app(name: &str) -> Option<App> {
+        match App::new_headless(HEADLESS_RESOLUTION) {
+            Ok(app) => Some(app),
+            Err(
+                error @ (vulkan::VulkanError::LoaderMissing(_)
+                | vulkan::VulkanError::NoSuitableDevice(_)),
+            ) => {
+                eprintln!("skipping {}: {}", name, error);
+                None
+            }
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    fn render_script(name: &str, mut script: impl FnMut(&mut App) -> anyhow::Result<()>) {
+        let _lock = APP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let Some(mut app) = new_app(name) else {
+            return;
+        };
+        for _ in 0..HEADLESS_FRAME_COUNT {
+            script(&mut app).unwrap();
+        }
+        let pixels = app.renderer.base.read_pixels().unwrap();
+        app.renderer.destroy();
+
+        compare_with_reference(name, HEADLESS_RESOLUTION, &pixels, Tolerance::default()).unwrap();
+    }
+
+    #[test]
+    fn device_lost_recovery() {
+        let _lock = APP_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let Some(mut app) = new_app("device_lost_recovery") else {
+            return;
+        };
+        app.update(HEADLESS_DT).unwrap();
+        // The injected loss destroys the previous device without waiting for it
+        app.renderer.base.device.wait_idle().unwrap();
+
+        let lost_device = app.renderer.base.device.device.handle;
+        app.handle_render_result(Err(vulkan::VulkanError::DeviceLost {
+            operation: "an injected frame",
+            object: None,
+        }))
+        .unwrap();
+
+        // The new device is created before the lost one is destroyed, their handles differ
+        assert_ne!(app.renderer.base.device.device.handle, lost_device);
+        assert!(app.renderer.base.shader_errors.is_empty());
+
+        // The glyphs are uploaded again to the new atlas during the next frames
+        for _ in 0..HEADLESS_FRAME_COUNT {
+            app.update(HEADLESS_DT).unwrap();
+        }
+        let pixels = app.renderer.base.read_pixels().unwrap();
+        app.renderer.destroy();
+
+        compare_with_reference(
+            "device_lost_recovery",
+            HEADLESS_RESOLUTION,
+            &pixels,
+            Tolerance::default(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn editor_layout() {
+        // Docking with the demo tab showing the compute output of `DemoNode`
+        render_script("editor_layout", |app| {
+            app.update(HEADLESS_DT)?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn ui_widgets() {
+        render_script("ui_widgets", |app| {
+            let drawer = Rc::get_mut(&mut app.drawer).unwrap();
+            drawer.clear();
+            app.ui.new_frame();
+
+            let em = app.ui.em();
+            let mut rect = Rect {
+                pos: [0.0, 0.0],
+                size: [HEADLESS_RESOLUTION[0] as f32, HEADLESS_RESOLUTION[1] as f32],
+            }
+            .inset(em);
+
+            let mut button_rect = rect.split_top(2.0 * em);
+            app.ui.button(
+                drawer,
+                ui::Button::with_label("Button")
+                    .rect(button_rect.split_left(10.0 * em).inset(0.25 * em)),
+            );
+            app.ui.button(
+                drawer,
+                ui::Button::with_label("Disabled")
+                    .rect(button_rect.split_left(10.0 * em).inset(0.25 * em))
+                    .enabled(false),
+            );
+
+            let colored_rect = rect.split_top(4.0 * em).split_left(8.0 * em);
+            drawer.draw_colored_rect(
+                ColoredRect::new(colored_rect.inset(0.25 * em))
+                    .color(ColorU32::from_f32(0.2, 0.6, 0.9, 1.0))
+                    .border_radius(em),
+            );
+
+            let label_rect = rect.split_top(2.0 * em);
+            drawer.draw_label(
+                &app.ui.theme.face(),
+                "The quick brown fox jumps over the lazy dog",
+                label_rect,
+                !0u32,
+                ColorU32::greyscale(255),
+            );
+
+            app.ui.end_frame();
+            app.renderer.render(Some(&app.drawer), None, HEADLESS_DT)?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn drawer2d_primitives() {
+        // Rounded corners, blending of overlapping rects and glyphs sampled from the atlas
+        render_script("drawer2d_primitives", |app| {
+            let drawer = Rc::get_mut(&mut app.drawer).unwrap();
+            drawer.clear();
+
+            let colors = [
+                ColorU32::red(),
+                ColorU32::green(),
+                ColorU32::blue(),
+                ColorU32::from_f32(1.0, 1.0, 1.0, 0.5),
+            ];
+            let rects: Vec<ColoredRect> = colors
+                .iter()
+                .enumerate()
+                .map(|(i, color)| {
+                    let offset = 40.0 * i as f32;
+                    ColoredRect::new(Rect {
+                        pos: [20.0 + offset, 20.0 + offset],
+                        size: [160.0, 100.0],
+                    })
+                    .color(*color)
+                    .border_radius(8.0 * i as f32)
+                })
+                .collect();
+            drawer.draw_colored_rects(&rects);
+
+            let face = app.ui.theme.face();
+            for (i, text) in ["Drawer2d", "0123456789 !?#&"].iter().enumerate() {
+                let rect = Rect {
+                    pos: [400.0, 20.0 + 60.0 * i as f32],
+                    size: [400.0, 60.0],
+                };
+                drawer.draw_label(&face, text, rect, !0u32, ColorU32::greyscale(255));
+            }
+
+            app.renderer.render(Some(&app.drawer), None, HEADLESS_DT)?;
+            Ok(())
+        });
+    }
+}
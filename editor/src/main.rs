@@ -3,12 +3,14 @@
 
 mod custom_render;
 mod custom_ui;
+mod headless;
 mod simple_renderer;
 
-use crate::simple_renderer::SimpleRenderer;
+use crate::simple_renderer::{OutputNode, SimpleRenderer};
 use drawer2d::{drawer::*, font::*, rect::*};
+use exo::pool::Handle;
 use raw_window_handle::HasRawWindowHandle;
-use render::{render_graph, shader, vulkan, vulkan::error::VulkanResult};
+use render::{render_graph, vk, vulkan, vulkan::error::VulkanResult};
 use std::{cell::RefCell, rc::Rc, time::Instant};
 use winit::{
     event::{ElementState, Event, MouseButton, WindowEvent},
@@ -20,11 +22,15 @@ use winit::{
 static mut DRAWER_VERTEX_MEMORY: [u8; 64 << 10] = [0; 64 << 10];
 static mut DRAWER_INDEX_MEMORY: [u32; 8 << 10] = [0; 8 << 10];
 const GLYPH_ATLAS_RESOLUTION: i32 = 4096;
+const GRAPH_PREVIEW_RESOLUTION: i32 = 512;
 
 struct Renderer {
     base: simple_renderer::SimpleRenderer,
     ui_node: custom_render::UiPass,
     demo_node: Rc<RefCell<custom_render::DemoNode>>,
+    // Texture of the render graph shown in the debug view, see `ResourceRegistry::is_previewable`
+    pub graph_preview_source: Option<Handle<render_graph::graph::TextureDesc>>,
+    graph_preview: Handle<vulkan::Image>,
 }
 
 impl Renderer {
@@ -32,7 +38,14 @@ impl Renderer {
         window_handle: &WindowHandle,
         window_size: [i32; 2],
     ) -> vulkan::VulkanResult<Self> {
-        let mut simple_renderer = SimpleRenderer::new(window_handle, window_size)?;
+        Self::from_simple_renderer(SimpleRenderer::new(window_handle, window_size)?)
+    }
+
+    pub fn new_headless(size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        Self::from_simple_renderer(SimpleRenderer::new_headless(size)?)
+    }
+
+    fn from_simple_renderer(mut simple_renderer: SimpleRenderer) -> vulkan::VulkanResult<Self> {
         let ui_node = custom_render::UiPass::new(
             &mut simple_renderer.device,
             [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
@@ -40,18 +53,67 @@ impl Renderer {
         let demo_node = Rc::new(RefCell::new(custom_render::DemoNode::new(
             &mut simple_renderer.device,
         )?));
+        let graph_preview = Self::create_graph_preview(&mut simple_renderer.device)?;
 
         Ok(Self {
             base: simple_renderer,
             ui_node,
             demo_node,
+            graph_preview_source: None,
+            graph_preview,
         })
     }
 
+    fn create_graph_preview(
+        device: &mut vulkan::Device,
+    ) -> vulkan::VulkanResult<Handle<vulkan::Image>> {
+        device.create_image(vulkan::ImageSpec {
+            name: String::from("render graph preview"),
+            size: [GRAPH_PREVIEW_RESOLUTION, GRAPH_PREVIEW_RESOLUTION, 1],
+            format: vk::Format::R8G8B8A8_UNORM,
+            usages: vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            ..Default::default()
+        })
+    }
+
+    pub fn get_graph_preview_descriptor(&self) -> u32 {
+        self.base
+            .device
+            .images
+            .get(self.graph_preview)
+            .full_view
+            .sampled_idx
+    }
+
     pub fn destroy(self) {
         self.base.destroy();
     }
 
+    // The passes' programs and images belonged to the lost device
+    pub fn recover_from_device_lost(&mut self) -> vulkan::VulkanResult<()> {
+        let ui_node = &self.ui_node;
+        let demo_node = &self.demo_node;
+        let graph_preview = self.graph_preview;
+        self.base.recover_from_device_lost(|lost_device| {
+            ui_node.destroy(lost_device);
+            demo_node.borrow().destroy(lost_device);
+            lost_device.destroy_image(graph_preview);
+        })?;
+
+        let debug = self.ui_node.debug;
+        self.ui_node = custom_render::UiPass::new(
+            &mut self.base.device,
+            [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
+        )?;
+        self.ui_node.debug = debug;
+        self.demo_node = Rc::new(RefCell::new(custom_render::DemoNode::new(
+            &mut self.base.device,
+        )?));
+        self.demo_node.borrow_mut().debug = debug;
+        self.graph_preview = Self::create_graph_preview(&mut self.base.device)?;
+        Ok(())
+    }
+
     pub fn get_glyph_atlas_descriptor(&self) -> u32 {
         self.base
             .device
@@ -77,6 +139,7 @@ impl Renderer {
             TextureSize::ScreenRelative([1.0, 1.0]),
         ));
 
+        let mut sampled_textures = Vec::new();
         if let Some(viewport_size) = demo_viewport {
             let demo_buffer = self.base.render_graph.output_image(TextureDesc::new(
                 String::from("demo viewport"),
@@ -90,11 +153,31 @@ impl Renderer {
                 dt,
                 self.base.time,
             );
+            sampled_textures.push(demo_buffer);
         }
 
         if let Some(drawer) = drawer {
-            self.ui_node
-                .register_graph(&mut self.base.render_graph, intermediate_buffer, drawer);
+            let graph_preview = self
+                .base
+                .render_graph
+                .import_image(&self.base.device, self.graph_preview);
+            sampled_textures.push(graph_preview);
+
+            self.ui_node.register_graph(
+                &mut self.base.render_graph,
+                &self.base.device,
+                intermediate_buffer,
+                &sampled_textures,
+                drawer,
+            );
+
+            // Copied after the UI pass, the UI shows the texture of the previous frame
+            if let Some(source) = self.graph_preview_source {
+                let graph = &mut self.base.render_graph;
+                if graph.resources.is_previewable(&self.base.device, source) {
+                    render_graph::builtins::blit_image(graph, source, graph_preview);
+                }
+            }
         }
 
         self.base.render(intermediate_buffer, dt)?;
@@ -116,11 +199,65 @@ struct App {
 }
 
 impl App {
+    pub fn new(renderer: Renderer, window_size: [i32; 2], scale_factor: f32) -> Self {
+        let ui_font = Font::from_file(
+            concat!(env!("OUT_DIR"), "/", "iAWriterQuattroS-Regular.ttf"),
+            0,
+        )
+        .unwrap();
+
+        let drawer = Drawer::new(
+            unsafe { &mut DRAWER_VERTEX_MEMORY },
+            unsafe { &mut DRAWER_INDEX_MEMORY },
+            [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
+            renderer.get_glyph_atlas_descriptor(),
+        );
+
+        let font_size = 18.0;
+        let ui = ui::Ui::new(Rc::new(ui_font), font_size * scale_factor);
+
+        Self {
+            renderer,
+            drawer: Rc::new(drawer),
+            ui,
+            fps_histogram: custom_ui::FpsHistogram::new(),
+            docking: ui_docking::Docking::new(),
+            show_fps: true,
+            font_size,
+            window_size: [window_size[0] as f32, window_size[1] as f32],
+            demo_viewport: None,
+        }
+    }
+
+    pub fn new_headless(size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        Ok(Self::new(Renderer::new_headless(size)?, size, 1.0))
+    }
+
     pub fn update(&mut self, dt: f32) -> vulkan::VulkanResult<()> {
         self.fps_histogram.push_time(dt);
         self.draw_ui();
-        self.renderer
-            .render(Some(&self.drawer), self.demo_viewport, dt)
+        let result = self
+            .renderer
+            .render(Some(&self.drawer), self.demo_viewport, dt);
+        self.handle_render_result(result)
+    }
+
+    // A lost device is recreated with everything created from it, the other errors are returned
+    fn handle_render_result(
+        &mut self,
+        result: vulkan::VulkanResult<()>,
+    ) -> vulkan::VulkanResult<()> {
+        match result {
+            Err(error) if error.is_device_lost() => {
+                eprintln!("{}, recreating the device", error);
+                self.renderer.recover_from_device_lost()?;
+                // The glyphs have to be uploaded again to the new atlas
+                let drawer = Rc::get_mut(&mut self.drawer).unwrap();
+                drawer.reset_glyph_atlas(self.renderer.get_glyph_atlas_descriptor());
+                Ok(())
+            }
+            result => result,
+        }
     }
 
     pub fn draw_ui(&mut self) {
@@ -151,7 +288,55 @@ impl App {
             self.demo_viewport = None;
         }
 
-        if let Some(_options_rect) = self.docking.tabview("Options") {}
+        if let Some(options_rect) = self.docking.tabview("Options") {
+            let button_rect = Rect {
+                pos: options_rect.inset(0.5 * em).pos,
+                size: [12.0 * em, 1.5 * em],
+            };
+            if self.ui.button(
+                drawer,
+                ui::Button::with_label("Toggle debug shaders").rect(button_rect),
+            ) {
+                let debug = !self.renderer.ui_node.debug;
+                self.renderer.ui_node.debug = debug;
+                self.renderer.demo_node.borrow_mut().debug = debug;
+            }
+        }
+
+        if let Some(mut graph_rect) = self.docking.tabview("Render graph") {
+            self.renderer.base.render_graph.request_dump();
+            let render_graph = &self.renderer.base.render_graph;
+            let toolbar_rect = graph_rect.split_top(2.0 * em).inset(0.25 * em);
+            let export_rect = Rect {
+                pos: toolbar_rect.pos,
+                size: [8.0 * em, toolbar_rect.size[1]],
+            };
+
+            // The first frame the tab is shown, no frame has been dumped yet
+            if let Some(dump) = render_graph.last_frame() {
+                if self
+                    .ui
+                    .button(drawer, ui::Button::with_label("Export").rect(export_rect))
+                {
+                    let result = std::fs::write("render_graph.dot", dump.to_dot())
+                        .and_then(|_| std::fs::write("render_graph.json", dump.to_json()));
+                    if let Err(e) = result {
+                        eprintln!("Failed to export the render graph: {:?}", e);
+                    }
+                }
+
+                custom_ui::widgets::render_graph_debug(
+                    &mut self.ui,
+                    drawer,
+                    custom_ui::widgets::RenderGraphDebug {
+                        dump,
+                        preview_descriptor: self.renderer.get_graph_preview_descriptor(),
+                        rect: graph_rect,
+                    },
+                    &mut self.renderer.graph_preview_source,
+                );
+            }
+        }
 
         self.docking.end_docking(&mut self.ui, drawer);
 
@@ -174,6 +359,25 @@ impl App {
             );
         }
 
+        // -- Shader errors
+        if !self.renderer.base.shader_errors.is_empty() {
+            let errors_rect = Rect {
+                pos: [
+                    fullscreen.pos[0] + 1.0 * em,
+                    fullscreen.pos[1] + fullscreen.size[1] - 11.0 * em,
+                ],
+                size: [fullscreen.size[0] - 2.0 * em, 10.0 * em],
+            };
+            custom_ui::widgets::shader_errors(
+                &mut self.ui,
+                drawer,
+                custom_ui::widgets::ShaderErrors {
+                    errors: &self.renderer.base.shader_errors,
+                    rect: errors_rect,
+                },
+            );
+        }
+
         self.ui.end_frame();
     }
 }
@@ -181,6 +385,17 @@ impl App {
 fn main() {
     profile::init();
 
+    // `--headless [output.png]` renders without a window, for CI with a software Vulkan driver
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i_arg) = args.iter().position(|arg| arg == "--headless") {
+        let output_path = args.get(i_arg + 1).map_or("headless.png", String::as_str);
+        if let Err(e) = headless::run_headless(output_path) {
+            eprintln!("Headless rendering failed: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_title("Editor")
@@ -192,34 +407,8 @@ fn main() {
         [window_size.width as i32, window_size.height as i32]
     };
 
-    let ui_font = Font::from_file(
-        concat!(env!("OUT_DIR"), "/", "iAWriterQuattroS-Regular.ttf"),
-        0,
-    )
-    .unwrap();
-
     let renderer = Renderer::new(&window, inner_size).unwrap();
-    let drawer = Drawer::new(
-        unsafe { &mut DRAWER_VERTEX_MEMORY },
-        unsafe { &mut DRAWER_INDEX_MEMORY },
-        [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
-        renderer.get_glyph_atlas_descriptor(),
-    );
-
-    let font_size = 18.0;
-    let ui = ui::Ui::new(Rc::new(ui_font), font_size * (window.scale_factor() as f32));
-
-    let mut app = App {
-        renderer,
-        drawer: Rc::new(drawer),
-        ui,
-        fps_histogram: custom_ui::FpsHistogram::new(),
-        docking: ui_docking::Docking::new(),
-        show_fps: true,
-        font_size,
-        window_size: [inner_size[0] as f32, inner_size[1] as f32],
-        demo_viewport: None,
-    };
+    let mut app = App::new(renderer, inner_size, window.scale_factor() as f32);
 
     let now = Instant::now();
     let mut last_time = now.elapsed();
@@ -241,10 +430,12 @@ fn main() {
                 window_id,
             } if window_id == window.id() => {
                 let window_size: winit::dpi::LogicalSize<f32> = physical_size.to_logical(1.0);
-                let mut surface = &mut app.renderer.base.swapchain_node.borrow_mut().surface;
-                surface.is_outdated = true;
-                surface.size_requested =
-                    Some([window_size.width as i32, window_size.height as i32]);
+                if let OutputNode::Swapchain(swapchain_node) = &app.renderer.base.output_node {
+                    let surface = &mut swapchain_node.borrow_mut().surface;
+                    surface.is_outdated = true;
+                    surface.size_requested =
+                        Some([window_size.width as i32, window_size.height as i32]);
+                }
                 app.window_size = [window_size.width, window_size.height];
             }
 
@@ -1,11 +1,45 @@
 use exo::dynamic_array::DynamicArray;
 use exo::pool::Handle;
 use raw_window_handle::HasRawWindowHandle;
-use render::{render_graph, ring_buffer::*, shader, vk, vulkan, vulkan::error::VulkanResult};
+use render::{
+    render_graph, ring_buffer::*, shader, upload::UploadManager, vk, vulkan,
+    vulkan::error::VulkanResult,
+};
 use render_graph::{builtins, graph::TextureDesc};
-use std::{cell::RefCell, ffi::CStr, os::raw::c_char, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 const FRAME_QUEUE_LENGTH: usize = 2;
+const UPLOAD_STAGING_SIZE: usize = 32 << 20;
+
+// The node presenting or reading back the final image of a frame
+pub enum OutputNode {
+    Swapchain(Rc<RefCell<builtins::SwapchainPass>>),
+    Offscreen(Rc<RefCell<builtins::OffscreenPass>>),
+}
+
+impl OutputNode {
+    fn i_frame(&self) -> usize {
+        match self {
+            Self::Swapchain(node) => node.borrow().i_frame,
+            Self::Offscreen(node) => node.borrow().i_frame,
+        }
+    }
+
+    fn wait_for_frame(&self, device: &vulkan::Device, wait_value: u64) -> VulkanResult<()> {
+        let wait_values = [wait_value];
+        match self {
+            Self::Swapchain(node) => device.wait_for_fences(&[&node.borrow().fence], &wait_values),
+            Self::Offscreen(node) => device.wait_for_fences(&[&node.borrow().fence], &wait_values),
+        }
+    }
+}
+
+// Next to the executable, the working directory can be anywhere
+fn pipeline_cache_path() -> Option<std::path::PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("pipeline_cache.bin"))
+}
 
 pub struct SimpleRenderer {
     pub instance: vulkan::Instance,
@@ -16,12 +50,14 @@ pub struct SimpleRenderer {
     pub uniform_buffer: RingBuffer,
     pub dynamic_vertex_buffer: RingBuffer,
     pub dynamic_index_buffer: RingBuffer,
-    pub upload_buffer: RingBuffer,
+    pub upload_manager: UploadManager,
     pub render_graph: render_graph::graph::RenderGraph,
-    pub swapchain_node: Rc<RefCell<render_graph::builtins::SwapchainPass>>,
+    pub output_node: OutputNode,
     pub frame_count: usize,
     pub time: f32,
     pub shader_watcher: shader::ShaderWatcher,
+    // Shaders whose last reload failed, they keep running their previous version
+    pub shader_errors: Vec<(Handle<vulkan::Shader>, String)>,
 }
 
 impl SimpleRenderer {
@@ -31,61 +67,92 @@ impl SimpleRenderer {
     ) -> vulkan::VulkanResult<Self> {
         let instance = vulkan::Instance::new(vulkan::InstanceSpec {
             enable_validation: cfg!(debug_assertions),
+            window_handle: Some(window_handle.raw_window_handle()),
             ..Default::default()
         })?;
-        let mut physical_devices = instance.get_physical_devices()?;
-
-        let mut i_selected = None;
-        for (i_device, physical_device) in (&physical_devices).into_iter().enumerate() {
-            let device_name =
-                unsafe { CStr::from_ptr(&physical_device.properties.device_name as *const c_char) };
-            println!("Found device: {:?}", device_name);
-            if i_selected.is_none()
-                && physical_device.properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
-            {
-                println!(
-                    "Prioritizing device {:?} because it is a discrete GPU.",
-                    device_name
-                );
-                i_selected = Some(i_device);
-            }
-        }
-
-        if i_selected.is_none() {
-            i_selected = Some(0);
-            let device_name = unsafe {
-                CStr::from_ptr(&physical_devices[0].properties.device_name as *const c_char)
-            };
-            println!(
-                "No discrete GPU found, defaulting to device #0 {:?}.",
-                device_name
-            )
-        }
-
-        let i_selected = i_selected.unwrap();
-        let physical_device = &mut physical_devices[i_selected];
-
-        let mut device = vulkan::Device::new(
-            &instance,
-            vulkan::DeviceSpec {
-                push_constant_size: 8,
-            },
-            physical_device,
-        )?;
+        let (mut physical_devices, i_selected, mut device) = Self::create_device(&instance)?;
 
         let surface = vulkan::Surface::new(
             &instance,
             &mut device,
-            physical_device,
+            &mut physical_devices[i_selected],
             window_handle,
             Some(window_size),
         )?;
-        let swapchain_node = Rc::new(RefCell::new(render_graph::builtins::SwapchainPass {
+        let swapchain_node = Rc::new(RefCell::new(builtins::SwapchainPass {
             i_frame: 0,
             fence: device.create_fence()?,
             surface,
         }));
 
+        Self::from_device(
+            instance,
+            physical_devices,
+            i_selected,
+            device,
+            OutputNode::Swapchain(swapchain_node),
+        )
+    }
+
+    // Renders without a window, see `read_pixels` to get the result of a frame
+    pub fn new_headless(size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        let instance = vulkan::Instance::new(vulkan::InstanceSpec {
+            enable_validation: cfg!(debug_assertions),
+            enable_graphic_windows: false,
+            panic_on_validation_error: cfg!(test),
+            ..Default::default()
+        })?;
+        let (physical_devices, i_selected, mut device) = Self::create_device(&instance)?;
+
+        let offscreen_node = Rc::new(RefCell::new(builtins::OffscreenPass::new(
+            &mut device,
+            size,
+        )?));
+
+        Self::from_device(
+            instance,
+            physical_devices,
+            i_selected,
+            device,
+            OutputNode::Offscreen(offscreen_node),
+        )
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn create_device(
+        instance: &vulkan::Instance,
+    ) -> VulkanResult<(
+        DynamicArray<vulkan::PhysicalDevice, { vulkan::MAX_PHYSICAL_DEVICES }>,
+        usize,
+        vulkan::Device,
+    )> {
+        let mut physical_devices = instance.get_physical_devices()?;
+
+        let i_selected = vulkan::select_physical_device_from_env(
+            &physical_devices,
+            &vulkan::PhysicalDeviceRequirements::default(),
+        )?;
+        let physical_device = &mut physical_devices[i_selected];
+
+        let device = vulkan::Device::new(
+            instance,
+            vulkan::DeviceSpec {
+                push_constant_size: 8,
+                pipeline_cache_path: pipeline_cache_path(),
+            },
+            physical_device,
+        )?;
+
+        Ok((physical_devices, i_selected, device))
+    }
+
+    fn from_device(
+        instance: vulkan::Instance,
+        physical_devices: DynamicArray<vulkan::PhysicalDevice, { vulkan::MAX_PHYSICAL_DEVICES }>,
+        i_device: usize,
+        mut device: vulkan::Device,
+        output_node: OutputNode,
+    ) -> VulkanResult<Self> {
         let context_pools: [vulkan::ContextPool; FRAME_QUEUE_LENGTH] =
             [device.create_context_pool()?, device.create_context_pool()?];
 
@@ -96,6 +163,7 @@ impl SimpleRenderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 1024,
+                allow_growth: true,
             },
         )?;
 
@@ -106,6 +174,7 @@ impl SimpleRenderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 128 << 10,
+                allow_growth: true,
             },
         )?;
 
@@ -116,18 +185,12 @@ impl SimpleRenderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 128 << 10,
+                allow_growth: true,
             },
         )?;
 
-        let upload_buffer = RingBuffer::new(
-            &mut device,
-            RingBufferSpec {
-                usages: vk::BufferUsageFlags::TRANSFER_SRC,
-                memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
-                frame_queue_length: FRAME_QUEUE_LENGTH,
-                buffer_size: 32 << 20,
-            },
-        )?;
+        let upload_manager =
+            UploadManager::new(&mut device, UPLOAD_STAGING_SIZE, FRAME_QUEUE_LENGTH)?;
 
         let render_graph = render_graph::graph::RenderGraph::new();
 
@@ -138,33 +201,106 @@ impl SimpleRenderer {
             instance,
             physical_devices,
             device,
-            i_device: i_selected,
+            i_device,
             context_pools,
             uniform_buffer,
             dynamic_vertex_buffer,
             dynamic_index_buffer,
-            upload_buffer,
+            upload_manager,
             render_graph,
-            swapchain_node,
+            output_node,
             frame_count: 0,
             time: 0.0,
             shader_watcher,
+            shader_errors: Vec::new(),
         })
     }
 
+    // Recreates the device and everything created from it, the instance and the surface are kept.
+    // Resources created by the application are destroyed by `destroy_resources` and have to be created
+    // again on the new device.
+    pub fn recover_from_device_lost(
+        &mut self,
+        destroy_resources: impl FnOnce(&mut vulkan::Device),
+    ) -> VulkanResult<()> {
+        let (mut physical_devices, i_device, device) = Self::create_device(&self.instance)?;
+
+        // Destroying objects is still valid on a lost device, waiting for it is not
+        let mut lost_device = std::mem::replace(&mut self.device, device);
+        match &self.output_node {
+            OutputNode::Swapchain(node) => {
+                let mut node = node.borrow_mut();
+                lost_device.destroy_fence(&node.fence);
+                node.fence = self.device.create_fence()?;
+                node.i_frame = 0;
+                node.surface.move_to_device(
+                    &self.instance,
+                    &mut lost_device,
+                    &mut self.device,
+                    &mut physical_devices[i_device],
+                )?;
+            }
+            OutputNode::Offscreen(node) => {
+                let size = node.borrow().size;
+                let lost_node = node.replace(builtins::OffscreenPass::new(&mut self.device, size)?);
+                lost_node.destroy(&mut lost_device);
+            }
+        }
+
+        std::mem::replace(
+            &mut self.render_graph,
+            render_graph::graph::RenderGraph::new(),
+        )
+        .destroy(&mut lost_device);
+
+        let context_pools = [
+            self.device.create_context_pool()?,
+            self.device.create_context_pool()?,
+        ];
+        for context_pool in std::mem::replace(&mut self.context_pools, context_pools) {
+            lost_device.destroy_context_pool(context_pool);
+        }
+
+        for ring_buffer in [
+            &mut self.uniform_buffer,
+            &mut self.dynamic_vertex_buffer,
+            &mut self.dynamic_index_buffer,
+        ] {
+            let new_ring_buffer = RingBuffer::new(&mut self.device, ring_buffer.spec().clone())?;
+            std::mem::replace(ring_buffer, new_ring_buffer).destroy(&mut lost_device);
+        }
+        let upload_manager =
+            UploadManager::new(&mut self.device, UPLOAD_STAGING_SIZE, FRAME_QUEUE_LENGTH)?;
+        std::mem::replace(&mut self.upload_manager, upload_manager).destroy(&mut lost_device);
+        destroy_resources(&mut lost_device);
+        lost_device.destroy_lost();
+
+        self.physical_devices = physical_devices;
+        self.i_device = i_device;
+        self.shader_errors.clear();
+        Ok(())
+    }
+
     pub fn destroy(mut self) {
         self.device.wait_idle().unwrap();
 
-        self.device
-            .destroy_fence(&self.swapchain_node.borrow().fence);
+        self.render_graph.destroy(&mut self.device);
+        self.uniform_buffer.destroy(&mut self.device);
+        self.dynamic_vertex_buffer.destroy(&mut self.device);
+        self.dynamic_index_buffer.destroy(&mut self.device);
+        self.upload_manager.destroy(&mut self.device);
         for context_pool in self.context_pools {
             self.device.destroy_context_pool(context_pool);
         }
 
-        self.swapchain_node
-            .borrow_mut()
-            .surface
-            .destroy(&self.instance, &mut self.device);
+        match &self.output_node {
+            OutputNode::Swapchain(node) => {
+                let mut node = node.borrow_mut();
+                self.device.destroy_fence(&node.fence);
+                node.surface.destroy(&self.instance, &mut self.device);
+            }
+            OutputNode::Offscreen(node) => node.borrow().destroy(&mut self.device),
+        }
 
         self.device.destroy();
         self.instance.destroy();
@@ -173,96 +309,64 @@ impl SimpleRenderer {
     pub fn render(&mut self, output: Handle<TextureDesc>, dt: f32) -> VulkanResult<()> {
         profile::scope!("simple_renderer render");
 
-        let i_frame = {
-            let b = self.swapchain_node.borrow();
-            b.i_frame
-        };
+        let i_frame = self.output_node.i_frame();
+        let signal_value = (i_frame + FRAME_QUEUE_LENGTH) as u64;
 
-        let swapchain_output = builtins::SwapchainPass::acquire_next_image(
-            &self.swapchain_node,
-            &mut self.render_graph,
-        );
+        match &self.output_node {
+            OutputNode::Swapchain(swapchain_node) => {
+                let swapchain_output = builtins::SwapchainPass::acquire_next_image(
+                    swapchain_node,
+                    &mut self.render_graph,
+                );
 
-        builtins::blit_image(&mut self.render_graph, output, swapchain_output);
+                builtins::blit_image(&mut self.render_graph, output, swapchain_output);
 
-        builtins::SwapchainPass::present(
-            &self.swapchain_node,
-            &mut self.render_graph,
-            (i_frame + FRAME_QUEUE_LENGTH) as u64,
-        );
+                builtins::SwapchainPass::present(
+                    swapchain_node,
+                    &mut self.render_graph,
+                    signal_value,
+                );
+            }
+            OutputNode::Offscreen(offscreen_node) => {
+                builtins::OffscreenPass::readback(
+                    offscreen_node,
+                    &mut self.render_graph,
+                    output,
+                    signal_value,
+                );
+            }
+        }
 
         let current_frame = i_frame % FRAME_QUEUE_LENGTH;
         let context_pool = &mut self.context_pools[current_frame];
 
         let wait_value: u64 = i_frame as u64;
-        {
-            let fence = &self.swapchain_node.borrow().fence;
-            let wait_values = [wait_value];
-            self.device.wait_for_fences(&[fence], &wait_values)?;
-        }
+        self.output_node.wait_for_frame(&self.device, wait_value)?;
 
         self.device.reset_context_pool(context_pool)?;
 
-        let reloaded_shader = self.shader_watcher.update(|watch_event| {
-            if let render::shader::DebouncedEvent::Write(path) = watch_event {
-                self.device
-                    .shaders
-                    .iter()
-                    .find(|(_handle, shader)| shader.path == path)
-                    .map(|(handle, _shader)| handle)
-            } else {
-                None
-            }
-        });
-
-        if let Some(reloaded_shader) = reloaded_shader {
-            self.device.wait_idle().unwrap();
-
-            self.device.update_shader_from_fs(reloaded_shader)?;
-
-            let graphics_programs_to_reload: Vec<_> = self
-                .device
-                .graphics_programs
-                .iter()
-                .filter(|(_handle, program)| {
-                    program.graphics_state.vertex_shader == reloaded_shader
-                        || program.graphics_state.fragment_shader == reloaded_shader
-                })
-                .map(|(handle, _program)| handle)
-                .collect();
-
-            for program_handle in graphics_programs_to_reload {
-                let pipeline_count = self
-                    .device
-                    .graphics_programs
-                    .get(program_handle)
-                    .pipelines
-                    .len();
-
-                for i_pipeline in 0..pipeline_count {
-                    self.device
-                        .compile_graphics_program_pipeline(program_handle, i_pipeline)?;
-                }
-            }
-
-            let compute_programs_to_reload: Vec<_> = self
-                .device
-                .compute_programs
-                .iter()
-                .filter(|(_handle, program)| program.shader == reloaded_shader)
-                .map(|(handle, _program)| handle)
-                .collect();
-
-            for program_handle in compute_programs_to_reload {
-                self.device.compile_compute_program(program_handle)?;
+        let changed_paths = self.shader_watcher.changed_paths();
+        for reload in self.device.reload_shaders(&changed_paths)? {
+            self.shader_errors
+                .retain(|(shader_handle, _error)| *shader_handle != reload.shader);
+            for error in reload.errors {
+                eprintln!("{}", error);
+                self.shader_errors.push((reload.shader, error.to_string()));
             }
         }
+        // Variants that failed to compile during the last frame were drawn with their base variant
+        for (shader_handle, error) in self.device.variant_errors.drain(..) {
+            eprintln!("{}", error);
+            self.shader_errors.push((shader_handle, error.to_string()));
+        }
 
         self.device.update_bindless_set();
-        self.uniform_buffer.start_frame();
-        self.dynamic_vertex_buffer.start_frame();
-        self.dynamic_index_buffer.start_frame();
-        self.upload_buffer.start_frame();
+        self.uniform_buffer.start_frame(&mut self.device);
+        self.dynamic_vertex_buffer.start_frame(&mut self.device);
+        self.dynamic_index_buffer.start_frame(&mut self.device);
+        self.upload_manager.start_frame(&self.device)?;
+        // Uploads staged since the last frame, the graph waits for them in its first context
+        self.upload_manager.flush(&mut self.device, context_pool)?;
 
         let pass_api = render_graph::graph::PassApi {
             instance: &self.instance,
@@ -272,7 +376,7 @@ impl SimpleRenderer {
             uniform_buffer: &mut self.uniform_buffer,
             dynamic_vertex_buffer: &mut self.dynamic_vertex_buffer,
             dynamic_index_buffer: &mut self.dynamic_index_buffer,
-            upload_buffer: &mut self.upload_buffer,
+            upload_manager: &mut self.upload_manager,
         };
 
         self.render_graph.execute(pass_api, context_pool)?;
@@ -281,4 +385,12 @@ impl SimpleRenderer {
 
         Ok(())
     }
+
+    // Waits for the last rendered frame of a headless renderer and returns its RGBA8 pixels
+    pub fn read_pixels(&mut self) -> VulkanResult<Vec<u8>> {
+        match &self.output_node {
+            OutputNode::Offscreen(node) => node.borrow_mut().read_pixels(&mut self.device),
+            OutputNode::Swapchain(_) => panic!("only headless renderers can read their pixels"),
+        }
+    }
 }
@@ -101,6 +101,16 @@ impl<'a, T, const CAPACITY: usize> IntoIterator for &'a DynamicArray<T, CAPACITY
     }
 }
 
+impl<T, const CAPACITY: usize> FromIterator<T> for DynamicArray<T, CAPACITY> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut dynarray = Self::new();
+        for value in iter {
+            dynarray.push(value);
+        }
+        dynarray
+    }
+}
+
 // Constructor from slice
 impl<T: Copy, const CAPACITY: usize> From<&[T]> for DynamicArray<T, CAPACITY> {
     fn from(slice: &[T]) -> Self {
@@ -1,2 +1,3 @@
 pub mod dynamic_array;
+pub mod pod;
 pub mod pool;
@@ -0,0 +1,66 @@
+// Plain old data, types that can be copied byte for byte to memory read by the GPU. Implementors must have no
+// padding, no pointers, and every bit pattern must be valid, see `pod_struct` to declare them.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PodField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+}
+
+// Structs declared with `pod_struct`, the fields are in declaration order
+pub trait PodStruct: Pod {
+    const FIELDS: &'static [PodField];
+}
+
+// Declares a `#[repr(C)]` struct implementing `Pod` and `PodStruct`, a struct with padding or non-Pod fields doesn't compile
+#[macro_export]
+macro_rules! pod_struct {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $field_ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        $vis struct $name {
+            $($field_vis $field: $field_ty),*
+        }
+
+        const _: () = {
+            const fn assert_pod<T: $crate::pod::Pod>() {}
+            $(assert_pod::<$field_ty>();)*
+            assert!(
+                ::core::mem::size_of::<$name>() == 0 $(+ ::core::mem::size_of::<$field_ty>())*,
+                concat!(stringify!($name), " has padding")
+            );
+        };
+
+        // The fields are `Pod` and there is no padding
+        unsafe impl $crate::pod::Pod for $name {}
+
+        impl $crate::pod::PodStruct for $name {
+            const FIELDS: &'static [$crate::pod::PodField] = &[$(
+                $crate::pod::PodField {
+                    name: stringify!($field),
+                    offset: ::core::mem::offset_of!($name, $field),
+                    size: ::core::mem::size_of::<$field_ty>(),
+                }
+            ),*];
+        }
+    };
+}
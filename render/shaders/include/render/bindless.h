@@ -6,6 +6,8 @@
 #define GLOBAL_SAMPLER_BINDING 0
 #define GLOBAL_IMAGE_BINDING 1
 #define GLOBAL_BUFFER_BINDING 2
+#define GLOBAL_SAMPLED_IMAGE_BINDING 3
+#define GLOBAL_SAMPLER_OBJECT_BINDING 4
 
 #define GLOBAL_BINDLESS_SET 0
 #define GLOBAL_UNIFORM_SET 1
@@ -20,6 +22,10 @@ BINDLESS_SAMPLER usampler2D global_textures_uint[];
 BINDLESS_SAMPLER sampler3D global_textures_3d[];
 BINDLESS_SAMPLER usampler3D global_textures_3d_uint[];
 
+// Same indices as `global_textures`, to combine with any sampler of `global_samplers`
+layout(set = GLOBAL_BINDLESS_SET, binding = GLOBAL_SAMPLED_IMAGE_BINDING) uniform texture2D global_sampled_images[];
+layout(set = GLOBAL_BINDLESS_SET, binding = GLOBAL_SAMPLER_OBJECT_BINDING) uniform sampler global_samplers[];
+
 BINDLESS_IMAGE(rgba8)   image2D global_images_2d_rgba8[];
 BINDLESS_IMAGE(rgba16f) image2D global_images_2d_rgba16f[];
 BINDLESS_IMAGE(rgba32f) image2D global_images_2d_rgba32f[];
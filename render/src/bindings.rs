@@ -1,33 +1,37 @@
 use super::ring_buffer::*;
 use super::vulkan::contexts::*;
+use super::vulkan::descriptor_set::SHADER_UNIFORM_SET;
 use super::vulkan::device::*;
 use super::vulkan::error::*;
+use exo::pod::PodStruct;
 use std::mem::size_of;
 
-pub fn bind_shader_options<Context: AsRef<ComputeContext>>(
+// The returned options are only valid for the current frame, their layout is checked against the shaders at the next
+// draw or dispatch
+pub fn bind_shader_options<'a, T: PodStruct, Context: AsRef<ComputeContext>>(
     device: &mut Device,
-    ring_buffer: &mut RingBuffer,
+    ring_buffer: &'a mut RingBuffer,
     ctx: &Context,
-    options_len: usize,
-) -> VulkanResult<*mut [u8]> {
-    let (slice, offset) = ring_buffer.allocate(options_len, 0x40); // 0x10 enough on AMD, should probably check device features
-    let i_descriptor = device.find_or_create_uniform_descriptor(ring_buffer.buffer, options_len)?;
+) -> VulkanResult<&'a mut T> {
+    let allocation = ring_buffer.allocate_slice_aligned::<T>(device, 1, 0x40)?; // 0x10 enough on AMD, should probably check device features
+    let i_descriptor =
+        device.find_or_create_uniform_descriptor(allocation.buffer, size_of::<T>())?;
     let descriptor = &device.descriptors.uniform_descriptor_sets[i_descriptor];
-    ctx.as_ref().bind_uniform_set(device, descriptor, offset, 2);
-    Ok(slice)
+    ctx.as_ref()
+        .bind_uniform_set(device, descriptor, allocation.offset, SHADER_UNIFORM_SET);
+    ctx.as_ref()
+        .base_context()
+        .bound_options
+        .set(Some(Device::validate_shader_options::<T>));
+    Ok(&mut allocation.data[0])
 }
 
-pub fn bind_and_copy_shader_options<Context: AsRef<ComputeContext>, T>(
+pub fn bind_and_copy_shader_options<Context: AsRef<ComputeContext>, T: PodStruct>(
     device: &mut Device,
     ring_buffer: &mut RingBuffer,
     ctx: &Context,
     data: T,
 ) -> VulkanResult<()> {
-    let options = bind_shader_options(device, ring_buffer, &ctx, size_of::<T>()).unwrap();
-    unsafe {
-        let p_options = std::slice::from_raw_parts_mut((*options).as_ptr() as *mut T, 1);
-        p_options[0] = data;
-    }
-
+    *bind_shader_options(device, ring_buffer, ctx)? = data;
     Ok(())
 }
@@ -1,6 +1,8 @@
 pub mod bindings;
+pub mod readback;
 pub mod ring_buffer;
 pub mod vulkan;
 pub use erupt::vk;
 pub mod render_graph;
 pub mod shader;
+pub mod upload;
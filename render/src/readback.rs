@@ -0,0 +1,350 @@
+use exo::pool::Handle;
+
+use super::vulkan::{buffer::*, contexts::*, device::*, error::*, fence::*, image::*};
+
+use erupt::vk;
+use std::{cell::Cell, collections::VecDeque, rc::Rc};
+
+const READBACK_ALIGNMENT: usize = 16;
+
+// A copy to the readback ring, its content is available once `fence` reached its value.
+// Dropping a request without consuming it gives its range back once the copy is done.
+pub struct ReadbackRequest {
+    offset: usize,
+    size: usize,
+    fence: Fence,
+    is_released: Rc<Cell<bool>>,
+}
+
+impl Drop for ReadbackRequest {
+    fn drop(&mut self) {
+        self.is_released.set(true);
+    }
+}
+
+struct ReadbackRange {
+    start: usize,
+    fence: Fence,
+    // Shared with the request, set when it is consumed or dropped
+    is_released: Rc<Cell<bool>>,
+}
+
+// Cursor math of the readback ring, ranges are reclaimed in order once released and written by the GPU
+pub struct ReadbackAllocator {
+    capacity: usize,
+    cursor: usize,
+    ranges: VecDeque<ReadbackRange>,
+}
+
+impl ReadbackAllocator {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            cursor: 0,
+            ranges: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn allocate(
+        &mut self,
+        size: usize,
+        mut is_fence_done: impl FnMut(&Fence) -> VulkanResult<bool>,
+    ) -> VulkanResult<Option<usize>> {
+        while let Some(oldest) = self.ranges.front() {
+            if !oldest.is_released.get() || !is_fence_done(&oldest.fence)? {
+                break;
+            }
+            self.ranges.pop_front();
+        }
+
+        if size > self.capacity {
+            return Ok(None);
+        }
+
+        let mut offset = self.cursor;
+        let dist = offset % READBACK_ALIGNMENT;
+        if dist != 0 {
+            offset += READBACK_ALIGNMENT - dist;
+        }
+        let mut wrapped = false;
+        if offset + size > self.capacity {
+            offset = 0;
+            wrapped = true;
+        }
+
+        // The ranges in flight go from the oldest start to the cursor, possibly wrapping around
+        if let Some(oldest) = self.ranges.front() {
+            let fits = if self.cursor > oldest.start {
+                !wrapped || offset + size <= oldest.start
+            } else {
+                !wrapped && offset + size <= oldest.start
+            };
+            if !fits {
+                return Ok(None);
+            }
+        }
+
+        self.cursor = offset + size;
+        Ok(Some(offset))
+    }
+
+    // `offset` has to be the last allocation
+    pub fn add_request(
+        &mut self,
+        offset: usize,
+        size: usize,
+        fence: &Fence,
+        signal_value: u64,
+    ) -> ReadbackRequest {
+        let is_released = Rc::new(Cell::new(false));
+        self.ranges.push_back(ReadbackRange {
+            start: offset,
+            fence: Fence {
+                timeline_semaphore: fence.timeline_semaphore,
+                value: signal_value,
+            },
+            is_released: Rc::clone(&is_released),
+        });
+
+        ReadbackRequest {
+            offset,
+            size,
+            fence: Fence {
+                timeline_semaphore: fence.timeline_semaphore,
+                value: signal_value,
+            },
+            is_released,
+        }
+    }
+}
+
+// Host-visible buffer receiving copies from the GPU, space is reclaimed once requests are consumed or dropped
+pub struct ReadbackRing {
+    pub buffer: Handle<Buffer>,
+    memory_buffer: *mut [u8],
+    allocator: ReadbackAllocator,
+}
+
+impl ReadbackRing {
+    pub fn new(device: &mut Device, buffer_size: usize) -> VulkanResult<Self> {
+        let buffer = device.create_buffer(BufferSpec {
+            size: buffer_size,
+            usages: vk::BufferUsageFlags::TRANSFER_DST,
+            memory_usage: MemoryUsageFlags::GpuToCpu,
+        })?;
+
+        Ok(Self {
+            buffer,
+            memory_buffer: device.map_buffer(buffer),
+            allocator: ReadbackAllocator::new(buffer_size),
+        })
+    }
+
+    pub fn destroy(&self, device: &mut Device) {
+        device.destroy_buffer(self.buffer);
+    }
+
+    fn allocate(&mut self, device: &Device, size: usize) -> VulkanResult<Option<usize>> {
+        self.allocator.allocate(size, |fence| {
+            Ok(device.get_fence_value(fence)? >= fence.value)
+        })
+    }
+
+    // Records a copy of the first mip level of `image`, the context has to signal `fence` with `signal_value`.
+    // Returns None when the ring is full.
+    pub fn copy_image(
+        &mut self,
+        device: &mut Device,
+        ctx: &TransferContext,
+        image: Handle<Image>,
+        fence: &Fence,
+        signal_value: u64,
+    ) -> VulkanResult<Option<ReadbackRequest>> {
+        let spec = &device.images.get(image).spec;
+        let size =
+            (spec.size[0] * spec.size[1] * spec.size[2]) as usize * format_texel_size(spec.format);
+        let offset = match self.allocate(device, size)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        ctx.base_context()
+            .barrier(device, image, ImageState::TransferSrc);
+        ctx.copy_image_to_buffer(device, image, self.buffer, offset as u64);
+        ctx.base_context().memory_barrier(
+            device,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::HOST,
+            vk::AccessFlags::HOST_READ,
+        );
+
+        Ok(Some(self.allocator.add_request(
+            offset,
+            size,
+            fence,
+            signal_value,
+        )))
+    }
+
+    // Records a copy of a part of `buffer`, see `copy_image`
+    #[allow(clippy::too_many_arguments)]
+    pub fn copy_buffer(
+        &mut self,
+        device: &Device,
+        ctx: &TransferContext,
+        buffer: Handle<Buffer>,
+        buffer_offset: usize,
+        size: usize,
+        fence: &Fence,
+        signal_value: u64,
+    ) -> VulkanResult<Option<ReadbackRequest>> {
+        let offset = match self.allocate(device, size)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+
+        ctx.base_context().memory_barrier(
+            device,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::AccessFlags::MEMORY_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_READ,
+        );
+        ctx.copy_buffer(
+            device,
+            buffer,
+            buffer_offset as u64,
+            self.buffer,
+            offset as u64,
+            size as u64,
+        );
+        ctx.base_context().memory_barrier(
+            device,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::HOST,
+            vk::AccessFlags::HOST_READ,
+        );
+
+        Ok(Some(self.allocator.add_request(
+            offset,
+            size,
+            fence,
+            signal_value,
+        )))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.allocator.capacity()
+    }
+
+    pub fn is_ready(&self, device: &Device, request: &ReadbackRequest) -> VulkanResult<bool> {
+        Ok(device.get_fence_value(&request.fence)? >= request.fence.value)
+    }
+
+    // Returns the content of the request if the GPU is done with it, the request is consumed in that case
+    pub fn poll(
+        &mut self,
+        device: &Device,
+        request: ReadbackRequest,
+    ) -> VulkanResult<Result<Vec<u8>, ReadbackRequest>> {
+        if self.is_ready(device, &request)? {
+            Ok(Ok(self.consume(request)))
+        } else {
+            Ok(Err(request))
+        }
+    }
+
+    pub fn wait(&mut self, device: &Device, request: ReadbackRequest) -> VulkanResult<Vec<u8>> {
+        device.wait_for_fences(&[&request.fence], &[request.fence.value])?;
+        Ok(self.consume(request))
+    }
+
+    // The range is released when the request is dropped
+    fn consume(&mut self, request: ReadbackRequest) -> Vec<u8> {
+        assert!(request.offset + request.size <= self.memory_buffer.len());
+        unsafe {
+            std::slice::from_raw_parts(
+                (self.memory_buffer as *const u8).add(request.offset),
+                request.size,
+            )
+            .to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fence() -> Fence {
+        Fence {
+            timeline_semaphore: vk::Semaphore::null(),
+            value: 0,
+        }
+    }
+
+    // Requests signaled up to `completed` are done
+    fn allocate(
+        allocator: &mut ReadbackAllocator,
+        size: usize,
+        completed: u64,
+    ) -> Option<ReadbackRequest> {
+        let offset = allocator
+            .allocate(size, |fence| Ok(fence.value <= completed))
+            .unwrap()?;
+        Some(allocator.add_request(offset, size, &fence(), completed + 1))
+    }
+
+    #[test]
+    fn aligns_and_wraps_around() {
+        let mut allocator = ReadbackAllocator::new(64);
+        let first = allocate(&mut allocator, 20, 0).unwrap();
+        let second = allocate(&mut allocator, 20, 0).unwrap();
+        assert_eq!(first.offset, 0);
+        assert_eq!(second.offset, 32);
+
+        // The end of the buffer is too small, the start is still used
+        assert!(allocate(&mut allocator, 20, 1).is_none());
+
+        drop(first);
+        let third = allocate(&mut allocator, 20, 1).unwrap();
+        assert_eq!(third.offset, 0);
+    }
+
+    #[test]
+    fn ranges_are_reclaimed_in_order() {
+        let mut allocator = ReadbackAllocator::new(64);
+        let first = allocate(&mut allocator, 32, 0).unwrap();
+        let second = allocate(&mut allocator, 32, 0).unwrap();
+
+        // The second range is free but comes after the first one
+        drop(second);
+        assert!(allocate(&mut allocator, 32, 1).is_none());
+
+        drop(first);
+        assert_eq!(allocate(&mut allocator, 64, 1).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn dropped_requests_wait_for_their_copy() {
+        let mut allocator = ReadbackAllocator::new(64);
+        drop(allocate(&mut allocator, 64, 0).unwrap());
+
+        // The GPU is still writing to the range of the dropped request
+        assert!(allocate(&mut allocator, 16, 0).is_none());
+        assert_eq!(allocate(&mut allocator, 16, 1).unwrap().offset, 0);
+    }
+
+    #[test]
+    fn rejects_requests_bigger_than_the_ring() {
+        let mut allocator = ReadbackAllocator::new(64);
+        assert!(allocate(&mut allocator, 65, 0).is_none());
+        assert_eq!(allocate(&mut allocator, 64, 0).unwrap().offset, 0);
+    }
+}
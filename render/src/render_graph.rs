@@ -1,3 +1,4 @@
 pub mod builtins;
+pub mod debug;
 pub mod graph;
 mod resource_registry;
@@ -1,5 +1,8 @@
 use super::graph::*;
-use crate::{vk, vulkan};
+use crate::{
+    readback::{ReadbackRequest, ReadbackRing},
+    vk, vulkan,
+};
 use exo::pool::Handle;
 use std::{cell::RefCell, rc::Rc};
 
@@ -56,6 +59,7 @@ impl SwapchainPass {
                 Ok(())
             },
         );
+        graph.set_pass_name("acquire swapchain");
 
         output
     }
@@ -84,7 +88,91 @@ impl SwapchainPass {
 
                 Ok(())
             },
-        )
+        );
+        graph.set_pass_name("present");
+    }
+}
+
+// Frames being copied at once, the renderer waits for the frame before the previous one
+const OFFSCREEN_READBACK_FRAMES: usize = 3;
+
+// Replaces `SwapchainPass` when rendering without a window, the output is copied to a ring readable from the CPU
+pub struct OffscreenPass {
+    pub i_frame: usize,
+    pub fence: vulkan::Fence,
+    pub size: [i32; 2],
+    readback_ring: ReadbackRing,
+    // Only the last frame is kept, dropping the previous request gives its range back
+    last_request: Option<ReadbackRequest>,
+    last_pixels: Vec<u8>,
+}
+
+impl OffscreenPass {
+    pub fn new(device: &mut vulkan::Device, size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        let frame_size = (size[0] * size[1] * 4) as usize;
+        Ok(Self {
+            i_frame: 0,
+            fence: device.create_fence()?,
+            size,
+            readback_ring: ReadbackRing::new(device, OFFSCREEN_READBACK_FRAMES * frame_size)?,
+            last_request: None,
+            last_pixels: Vec::new(),
+        })
+    }
+
+    pub fn destroy(&self, device: &mut vulkan::Device) {
+        device.destroy_fence(&self.fence);
+        self.readback_ring.destroy(device);
+    }
+
+    // Copies `input` to the readback ring and submits the frame, `input` has to be a RGBA8 texture of the offscreen size
+    pub fn readback(
+        pass: &Rc<RefCell<Self>>,
+        graph: &mut RenderGraph,
+        input: Handle<TextureDesc>,
+        signal_value: u64,
+    ) {
+        let size = pass.borrow().size;
+        graph.resources.screen_size = [size[0] as f32, size[1] as f32];
+
+        let pass = Rc::clone(pass);
+        graph.use_texture(input, vk::ImageUsageFlags::TRANSFER_SRC);
+        graph.raw_pass(
+            move |graph: &mut RenderGraph, api: &mut PassApi, ctx: &mut vulkan::ComputeContext| {
+                let mut pass_ref = pass.borrow_mut();
+                let pass = &mut *pass_ref;
+
+                let input = graph.resources.resolve_image(api.device, input)?;
+                let spec = &api.device.images.get(input).spec;
+                assert!(spec.format == vk::Format::R8G8B8A8_UNORM);
+                assert!(spec.size[0] == pass.size[0] && spec.size[1] == pass.size[1]);
+
+                let request = pass
+                    .readback_ring
+                    .copy_image(api.device, ctx.transfer(), input, &pass.fence, signal_value)?
+                    .ok_or(vulkan::VulkanError::RingBufferFull {
+                        requested: (pass.size[0] * pass.size[1] * 4) as usize,
+                        capacity: pass.readback_ring.capacity(),
+                    })?;
+                ctx.base_context().end(api.device)?;
+
+                let signal_values = [signal_value];
+                api.device.submit(&ctx, &[&pass.fence], &signal_values)?;
+
+                pass.last_request = Some(request);
+                pass.i_frame += 1;
+                Ok(())
+            },
+        );
+        graph.set_pass_name("readback");
+    }
+
+    // Waits for the last submitted frame and returns its RGBA8 pixels
+    pub fn read_pixels(&mut self, device: &mut vulkan::Device) -> vulkan::VulkanResult<Vec<u8>> {
+        if let Some(request) = self.last_request.take() {
+            self.last_pixels = self.readback_ring.wait(device, request)?;
+        }
+        Ok(self.last_pixels.clone())
     }
 }
 
@@ -94,6 +182,8 @@ pub fn copy_image(
     output: Handle<TextureDesc>,
 ) {
     assert!(input != output);
+    graph.use_texture(input, vk::ImageUsageFlags::TRANSFER_SRC);
+    graph.use_texture(output, vk::ImageUsageFlags::TRANSFER_DST);
     graph.raw_pass(
         move |graph: &mut RenderGraph,
               api: &mut PassApi,
@@ -111,6 +201,7 @@ pub fn copy_image(
             Ok(())
         },
     );
+    graph.set_pass_name("copy image");
 }
 
 pub fn blit_image(
@@ -119,6 +210,8 @@ pub fn blit_image(
     output: Handle<TextureDesc>,
 ) {
     assert!(input != output);
+    graph.use_texture(input, vk::ImageUsageFlags::TRANSFER_SRC);
+    graph.use_texture(output, vk::ImageUsageFlags::TRANSFER_DST);
     graph.raw_pass(
         move |graph: &mut RenderGraph,
               api: &mut PassApi,
@@ -136,4 +229,5 @@ pub fn blit_image(
             Ok(())
         },
     );
+    graph.set_pass_name("blit image");
 }
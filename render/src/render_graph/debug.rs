@@ -0,0 +1,458 @@
+use super::graph::*;
+use crate::{vk, vulkan};
+use exo::pool::Handle;
+use std::fmt::Write;
+
+// Snapshot of the last executed frame, used to debug what the graph did
+#[derive(Default)]
+pub struct GraphDump {
+    pub i_frame: u64,
+    pub passes: Vec<PassDump>,
+    pub textures: Vec<TextureDump>,
+}
+
+pub struct PassDump {
+    pub name: String,
+    pub queue: &'static str,
+    // Indices into `GraphDump::textures`
+    pub reads: Vec<usize>,
+    pub writes: Vec<usize>,
+    pub barriers: Vec<BarrierDump>,
+}
+
+pub struct BarrierDump {
+    pub texture: usize,
+    pub state_before: Option<vulkan::ImageState>,
+    pub state_after: vulkan::ImageState,
+}
+
+pub struct TextureDump {
+    pub handle: Handle<TextureDesc>,
+    pub name: String,
+    pub size: [i32; 3],
+    pub format: vk::Format,
+    pub usages: vk::ImageUsageFlags,
+    pub origin: &'static str,
+    // Textures with the same image index alias the same memory
+    pub image: Option<usize>,
+    // See `ResourceRegistry::is_previewable`
+    pub previewable: bool,
+}
+
+const WRITE_USAGES: vk::ImageUsageFlags = vk::ImageUsageFlags::from_bits_truncate(
+    vk::ImageUsageFlags::COLOR_ATTACHMENT.bits()
+        | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT.bits()
+        | vk::ImageUsageFlags::STORAGE.bits()
+        | vk::ImageUsageFlags::TRANSFER_DST.bits(),
+);
+
+const READ_USAGES: vk::ImageUsageFlags = vk::ImageUsageFlags::from_bits_truncate(
+    vk::ImageUsageFlags::SAMPLED.bits()
+        | vk::ImageUsageFlags::INPUT_ATTACHMENT.bits()
+        | vk::ImageUsageFlags::TRANSFER_SRC.bits(),
+);
+
+// Usages declared by a pass with `RenderGraph::use_texture`, and the states of their images before the pass
+pub(crate) struct PassRecord {
+    pub(crate) name: String,
+    pub(crate) queue: &'static str,
+    pub(crate) uses: Vec<(Handle<TextureDesc>, vk::ImageUsageFlags)>,
+    pub(crate) states_before: Vec<Option<vulkan::ImageState>>,
+    pub(crate) states_after: Vec<Option<vulkan::ImageState>>,
+}
+
+impl PassRecord {
+    pub(crate) fn snapshot_states(
+        &self,
+        resources: &ResourceRegistry,
+        device: &vulkan::Device,
+    ) -> Vec<Option<vulkan::ImageState>> {
+        self.uses
+            .iter()
+            .map(|(desc, _usages)| {
+                let image = resources.texture_descs.get(*desc).resolved_image;
+                // Images in several states are shown as unknown
+                if image.is_valid() {
+                    device.images.get(image).states.uniform_state()
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl GraphDump {
+    pub(crate) fn new(
+        i_frame: u64,
+        resources: &ResourceRegistry,
+        device: &vulkan::Device,
+        records: &[PassRecord],
+    ) -> Self {
+        let mut images: Vec<Handle<vulkan::Image>> = Vec::new();
+        let mut textures: Vec<TextureDump> = Vec::new();
+
+        for (desc_handle, desc) in resources.texture_descs.iter() {
+            let image = if desc.resolved_image.is_valid() {
+                let i_image = images
+                    .iter()
+                    .position(|image| *image == desc.resolved_image)
+                    .unwrap_or_else(|| {
+                        images.push(desc.resolved_image);
+                        images.len() - 1
+                    });
+                Some(i_image)
+            } else {
+                None
+            };
+
+            textures.push(TextureDump {
+                handle: desc_handle,
+                name: desc.name.clone(),
+                size: resources.texture_desc_size(desc.size),
+                format: desc.format,
+                usages: desc.usages,
+                origin: desc.origin.name(),
+                image,
+                previewable: resources.is_previewable(device, desc_handle),
+            });
+        }
+
+        let texture_index = |desc_handle: Handle<TextureDesc>| {
+            textures
+                .iter()
+                .position(|texture| texture.handle == desc_handle)
+                .unwrap()
+        };
+
+        let passes = records
+            .iter()
+            .map(|record| {
+                let mut pass = PassDump {
+                    name: record.name.clone(),
+                    queue: record.queue,
+                    reads: Vec::new(),
+                    writes: Vec::new(),
+                    barriers: Vec::new(),
+                };
+
+                for (i_use, (desc_handle, usages)) in record.uses.iter().enumerate() {
+                    let i_texture = texture_index(*desc_handle);
+                    if usages.intersects(READ_USAGES) {
+                        pass.reads.push(i_texture);
+                    }
+                    if usages.intersects(WRITE_USAGES) {
+                        pass.writes.push(i_texture);
+                    }
+
+                    let state_before = record.states_before[i_use];
+                    if let Some(state_after) = record.states_after[i_use] {
+                        let changed = match state_before {
+                            Some(state_before) => {
+                                std::mem::discriminant(&state_before)
+                                    != std::mem::discriminant(&state_after)
+                            }
+                            None => true,
+                        };
+                        if changed {
+                            pass.barriers.push(BarrierDump {
+                                texture: i_texture,
+                                state_before,
+                                state_after,
+                            });
+                        }
+                    }
+                }
+
+                pass
+            })
+            .collect();
+
+        Self {
+            i_frame,
+            passes,
+            textures,
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph render_graph {{").unwrap();
+        writeln!(dot, "    rankdir=LR;").unwrap();
+        writeln!(dot, "    label=\"frame {}\";", self.i_frame).unwrap();
+
+        for (i_texture, texture) in self.textures.iter().enumerate() {
+            writeln!(
+                dot,
+                "    t{} [shape=ellipse, label=\"{}\\n{}x{}x{} {:?}\\n{}\"];",
+                i_texture,
+                escape(&texture.name),
+                texture.size[0],
+                texture.size[1],
+                texture.size[2],
+                texture.format,
+                texture.origin
+            )
+            .unwrap();
+        }
+
+        for (i_pass, pass) in self.passes.iter().enumerate() {
+            writeln!(
+                dot,
+                "    p{} [shape=box, style=filled, label=\"{}: {}\\n{}\"];",
+                i_pass,
+                i_pass,
+                escape(&pass.name),
+                pass.queue
+            )
+            .unwrap();
+
+            for i_texture in &pass.reads {
+                writeln!(
+                    dot,
+                    "    t{} -> p{}{};",
+                    i_texture,
+                    i_pass,
+                    pass.edge_attributes(*i_texture)
+                )
+                .unwrap();
+            }
+
+            for i_texture in &pass.writes {
+                writeln!(
+                    dot,
+                    "    p{} -> t{}{};",
+                    i_pass,
+                    i_texture,
+                    pass.edge_attributes(*i_texture)
+                )
+                .unwrap();
+            }
+        }
+
+        // Link textures backed by the same image
+        for (i_texture, texture) in self.textures.iter().enumerate() {
+            let aliased = self.textures[..i_texture]
+                .iter()
+                .position(|other| other.image.is_some() && other.image == texture.image);
+            if let Some(i_other) = aliased {
+                writeln!(
+                    dot,
+                    "    t{} -> t{} [style=dashed, dir=none, label=\"aliases\"];",
+                    i_other, i_texture
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write!(json, "{{\"frame\":{},\"textures\":[", self.i_frame).unwrap();
+
+        for (i_texture, texture) in self.textures.iter().enumerate() {
+            if i_texture > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"size\":[{},{},{}],\"format\":\"{:?}\",\"usages\":\"{}\",\"origin\":\"{}\",\"image\":{}}}",
+                escape(&texture.name),
+                texture.size[0],
+                texture.size[1],
+                texture.size[2],
+                texture.format,
+                escape(&format!("{:?}", texture.usages)),
+                texture.origin,
+                match texture.image {
+                    Some(i_image) => i_image.to_string(),
+                    None => String::from("null"),
+                }
+            )
+            .unwrap();
+        }
+
+        json.push_str("],\"passes\":[");
+        for (i_pass, pass) in self.passes.iter().enumerate() {
+            if i_pass > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"queue\":\"{}\",\"reads\":{:?},\"writes\":{:?},\"barriers\":[",
+                escape(&pass.name),
+                pass.queue,
+                pass.reads,
+                pass.writes
+            )
+            .unwrap();
+
+            for (i_barrier, barrier) in pass.barriers.iter().enumerate() {
+                if i_barrier > 0 {
+                    json.push(',');
+                }
+                write!(
+                    json,
+                    "{{\"texture\":{},\"before\":\"{:?}\",\"after\":\"{:?}\"}}",
+                    barrier.texture,
+                    barrier.state_before.unwrap_or(vulkan::ImageState::Null),
+                    barrier.state_after
+                )
+                .unwrap();
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+
+        json
+    }
+}
+
+impl PassDump {
+    // Edges are labeled with the barriers done on their texture
+    fn edge_attributes(&self, i_texture: usize) -> String {
+        match self
+            .barriers
+            .iter()
+            .find(|barrier| barrier.texture == i_texture)
+        {
+            Some(barrier) => format!(" [label=\"{}\"]", barrier.label()),
+            None => String::new(),
+        }
+    }
+}
+
+impl BarrierDump {
+    pub fn label(&self) -> String {
+        format!(
+            "{:?} -> {:?}",
+            self.state_before.unwrap_or(vulkan::ImageState::Null),
+            self.state_after
+        )
+    }
+}
+
+// Escapes a string for both DOT and JSON string literals
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(name: &str, origin: &'static str, image: usize) -> TextureDump {
+        TextureDump {
+            handle: Handle::invalid(),
+            name: String::from(name),
+            size: [64, 32, 1],
+            format: vk::Format::R8G8B8A8_UNORM,
+            usages: vk::ImageUsageFlags::empty(),
+            origin,
+            image: Some(image),
+            previewable: false,
+        }
+    }
+
+    // A pass drawing to a transient texture blitted to the swapchain by a second pass
+    fn two_pass_dump() -> GraphDump {
+        GraphDump {
+            i_frame: 7,
+            passes: vec![
+                PassDump {
+                    name: String::from("draw"),
+                    queue: "graphics",
+                    reads: Vec::new(),
+                    writes: vec![0],
+                    barriers: vec![BarrierDump {
+                        texture: 0,
+                        state_before: None,
+                        state_after: vulkan::ImageState::ColorAttachment,
+                    }],
+                },
+                PassDump {
+                    name: String::from("blit \"final\""),
+                    queue: "graphics",
+                    reads: vec![0],
+                    writes: vec![1],
+                    barriers: vec![
+                        BarrierDump {
+                            texture: 0,
+                            state_before: Some(vulkan::ImageState::ColorAttachment),
+                            state_after: vulkan::ImageState::TransferSrc,
+                        },
+                        BarrierDump {
+                            texture: 1,
+                            state_before: Some(vulkan::ImageState::Present),
+                            state_after: vulkan::ImageState::TransferDst,
+                        },
+                    ],
+                },
+            ],
+            textures: vec![
+                texture("color", "transient", 0),
+                texture("swapchain", "imported", 1),
+            ],
+        }
+    }
+
+    #[test]
+    fn dot_links_passes_through_their_textures() {
+        let dot = two_pass_dump().to_dot();
+        assert!(dot.starts_with("digraph render_graph {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert!(dot.contains("label=\"frame 7\""));
+        assert!(dot
+            .contains("t0 [shape=ellipse, label=\"color\\n64x32x1 R8G8B8A8_UNORM\\ntransient\"];"));
+        assert!(dot.contains("p0 [shape=box, style=filled, label=\"0: draw\\ngraphics\"];"));
+        assert!(dot
+            .contains("p1 [shape=box, style=filled, label=\"1: blit \\\"final\\\"\\ngraphics\"];"));
+        assert!(dot.contains("p0 -> t0 [label=\"Null -> ColorAttachment\"];"));
+        assert!(dot.contains("t0 -> p1 [label=\"ColorAttachment -> TransferSrc\"];"));
+        assert!(dot.contains("p1 -> t1 [label=\"Present -> TransferDst\"];"));
+        assert!(!dot.contains("aliases"));
+    }
+
+    #[test]
+    fn dot_links_aliased_textures() {
+        let mut dump = two_pass_dump();
+        dump.textures[1].image = Some(0);
+        assert!(dump
+            .to_dot()
+            .contains("t0 -> t1 [style=dashed, dir=none, label=\"aliases\"];"));
+    }
+
+    #[test]
+    fn json_lists_textures_and_passes() {
+        let usages = format!("{:?}", vk::ImageUsageFlags::empty());
+        let expected = format!(
+            concat!(
+                "{{\"frame\":7,\"textures\":[",
+                "{{\"name\":\"color\",\"size\":[64,32,1],\"format\":\"R8G8B8A8_UNORM\",\"usages\":\"{usages}\",\"origin\":\"transient\",\"image\":0}},",
+                "{{\"name\":\"swapchain\",\"size\":[64,32,1],\"format\":\"R8G8B8A8_UNORM\",\"usages\":\"{usages}\",\"origin\":\"imported\",\"image\":1}}",
+                "],\"passes\":[",
+                "{{\"name\":\"draw\",\"queue\":\"graphics\",\"reads\":[],\"writes\":[0],\"barriers\":[",
+                "{{\"texture\":0,\"before\":\"Null\",\"after\":\"ColorAttachment\"}}]}},",
+                "{{\"name\":\"blit \\\"final\\\"\",\"queue\":\"graphics\",\"reads\":[0],\"writes\":[1],\"barriers\":[",
+                "{{\"texture\":0,\"before\":\"ColorAttachment\",\"after\":\"TransferSrc\"}},",
+                "{{\"texture\":1,\"before\":\"Present\",\"after\":\"TransferDst\"}}]}}",
+                "]}}"
+            ),
+            usages = escape(&usages)
+        );
+        assert_eq!(two_pass_dump().to_json(), expected);
+    }
+}
@@ -1,15 +1,40 @@
+use super::debug::{GraphDump, PassRecord};
 pub use super::resource_registry::*;
-use crate::{ring_buffer::RingBuffer, vk, vulkan};
+use crate::{ring_buffer::RingBuffer, upload::UploadManager, vk, vulkan};
 use exo::{dynamic_array::DynamicArray, pool::Handle};
 
 enum Pass {
     Graphic(GraphicPass),
     Raw(RawPass),
+    AsyncCompute(AsyncComputePass),
+    Transfer(TransferPass),
+}
+
+// Timeline semaphores used to synchronize the graphics queue with the async queues
+struct QueueFences {
+    graphics: vulkan::Fence,
+    async_queues: vulkan::Fence,
+}
+
+// Async work that the graphics queue didn't wait for yet
+struct PendingAsyncWork {
+    fence_value: u64,
+    src_family_idx: u32,
+    textures: Vec<Handle<TextureDesc>>,
+    // Released by the async queue, the graphics queue acquires them when it waits
+    released_images: Vec<Handle<vulkan::Image>>,
 }
 
 pub struct RenderGraph {
     pub resources: ResourceRegistry,
     passes: Vec<Pass>,
+    pass_records: Vec<PassRecord>,
+    pending_uses: Vec<(Handle<TextureDesc>, vk::ImageUsageFlags)>,
+    // Only built for the frames following `request_dump`
+    dump_requested: bool,
+    last_frame: Option<GraphDump>,
+    fences: Option<QueueFences>,
+    pending_async_work: Vec<PendingAsyncWork>,
     i_frame: u64,
 }
 
@@ -18,9 +43,22 @@ impl RenderGraph {
         Self {
             resources: ResourceRegistry::new(),
             passes: Vec::new(),
+            pass_records: Vec::new(),
+            pending_uses: Vec::new(),
+            dump_requested: false,
+            last_frame: None,
+            fences: None,
+            pending_async_work: Vec::new(),
             i_frame: 0,
         }
     }
+
+    pub fn destroy(&mut self, device: &mut vulkan::Device) {
+        if let Some(fences) = self.fences.take() {
+            device.destroy_fence(&fences.graphics);
+            device.destroy_fence(&fences.async_queues);
+        }
+    }
 }
 
 pub struct PassApi<'device, 'buffers> {
@@ -32,7 +70,7 @@ pub struct PassApi<'device, 'buffers> {
     pub uniform_buffer: &'buffers mut RingBuffer,
     pub dynamic_vertex_buffer: &'buffers mut RingBuffer,
     pub dynamic_index_buffer: &'buffers mut RingBuffer,
-    pub upload_buffer: &'buffers mut RingBuffer,
+    pub upload_manager: &'buffers mut UploadManager,
 }
 
 impl RenderGraph {
@@ -46,24 +84,80 @@ impl RenderGraph {
 
         let mut ctx = api.device.get_graphics_context(context_pool)?;
         ctx.base().begin(api.device)?;
+        api.upload_manager.wait_for_uploads(api.device, &mut ctx);
 
         // Consume all passes
         let passes = std::mem::take(&mut self.passes);
+        let mut pass_records = std::mem::take(&mut self.pass_records);
+        self.pending_uses.clear();
+
+        let pass_textures: Vec<Vec<Handle<TextureDesc>>> = passes
+            .iter()
+            .zip(&pass_records)
+            .map(|(pass, record)| {
+                let mut textures: Vec<_> =
+                    record.uses.iter().map(|(texture, _)| *texture).collect();
+                if let Pass::AsyncCompute(AsyncComputePass {
+                    textures: async_textures,
+                    ..
+                })
+                | Pass::Transfer(TransferPass {
+                    textures: async_textures,
+                    ..
+                }) = pass
+                {
+                    textures.extend_from_slice(async_textures);
+                }
+                textures
+            })
+            .collect();
+        let pass_count = passes.len();
+        let dump_requested = std::mem::take(&mut self.dump_requested);
+
+        for (i_pass, pass) in passes.into_iter().enumerate() {
+            // The last pass submits the frame, its fence has to cover the async work
+            self.wait_for_async_work(
+                &mut api,
+                context_pool,
+                &mut ctx,
+                &pass_textures[i_pass],
+                i_pass + 1 == pass_count,
+            )?;
 
-        for pass in passes {
+            let later_textures = &pass_textures[i_pass + 1..];
+            let record = &mut pass_records[i_pass];
+            if dump_requested {
+                record.states_before = record.snapshot_states(&self.resources, api.device);
+            }
             match pass {
                 Pass::Graphic(mut pass) => {
                     profile::scope!("graphics");
-                    let output_desc = self.resources.texture_descs.get(pass.color_attachment);
+                    let color_attachment = pass.color_attachment;
+                    let output_desc = self.resources.texture_descs.get(color_attachment.texture);
                     let output_size = self.resources.texture_desc_size(output_desc.size);
 
                     let output_image = self
                         .resources
-                        .resolve_image(api.device, pass.color_attachment)?;
+                        .resolve_image(api.device, color_attachment.texture)?;
+
+                    let resolve_attachments = if color_attachment.resolve.is_valid() {
+                        let resolve_image = self
+                            .resources
+                            .resolve_image(api.device, color_attachment.resolve)?;
+                        ctx.base().barrier(
+                            api.device,
+                            resolve_image,
+                            vulkan::ImageState::ColorAttachment,
+                        );
+                        vec![color_attachment.resolve]
+                    } else {
+                        Vec::new()
+                    };
 
                     let framebuffer = self.resources.resolve_framebuffer(
                         api.device,
-                        &[pass.color_attachment],
+                        &[color_attachment.texture],
+                        &resolve_attachments,
                         Handle::invalid(),
                     )?;
 
@@ -76,9 +170,8 @@ impl RenderGraph {
                     ctx.begin_pass(
                         api.device,
                         framebuffer,
-                        &[vulkan::LoadOp::ClearColor(
-                            vulkan::ClearColorValue::Float32([0.0, 0.0, 0.0, 1.0]),
-                        )],
+                        &[color_attachment.load_op],
+                        &[color_attachment.store_op],
                     )?;
                     ctx.set_viewport(
                         api.device,
@@ -97,7 +190,7 @@ impl RenderGraph {
                         ),
                     );
 
-                    (pass.execute_cb)(self, &mut api, ctx.as_mut());
+                    (pass.execute_cb)(self, &mut api, ctx.as_mut())?;
 
                     ctx.end_pass(api.device);
                 }
@@ -105,9 +198,42 @@ impl RenderGraph {
                     profile::scope!("raw");
                     (pass.execute_cb)(self, &mut api, ctx.as_mut())?;
                 }
+                Pass::AsyncCompute(mut pass) => {
+                    profile::scope!("async compute");
+                    let async_ctx = api.device.get_compute_context(context_pool)?;
+                    self.execute_async(
+                        &mut api,
+                        context_pool,
+                        &mut ctx,
+                        &pass.textures,
+                        later_textures,
+                        async_ctx,
+                        &mut *pass.execute_cb,
+                    )?;
+                }
+                Pass::Transfer(mut pass) => {
+                    profile::scope!("transfer");
+                    let async_ctx = api.device.get_transfer_context(context_pool)?;
+                    self.execute_async(
+                        &mut api,
+                        context_pool,
+                        &mut ctx,
+                        &pass.textures,
+                        later_textures,
+                        async_ctx,
+                        &mut *pass.execute_cb,
+                    )?;
+                }
+            }
+            if dump_requested {
+                record.states_after = record.snapshot_states(&self.resources, api.device);
             }
         }
+        // Only when the frame ends with an async pass
+        self.wait_for_async_work(&mut api, context_pool, &mut ctx, &[], true)?;
 
+        self.last_frame = dump_requested
+            .then(|| GraphDump::new(self.i_frame, &self.resources, api.device, &pass_records));
         self.resources.end_frame();
 
         self.i_frame += 1;
@@ -118,26 +244,371 @@ impl RenderGraph {
     pub fn i_frame(&self) -> u64 {
         self.i_frame
     }
+
+    // Records what the next executed frame does, see `last_frame`
+    pub fn request_dump(&mut self) {
+        self.dump_requested = true;
+    }
+
+    // What the graph did during the last executed frame, if a dump was requested before it
+    pub fn last_frame(&self) -> Option<&GraphDump> {
+        self.last_frame.as_ref()
+    }
+
+    fn add_pass(&mut self, pass: Pass) {
+        let (name, queue) = match pass {
+            Pass::Graphic(_) => ("graphics pass", "graphics"),
+            Pass::Raw(_) => ("raw pass", "graphics"),
+            Pass::AsyncCompute(_) => ("async compute pass", "compute"),
+            Pass::Transfer(_) => ("transfer pass", "transfer"),
+        };
+        // Textures used since the previous pass belong to this pass
+        self.pass_records.push(PassRecord {
+            name: String::from(name),
+            queue,
+            uses: std::mem::take(&mut self.pending_uses),
+            states_before: Vec::new(),
+            states_after: Vec::new(),
+        });
+        self.passes.push(pass);
+    }
+
+    // Names the last added pass, only used for debugging
+    pub fn set_pass_name(&mut self, name: &str) {
+        self.pass_records.last_mut().unwrap().name = String::from(name);
+    }
+
+    // Submits the graphics work recorded so far and continues in a new context, returns the value signaled
+    // on the graphics fence
+    fn split_graphics_submission(
+        &mut self,
+        api: &mut PassApi,
+        context_pool: &mut vulkan::ContextPool,
+        ctx: &mut vulkan::GraphicsContext,
+    ) -> vulkan::VulkanResult<u64> {
+        self.create_queue_fences(api.device)?;
+        ctx.base().end(api.device)?;
+
+        // The swapchain image is used by the following graphics work
+        let image_acquired_semaphore = ctx.base_mut().image_acquired_semaphore.take();
+        let image_acquired_stage = ctx.base_mut().image_acquired_stage.take();
+
+        let graphics_fence = &mut self.fences.as_mut().unwrap().graphics;
+        graphics_fence.value += 1;
+        let graphics_value = graphics_fence.value;
+        api.device
+            .submit(ctx, &[graphics_fence], &[graphics_value])?;
+
+        *ctx = api.device.get_graphics_context(context_pool)?;
+        ctx.base().begin(api.device)?;
+        ctx.base_mut().image_acquired_semaphore = image_acquired_semaphore;
+        ctx.base_mut().image_acquired_stage = image_acquired_stage;
+
+        Ok(graphics_value)
+    }
+
+    fn create_queue_fences(&mut self, device: &mut vulkan::Device) -> vulkan::VulkanResult<()> {
+        if self.fences.is_none() {
+            self.fences = Some(QueueFences {
+                graphics: device.create_fence()?,
+                async_queues: device.create_fence()?,
+            });
+        }
+        Ok(())
+    }
+
+    // Graphics work waits for an async pass from the first pass using one of its textures, the work recorded
+    // before it can overlap with the async pass
+    fn wait_for_async_work(
+        &mut self,
+        api: &mut PassApi,
+        context_pool: &mut vulkan::ContextPool,
+        ctx: &mut vulkan::GraphicsContext,
+        textures: &[Handle<TextureDesc>],
+        wait_for_all: bool,
+    ) -> vulkan::VulkanResult<()> {
+        let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_async_work)
+            .into_iter()
+            .partition(|work| {
+                wait_for_all
+                    || work
+                        .textures
+                        .iter()
+                        .any(|texture| textures.contains(texture))
+            });
+        self.pending_async_work = pending;
+
+        let fence_value = match ready.iter().map(|work| work.fence_value).max() {
+            Some(fence_value) => fence_value,
+            None => return Ok(()),
+        };
+
+        // A semaphore wait applies to a whole submission
+        self.split_graphics_submission(api, context_pool, ctx)?;
+        ctx.base_mut().wait_for_fence(
+            &self.fences.as_ref().unwrap().async_queues,
+            fence_value,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+        );
+        for work in ready {
+            for image in work.released_images {
+                ctx.base()
+                    .acquire_image(api.device, image, work.src_family_idx);
+            }
+        }
+
+        Ok(())
+    }
+
+    // When the textures of the async pass have content, the graphics work recorded so far is submitted
+    // before it. The graphics queue waits for the async pass at the first pass using its textures, the
+    // textures used by later passes or persisting across frames are transferred back to the graphics queue
+    // family at that point.
+    #[allow(clippy::too_many_arguments)]
+    fn execute_async<Context: AsRef<vulkan::TransferContext> + AsMut<vulkan::TransferContext>>(
+        &mut self,
+        api: &mut PassApi,
+        context_pool: &mut vulkan::ContextPool,
+        ctx: &mut vulkan::GraphicsContext,
+        textures: &[Handle<TextureDesc>],
+        later_textures: &[Vec<Handle<TextureDesc>>],
+        mut async_ctx: Context,
+        execute_cb: &mut dyn FnMut(
+            &mut RenderGraph,
+            &mut PassApi,
+            &mut Context,
+        ) -> vulkan::VulkanResult<()>,
+    ) -> vulkan::VulkanResult<()> {
+        self.create_queue_fences(api.device)?;
+
+        let graphics_family_idx = api.device.graphics_family_idx;
+        let async_family_idx = async_ctx.as_ref().base_context().queue_family_idx;
+        let transfer_ownership = graphics_family_idx != async_family_idx;
+
+        let mut images = Vec::with_capacity(textures.len());
+        for texture in textures {
+            let image = self.resources.resolve_image(api.device, *texture)?;
+            // Images without content can be used by any queue without a transfer
+            let has_content = !matches!(
+                api.device.images.get(image).states.uniform_state(),
+                Some(vulkan::ImageState::Null)
+            );
+            let used_later = later_textures
+                .iter()
+                .any(|pass_textures| pass_textures.contains(texture))
+                || self.resources.texture_descs.get(*texture).origin != TextureOrigin::Transient;
+            images.push((image, has_content, used_later));
+        }
+
+        // Only previous writes to the textures need to complete before the async pass
+        async_ctx.as_ref().base_context().begin(api.device)?;
+        if images.iter().any(|(_, has_content, _)| *has_content) {
+            if transfer_ownership {
+                for &(image, has_content, _) in &images {
+                    if has_content {
+                        ctx.base()
+                            .release_image(api.device, image, async_family_idx);
+                    }
+                }
+            }
+            let graphics_value = self.split_graphics_submission(api, context_pool, ctx)?;
+
+            async_ctx.as_mut().base_context_mut().wait_for_fence(
+                &self.fences.as_ref().unwrap().graphics,
+                graphics_value,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+            );
+            if transfer_ownership {
+                for &(image, has_content, _) in &images {
+                    if has_content {
+                        async_ctx.as_ref().base_context().acquire_image(
+                            api.device,
+                            image,
+                            graphics_family_idx,
+                        );
+                    }
+                }
+            }
+        }
+
+        execute_cb(self, api, &mut async_ctx)?;
+
+        let mut released_images = Vec::with_capacity(images.len());
+        if transfer_ownership {
+            for &(image, _, used_later) in &images {
+                if used_later
+                    && !matches!(
+                        api.device.images.get(image).states.uniform_state(),
+                        Some(vulkan::ImageState::Null)
+                    )
+                {
+                    async_ctx.as_ref().base_context().release_image(
+                        api.device,
+                        image,
+                        graphics_family_idx,
+                    );
+                    released_images.push(image);
+                }
+            }
+        }
+        async_ctx.as_ref().base_context().end(api.device)?;
+
+        let async_fence = &mut self.fences.as_mut().unwrap().async_queues;
+        async_fence.value += 1;
+        let async_value = async_fence.value;
+        api.device
+            .submit(&async_ctx, &[async_fence], &[async_value])?;
+
+        self.pending_async_work.push(PendingAsyncWork {
+            fence_value: async_value,
+            src_family_idx: async_family_idx,
+            textures: textures.to_vec(),
+            released_images,
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Attachment {
+    pub texture: Handle<TextureDesc>,
+    pub load_op: vulkan::LoadOp,
+    pub store_op: vulkan::StoreOp,
+    // Single sampled texture receiving the multisampled `texture` at the end of the pass. Graphics passes only
+    // have a color attachment, multisampling in the graph is color-only.
+    pub resolve: Handle<TextureDesc>,
+}
+
+impl Attachment {
+    pub fn new(texture: Handle<TextureDesc>) -> Self {
+        Self {
+            texture,
+            load_op: vulkan::LoadOp::ClearColor(vulkan::ClearColorValue::Float32([
+                0.0, 0.0, 0.0, 1.0,
+            ])),
+            store_op: vulkan::StoreOp::Store,
+            resolve: Handle::invalid(),
+        }
+    }
+
+    pub fn load_op(mut self, load_op: vulkan::LoadOp) -> Self {
+        self.load_op = load_op;
+        self
+    }
+
+    pub fn store_op(mut self, store_op: vulkan::StoreOp) -> Self {
+        self.store_op = store_op;
+        self
+    }
+
+    // The multisampled content is usually not needed after the resolve
+    pub fn resolve(mut self, resolve: Handle<TextureDesc>) -> Self {
+        self.resolve = resolve;
+        self.store_op = vulkan::StoreOp::Ignore;
+        self
+    }
 }
 
 pub struct GraphicPass {
-    color_attachment: Handle<TextureDesc>,
-    execute_cb: Box<dyn FnMut(&mut RenderGraph, &mut PassApi, &mut vulkan::GraphicsContext)>,
+    color_attachment: Attachment,
+    execute_cb: Box<
+        dyn FnMut(
+            &mut RenderGraph,
+            &mut PassApi,
+            &mut vulkan::GraphicsContext,
+        ) -> vulkan::VulkanResult<()>,
+    >,
 }
 
 impl RenderGraph {
     pub fn graphics_pass(
         &mut self,
-        color_attachment: Handle<TextureDesc>,
-        execute: impl (FnMut(&mut RenderGraph, &mut PassApi, &mut vulkan::GraphicsContext)) + 'static,
+        color_attachment: Attachment,
+        execute: impl (FnMut(
+                &mut RenderGraph,
+                &mut PassApi,
+                &mut vulkan::GraphicsContext,
+            ) -> vulkan::VulkanResult<()>)
+            + 'static,
     ) {
-        self.passes.push(Pass::Graphic(GraphicPass {
+        self.use_texture(
+            color_attachment.texture,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT,
+        );
+        if color_attachment.resolve.is_valid() {
+            self.use_texture(
+                color_attachment.resolve,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            );
+        }
+        self.add_pass(Pass::Graphic(GraphicPass {
             color_attachment,
             execute_cb: Box::new(execute),
         }))
     }
 }
 
+type ComputeCallback = Box<
+    dyn FnMut(
+        &mut RenderGraph,
+        &mut PassApi,
+        &mut vulkan::ComputeContext,
+    ) -> vulkan::VulkanResult<()>,
+>;
+type TransferCallback = Box<
+    dyn FnMut(
+        &mut RenderGraph,
+        &mut PassApi,
+        &mut vulkan::TransferContext,
+    ) -> vulkan::VulkanResult<()>,
+>;
+
+pub struct AsyncComputePass {
+    textures: Vec<Handle<TextureDesc>>,
+    execute_cb: ComputeCallback,
+}
+
+pub struct TransferPass {
+    textures: Vec<Handle<TextureDesc>>,
+    execute_cb: TransferCallback,
+}
+
+impl RenderGraph {
+    // `textures` lists all the textures used by the pass, they are transferred to the compute queue family
+    pub fn async_compute_pass(
+        &mut self,
+        textures: &[Handle<TextureDesc>],
+        execute: impl (FnMut(
+                &mut RenderGraph,
+                &mut PassApi,
+                &mut vulkan::ComputeContext,
+            ) -> vulkan::VulkanResult<()>)
+            + 'static,
+    ) {
+        self.add_pass(Pass::AsyncCompute(AsyncComputePass {
+            textures: textures.to_vec(),
+            execute_cb: Box::new(execute),
+        }))
+    }
+
+    pub fn transfer_pass(
+        &mut self,
+        textures: &[Handle<TextureDesc>],
+        execute: impl (FnMut(
+                &mut RenderGraph,
+                &mut PassApi,
+                &mut vulkan::TransferContext,
+            ) -> vulkan::VulkanResult<()>)
+            + 'static,
+    ) {
+        self.add_pass(Pass::Transfer(TransferPass {
+            textures: textures.to_vec(),
+            execute_cb: Box::new(execute),
+        }))
+    }
+}
+
 pub struct RawPass {
     execute_cb: Box<
         dyn FnMut(
@@ -158,7 +629,7 @@ impl RenderGraph {
             ) -> vulkan::VulkanResult<()>)
             + 'static,
     ) {
-        self.passes.push(Pass::Raw(RawPass {
+        self.add_pass(Pass::Raw(RawPass {
             execute_cb: Box::new(execute),
         }))
     }
@@ -169,6 +640,39 @@ impl RenderGraph {
         self.resources.texture_descs.add(output_desc)
     }
 
+    // Images owned outside of the graph, they are never pooled nor destroyed by the graph
+    pub fn import_image(
+        &mut self,
+        device: &vulkan::Device,
+        image_handle: Handle<vulkan::Image>,
+    ) -> Handle<TextureDesc> {
+        self.resources.import_image(device, image_handle)
+    }
+
+    // Histories are owned by the caller and persist until destroyed, unlike the textures of a frame
+    pub fn create_history(&mut self) -> Handle<HistoryResource> {
+        self.resources.create_history()
+    }
+
+    pub fn destroy_history(&mut self, history: Handle<HistoryResource>) {
+        self.resources.destroy_history(history)
+    }
+
+    // Textures persisting across frames, `previous` contains what was written to `current` last frame
+    pub fn history_image(
+        &mut self,
+        history: Handle<HistoryResource>,
+        desc: TextureDesc,
+    ) -> HistoryTexture {
+        self.resources.add_history(history, desc)
+    }
+
+    // The image backing a texture is created with the union of the usages declared by passes
+    pub fn use_texture(&mut self, desc_handle: Handle<TextureDesc>, usages: vk::ImageUsageFlags) {
+        self.resources.texture_descs.get_mut(desc_handle).usages |= usages;
+        self.pending_uses.push((desc_handle, usages));
+    }
+
     pub fn image_size(&self, desc_handle: Handle<TextureDesc>) -> [i32; 3] {
         let desc = self.resources.texture_descs.get(desc_handle);
         self.resources.texture_desc_size(desc.size)
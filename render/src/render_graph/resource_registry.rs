@@ -7,9 +7,66 @@ struct ImageMetadata {
     pub(crate) last_frame_used: u64,
 }
 
+// Images unused for 18 frames are unbound from the bindless set, and destroyed the frame after
+fn should_unbind(last_frame_used: u64, i_frame: u64) -> bool {
+    (last_frame_used + 18) < i_frame
+}
+
+fn should_destroy(last_frame_used: u64, i_frame: u64) -> bool {
+    (last_frame_used + 19) < i_frame
+}
+
+// A pair of images alternating between "current" and "previous" every frame the history is used, created with
+// `RenderGraph::create_history`
+pub struct HistoryResource {
+    images: [Handle<vulkan::Image>; 2],
+    descs: [Handle<TextureDesc>; 2],
+    // The frame each image was last resolved as the current image
+    written_frames: [Option<u64>; 2],
+    i_current: usize,
+    last_frame_used: Option<u64>,
+}
+
+impl HistoryResource {
+    fn new() -> Self {
+        Self {
+            images: [Handle::invalid(), Handle::invalid()],
+            descs: [Handle::invalid(), Handle::invalid()],
+            written_frames: [None, None],
+            i_current: 0,
+            last_frame_used: None,
+        }
+    }
+
+    // The current image of the last frame using the history is the previous image of the next one
+    fn image_index(&self, i_frame: u64, previous: bool) -> usize {
+        let i_current = if self.last_frame_used == Some(i_frame) {
+            self.i_current
+        } else {
+            self.i_current ^ 1
+        };
+        i_current ^ previous as usize
+    }
+
+    fn use_frame(&mut self, i_frame: u64) {
+        self.i_current = self.image_index(i_frame, false);
+        self.last_frame_used = Some(i_frame);
+    }
+
+    // The previous image is garbage when it wasn't written the frame before, because the images got created or
+    // because no pass used the history that frame
+    fn has_previous_frame(&self, i_frame: u64) -> bool {
+        let previous = self.image_index(i_frame, true);
+        i_frame > 0 && self.written_frames[previous] == Some(i_frame - 1)
+    }
+}
+
 pub struct ResourceRegistry {
     pub(crate) texture_descs: Pool<TextureDesc>,
     image_pool: HashMap<Handle<vulkan::Image>, ImageMetadata>,
+    history_resources: Pool<HistoryResource>,
+    // Images that frames in flight may still use, with the last frame they were used in
+    retired_images: Vec<(Handle<vulkan::Image>, u64)>,
     framebuffers: Vec<Handle<vulkan::Framebuffer>>,
     framebuffer_pool: HashMap<Handle<vulkan::Framebuffer>, u64>,
     pub(crate) screen_size: [f32; 2],
@@ -21,6 +78,8 @@ impl ResourceRegistry {
         Self {
             texture_descs: Default::default(),
             image_pool: Default::default(),
+            history_resources: Pool::new(),
+            retired_images: Vec::new(),
             framebuffers: Vec::new(),
             framebuffer_pool: Default::default(),
             screen_size: [1.0, 1.0],
@@ -35,12 +94,47 @@ pub enum TextureSize {
     Absolute([i32; 3]),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum TextureOrigin {
+    Transient,
+    Imported,
+    History {
+        history: Handle<HistoryResource>,
+        previous: bool,
+    },
+}
+
+impl TextureOrigin {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Transient => "transient",
+            Self::Imported => "imported",
+            Self::History {
+                previous: false, ..
+            } => "history",
+            Self::History { previous: true, .. } => "history (previous)",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct HistoryTexture {
+    pub current: Handle<TextureDesc>,
+    pub previous: Handle<TextureDesc>,
+}
+
 pub struct TextureDesc {
     pub name: String,
     pub size: TextureSize,
     pub format: vk::Format,
     pub image_type: vk::ImageType,
-    resolved_image: Handle<vulkan::Image>,
+    pub mip_levels: u32,
+    pub array_layers: u32,
+    pub samples: vk::SampleCountFlagBits,
+    // Accumulated from the passes using the texture, see `RenderGraph::use_texture`
+    pub usages: vk::ImageUsageFlags,
+    pub(crate) resolved_image: Handle<vulkan::Image>,
+    pub(crate) origin: TextureOrigin,
 }
 
 impl TextureDesc {
@@ -50,7 +144,12 @@ impl TextureDesc {
             size,
             format: vk::Format::R8G8B8A8_UNORM,
             image_type: vk::ImageType::_2D,
+            mip_levels: 1,
+            array_layers: 1,
+            samples: vk::SampleCountFlagBits::_1,
+            usages: vk::ImageUsageFlags::empty(),
             resolved_image: Handle::invalid(),
+            origin: TextureOrigin::Transient,
         }
     }
 
@@ -63,6 +162,21 @@ impl TextureDesc {
         self.image_type = image_type;
         self
     }
+
+    pub fn mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = mip_levels;
+        self
+    }
+
+    pub fn array_layers(mut self, array_layers: u32) -> Self {
+        self.array_layers = array_layers;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlagBits) -> Self {
+        self.samples = samples;
+        self
+    }
 }
 
 impl ResourceRegistry {
@@ -90,13 +204,11 @@ impl ResourceRegistry {
 
         let mut img_to_remove: Vec<Handle<vulkan::Image>> = Default::default();
         for (image_handle, metadata) in &mut self.image_pool {
-            // Unbind images from the bindless set unused for 18 frames
-            if (metadata.last_frame_used + 18) < i_frame {
+            if should_unbind(metadata.last_frame_used, i_frame) {
                 device.unbind_image(*image_handle);
             }
 
-            // Destroy images unused for 19 frames
-            if (metadata.last_frame_used + 19) < i_frame {
+            if should_destroy(metadata.last_frame_used, i_frame) {
                 img_to_remove.push(*image_handle);
             }
         }
@@ -123,6 +235,38 @@ impl ResourceRegistry {
             device.destroy_image(handle);
             self.image_pool.remove(&handle);
         }
+
+        // History images are not pooled, retire them once the history isn't requested anymore
+        let histories: Vec<Handle<HistoryResource>> = self
+            .history_resources
+            .iter()
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in histories {
+            let history = self.history_resources.get_mut(handle);
+            if let Some(last_frame_used) = history.last_frame_used {
+                if history.images[0].is_valid() && should_unbind(last_frame_used, i_frame) {
+                    for image in &mut history.images {
+                        self.retired_images.push((*image, last_frame_used));
+                        *image = Handle::invalid();
+                    }
+                    history.written_frames = [None, None];
+                }
+            }
+        }
+
+        self.retired_images
+            .retain(|(image_handle, last_frame_used)| {
+                if should_unbind(*last_frame_used, i_frame) {
+                    device.unbind_image(*image_handle);
+                }
+                if should_destroy(*last_frame_used, i_frame) {
+                    device.destroy_image(*image_handle);
+                    false
+                } else {
+                    true
+                }
+            });
     }
 
     pub fn end_frame(&mut self) {
@@ -131,6 +275,126 @@ impl ResourceRegistry {
         for metadata in self.image_pool.values_mut() {
             metadata.resolved_desc = Handle::invalid();
         }
+        let histories: Vec<Handle<HistoryResource>> = self
+            .history_resources
+            .iter()
+            .map(|(handle, _)| handle)
+            .collect();
+        for handle in histories {
+            self.history_resources.get_mut(handle).descs = [Handle::invalid(), Handle::invalid()];
+        }
+    }
+
+    pub fn import_image(
+        &mut self,
+        device: &vulkan::Device,
+        image_handle: Handle<vulkan::Image>,
+    ) -> Handle<TextureDesc> {
+        let spec = &device.images.get(image_handle).spec;
+        let mut desc = TextureDesc::new(spec.name.clone(), TextureSize::Absolute(spec.size))
+            .format(spec.format)
+            .image_type(spec.image_type)
+            .mip_levels(spec.mip_levels)
+            .array_layers(spec.array_layers)
+            .samples(spec.samples);
+        desc.usages = spec.usages;
+        desc.resolved_image = image_handle;
+        desc.origin = TextureOrigin::Imported;
+        self.texture_descs.add(desc)
+    }
+
+    pub fn create_history(&mut self) -> Handle<HistoryResource> {
+        self.history_resources.add(HistoryResource::new())
+    }
+
+    // Frames in flight can still be using the images of the history
+    pub fn destroy_history(&mut self, history: Handle<HistoryResource>) {
+        let resource = self.history_resources.get(history);
+        for image in resource.images {
+            if image.is_valid() {
+                self.retired_images.push((image, self.i_frame));
+            }
+        }
+        self.history_resources.remove(history);
+    }
+
+    pub fn add_history(
+        &mut self,
+        history: Handle<HistoryResource>,
+        desc: TextureDesc,
+    ) -> HistoryTexture {
+        let previous_desc = TextureDesc {
+            name: format!("{} (previous)", desc.name),
+            size: desc.size,
+            format: desc.format,
+            image_type: desc.image_type,
+            mip_levels: desc.mip_levels,
+            array_layers: desc.array_layers,
+            samples: desc.samples,
+            usages: vk::ImageUsageFlags::empty(),
+            resolved_image: Handle::invalid(),
+            origin: TextureOrigin::History {
+                history,
+                previous: true,
+            },
+        };
+        let current_desc = TextureDesc {
+            origin: TextureOrigin::History {
+                history,
+                previous: false,
+            },
+            ..desc
+        };
+
+        let current = self.texture_descs.add(current_desc);
+        let previous = self.texture_descs.add(previous_desc);
+        self.history_resources.get_mut(history).descs = [current, previous];
+
+        HistoryTexture { current, previous }
+    }
+
+    // The previous frame of a history is garbage the first frame it is used, after it got resized or after a frame
+    // that didn't use it
+    pub fn is_history_valid(&self, desc_handle: Handle<TextureDesc>) -> bool {
+        match self.texture_descs.get(desc_handle).origin {
+            TextureOrigin::History { history, .. } => {
+                let history = self.history_resources.get(history);
+                history.images[0].is_valid() && history.has_previous_frame(self.i_frame)
+            }
+            _ => false,
+        }
+    }
+
+    // Textures that can be blitted to a preview image at the end of a frame. Transient images are not aliased
+    // before the end of the frame, so any texture written by a pass can be copied. The handles of a frame are found
+    // again the next frame when the graph is declared in the same order.
+    pub fn is_previewable(
+        &self,
+        device: &vulkan::Device,
+        desc_handle: Handle<TextureDesc>,
+    ) -> bool {
+        let desc = match self
+            .texture_descs
+            .iter()
+            .find(|(handle, _desc)| *handle == desc_handle)
+        {
+            Some((_handle, desc)) => desc,
+            None => return false,
+        };
+
+        let readable = match desc.origin {
+            TextureOrigin::Imported => device
+                .images
+                .get(desc.resolved_image)
+                .spec
+                .usages
+                .contains(vk::ImageUsageFlags::TRANSFER_SRC),
+            // The blit adds TRANSFER_SRC to the usages the image is created with
+            TextureOrigin::History { .. } | TextureOrigin::Transient => !desc.usages.is_empty(),
+        };
+        readable
+            && desc.samples == vk::SampleCountFlagBits::_1
+            && !vulkan::is_depth_format(desc.format)
     }
 
     pub fn set_image(
@@ -165,24 +429,26 @@ impl ResourceRegistry {
     ) -> vulkan::VulkanResult<Handle<vulkan::Image>> {
         let desc = self.texture_descs.get(desc_handle);
 
+        match desc.origin {
+            TextureOrigin::Imported => return Ok(desc.resolved_image),
+            TextureOrigin::History { history, previous } => {
+                return self.resolve_history_image(device, history, previous)
+            }
+            TextureOrigin::Transient => {}
+        }
+
         let resolved_image_handle = if desc.resolved_image.is_valid() {
             // The image has already been resolved
             desc.resolved_image
         } else {
+            assert!(
+                !desc.usages.is_empty(),
+                "texture {:?} is resolved but no pass declared its usage",
+                desc.name
+            );
+
             // Find a free image in our pool that matches the spec
-            let desc_spec = vulkan::ImageSpec {
-                name: desc.name.clone(),
-                size: self.texture_desc_size(desc.size),
-                mip_levels: 1,
-                image_type: desc.image_type,
-                format: desc.format,
-                usages: vk::ImageUsageFlags::TRANSFER_SRC
-                    | vk::ImageUsageFlags::TRANSFER_DST
-                    | vk::ImageUsageFlags::SAMPLED
-                    | vk::ImageUsageFlags::COLOR_ATTACHMENT
-                    | vk::ImageUsageFlags::STORAGE,
-                ..Default::default()
-            };
+            let desc_spec = self.desc_spec(desc, desc.usages);
 
             let mut resolved_image_handle = None;
             for (image_handle, metadata) in &self.image_pool {
@@ -212,6 +478,67 @@ impl ResourceRegistry {
         Ok(resolved_image_handle)
     }
 
+    fn resolve_history_image(
+        &mut self,
+        device: &mut vulkan::Device,
+        history_handle: Handle<HistoryResource>,
+        previous: bool,
+    ) -> vulkan::VulkanResult<Handle<vulkan::Image>> {
+        let history = self.history_resources.get(history_handle);
+        let [current_desc, previous_desc] = history.descs;
+        let current_desc = self.texture_descs.get(current_desc);
+        let usages = current_desc.usages | self.texture_descs.get(previous_desc).usages;
+        assert!(
+            !usages.is_empty(),
+            "history {:?} is resolved but no pass declared its usage",
+            current_desc.name
+        );
+        let desc_spec = self.desc_spec(current_desc, usages);
+
+        let history = self.history_resources.get_mut(history_handle);
+        let needs_creation =
+            !history.images[0].is_valid() || device.images.get(history.images[0]).spec != desc_spec;
+
+        if needs_creation {
+            for image in &mut history.images {
+                // Frames in flight can still be using the previous pair
+                if image.is_valid() {
+                    self.retired_images.push((*image, self.i_frame));
+                }
+                *image = device.create_image(vulkan::ImageSpec {
+                    name: desc_spec.name.clone(),
+                    ..desc_spec
+                })?;
+            }
+            device.update_bindless_set();
+            history.written_frames = [None, None];
+        }
+
+        history.use_frame(self.i_frame);
+        let i_image = history.image_index(self.i_frame, previous);
+        if !previous {
+            history.written_frames[i_image] = Some(self.i_frame);
+        }
+        let image_handle = history.images[i_image];
+        self.texture_descs
+            .get_mut(history.descs[previous as usize])
+            .resolved_image = image_handle;
+        Ok(image_handle)
+    }
+
+    fn desc_spec(&self, desc: &TextureDesc, usages: vk::ImageUsageFlags) -> vulkan::ImageSpec {
+        vulkan::ImageSpec {
+            name: desc.name.clone(),
+            size: self.texture_desc_size(desc.size),
+            mip_levels: desc.mip_levels,
+            array_layers: desc.array_layers,
+            image_type: desc.image_type,
+            format: desc.format,
+            samples: desc.samples,
+            usages,
+        }
+    }
+
     pub(crate) fn texture_desc_size(&self, texture_size: TextureSize) -> [i32; 3] {
         match texture_size {
             TextureSize::Absolute(absolute) => absolute,
@@ -239,12 +566,17 @@ impl ResourceRegistry {
         &mut self,
         device: &mut vulkan::Device,
         color_attachments: &[Handle<TextureDesc>],
+        resolve_attachments: &[Handle<TextureDesc>],
         depth_attachment: Handle<TextureDesc>,
     ) -> vulkan::VulkanResult<Handle<vulkan::Framebuffer>> {
         let color_attachments: Vec<Handle<vulkan::Image>> = color_attachments
             .iter()
             .map(|desc_handle| self.texture_descs.get(*desc_handle).resolved_image)
             .collect();
+        let resolve_attachments: Vec<Handle<vulkan::Image>> = resolve_attachments
+            .iter()
+            .map(|desc_handle| self.texture_descs.get(*desc_handle).resolved_image)
+            .collect();
 
         let depth_attachment = if depth_attachment.is_valid() {
             self.resolve_image(device, depth_attachment)?
@@ -265,6 +597,7 @@ impl ResourceRegistry {
         for framebuffer_handle in &self.framebuffers {
             let framebuffer = device.framebuffers.get(*framebuffer_handle);
             if framebuffer.color_attachments.as_slice() == color_attachments.as_slice()
+                && framebuffer.resolve_attachments.as_slice() == resolve_attachments.as_slice()
                 && framebuffer.depth_attachment == depth_attachment
                 && framebuffer.format.size == size
             {
@@ -277,7 +610,12 @@ impl ResourceRegistry {
             }
         }
 
-        let new_handle = device.create_framebuffer(size, &color_attachments, depth_attachment)?;
+        let new_handle = device.create_framebuffer(
+            size,
+            &color_attachments,
+            &resolve_attachments,
+            depth_attachment,
+        )?;
         Self::update_framebuffer_metadata(&mut self.framebuffer_pool, self.i_frame, new_handle);
         self.framebuffers.push(new_handle);
         Ok(new_handle)
@@ -289,3 +627,60 @@ impl Default for ResourceRegistry {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_images_alternate() {
+        let mut history = HistoryResource::new();
+        for i_frame in 0..4 {
+            history.use_frame(i_frame);
+            let current = history.image_index(i_frame, false);
+            assert_ne!(current, history.image_index(i_frame, true));
+            assert_eq!(history.image_index(i_frame + 1, true), current);
+        }
+    }
+
+    #[test]
+    fn history_is_invalid_after_a_skipped_frame() {
+        let mut history = HistoryResource::new();
+        let write = |history: &mut HistoryResource, i_frame: u64| {
+            history.use_frame(i_frame);
+            let current = history.image_index(i_frame, false);
+            history.written_frames[current] = Some(i_frame);
+        };
+
+        assert!(!history.has_previous_frame(5));
+        write(&mut history, 5);
+        assert!(history.has_previous_frame(6));
+        write(&mut history, 6);
+        // Nothing wrote the history on frame 7, the previous image still holds frame 5
+        assert!(!history.has_previous_frame(8));
+        write(&mut history, 8);
+        assert!(history.has_previous_frame(9));
+    }
+
+    #[test]
+    fn histories_with_the_same_name_are_distinct() {
+        let mut registry = ResourceRegistry::new();
+        let first = registry.create_history();
+        let second = registry.create_history();
+        let desc = || TextureDesc::new(String::from("taa"), TextureSize::Absolute([4, 4, 1]));
+        let first_texture = registry.add_history(first, desc());
+        let second_texture = registry.add_history(second, desc());
+        assert!(
+            registry.texture_descs.get(first_texture.current).origin
+                != registry.texture_descs.get(second_texture.current).origin
+        );
+    }
+
+    #[test]
+    fn retired_images_are_unbound_before_destruction() {
+        assert!(!should_unbind(10, 28));
+        assert!(should_unbind(10, 29));
+        assert!(!should_destroy(10, 29));
+        assert!(should_destroy(10, 30));
+    }
+}
@@ -1,29 +1,127 @@
-use exo::{dynamic_array::DynamicArray, pool::Handle};
+use exo::{dynamic_array::DynamicArray, pod::Pod, pool::Handle};
 
 use super::vulkan::{buffer::*, device::*, error::*};
 
 use erupt::vk;
 
+#[derive(Clone)]
 pub struct RingBufferSpec {
     pub usages: vk::BufferUsageFlags,
     pub memory_usage: MemoryUsageFlags,
     pub frame_queue_length: usize,
     pub buffer_size: usize,
+    // When full, chain a new buffer twice as big instead of failing
+    pub allow_growth: bool,
+}
+
+// An allocation is only valid for the frame it was made in, `buffer` can differ between allocations of a
+// same frame when the ring buffer grows.
+pub struct RingAllocation<'a, T> {
+    pub buffer: Handle<Buffer>,
+    pub offset: u32,
+    pub data: &'a mut [T],
+}
+
+// Cursor math of the ring buffer, independent of the backing memory
+pub struct RingAllocator {
+    capacity: usize,
+    cursor: usize,
+    i_frame: usize,
+    // Bytes consumed by each in-flight frame, including alignment padding and the end skipped when wrapping
+    used_per_frame: DynamicArray<usize, 8>,
+    frame_high_water_mark: usize,
+    in_flight_high_water_mark: usize,
+}
+
+impl RingAllocator {
+    pub fn new(capacity: usize, frame_queue_length: usize) -> Self {
+        let mut used_per_frame = DynamicArray::<usize, 8>::new();
+        used_per_frame.resize(frame_queue_length, 0);
+
+        Self {
+            capacity,
+            cursor: 0,
+            i_frame: 0,
+            used_per_frame,
+            frame_high_water_mark: 0,
+            in_flight_high_water_mark: 0,
+        }
+    }
+
+    // The frame that used this slot `frame_queue_length` frames ago is done with its data
+    pub fn start_frame(&mut self) {
+        self.i_frame += 1;
+        let i_slot = self.i_frame % self.used_per_frame.len();
+        self.used_per_frame[i_slot] = 0;
+    }
+
+    pub fn allocate(&mut self, size: usize, alignment: usize) -> Option<usize> {
+        let in_flight: usize = self.used_per_frame.as_slice().iter().sum();
+
+        let mut offset = self.cursor;
+        let dist = offset % alignment;
+        if dist != 0 {
+            offset += alignment - dist;
+        }
+        let mut consumed = offset - self.cursor;
+
+        if offset + size > self.capacity {
+            consumed = self.capacity - self.cursor;
+            offset = 0;
+        }
+        consumed += size;
+
+        if in_flight + consumed > self.capacity {
+            return None;
+        }
+
+        self.cursor = offset + size;
+        let i_slot = self.i_frame % self.used_per_frame.len();
+        self.used_per_frame[i_slot] += consumed;
+
+        self.frame_high_water_mark = self.frame_high_water_mark.max(self.used_per_frame[i_slot]);
+        self.in_flight_high_water_mark = self.in_flight_high_water_mark.max(in_flight + consumed);
+
+        Some(offset)
+    }
+
+    // An empty allocator for a bigger buffer, the frame count and the statistics carry over
+    pub fn grown(&self, min_size: usize) -> Self {
+        let capacity = (2 * self.capacity).max(min_size.next_power_of_two());
+        let mut allocator = Self::new(capacity, self.used_per_frame.len());
+        allocator.i_frame = self.i_frame;
+        allocator.frame_high_water_mark = self.frame_high_water_mark;
+        allocator.in_flight_high_water_mark = self.in_flight_high_water_mark;
+        allocator
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Most bytes used by a single frame
+    pub fn frame_high_water_mark(&self) -> usize {
+        self.frame_high_water_mark
+    }
+
+    // Most bytes used at once by all the in-flight frames, `buffer_size` needs to be at least this
+    pub fn in_flight_high_water_mark(&self) -> usize {
+        self.in_flight_high_water_mark
+    }
 }
 
 pub struct RingBuffer {
     pub buffer: Handle<Buffer>,
+    spec: RingBufferSpec,
     memory_buffer: *mut [u8],
-    cursor: usize,
+    allocator: RingAllocator,
+    // Buffers replaced when growing, with the frame they were last used in
+    retired_buffers: Vec<(Handle<Buffer>, usize)>,
     i_frame: usize,
-    start_per_frame: DynamicArray<Option<usize>, 8>,
 }
 
 impl RingBuffer {
     pub fn new(device: &mut Device, spec: RingBufferSpec) -> VulkanResult<Self> {
-        let mut start_per_frame = DynamicArray::<Option<usize>, 8>::new();
-        start_per_frame.resize(spec.frame_queue_length, None);
-
         let buffer = device.create_buffer(BufferSpec {
             size: spec.buffer_size,
             usages: spec.usages,
@@ -33,47 +131,308 @@ impl RingBuffer {
         Ok(Self {
             buffer,
             memory_buffer: device.map_buffer(buffer),
-            cursor: 0,
+            allocator: RingAllocator::new(spec.buffer_size, spec.frame_queue_length),
+            spec,
+            retired_buffers: Vec::new(),
             i_frame: 0,
-            start_per_frame,
         })
     }
 
-    pub fn start_frame(&mut self) {
+    pub fn spec(&self) -> &RingBufferSpec {
+        &self.spec
+    }
+
+    pub fn destroy(&mut self, device: &mut Device) {
+        for (buffer, _) in self.retired_buffers.drain(..) {
+            device.destroy_buffer(buffer);
+        }
+        device.destroy_buffer(self.buffer);
+    }
+
+    pub fn start_frame(&mut self, device: &mut Device) {
         self.i_frame += 1;
-        let i_start = self.i_frame % self.start_per_frame.len();
-        self.start_per_frame[i_start] = Some(self.cursor);
+        self.allocator.start_frame();
+
+        let frame_queue_length = self.spec.frame_queue_length;
+        let i_frame = self.i_frame;
+        self.retired_buffers.retain(|(buffer, i_last_frame)| {
+            if i_frame >= i_last_frame + frame_queue_length {
+                device.destroy_buffer(*buffer);
+                false
+            } else {
+                true
+            }
+        });
     }
 
-    pub fn allocate(&mut self, size: usize, alignment: usize) -> (*mut [u8], u32) {
-        let dist = self.cursor % alignment;
-        if dist != 0 {
-            self.cursor += alignment - dist;
-            assert!(self.cursor % alignment == 0);
+    pub fn allocate_slice<T: Pod>(
+        &mut self,
+        device: &mut Device,
+        len: usize,
+    ) -> VulkanResult<RingAllocation<'_, T>> {
+        self.allocate_slice_aligned(device, len, std::mem::align_of::<T>())
+    }
+
+    // `alignment` is the alignment of the offset in the buffer, for example the minimum uniform buffer offset alignment
+    pub fn allocate_slice_aligned<T: Pod>(
+        &mut self,
+        device: &mut Device,
+        len: usize,
+        alignment: usize,
+    ) -> VulkanResult<RingAllocation<'_, T>> {
+        assert!(alignment % std::mem::align_of::<T>() == 0);
+        let size = len * std::mem::size_of::<T>();
+
+        let offset = match self.allocator.allocate(size, alignment) {
+            Some(offset) => offset,
+            None if self.spec.allow_growth => {
+                self.grow(device, size)?;
+                self.allocator.allocate(size, alignment).unwrap()
+            }
+            None => {
+                return Err(VulkanError::RingBufferFull {
+                    requested: size,
+                    capacity: self.allocator.capacity(),
+                })
+            }
+        };
+        assert!(offset < u32::MAX as usize);
+
+        // The mapped memory is aligned to at least `minMemoryMapAlignment`, a Pod type is valid for any bytes
+        let data = unsafe {
+            assert!(offset + size <= self.memory_buffer.len());
+            let bytes =
+                std::slice::from_raw_parts_mut((self.memory_buffer as *mut u8).add(offset), size);
+            assert!(bytes.as_ptr() as usize % std::mem::align_of::<T>() == 0);
+            std::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut T, len)
+        };
+
+        Ok(RingAllocation {
+            buffer: self.buffer,
+            offset: offset as u32,
+            data,
+        })
+    }
+
+    pub fn frame_high_water_mark(&self) -> usize {
+        self.allocator.frame_high_water_mark()
+    }
+
+    pub fn in_flight_high_water_mark(&self) -> usize {
+        self.allocator.in_flight_high_water_mark()
+    }
+
+    // Allocations already made this frame stay in the old buffer until the GPU is done with it
+    fn grow(&mut self, device: &mut Device, min_size: usize) -> VulkanResult<()> {
+        let allocator = self.allocator.grown(min_size);
+        let buffer = device.create_buffer(BufferSpec {
+            size: allocator.capacity(),
+            usages: self.spec.usages,
+            memory_usage: self.spec.memory_usage,
+        })?;
+
+        // The storage descriptor of the new buffer has to be written before the caller records its index
+        if self
+            .spec
+            .usages
+            .contains(vk::BufferUsageFlags::STORAGE_BUFFER)
+        {
+            device.update_bindless_set();
+        }
+
+        self.retired_buffers.push((self.buffer, self.i_frame));
+        self.buffer = buffer;
+        self.memory_buffer = device.map_buffer(buffer);
+
+        self.spec.buffer_size = allocator.capacity();
+        self.allocator = allocator;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frame id, offset and size
+    type Allocation = (u8, usize, usize);
+
+    // Writes a frame id in every allocated byte and checks that the data of in-flight frames is intact
+    struct FakeRingBuffer {
+        allocator: RingAllocator,
+        memory: Vec<u8>,
+        allocations: Vec<Allocation>,
+        frame_queue_length: usize,
+        i_frame: u8,
+        allow_growth: bool,
+        // Memory replaced when growing, with its allocations
+        retired: Vec<(Vec<u8>, Vec<Allocation>)>,
+    }
+
+    impl FakeRingBuffer {
+        fn new(capacity: usize, frame_queue_length: usize) -> Self {
+            Self {
+                allocator: RingAllocator::new(capacity, frame_queue_length),
+                memory: vec![0; capacity],
+                allocations: Vec::new(),
+                frame_queue_length,
+                i_frame: 0,
+                allow_growth: false,
+                retired: Vec::new(),
+            }
         }
 
-        if self.cursor + size > unsafe { (*self.memory_buffer).len() } {
-            self.cursor = 0;
+        fn start_frame(&mut self) {
+            self.allocator.start_frame();
+            self.i_frame += 1;
+            let oldest_frame = (self.i_frame as usize + 1).saturating_sub(self.frame_queue_length);
+            self.allocations
+                .retain(|(i_frame, _, _)| *i_frame as usize >= oldest_frame);
+            for (_, allocations) in &mut self.retired {
+                allocations.retain(|(i_frame, _, _)| *i_frame as usize >= oldest_frame);
+            }
+            self.retired
+                .retain(|(_, allocations)| !allocations.is_empty());
         }
 
-        let frame_size = self.start_per_frame.len();
-        let previous_frame_start =
-            self.start_per_frame[(self.i_frame + frame_size - 1) % frame_size];
+        fn allocate(&mut self, size: usize, alignment: usize) -> Option<usize> {
+            let offset = match self.allocator.allocate(size, alignment) {
+                Some(offset) => offset,
+                None if self.allow_growth => {
+                    self.grow(size);
+                    self.allocator.allocate(size, alignment).unwrap()
+                }
+                None => return None,
+            };
+            assert_eq!(offset % alignment, 0);
+            assert!(offset + size <= self.memory.len());
 
-        if previous_frame_start.is_some()
-            && self.cursor < previous_frame_start.unwrap()
-            && self.cursor + size > previous_frame_start.unwrap()
-        {
-            panic!("Not enough space in the ring buffer");
+            self.memory[offset..offset + size].fill(self.i_frame);
+            self.allocations.push((self.i_frame, offset, size));
+            self.check_in_flight_data();
+            Some(offset)
+        }
+
+        fn grow(&mut self, min_size: usize) {
+            self.allocator = self.allocator.grown(min_size);
+            let memory = std::mem::replace(&mut self.memory, vec![0; self.allocator.capacity()]);
+            let allocations = std::mem::take(&mut self.allocations);
+            self.retired.push((memory, allocations));
         }
 
-        let offset = self.cursor;
-        self.cursor += size;
+        fn check_in_flight_data(&self) {
+            let retired = self
+                .retired
+                .iter()
+                .map(|(memory, allocations)| (memory, allocations));
+            for (memory, allocations) in
+                retired.chain(std::iter::once((&self.memory, &self.allocations)))
+            {
+                for (i_frame, offset, size) in allocations {
+                    assert!(memory[*offset..*offset + *size]
+                        .iter()
+                        .all(|byte| byte == i_frame));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wraps_around_when_previous_frames_are_done() {
+        let mut ring = FakeRingBuffer::new(100, 2);
+        for _ in 0..10 {
+            ring.start_frame();
+            assert!(ring.allocate(30, 1).is_some());
+            assert!(ring.allocate(15, 1).is_some());
+        }
+        // Frames that wrap also count the skipped end of the buffer
+        assert!(ring.allocator.frame_high_water_mark() >= 45);
+        assert!(ring.allocator.in_flight_high_water_mark() <= 100);
+    }
+
+    #[test]
+    fn checks_every_in_flight_frame() {
+        // The oldest frame is not the previous one, with three frames in flight
+        let mut ring = FakeRingBuffer::new(100, 3);
+        ring.start_frame();
+        assert_eq!(ring.allocate(40, 1), Some(0));
+        ring.start_frame();
+        assert_eq!(ring.allocate(40, 1), Some(40));
+        ring.start_frame();
+        assert_eq!(ring.allocate(20, 1), Some(80));
+        // Wrapping would overwrite the first frame that is still in flight
+        assert_eq!(ring.allocate(10, 1), None);
+
+        ring.start_frame();
+        assert_eq!(ring.allocate(40, 1), Some(0));
+        assert_eq!(ring.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn skipped_end_counts_as_used() {
+        let mut ring = FakeRingBuffer::new(100, 2);
+        ring.start_frame();
+        assert_eq!(ring.allocate(70, 1), Some(0));
+        ring.start_frame();
+        // 30 bytes are free at the end but it doesn't fit, the start is still used by the previous frame
+        assert_eq!(ring.allocate(40, 1), None);
+        assert_eq!(ring.allocate(30, 1), Some(70));
+
+        ring.start_frame();
+        // The first frame is done, its 70 bytes are free again
+        assert_eq!(ring.allocate(50, 1), Some(0));
+        assert_eq!(ring.allocator.in_flight_high_water_mark(), 100);
+    }
+
+    #[test]
+    fn aligns_allocations() {
+        let mut ring = FakeRingBuffer::new(256, 2);
+        ring.start_frame();
+        assert_eq!(ring.allocate(3, 1), Some(0));
+        assert_eq!(ring.allocate(8, 64), Some(64));
+        assert_eq!(ring.allocate(8, 4), Some(72));
+        assert_eq!(ring.allocate(100, 128), Some(128));
+        // Wrapping would overwrite the start of this frame
+        assert_eq!(ring.allocate(100, 128), None);
+
+        ring.start_frame();
+        ring.start_frame();
+        assert_eq!(ring.allocate(100, 128), Some(0));
+    }
+
+    #[test]
+    fn rejects_allocations_bigger_than_the_buffer() {
+        let mut ring = FakeRingBuffer::new(64, 2);
+        ring.start_frame();
+        assert_eq!(ring.allocate(65, 1), None);
+        assert_eq!(ring.allocate(64, 1), Some(0));
+    }
+
+    #[test]
+    fn grows_instead_of_failing() {
+        let mut ring = FakeRingBuffer::new(64, 2);
+        ring.allow_growth = true;
+        ring.start_frame();
+        assert_eq!(ring.allocate(48, 1), Some(0));
+        ring.start_frame();
+        assert_eq!(ring.allocate(10, 1), Some(48));
+
+        // The previous frame still uses the start of the buffer, the new one starts empty
+        assert_eq!(ring.allocate(40, 1), Some(0));
+        assert_eq!(ring.allocator.capacity(), 128);
+        assert_eq!(ring.retired.len(), 1);
+        assert_eq!(ring.allocator.in_flight_high_water_mark(), 58);
 
-        let res = unsafe { &mut (*self.memory_buffer)[offset..offset + size] as *mut [u8] };
+        // Twice as big is not enough for this one
+        assert_eq!(ring.allocate(300, 1), Some(0));
+        assert_eq!(ring.allocator.capacity(), 512);
+        assert_eq!(ring.retired.len(), 2);
 
-        assert!(unsafe { (*res).len() } == size);
-        assert!(offset < std::u32::MAX as usize);
-        (res, offset as u32)
+        // Retired memory is released once its frames are done, and the frame count carried over
+        ring.start_frame();
+        ring.start_frame();
+        assert!(ring.retired.is_empty());
+        assert_eq!(ring.allocate(100, 1), Some(300));
     }
 }
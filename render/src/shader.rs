@@ -1,24 +1,239 @@
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver};
 use std::time::Duration;
 
+use glslang::error::GlslangError;
+use glslang::include::{IncludeHandler, IncludeResult, IncludeType};
+use glslang::{
+    Compiler, CompilerOptions, ShaderInput, ShaderMessage, ShaderSource, ShaderStage,
+    SourceLanguage, SpirvVersion, Target, VulkanVersion,
+};
 use notify::Watcher;
 pub use notify::{DebouncedEvent, RecursiveMode};
 
+use crate::vulkan::error::{VulkanError, VulkanResult};
+
+// Headers shared by every crate, included as "render/*.h"
+pub const RENDER_INCLUDE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/include");
+
 #[macro_export]
 macro_rules! shader_path {
     ($shader:literal) => {
-        concat!(env!("OUT_DIR"), "/shaders/", $shader)
+        concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/", $shader)
     };
 }
 
 #[macro_export]
 macro_rules! watch_crate_shaders {
     ($watcher:expr) => {
-        let crate_shader_dir = concat!(env!("OUT_DIR"), "/shaders/");
-        $watcher.watch(crate_shader_dir, crate::shader::RecursiveMode::Recursive);
+        let crate_shader_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders/");
+        $watcher.watch(crate_shader_dir, $crate::shader::RecursiveMode::Recursive);
+        $watcher.watch(
+            $crate::shader::RENDER_INCLUDE_DIR,
+            $crate::shader::RecursiveMode::Recursive,
+        );
     };
 }
 
+pub struct CompiledShader {
+    pub bytecode: Vec<u8>,
+    // The source file followed by every header it included
+    pub dependencies: Vec<PathBuf>,
+}
+
+struct Includer {
+    include_dirs: Vec<PathBuf>,
+    root: PathBuf,
+    dependencies: Vec<PathBuf>,
+}
+
+impl IncludeHandler for Includer {
+    fn include(
+        &mut self,
+        ty: IncludeType,
+        header_name: &str,
+        includer_name: &str,
+        _include_depth: usize,
+    ) -> Option<IncludeResult> {
+        let includer = if includer_name.is_empty() {
+            self.root.as_path()
+        } else {
+            Path::new(includer_name)
+        };
+
+        let relative_dir = match ty {
+            IncludeType::Local => includer.parent(),
+            IncludeType::System => None,
+        };
+
+        let path = relative_dir
+            .into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(header_name))
+            .find(|path| path.is_file())?;
+        let data = std::fs::read_to_string(&path).ok()?;
+
+        if !self.dependencies.contains(&path) {
+            self.dependencies.push(path.clone());
+        }
+
+        Some(IncludeResult {
+            name: path.to_string_lossy().into_owned(),
+            data,
+        })
+    }
+}
+
+// "ui.frag.glsl" is a GLSL fragment shader, "blit.comp.hlsl" a HLSL compute shader
+fn shader_kind(path: &Path) -> Option<(ShaderStage, SourceLanguage)> {
+    let language = match path.extension()?.to_str()? {
+        "glsl" => SourceLanguage::GLSL,
+        "hlsl" => SourceLanguage::HLSL,
+        _ => return None,
+    };
+
+    let stage = match Path::new(path.file_stem()?).extension()?.to_str()? {
+        "vert" => ShaderStage::Vertex,
+        "tesc" => ShaderStage::TesselationControl,
+        "tese" => ShaderStage::TesselationEvaluation,
+        "geom" => ShaderStage::Geometry,
+        "frag" => ShaderStage::Fragment,
+        "comp" => ShaderStage::Compute,
+        _ => return None,
+    };
+
+    Some((stage, language))
+}
+
+// glslang only accepts #include once the extension is enabled, it has to come after #version.
+// The #line directive keeps the line numbers of the diagnostics matching the file.
+fn enable_include_directive(source: &str) -> String {
+    let version_end = source.find("#version").map(|start| {
+        source[start..]
+            .find('\n')
+            .map_or(source.len(), |offset| start + offset + 1)
+    });
+
+    match version_end {
+        Some(version_end) => {
+            let next_line = source[..version_end].lines().count() + 1;
+            format!(
+                "{}#extension GL_GOOGLE_include_directive : require\n#line {}\n{}",
+                &source[..version_end],
+                next_line,
+                &source[version_end..]
+            )
+        }
+        None => format!(
+            "#extension GL_GOOGLE_include_directive : require\n#line 1\n{}",
+            source
+        ),
+    }
+}
+
+// glslang names the root file "0", replace it with the path so that errors can be clicked
+fn format_log(path: &Path, log: &str) -> String {
+    let root_name = format!("{}:", path.display());
+    log.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            for severity in ["ERROR: ", "WARNING: "] {
+                if let Some(location) = line
+                    .strip_prefix(severity)
+                    .and_then(|l| l.strip_prefix("0:"))
+                {
+                    return format!("{}{}{}", severity, root_name, location);
+                }
+            }
+            line.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn compilation_error(path: &Path, error: GlslangError) -> VulkanError {
+    let log = match error {
+        GlslangError::PreprocessError(log)
+        | GlslangError::ParseError(log)
+        | GlslangError::LinkError(log) => format_log(path, &log.log),
+        error => error.to_string(),
+    };
+    VulkanError::ShaderCompilation {
+        path: PathBuf::from(path),
+        log,
+    }
+}
+
+// Includes are looked up next to the including file, in the crate's shaders/include and in render's
+pub fn compile_shader(path: &Path, defines: &[(String, String)]) -> VulkanResult<CompiledShader> {
+    profile::scope!("compile shader");
+
+    let (stage, source_language) =
+        shader_kind(path).ok_or_else(|| VulkanError::ShaderCompilation {
+            path: PathBuf::from(path),
+            log: String::from(
+                "expected a file named <name>.<vert|tesc|tese|geom|frag|comp>.<glsl|hlsl>",
+            ),
+        })?;
+
+    let source = std::fs::read_to_string(path).map_err(|error| VulkanError::ShaderIo {
+        path: PathBuf::from(path),
+        error,
+    })?;
+    let source = match source_language {
+        SourceLanguage::GLSL => ShaderSource::from(enable_include_directive(&source)),
+        _ => ShaderSource::from(source),
+    };
+
+    let mut include_dirs = Vec::with_capacity(2);
+    if let Some(shader_dir) = path.parent() {
+        include_dirs.push(shader_dir.join("include"));
+    }
+    include_dirs.push(PathBuf::from(RENDER_INCLUDE_DIR));
+
+    let mut includer = Includer {
+        include_dirs,
+        root: PathBuf::from(path),
+        dependencies: vec![PathBuf::from(path)],
+    };
+
+    let defines: Vec<(&str, Option<&str>)> = defines
+        .iter()
+        .map(|(name, value)| (name.as_str(), Some(value.as_str())))
+        .collect();
+
+    let options = CompilerOptions {
+        source_language,
+        target: Target::Vulkan {
+            version: VulkanVersion::Vulkan1_2,
+            spirv_version: SpirvVersion::SPIRV1_5,
+        },
+        version_profile: None,
+        messages: ShaderMessage::DEFAULT,
+    };
+
+    let compiler = Compiler::acquire().expect("failed to initialize glslang");
+    let words = ShaderInput::new(
+        &source,
+        stage,
+        &options,
+        Some(defines.as_slice()),
+        Some(&mut includer),
+    )
+    .and_then(|input| glslang::Shader::new(compiler, input).parse())
+    .and_then(|shader| {
+        let mut program = compiler.create_program();
+        program.add_shader(&shader);
+        program.compile(stage)
+    })
+    .map_err(|error| compilation_error(path, error))?;
+
+    Ok(CompiledShader {
+        bytecode: words.iter().flat_map(|word| word.to_ne_bytes()).collect(),
+        dependencies: includer.dependencies,
+    })
+}
+
 pub struct ShaderWatcher {
     receiver: Receiver<notify::DebouncedEvent>,
     watcher: notify::RecommendedWatcher,
@@ -51,6 +266,23 @@ impl ShaderWatcher {
             None
         }
     }
+
+    // Drains the pending events, editors saving through a temporary file show up as renames
+    pub fn changed_paths(&mut self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        while let Ok(event) = self.receiver.try_recv() {
+            let path = match event {
+                DebouncedEvent::Write(path)
+                | DebouncedEvent::Create(path)
+                | DebouncedEvent::Rename(_, path) => path,
+                _ => continue,
+            };
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
 }
 
 impl Default for ShaderWatcher {
@@ -58,3 +290,87 @@ impl Default for ShaderWatcher {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_shader_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("render_shader_{}_{}", name, std::process::id()));
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn detects_stage_and_language_from_file_name() {
+        assert!(matches!(
+            shader_kind(Path::new("shaders/ui.frag.glsl")),
+            Some((ShaderStage::Fragment, SourceLanguage::GLSL))
+        ));
+        assert!(matches!(
+            shader_kind(Path::new("blit.comp.hlsl")),
+            Some((ShaderStage::Compute, SourceLanguage::HLSL))
+        ));
+        assert!(shader_kind(Path::new("ui.glsl")).is_none());
+        assert!(shader_kind(Path::new("ui.vert.spv")).is_none());
+    }
+
+    #[test]
+    fn compiles_with_includes_and_defines() {
+        let dir = write_shader_dir(
+            "includes",
+            &[
+                (
+                    "test.comp.glsl",
+                    "#version 460\n#include \"test/common.h\"\nlayout(local_size_x = SIZE) in;\nvoid main() { value(); }\n",
+                ),
+                ("include/test/common.h", "#include \"render/types.h\"\nvoid value() {}\n"),
+            ],
+        );
+        let path = dir.join("test.comp.glsl");
+
+        let compiled = compile_shader(&path, &[(String::from("SIZE"), String::from("8"))]).unwrap();
+        assert_eq!(compiled.bytecode[..4], 0x07230203u32.to_ne_bytes());
+        assert_eq!(
+            compiled.dependencies,
+            vec![
+                path.clone(),
+                dir.join("include/test/common.h"),
+                Path::new(RENDER_INCLUDE_DIR).join("render/types.h"),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reports_errors_with_file_and_line() {
+        let dir = write_shader_dir(
+            "errors",
+            &[(
+                "broken.frag.glsl",
+                "#version 460\n\nvoid main() {\n\tundefined_function();\n}\n",
+            )],
+        );
+        let path = dir.join("broken.frag.glsl");
+
+        match compile_shader(&path, &[]) {
+            Err(VulkanError::ShaderCompilation { log, .. }) => {
+                assert!(
+                    log.contains(&format!("ERROR: {}:4:", path.display())),
+                    "{}",
+                    log
+                );
+            }
+            Err(error) => panic!("unexpected error {}", error),
+            Ok(_) => panic!("the shader should not compile"),
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
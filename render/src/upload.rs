@@ -0,0 +1,439 @@
+use exo::pool::Handle;
+
+use super::ring_buffer::RingAllocator;
+use super::vulkan::{
+    buffer::*, context_pool::*, contexts::*, device::*, error::*, fence::*, image::*,
+};
+
+use erupt::vk;
+
+const STAGING_ALIGNMENT: usize = 16;
+
+#[derive(Clone, Copy, PartialEq)]
+enum UploadDst {
+    Buffer(Handle<Buffer>),
+    Image(Handle<Image>),
+}
+
+enum UploadCopy {
+    Buffer(BufferCopy),
+    Image(BufferImageCopy),
+}
+
+// Groups the copies by destination, in the order of the first copy to each destination
+fn batch_by_dst<Dst: PartialEq, Copy>(
+    uploads: impl IntoIterator<Item = (Dst, Copy)>,
+) -> Vec<(Dst, Vec<Copy>)> {
+    let mut batches: Vec<(Dst, Vec<Copy>)> = Vec::new();
+    for (dst, copy) in uploads {
+        match batches.iter_mut().find(|(batch_dst, _)| *batch_dst == dst) {
+            Some((_, copies)) => copies.push(copy),
+            None => batches.push((dst, vec![copy])),
+        }
+    }
+    batches
+}
+
+// Whether the copies overwrite the whole `[0, size)` range of their destination
+fn covers_whole_buffer(copies: &[BufferCopy], size: u64) -> bool {
+    let mut ranges: Vec<(u64, u64)> = copies
+        .iter()
+        .map(|copy| (copy.dst_offset, copy.dst_offset + copy.size))
+        .collect();
+    ranges.sort_unstable();
+
+    let mut covered = 0;
+    for (start, end) in ranges {
+        if start > covered {
+            return false;
+        }
+        covered = covered.max(end);
+    }
+    covered >= size
+}
+
+// The transfer fence values to wait for before reusing the staging memory of a frame
+struct StagingFences {
+    values: Vec<u64>,
+    i_frame: usize,
+}
+
+impl StagingFences {
+    fn new(frame_queue_length: usize) -> Self {
+        Self {
+            values: vec![0; frame_queue_length],
+            i_frame: 0,
+        }
+    }
+
+    // Returns the value signaled once the transfer queue stopped reading the staging memory of the new frame
+    fn start_frame(&mut self) -> Option<u64> {
+        self.i_frame += 1;
+        let i_slot = self.i_frame % self.values.len();
+        Some(std::mem::take(&mut self.values[i_slot])).filter(|value| *value != 0)
+    }
+
+    fn submitted(&mut self, value: u64) {
+        let i_slot = self.i_frame % self.values.len();
+        self.values[i_slot] = value;
+    }
+}
+
+// Copies data to DEVICE_LOCAL buffers and images through staging memory. Uploads are batched until they are
+// recorded in a context of the frame with `record_uploads`, or submitted to the transfer queue with `flush`.
+// The staging memory of a frame is reused `frame_queue_length` frames later, once the transfer queue is done with it.
+pub struct UploadManager {
+    pub staging_buffer: Handle<Buffer>,
+    pub fence: Fence,
+    memory_buffer: *mut [u8],
+    allocator: RingAllocator,
+    staging_fences: StagingFences,
+    pending_uploads: Vec<(UploadDst, UploadCopy)>,
+    // Set by `flush`, the next graphics context has to wait for the transfer queue
+    wait_value: Option<u64>,
+    // Resources released by the transfer queue that the graphics queue needs to acquire
+    released_buffers: Vec<Handle<Buffer>>,
+    released_images: Vec<Handle<Image>>,
+}
+
+impl UploadManager {
+    pub fn new(
+        device: &mut Device,
+        staging_size: usize,
+        frame_queue_length: usize,
+    ) -> VulkanResult<Self> {
+        let staging_buffer = device.create_buffer(BufferSpec {
+            size: staging_size,
+            usages: vk::BufferUsageFlags::TRANSFER_SRC,
+            memory_usage: MemoryUsageFlags::CpuToGpu,
+        })?;
+
+        Ok(Self {
+            staging_buffer,
+            fence: device.create_fence()?,
+            memory_buffer: device.map_buffer(staging_buffer),
+            allocator: RingAllocator::new(staging_size, frame_queue_length),
+            staging_fences: StagingFences::new(frame_queue_length),
+            pending_uploads: Vec::new(),
+            wait_value: None,
+            released_buffers: Vec::new(),
+            released_images: Vec::new(),
+        })
+    }
+
+    pub fn destroy(&self, device: &mut Device) {
+        device.destroy_fence(&self.fence);
+        device.destroy_buffer(self.staging_buffer);
+    }
+
+    // The caller waits for the graphics work of the frame that used the staging memory `frame_queue_length` frames
+    // ago, its transfer work is waited for here
+    pub fn start_frame(&mut self, device: &Device) -> VulkanResult<()> {
+        if let Some(wait_value) = self.staging_fences.start_frame() {
+            device.wait_for_fences(&[&self.fence], &[wait_value])?;
+        }
+        self.allocator.start_frame();
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.allocator.capacity()
+    }
+
+    fn stage(&mut self, data: &[u8]) -> Option<usize> {
+        let staging_offset = self.allocator.allocate(data.len(), STAGING_ALIGNMENT)?;
+        assert!(staging_offset + data.len() <= self.memory_buffer.len());
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                (self.memory_buffer as *mut u8).add(staging_offset),
+                data.len(),
+            )
+            .copy_from_slice(data);
+        }
+        Some(staging_offset)
+    }
+
+    // Returns false when the staging memory of the frame is full
+    pub fn upload_buffer(
+        &mut self,
+        device: &Device,
+        buffer: Handle<Buffer>,
+        offset: usize,
+        data: &[u8],
+    ) -> bool {
+        let spec = &device.buffers.get(buffer).spec;
+        assert!(spec.usages.contains(vk::BufferUsageFlags::TRANSFER_DST));
+        assert!(offset + data.len() <= spec.size);
+
+        let staging_offset = match self.stage(data) {
+            Some(staging_offset) => staging_offset,
+            None => return false,
+        };
+        self.pending_uploads.push((
+            UploadDst::Buffer(buffer),
+            UploadCopy::Buffer(BufferCopy {
+                src_offset: staging_offset as u64,
+                dst_offset: offset as u64,
+                size: data.len() as u64,
+            }),
+        ));
+        true
+    }
+
+    // Uploads the first mip level of an image, see `upload_buffer`
+    pub fn upload_image(&mut self, device: &Device, image: Handle<Image>, data: &[u8]) -> bool {
+        let size = device.images.get(image).spec.size;
+        self.upload_image_region(
+            device,
+            image,
+            [0, 0, 0],
+            [size[0] as u32, size[1] as u32, size[2] as u32],
+            data,
+        )
+    }
+
+    // Uploads a region of the first mip level of an image, see `upload_buffer`
+    pub fn upload_image_region(
+        &mut self,
+        device: &Device,
+        image: Handle<Image>,
+        image_offset: [i32; 3],
+        image_extent: [u32; 3],
+        data: &[u8],
+    ) -> bool {
+        let spec = &device.images.get(image).spec;
+        assert!(spec.usages.contains(vk::ImageUsageFlags::TRANSFER_DST));
+        let size = (image_extent[0] * image_extent[1] * image_extent[2]) as usize
+            * format_texel_size(spec.format);
+        assert!(data.len() == size);
+
+        let staging_offset = match self.stage(data) {
+            Some(staging_offset) => staging_offset,
+            None => return false,
+        };
+        self.pending_uploads.push((
+            UploadDst::Image(image),
+            UploadCopy::Image(BufferImageCopy {
+                buffer_offset: staging_offset as u64,
+                buffer_size: data.len() as u32,
+                image_offset,
+                image_extent,
+            }),
+        ));
+        true
+    }
+
+    pub fn has_pending_uploads(&self) -> bool {
+        !self.pending_uploads.is_empty()
+    }
+
+    // Records the batched copies, one per destination, and returns the destinations
+    fn record_copies(
+        &mut self,
+        device: &mut Device,
+        ctx: &mut TransferContext,
+        uploads: Vec<(UploadDst, UploadCopy)>,
+    ) -> (Vec<Handle<Buffer>>, Vec<Handle<Image>>) {
+        let mut buffer_uploads = Vec::new();
+        let mut image_uploads = Vec::new();
+        for (dst, copy) in uploads {
+            match (dst, copy) {
+                (UploadDst::Buffer(buffer), UploadCopy::Buffer(copy)) => {
+                    buffer_uploads.push((buffer, copy))
+                }
+                (UploadDst::Image(image), UploadCopy::Image(copy)) => {
+                    image_uploads.push((image, copy))
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        let mut buffers = Vec::new();
+        for (buffer, regions) in batch_by_dst(buffer_uploads) {
+            ctx.copy_buffer_regions(device, self.staging_buffer, buffer, &regions);
+            buffers.push(buffer);
+        }
+
+        let mut images = Vec::new();
+        for (image, regions) in batch_by_dst(image_uploads) {
+            ctx.base_context()
+                .barrier(device, image, ImageState::TransferDst);
+            ctx.copy_buffer_to_image(device, self.staging_buffer, image, &regions);
+            images.push(image);
+        }
+
+        (buffers, images)
+    }
+
+    // Records the batched uploads in a context of the frame, the destination images are left in the
+    // TransferDst state
+    pub fn record_uploads(&mut self, device: &mut Device, ctx: &mut TransferContext) {
+        let uploads = std::mem::take(&mut self.pending_uploads);
+        self.record_copies(device, ctx, uploads);
+    }
+
+    // Without a release by the graphics queue, the transfer queue can only write destinations whose content
+    // doesn't need to be kept: buffers overwritten entirely and images that were never written
+    fn can_transfer_ownership(&self, device: &Device, dst: UploadDst) -> bool {
+        match dst {
+            UploadDst::Buffer(buffer) => {
+                let copies: Vec<BufferCopy> = self
+                    .pending_uploads
+                    .iter()
+                    .filter_map(|(upload_dst, copy)| match copy {
+                        UploadCopy::Buffer(copy) if *upload_dst == dst => Some(*copy),
+                        _ => None,
+                    })
+                    .collect();
+                covers_whole_buffer(&copies, device.buffers.get(buffer).spec.size as u64)
+            }
+            UploadDst::Image(image) => matches!(
+                device.images.get(image).states.uniform_state(),
+                Some(ImageState::Null)
+            ),
+        }
+    }
+
+    // Submits the batched uploads to the transfer queue, the graphics work of the frame has to call
+    // `wait_for_uploads` before using them. Uploads that have to keep the content of their destination stay
+    // pending and are recorded by `wait_for_uploads` on the graphics queue.
+    pub fn flush(
+        &mut self,
+        device: &mut Device,
+        context_pool: &mut ContextPool,
+    ) -> VulkanResult<()> {
+        if self.pending_uploads.is_empty() {
+            return Ok(());
+        }
+
+        let mut ctx = device.get_transfer_context(context_pool)?;
+        let graphics_family_idx = device.graphics_family_idx;
+        let transfer_ownership = ctx.base_context().queue_family_idx != graphics_family_idx;
+
+        let (uploads, kept): (Vec<_>, Vec<_>) = if transfer_ownership {
+            let transferable: Vec<UploadDst> = self
+                .pending_uploads
+                .iter()
+                .map(|(dst, _)| *dst)
+                .filter(|dst| self.can_transfer_ownership(device, *dst))
+                .collect();
+            std::mem::take(&mut self.pending_uploads)
+                .into_iter()
+                .partition(|(dst, _)| transferable.contains(dst))
+        } else {
+            (std::mem::take(&mut self.pending_uploads), Vec::new())
+        };
+        self.pending_uploads = kept;
+        if uploads.is_empty() {
+            return Ok(());
+        }
+
+        ctx.base_context().begin(device)?;
+        let (buffers, images) = self.record_copies(device, &mut ctx, uploads);
+
+        if transfer_ownership {
+            for buffer in buffers {
+                ctx.base_context()
+                    .release_buffer(device, buffer, graphics_family_idx);
+                self.released_buffers.push(buffer);
+            }
+            for image in images {
+                ctx.base_context()
+                    .release_image(device, image, graphics_family_idx);
+                self.released_images.push(image);
+            }
+        }
+
+        ctx.base_context().end(device)?;
+
+        self.fence.value += 1;
+        let signal_value = self.fence.value;
+        device.submit(&ctx, &[&self.fence], &[signal_value])?;
+        self.staging_fences.submitted(signal_value);
+        self.wait_value = Some(signal_value);
+
+        Ok(())
+    }
+
+    // Makes the graphics context wait for the submitted uploads, acquires their resources and records the uploads
+    // that stayed on the graphics queue
+    pub fn wait_for_uploads(&mut self, device: &mut Device, ctx: &mut GraphicsContext) {
+        if let Some(wait_value) = self.wait_value.take() {
+            ctx.base_mut().wait_for_fence(
+                &self.fence,
+                wait_value,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+            );
+
+            let transfer_family_idx = device.transfer_family_idx;
+            for buffer in self.released_buffers.drain(..) {
+                ctx.base()
+                    .acquire_buffer(device, buffer, transfer_family_idx);
+            }
+            for image in self.released_images.drain(..) {
+                ctx.base().acquire_image(device, image, transfer_family_idx);
+            }
+        }
+
+        if self.has_pending_uploads() {
+            self.record_uploads(device, ctx.transfer_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_copies_by_destination() {
+        let uploads = [(1, "a"), (2, "b"), (1, "c"), (3, "d"), (2, "e")];
+        assert_eq!(
+            batch_by_dst(uploads),
+            vec![(1, vec!["a", "c"]), (2, vec!["b", "e"]), (3, vec!["d"])]
+        );
+        assert!(batch_by_dst(Vec::<(u32, u32)>::new()).is_empty());
+    }
+
+    #[test]
+    fn staging_memory_is_reused_after_the_frame_queue() {
+        // Glyph-sized uploads of three frames in a staging buffer that holds two
+        let mut allocator = RingAllocator::new(256, 2);
+        for _ in 0..3 {
+            allocator.start_frame();
+            for _ in 0..4 {
+                assert!(allocator.allocate(24, STAGING_ALIGNMENT).is_some());
+            }
+        }
+        // 4 uploads of 24 bytes aligned to 16 take 128 bytes per frame
+        assert_eq!(allocator.frame_high_water_mark(), 128);
+        assert_eq!(allocator.in_flight_high_water_mark(), 256);
+        assert!(allocator.allocate(16, STAGING_ALIGNMENT).is_none());
+    }
+
+    #[test]
+    fn staging_memory_waits_for_the_transfer_queue() {
+        let mut fences = StagingFences::new(2);
+        assert_eq!(fences.start_frame(), None);
+        fences.submitted(1);
+        // Frames that didn't flush don't wait
+        assert_eq!(fences.start_frame(), None);
+        assert_eq!(fences.start_frame(), Some(1));
+        assert_eq!(fences.start_frame(), None);
+        assert_eq!(fences.start_frame(), None);
+    }
+
+    #[test]
+    fn only_whole_buffer_uploads_skip_the_graphics_release() {
+        let copy = |dst_offset, size| BufferCopy {
+            src_offset: 0,
+            dst_offset,
+            size,
+        };
+        assert!(covers_whole_buffer(&[copy(0, 64)], 64));
+        assert!(covers_whole_buffer(&[copy(32, 32), copy(0, 40)], 64));
+        assert!(!covers_whole_buffer(&[copy(0, 32)], 64));
+        assert!(!covers_whole_buffer(&[copy(0, 16), copy(32, 32)], 64));
+        assert!(!covers_whole_buffer(&[], 64));
+    }
+}
@@ -12,9 +12,14 @@ pub mod image;
 pub mod instance;
 pub mod memory;
 pub mod physical_device;
+pub mod pipeline_cache;
 pub mod queues;
+pub mod sampler;
 pub mod shader;
+pub mod shader_reflection;
+pub mod shader_variant;
 pub mod surface;
+pub mod validation;
 pub use buffer::*;
 pub use compute_pipeline::*;
 pub use context_pool::*;
@@ -28,5 +33,9 @@ pub use graphics_pipeline::*;
 pub use image::*;
 pub use instance::*;
 pub use physical_device::*;
+pub use sampler::*;
 pub use shader::*;
+pub use shader_reflection::*;
+pub use shader_variant::*;
 pub use surface::*;
+pub use validation::*;
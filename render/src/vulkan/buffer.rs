@@ -99,4 +99,42 @@ impl Device {
         }
         std::ptr::slice_from_raw_parts_mut(buffer.mapped_ptr, buffer.spec.size)
     }
+
+    pub fn destroy_buffer(&mut self, buffer_handle: Handle<Buffer>) {
+        let buffer = self.buffers.get_mut(buffer_handle);
+
+        if buffer
+            .spec
+            .usages
+            .contains(vk::BufferUsageFlags::STORAGE_BUFFER)
+        {
+            self.descriptors
+                .bindless_set
+                .unbind_storage_buffer(buffer.storage_idx as usize);
+        }
+
+        let vkpool = self.descriptors.uniform_descriptor_pool;
+        let device = &self.device;
+        self.descriptors
+            .uniform_descriptor_sets
+            .retain_mut(|descriptor| {
+                if descriptor.buffer != buffer_handle {
+                    return true;
+                }
+                descriptor.destroy(device, vkpool).unwrap();
+                false
+            });
+
+        unsafe {
+            self.device.destroy_buffer(buffer.vkhandle, None);
+        }
+
+        if let Some(block) = buffer.memory_block.take() {
+            unsafe {
+                self.allocator.deallocate(&self.device, &block).unwrap();
+            }
+        }
+
+        self.buffers.remove(buffer_handle);
+    }
 }
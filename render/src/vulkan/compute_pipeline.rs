@@ -1,15 +1,28 @@
 use super::device::*;
 use super::error::*;
 use super::shader::*;
+use super::shader_variant::*;
 use erupt::vk;
 use exo::pool::Handle;
 use std::ffi::CString;
 
+#[derive(Debug)]
+pub struct ComputeVariant {
+    pub variant: ShaderVariant,
+    pub shader: Handle<Shader>,
+    pub pipeline: vk::Pipeline,
+}
+
 #[derive(Debug)]
 pub struct ComputeProgram {
     pub name: String,
-    pub pipeline: vk::Pipeline,
     pub shader: Handle<Shader>,
+    pub features: ShaderFeatures,
+    // The first variant has no feature enabled and uses `shader`,
+    // the others are compiled the first time they are bound
+    pub variants: Vec<ComputeVariant>,
+    // Variants that failed to compile, replaced by the first variant until the shader is reloaded
+    pub failed_variants: Vec<ShaderVariant>,
 }
 
 impl Device {
@@ -17,32 +30,125 @@ impl Device {
         &mut self,
         name: String,
         shader_handle: Handle<Shader>,
+        features: ShaderFeatures,
     ) -> VulkanResult<Handle<ComputeProgram>> {
+        let base_variant = ComputeVariant {
+            variant: ShaderVariant::default(),
+            shader: shader_handle,
+            pipeline: create_compute_pipeline(self, shader_handle)?,
+        };
+
         let res = self.compute_programs.add(ComputeProgram {
             name,
-            pipeline: create_compute_pipeline(self, shader_handle)?,
             shader: shader_handle,
+            features,
+            variants: vec![base_variant],
+            failed_variants: Vec::new(),
         });
 
         Ok(res)
     }
 
+    pub fn destroy_compute_program(&mut self, program_handle: Handle<ComputeProgram>) {
+        let program = self.compute_programs.get(program_handle);
+        for variant in &program.variants {
+            unsafe {
+                self.device.destroy_pipeline(variant.pipeline, None);
+            }
+        }
+
+        // The shader of the base variant belongs to the caller
+        let variant_shaders: Vec<_> = program.variants[1..]
+            .iter()
+            .map(|variant| variant.shader)
+            .collect();
+        for shader_handle in variant_shaders {
+            self.destroy_shader(shader_handle);
+        }
+
+        self.compute_programs.remove(program_handle);
+    }
+
+    // Recompiles the pipeline of every variant compiled so far, returns the index of each variant that failed with
+    // its error, these keep their previous pipeline
     pub fn compile_compute_program(
         &mut self,
         program_handle: Handle<ComputeProgram>,
-    ) -> VulkanResult<()> {
+    ) -> Vec<(usize, VulkanError)> {
+        let mut errors = Vec::new();
+        let variant_count = self.compute_programs.get(program_handle).variants.len();
+        for i_variant in 0..variant_count {
+            let variant = &self.compute_programs.get(program_handle).variants[i_variant];
+            let new_pipeline = match create_compute_pipeline(self, variant.shader) {
+                Ok(pipeline) => pipeline,
+                Err(error) => {
+                    errors.push((i_variant, error));
+                    continue;
+                }
+            };
+            if !variant.pipeline.is_null() {
+                unsafe {
+                    self.device.destroy_pipeline(variant.pipeline, None);
+                }
+            }
+
+            let program = self.compute_programs.get_mut(program_handle);
+            program.variants[i_variant].pipeline = new_pipeline;
+        }
+
+        errors
+    }
+
+    // Returns the index of the variant in the program, compiling its shader and pipeline if needed.
+    // A variant that fails to compile is reported in `variant_errors` and the first variant is returned instead
+    pub fn get_or_compile_compute_variant(
+        &mut self,
+        program_handle: Handle<ComputeProgram>,
+        variant: ShaderVariant,
+    ) -> VulkanResult<usize> {
         let program = self.compute_programs.get(program_handle);
-        let new_pipeline = create_compute_pipeline(self, program.shader)?;
-        if !program.pipeline.is_null() {
-            unsafe {
-                self.device.destroy_pipeline(program.pipeline, None);
+        if let Some(i_variant) = program.variants.iter().position(|v| v.variant == variant) {
+            return Ok(i_variant);
+        }
+        if program.failed_variants.contains(&variant) {
+            return Ok(0);
+        }
+
+        match self.compile_compute_variant(program_handle, variant) {
+            Err(error) if !error.is_device_lost() => {
+                let program = self.compute_programs.get_mut(program_handle);
+                program.failed_variants.push(variant);
+                let shader_handle = program.shader;
+                self.variant_errors.push((shader_handle, error));
+                Ok(0)
             }
+            result => result,
         }
+    }
+
+    fn compile_compute_variant(
+        &mut self,
+        program_handle: Handle<ComputeProgram>,
+        variant: ShaderVariant,
+    ) -> VulkanResult<usize> {
+        let program = self.compute_programs.get(program_handle);
+        let shader = self.create_shader_variant(program.shader, program.features, variant)?;
+        let pipeline = match create_compute_pipeline(self, shader) {
+            Ok(pipeline) => pipeline,
+            Err(error) => {
+                self.destroy_shader(shader);
+                return Err(error);
+            }
+        };
 
         let program = self.compute_programs.get_mut(program_handle);
-        program.pipeline = new_pipeline;
+        program.variants.push(ComputeVariant {
+            variant,
+            shader,
+            pipeline,
+        });
 
-        Ok(())
+        Ok(program.variants.len() - 1)
     }
 }
 
@@ -64,7 +170,7 @@ fn create_compute_pipeline(
     let vkpipeline = unsafe {
         device
             .device
-            .create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+            .create_compute_pipelines(device.pipeline_cache, &[pipeline_info], None)
             .result()?[0]
     };
 
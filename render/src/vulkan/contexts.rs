@@ -9,12 +9,18 @@ use super::framebuffer::*;
 use super::graphics_pipeline::*;
 use super::image::*;
 use super::queues;
+use super::shader::*;
+use super::shader_variant::*;
 use super::surface::*;
 use erupt::vk;
 use exo::{dynamic_array::DynamicArray, pool::Handle};
+use std::cell::Cell;
 
 pub const MAX_SEMAPHORES: usize = 4;
 
+// Checks the layout of the bound shader options against a shader, see `Device::validate_shader_options`
+pub type OptionsValidator = fn(&Device, Handle<Shader>) -> VulkanResult<()>;
+
 pub struct BaseContext {
     pub cmd: vk::CommandBuffer,
     pub wait_fence_list: DynamicArray<Fence, MAX_SEMAPHORES>,
@@ -22,9 +28,14 @@ pub struct BaseContext {
     pub wait_stage_list: DynamicArray<vk::PipelineStageFlags, MAX_SEMAPHORES>,
     pub queue: vk::Queue,
     pub queue_type: usize,
+    pub queue_family_idx: u32,
     pub image_acquired_semaphore: Option<vk::Semaphore>,
     pub image_acquired_stage: Option<vk::PipelineStageFlags>,
     pub can_present_semaphore: Option<vk::Semaphore>,
+    // Shaders of the last bound pipeline and the layout of the last bound options, checked before each draw and
+    // dispatch because either can be bound first
+    pub bound_shaders: Cell<[Handle<Shader>; 2]>,
+    pub bound_options: Cell<Option<OptionsValidator>>,
 }
 
 impl BaseContext {
@@ -75,6 +86,17 @@ impl BaseContext {
         Ok(())
     }
 
+    pub fn validate_bound_options(&self, device: &Device) -> VulkanResult<()> {
+        if let Some(validate) = self.bound_options.get() {
+            for shader in self.bound_shaders.get() {
+                if shader.is_valid() {
+                    validate(device, shader)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn end(&self, device: &Device) -> VulkanResult<()> {
         unsafe {
             device.device.end_command_buffer(self.cmd).result()?;
@@ -93,15 +115,95 @@ impl BaseContext {
             Some(surface.can_present_semaphores[surface.current_image as usize]);
     }
 
+    pub fn wait_for_fence(&mut self, fence: &Fence, value: u64, stage_dst: vk::PipelineStageFlags) {
+        self.wait_fence_list.push(Fence {
+            timeline_semaphore: fence.timeline_semaphore,
+            value,
+        });
+        self.wait_value_list.push(value);
+        self.wait_stage_list.push(stage_dst);
+    }
+
+    // Accesses from stages that this queue doesn't support have been synchronized by a queue ownership transfer
+    fn clamp_access(
+        &self,
+        access: ImageAccess,
+        default_stage: vk::PipelineStageFlags,
+    ) -> ImageAccess {
+        let supported_stages = match self.queue_type {
+            queues::GRAPHICS => return access,
+            queues::COMPUTE => {
+                vk::PipelineStageFlags::COMPUTE_SHADER
+                    | vk::PipelineStageFlags::TRANSFER
+                    | vk::PipelineStageFlags::TOP_OF_PIPE
+                    | vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            }
+            _ => {
+                vk::PipelineStageFlags::TRANSFER
+                    | vk::PipelineStageFlags::TOP_OF_PIPE
+                    | vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            }
+        };
+
+        if supported_stages.contains(access.stage) {
+            access
+        } else {
+            ImageAccess {
+                stage: default_stage,
+                access: vk::AccessFlags::NONE,
+                layout: access.layout,
+            }
+        }
+    }
+
     pub fn barrier(&self, device: &mut Device, image_handle: Handle<Image>, state_dst: ImageState) {
-        let image = device.images.get_mut(image_handle);
+        let full_range = device.images.get(image_handle).full_range();
+        self.barrier_range(device, image_handle, full_range, state_dst);
+    }
 
-        let src_access = image.state.get_src_access();
-        let dst_access = state_dst.get_dst_access();
+    // Only the subresources of `range` that need it are transitioned
+    pub fn barrier_range(
+        &self,
+        device: &mut Device,
+        image_handle: Handle<Image>,
+        range: SubresourceRange,
+        state_dst: ImageState,
+    ) {
+        let transitions = device
+            .images
+            .get_mut(image_handle)
+            .states
+            .transition(range, state_dst);
+
+        let image = device.images.get(image_handle);
+        for (range, state_src) in transitions {
+            self.range_barrier(
+                device,
+                image.vkhandle,
+                image.vk_range(range),
+                state_src,
+                state_dst,
+            );
+        }
+    }
 
-        image.state = state_dst;
+    fn range_barrier(
+        &self,
+        device: &Device,
+        vkimage: vk::Image,
+        range: vk::ImageSubresourceRange,
+        state_src: ImageState,
+        state_dst: ImageState,
+    ) {
+        let src_access = self.clamp_access(
+            state_src.get_src_access(),
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+        );
+        let dst_access = self.clamp_access(
+            state_dst.get_dst_access(),
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        );
 
-        const QUEUE_FAMILY_IGNORED: u32 = !0u32;
         let barrier = vk::ImageMemoryBarrierBuilder::new()
             .old_layout(src_access.layout)
             .new_layout(dst_access.layout)
@@ -109,8 +211,8 @@ impl BaseContext {
             .dst_access_mask(dst_access.access)
             .src_queue_family_index(QUEUE_FAMILY_IGNORED)
             .dst_queue_family_index(QUEUE_FAMILY_IGNORED)
-            .image(image.vkhandle)
-            .subresource_range(image.full_view.range);
+            .image(vkimage)
+            .subresource_range(range);
 
         unsafe {
             device.device.cmd_pipeline_barrier(
@@ -124,12 +226,174 @@ impl BaseContext {
             );
         }
     }
+
+    // Barrier on all memory, used for buffers
+    pub fn memory_barrier(
+        &self,
+        device: &Device,
+        src_stage: vk::PipelineStageFlags,
+        src_access: vk::AccessFlags,
+        dst_stage: vk::PipelineStageFlags,
+        dst_access: vk::AccessFlags,
+    ) {
+        let barrier = vk::MemoryBarrierBuilder::new()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access);
+
+        unsafe {
+            device.device.cmd_pipeline_barrier(
+                self.cmd,
+                src_stage,
+                dst_stage,
+                vk::DependencyFlags::empty(),
+                &[barrier],
+                &[],
+                &[],
+            );
+        }
+    }
+
+    // Queue family ownership transfers keep the image layout, the release and acquire barriers have to match
+    pub fn release_image(&self, device: &Device, image_handle: Handle<Image>, dst_family_idx: u32) {
+        let image = device.images.get(image_handle);
+
+        for (range, state) in image.states.ranges() {
+            let src_access =
+                self.clamp_access(state.get_src_access(), vk::PipelineStageFlags::TOP_OF_PIPE);
+
+            let barrier = vk::ImageMemoryBarrierBuilder::new()
+                .old_layout(src_access.layout)
+                .new_layout(src_access.layout)
+                .src_access_mask(src_access.access)
+                .dst_access_mask(vk::AccessFlags::NONE)
+                .src_queue_family_index(self.queue_family_idx)
+                .dst_queue_family_index(dst_family_idx)
+                .image(image.vkhandle)
+                .subresource_range(image.vk_range(range));
+
+            unsafe {
+                device.device.cmd_pipeline_barrier(
+                    self.cmd,
+                    src_access.stage,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
+        }
+    }
+
+    pub fn acquire_image(&self, device: &Device, image_handle: Handle<Image>, src_family_idx: u32) {
+        let image = device.images.get(image_handle);
+
+        for (range, state) in image.states.ranges() {
+            let layout = state.get_src_access().layout;
+
+            let barrier = vk::ImageMemoryBarrierBuilder::new()
+                .old_layout(layout)
+                .new_layout(layout)
+                .src_access_mask(vk::AccessFlags::NONE)
+                .dst_access_mask(vk::AccessFlags::NONE)
+                .src_queue_family_index(src_family_idx)
+                .dst_queue_family_index(self.queue_family_idx)
+                .image(image.vkhandle)
+                .subresource_range(image.vk_range(range));
+
+            unsafe {
+                device.device.cmd_pipeline_barrier(
+                    self.cmd,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::ALL_COMMANDS,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
+        }
+    }
+
+    pub fn release_buffer(
+        &self,
+        device: &Device,
+        buffer_handle: Handle<Buffer>,
+        dst_family_idx: u32,
+    ) {
+        let buffer = device.buffers.get(buffer_handle);
+
+        let barrier = vk::BufferMemoryBarrierBuilder::new()
+            .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+            .dst_access_mask(vk::AccessFlags::NONE)
+            .src_queue_family_index(self.queue_family_idx)
+            .dst_queue_family_index(dst_family_idx)
+            .buffer(buffer.vkhandle)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+
+        unsafe {
+            device.device.cmd_pipeline_barrier(
+                self.cmd,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+    }
+
+    pub fn acquire_buffer(
+        &self,
+        device: &Device,
+        buffer_handle: Handle<Buffer>,
+        src_family_idx: u32,
+    ) {
+        let buffer = device.buffers.get(buffer_handle);
+
+        let barrier = vk::BufferMemoryBarrierBuilder::new()
+            .src_access_mask(vk::AccessFlags::NONE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ)
+            .src_queue_family_index(src_family_idx)
+            .dst_queue_family_index(self.queue_family_idx)
+            .buffer(buffer.vkhandle)
+            .offset(0)
+            .size(vk::WHOLE_SIZE);
+
+        unsafe {
+            device.device.cmd_pipeline_barrier(
+                self.cmd,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[barrier],
+                &[],
+            );
+        }
+    }
 }
 
+const QUEUE_FAMILY_IGNORED: u32 = !0u32;
+
 pub struct TransferContext {
     base: BaseContext,
 }
 
+impl AsRef<TransferContext> for TransferContext {
+    fn as_ref(&self) -> &TransferContext {
+        self
+    }
+}
+
+impl AsMut<TransferContext> for TransferContext {
+    fn as_mut(&mut self) -> &mut TransferContext {
+        self
+    }
+}
+
 impl TransferContext {
     pub fn base_context(&self) -> &BaseContext {
         &self.base
@@ -140,7 +404,7 @@ impl TransferContext {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct BufferImageCopy {
     pub buffer_offset: u64,
     pub buffer_size: u32,
@@ -148,6 +412,13 @@ pub struct BufferImageCopy {
     pub image_extent: [u32; 3],
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferCopy {
+    pub src_offset: u64,
+    pub dst_offset: u64,
+    pub size: u64,
+}
+
 impl TransferContext {
     pub fn copy_buffer_to_image(
         &mut self,
@@ -195,6 +466,94 @@ impl TransferContext {
         }
     }
 
+    pub fn copy_buffer(
+        &self,
+        device: &Device,
+        src: Handle<Buffer>,
+        src_offset: u64,
+        dst: Handle<Buffer>,
+        dst_offset: u64,
+        size: u64,
+    ) {
+        let src = device.buffers.get(src);
+        let dst = device.buffers.get(dst);
+
+        let region = vk::BufferCopyBuilder::new()
+            .src_offset(src_offset)
+            .dst_offset(dst_offset)
+            .size(size);
+
+        unsafe {
+            device
+                .device
+                .cmd_copy_buffer(self.base.cmd, src.vkhandle, dst.vkhandle, &[region]);
+        }
+    }
+
+    pub fn copy_buffer_regions(
+        &self,
+        device: &Device,
+        src: Handle<Buffer>,
+        dst: Handle<Buffer>,
+        copies: &[BufferCopy],
+    ) {
+        let src = device.buffers.get(src);
+        let dst = device.buffers.get(dst);
+
+        let regions: Vec<_> = copies
+            .iter()
+            .map(|copy| {
+                vk::BufferCopyBuilder::new()
+                    .src_offset(copy.src_offset)
+                    .dst_offset(copy.dst_offset)
+                    .size(copy.size)
+            })
+            .collect();
+
+        unsafe {
+            device
+                .device
+                .cmd_copy_buffer(self.base.cmd, src.vkhandle, dst.vkhandle, &regions);
+        }
+    }
+
+    // Copies the first mip level of `image` tightly packed into `buffer`
+    pub fn copy_image_to_buffer(
+        &self,
+        device: &Device,
+        image: Handle<Image>,
+        buffer: Handle<Buffer>,
+        buffer_offset: u64,
+    ) {
+        let image = device.images.get(image);
+        let buffer = device.buffers.get(buffer);
+
+        let region = vk::BufferImageCopyBuilder::new()
+            .image_subresource(
+                *vk::ImageSubresourceLayersBuilder::new()
+                    .aspect_mask(image.full_view.range.aspect_mask)
+                    .mip_level(0)
+                    .base_array_layer(0)
+                    .layer_count(1),
+            )
+            .image_extent(vk::Extent3D {
+                width: image.spec.size[0] as u32,
+                height: image.spec.size[1] as u32,
+                depth: image.spec.size[2] as u32,
+            })
+            .buffer_offset(buffer_offset);
+
+        unsafe {
+            device.device.cmd_copy_image_to_buffer(
+                self.base.cmd,
+                image.vkhandle,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer.vkhandle,
+                &[region],
+            );
+        }
+    }
+
     pub fn copy_image(&self, device: &Device, src: Handle<Image>, dst: Handle<Image>) {
         let src = device.images.get(src);
         let dst = device.images.get(dst);
@@ -278,6 +637,55 @@ impl TransferContext {
         }
     }
 
+    // Fills every level from the level 0 with successive linear blits, the image ends in the `TransferSrc` state
+    pub fn generate_mipmaps(&self, device: &mut Device, image_handle: Handle<Image>) {
+        let mip_levels = device.images.get(image_handle).spec.mip_levels;
+        let full_range = device.images.get(image_handle).full_range();
+        self.base
+            .barrier_range(device, image_handle, full_range, ImageState::TransferDst);
+
+        for level in 1..mip_levels {
+            let src_range = device.images.get(image_handle).mip_range(level - 1);
+            self.base
+                .barrier_range(device, image_handle, src_range, ImageState::TransferSrc);
+
+            let image = device.images.get(image_handle);
+            let level_layers = |level: u32| {
+                *vk::ImageSubresourceLayersBuilder::new()
+                    .aspect_mask(image.full_view.range.aspect_mask)
+                    .mip_level(level)
+                    .base_array_layer(0)
+                    .layer_count(image.spec.array_layers)
+            };
+            let level_extent = |level: u32| {
+                let size = image.mip_size(level);
+                *vk::Offset3DBuilder::new().x(size[0]).y(size[1]).z(size[2])
+            };
+
+            let image_blit = vk::ImageBlitBuilder::new()
+                .src_subresource(level_layers(level - 1))
+                .src_offsets([vk::Offset3D::default(), level_extent(level - 1)])
+                .dst_subresource(level_layers(level))
+                .dst_offsets([vk::Offset3D::default(), level_extent(level)]);
+
+            unsafe {
+                device.device.cmd_blit_image(
+                    self.base.cmd,
+                    image.vkhandle,
+                    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                    image.vkhandle,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[image_blit],
+                    vk::Filter::LINEAR,
+                );
+            }
+        }
+
+        // Only the last level is still a transfer destination
+        self.base
+            .barrier_range(device, image_handle, full_range, ImageState::TransferSrc);
+    }
+
     pub fn clear_image(&self, device: &Device, image: Handle<Image>, clear_color: ClearColorValue) {
         let image = device.images.get(image);
         let range = image.full_view.range;
@@ -355,7 +763,7 @@ impl ComputeContext {
         offset: u32,
         i_set: u32,
     ) {
-        assert!(i_set == 1 || i_set == 2);
+        assert!(i_set == GLOBAL_UNIFORM_SET || i_set == SHADER_UNIFORM_SET);
         let base_context = self.base_context();
 
         unsafe {
@@ -386,7 +794,32 @@ impl ComputeContext {
     pub fn bind_compute_pipeline(&self, device: &Device, program_handle: Handle<ComputeProgram>) {
         let base_context = self.base_context();
         let program = device.compute_programs.get(program_handle);
-        let pipeline = program.pipeline;
+        let pipeline = program.variants[0].pipeline;
+        base_context
+            .bound_shaders
+            .set([program.variants[0].shader, Handle::invalid()]);
+        unsafe {
+            device.device.cmd_bind_pipeline(
+                base_context.cmd,
+                vk::PipelineBindPoint::COMPUTE,
+                pipeline,
+            );
+        }
+    }
+
+    pub fn bind_compute_variant(
+        &self,
+        device: &mut Device,
+        program_handle: Handle<ComputeProgram>,
+        variant: ShaderVariant,
+    ) -> VulkanResult<()> {
+        let i_variant = device.get_or_compile_compute_variant(program_handle, variant)?;
+        let base_context = self.base_context();
+        let program = device.compute_programs.get(program_handle);
+        let pipeline = program.variants[i_variant].pipeline;
+        base_context
+            .bound_shaders
+            .set([program.variants[i_variant].shader, Handle::invalid()]);
         unsafe {
             device.device.cmd_bind_pipeline(
                 base_context.cmd,
@@ -394,15 +827,18 @@ impl ComputeContext {
                 pipeline,
             );
         }
+        Ok(())
     }
 
-    pub fn dispatch(&self, device: &Device, size: [u32; 3]) {
+    pub fn dispatch(&self, device: &Device, size: [u32; 3]) -> VulkanResult<()> {
         let base_context = self.base_context();
+        base_context.validate_bound_options(device)?;
         unsafe {
             device
                 .device
                 .cmd_dispatch(base_context.cmd, size[0], size[1], size[2]);
         }
+        Ok(())
     }
 }
 
@@ -470,11 +906,12 @@ impl GraphicsContext {
         device: &mut Device,
         framebuffer_handle: Handle<Framebuffer>,
         load_ops: &[LoadOp],
+        store_ops: &[StoreOp],
     ) -> VulkanResult<()> {
         let base_context = self.base_context_mut();
 
         let (framebuffer, renderpass) =
-            device.find_framebuffer_renderpass(framebuffer_handle, load_ops)?;
+            device.find_framebuffer_renderpass(framebuffer_handle, load_ops, store_ops)?;
 
         let mut clear_values = DynamicArray::<vk::ClearValue, MAX_ATTACHMENTS>::new();
         for load_op in load_ops {
@@ -519,7 +956,35 @@ impl GraphicsContext {
     ) {
         let base_context = self.base_context();
         let program = device.graphics_programs.get(program_handle);
-        let pipeline = program.pipelines[index];
+        let variant = &program.variants[0];
+        let pipeline = variant.pipelines[index];
+        base_context
+            .bound_shaders
+            .set([variant.vertex_shader, variant.fragment_shader]);
+        unsafe {
+            device.device.cmd_bind_pipeline(
+                base_context.cmd,
+                vk::PipelineBindPoint::GRAPHICS,
+                pipeline,
+            );
+        }
+    }
+
+    pub fn bind_graphics_variant(
+        &self,
+        device: &mut Device,
+        program_handle: Handle<GraphicsProgram>,
+        index: usize,
+        variant: ShaderVariant,
+    ) -> VulkanResult<()> {
+        let i_variant = device.get_or_compile_graphics_variant(program_handle, variant)?;
+        let base_context = self.base_context();
+        let program = device.graphics_programs.get(program_handle);
+        let variant = &program.variants[i_variant];
+        let pipeline = variant.pipelines[index];
+        base_context
+            .bound_shaders
+            .set([variant.vertex_shader, variant.fragment_shader]);
         unsafe {
             device.device.cmd_bind_pipeline(
                 base_context.cmd,
@@ -527,6 +992,7 @@ impl GraphicsContext {
                 pipeline,
             );
         }
+        Ok(())
     }
 
     pub fn set_viewport(&self, device: &Device, viewport: vk::ViewportBuilder) {
@@ -567,6 +1033,25 @@ impl GraphicsContext {
             );
         }
     }
+
+    pub fn bind_vertex_buffer(
+        &self,
+        device: &Device,
+        binding: u32,
+        buffer_handle: Handle<Buffer>,
+        offset: usize,
+    ) {
+        let base_context = self.base_context();
+        let buffer = device.buffers.get(buffer_handle);
+        unsafe {
+            device.device.cmd_bind_vertex_buffers(
+                base_context.cmd,
+                binding,
+                &[buffer.vkhandle],
+                &[offset as u64],
+            );
+        }
+    }
 }
 
 pub struct DrawOptions {
@@ -588,8 +1073,9 @@ impl Default for DrawOptions {
 }
 
 impl GraphicsContext {
-    pub fn draw(&self, device: &Device, draw_options: DrawOptions) {
+    pub fn draw(&self, device: &Device, draw_options: DrawOptions) -> VulkanResult<()> {
         let base_context = self.base_context();
+        base_context.validate_bound_options(device)?;
         unsafe {
             device.device.cmd_draw(
                 base_context.cmd,
@@ -599,6 +1085,7 @@ impl GraphicsContext {
                 draw_options.instance_offset,
             );
         }
+        Ok(())
     }
 }
 
@@ -623,8 +1110,13 @@ impl Default for DrawIndexedOptions {
 }
 
 impl GraphicsContext {
-    pub fn draw_indexed(&self, device: &Device, draw_options: DrawIndexedOptions) {
+    pub fn draw_indexed(
+        &self,
+        device: &Device,
+        draw_options: DrawIndexedOptions,
+    ) -> VulkanResult<()> {
         let base_context = self.base_context();
+        base_context.validate_bound_options(device)?;
         unsafe {
             device.device.cmd_draw_indexed(
                 base_context.cmd,
@@ -635,6 +1127,7 @@ impl GraphicsContext {
                 draw_options.instance_offset,
             );
         }
+        Ok(())
     }
 }
 
@@ -686,9 +1179,12 @@ impl Device {
             wait_stage_list: DynamicArray::new(),
             queue,
             queue_type: i_queue,
+            queue_family_idx,
             image_acquired_semaphore: None,
             image_acquired_stage: None,
             can_present_semaphore: None,
+            bound_shaders: Cell::new([Handle::invalid(); 2]),
+            bound_options: Cell::new(None),
         })
     }
 
@@ -697,7 +1193,7 @@ impl Device {
         context_pool: &mut ContextPool,
     ) -> VulkanResult<TransferContext> {
         Ok(TransferContext {
-            base: self.get_base_context(context_pool, queues::GRAPHICS)?,
+            base: self.get_base_context(context_pool, queues::TRANSFER)?,
         })
     }
 
@@ -8,23 +8,35 @@ use super::image::*;
 use erupt::{vk, DeviceLoader, ExtendableFrom};
 
 pub struct DynamicBufferDescriptor {
-    buffer: Handle<Buffer>,
+    pub buffer: Handle<Buffer>,
     pub vkset: vk::DescriptorSet,
     size: usize,
 }
 
+// Descriptor sets of the pipeline layout shared by every program, see render/bindless.h
+pub const GLOBAL_BINDLESS_SET: u32 = 0;
+pub const GLOBAL_UNIFORM_SET: u32 = 1;
+pub const SHADER_UNIFORM_SET: u32 = 2;
+
 pub const BINDLESS_SETS: usize = 3;
 type PerSet<T> = [T; BINDLESS_SETS];
 pub const PER_SAMPLER: usize = 0;
 pub const PER_IMAGE: usize = 1;
 pub const PER_BUFFER: usize = 2;
 
+// Sampled images are also bound without sampler at the same index, to be used with the sampler array
+pub const SAMPLED_IMAGE_BINDING: u32 = 3;
+pub const SAMPLER_BINDING: u32 = 4;
+pub const MAX_SAMPLERS: u32 = 64;
+const BINDLESS_BINDINGS: usize = 5;
+
 pub struct BindlessSet {
     pub vkpool: vk::DescriptorPool,
     pub vklayout: vk::DescriptorSetLayout,
     pub vkset: vk::DescriptorSet,
-    pub sampler_images: Vec<Handle<Image>>,
-    pub storage_images: Vec<Handle<Image>>,
+    // The mip level is `None` for the view of all the levels
+    pub sampler_images: Vec<(Handle<Image>, Option<u32>)>,
+    pub storage_images: Vec<(Handle<Image>, Option<u32>)>,
     pub storage_buffers: Vec<Handle<Buffer>>,
     pub free_lists: PerSet<Vec<usize>>,
     pub pending_binds: PerSet<Vec<usize>>,
@@ -48,6 +60,12 @@ impl BindlessSet {
             vk::DescriptorPoolSizeBuilder::new()
                 ._type(vk::DescriptorType::STORAGE_BUFFER)
                 .descriptor_count(buffer_count),
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(sampler_count),
+            vk::DescriptorPoolSizeBuilder::new()
+                ._type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(MAX_SAMPLERS),
         ];
         let pool_info = vk::DescriptorPoolCreateInfoBuilder::new()
             .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
@@ -56,8 +74,8 @@ impl BindlessSet {
         let vkpool = unsafe { device.create_descriptor_pool(&pool_info, None).result()? };
 
         let mut bindings =
-            DynamicArray::<vk::DescriptorSetLayoutBindingBuilder, BINDLESS_SETS>::new();
-        let mut flags = DynamicArray::<vk::DescriptorBindingFlags, BINDLESS_SETS>::new();
+            DynamicArray::<vk::DescriptorSetLayoutBindingBuilder, BINDLESS_BINDINGS>::new();
+        let mut flags = DynamicArray::<vk::DescriptorBindingFlags, BINDLESS_BINDINGS>::new();
 
         bindings.push(
             vk::DescriptorSetLayoutBindingBuilder::new()
@@ -98,6 +116,32 @@ impl BindlessSet {
                 | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING,
         );
 
+        bindings.push(
+            vk::DescriptorSetLayoutBindingBuilder::new()
+                .binding(SAMPLED_IMAGE_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(sampler_count)
+                .stage_flags(vk::ShaderStageFlags::ALL),
+        );
+        flags.push(
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING,
+        );
+
+        bindings.push(
+            vk::DescriptorSetLayoutBindingBuilder::new()
+                .binding(SAMPLER_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(MAX_SAMPLERS)
+                .stage_flags(vk::ShaderStageFlags::ALL),
+        );
+        flags.push(
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                | vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING,
+        );
+
         let mut flags_info =
             vk::DescriptorSetLayoutBindingFlagsCreateInfoBuilder::new().binding_flags(&flags);
         let layout_info = vk::DescriptorSetLayoutCreateInfoBuilder::new()
@@ -131,8 +175,8 @@ impl BindlessSet {
             vkpool,
             vklayout,
             vkset,
-            sampler_images: vec![Handle::<Image>::invalid(); sampler_count as usize],
-            storage_images: vec![Handle::<Image>::invalid(); image_count as usize],
+            sampler_images: vec![(Handle::<Image>::invalid(), None); sampler_count as usize],
+            storage_images: vec![(Handle::<Image>::invalid(), None); image_count as usize],
             storage_buffers: vec![Handle::<Buffer>::invalid(); buffer_count as usize],
             free_lists,
             pending_binds: [vec![], vec![], vec![]],
@@ -162,40 +206,48 @@ impl BindlessSet {
         }
     }
 
-    pub fn bind_sampler_image(&mut self, image_handle: Handle<Image>) -> usize {
+    pub fn bind_sampler_image(
+        &mut self,
+        image_handle: Handle<Image>,
+        mip_level: Option<u32>,
+    ) -> usize {
         let new_index = self.free_lists[PER_SAMPLER].pop().unwrap();
         assert!(new_index != !0usize);
-        self.sampler_images[new_index] = image_handle;
+        self.sampler_images[new_index] = (image_handle, mip_level);
         self.pending_binds[PER_SAMPLER].push(new_index);
         new_index
     }
 
     pub fn unbind_sampler_image(&mut self, image_index: usize) {
-        self.sampler_images[image_index] = Handle::invalid();
+        self.sampler_images[image_index] = (Handle::invalid(), None);
         self.free_lists[PER_SAMPLER].push(image_index);
         self.pending_unbinds[PER_SAMPLER].push(image_index);
     }
 
     pub fn get_sampler_image(&self, image_index: usize) -> Handle<Image> {
-        self.sampler_images[image_index]
+        self.sampler_images[image_index].0
     }
 
-    pub fn bind_storage_image(&mut self, image_handle: Handle<Image>) -> usize {
+    pub fn bind_storage_image(
+        &mut self,
+        image_handle: Handle<Image>,
+        mip_level: Option<u32>,
+    ) -> usize {
         let new_index = self.free_lists[PER_IMAGE].pop().unwrap();
         assert!(new_index != !0usize);
-        self.storage_images[new_index] = image_handle;
+        self.storage_images[new_index] = (image_handle, mip_level);
         self.pending_binds[PER_IMAGE].push(new_index);
         new_index
     }
 
     pub fn unbind_storage_image(&mut self, image_index: usize) {
-        self.storage_images[image_index] = Handle::invalid();
+        self.storage_images[image_index] = (Handle::invalid(), None);
         self.free_lists[PER_IMAGE].push(image_index);
         self.pending_unbinds[PER_IMAGE].push(image_index);
     }
 
     pub fn get_storage_image(&self, image_index: usize) -> Handle<Image> {
-        self.storage_images[image_index]
+        self.storage_images[image_index].0
     }
 
     pub fn bind_storage_buffer(&mut self, buffer_handle: Handle<Buffer>) -> usize {
@@ -274,8 +326,11 @@ impl DynamicBufferDescriptor {
         })
     }
 
-    pub fn destroy(&mut self, device: &DeviceLoader) -> VulkanResult<()> {
-        let vkpool = vk::DescriptorPool::null();
+    pub fn destroy(
+        &mut self,
+        device: &DeviceLoader,
+        vkpool: vk::DescriptorPool,
+    ) -> VulkanResult<()> {
         let vksets = [self.vkset];
         unsafe {
             device.free_descriptor_sets(vkpool, &vksets).result()?;
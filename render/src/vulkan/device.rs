@@ -10,19 +10,28 @@ use super::image::*;
 use super::instance::*;
 use super::memory;
 use super::physical_device::*;
+use super::pipeline_cache::*;
+use super::sampler::*;
 use super::shader::*;
 use super::surface::*;
+use super::validation::*;
 
-use exo::{dynamic_array::DynamicArray, pool::Pool};
+use exo::{
+    dynamic_array::DynamicArray,
+    pool::{Handle, Pool},
+};
 
 use erupt::{cstr, vk, DeviceLoader, ExtendableFrom};
 use std::ffi::CString;
 use std::os::raw::c_char;
+use std::path::PathBuf;
 
 const VK_KHR_SWAPCHAIN_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_swapchain");
 
 pub struct DeviceSpec {
     pub push_constant_size: usize,
+    // Loaded when the device is created and saved when it is destroyed
+    pub pipeline_cache_path: Option<PathBuf>,
 }
 
 pub struct DeviceDescriptors {
@@ -47,7 +56,21 @@ pub struct Device {
     pub descriptors: DeviceDescriptors,
     pub graphics_programs: Pool<GraphicsProgram>,
     pub compute_programs: Pool<ComputeProgram>,
+    // Variants that failed to compile while recording, with the base shader of the stage that failed
+    pub variant_errors: Vec<(Handle<Shader>, VulkanError)>,
+    // Used for the combined image samplers
     pub sampler: vk::Sampler,
+    // Indexed by the bindless sampler index, see `get_or_create_sampler`
+    pub samplers: Vec<Sampler>,
+    pub max_sampler_anisotropy: f32,
+    // The supported features are all enabled, wireframe rasterization needs `fillModeNonSolid`
+    pub fill_mode_non_solid: bool,
+    // Shared by all graphics and compute pipelines
+    pub pipeline_cache: vk::PipelineCache,
+    // Sample counts usable for both color and depth attachments
+    pub framebuffer_sample_counts: vk::SampleCountFlags,
+    // Shared with the instance when it panics on validation errors
+    validation_errors: Option<ValidationErrors>,
 }
 
 impl Device {
@@ -58,7 +81,9 @@ impl Device {
         physical_device: &mut PhysicalDevice,
     ) -> VulkanResult<Self> {
         let mut device_extensions = DynamicArray::<_, 8>::new();
-        device_extensions.push(VK_KHR_SWAPCHAIN_EXTENSION_NAME);
+        if instance.graphic_windows_enabled || instance.headless_surface_enabled {
+            device_extensions.push(VK_KHR_SWAPCHAIN_EXTENSION_NAME);
+        }
 
         let queue_families = unsafe {
             instance
@@ -129,8 +154,7 @@ impl Device {
             }
 
             DeviceLoader::new(&instance.instance, physical_device.device, &device_info)
-        }
-        .unwrap();
+        }?;
         let device = Box::new(device);
 
         let allocator = unsafe {
@@ -140,8 +164,7 @@ impl Device {
                 &vk_alloc::AllocatorDescriptor {
                     ..Default::default()
                 },
-            )
-            .unwrap()
+            )?
         };
 
         let bindless_set = BindlessSet::new(&device, 1024, 1024, 1024)?;
@@ -150,7 +173,9 @@ impl Device {
             let pool_sizes = [vk::DescriptorPoolSizeBuilder::new()
                 ._type(vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC)
                 .descriptor_count(16)];
+            // Descriptors are freed when their buffer is destroyed
             let pool_info = vk::DescriptorPoolCreateInfoBuilder::new()
+                .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
                 .pool_sizes(&pool_sizes)
                 .max_sets(16);
             unsafe { device.create_descriptor_pool(&pool_info, None).result()? }
@@ -184,6 +209,24 @@ impl Device {
             device.create_sampler(&sampler_info, None).result()?
         };
 
+        let max_sampler_anisotropy = if physical_device.features.features.sampler_anisotropy != 0 {
+            physical_device.properties.limits.max_sampler_anisotropy
+        } else {
+            1.0
+        };
+
+        let fill_mode_non_solid = physical_device.features.features.fill_mode_non_solid == vk::TRUE;
+
+        let limits = &physical_device.properties.limits;
+        let framebuffer_sample_counts =
+            limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+
+        let pipeline_cache = create_pipeline_cache(
+            &device,
+            &physical_device.properties,
+            spec.pipeline_cache_path.as_deref(),
+        )?;
+
         let mut device = Device {
             device,
             spec,
@@ -204,28 +247,45 @@ impl Device {
             },
             graphics_programs: Pool::new(),
             compute_programs: Pool::new(),
+            variant_errors: Vec::new(),
             sampler,
+            samplers: Vec::new(),
+            max_sampler_anisotropy,
+            fill_mode_non_solid,
+            pipeline_cache,
+            framebuffer_sample_counts,
+            validation_errors: instance.validation_errors(),
         };
 
         // Empty image for bindless clear #0
-        device
-            .create_image(ImageSpec {
-                name: String::from("empty"),
-                usages: vk::ImageUsageFlags::TRANSFER_SRC
-                    | vk::ImageUsageFlags::TRANSFER_DST
-                    | vk::ImageUsageFlags::SAMPLED
-                    | vk::ImageUsageFlags::COLOR_ATTACHMENT
-                    | vk::ImageUsageFlags::STORAGE,
-
-                ..Default::default()
-            })
-            .unwrap();
+        device.create_image(ImageSpec {
+            name: String::from("empty"),
+            usages: vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::STORAGE,
+
+            ..Default::default()
+        })?;
 
         Ok(device)
     }
 
     pub fn destroy(self) {
-        unsafe { self.device.destroy_device(None) };
+        if let Err(error) = self.save_pipeline_cache() {
+            eprintln!("failed to save the pipeline cache: {}", error);
+        }
+        self.destroy_lost();
+    }
+
+    // The pipeline cache of a lost device is not saved, getting its data is not valid anymore
+    pub fn destroy_lost(self) {
+        unsafe {
+            self.device
+                .destroy_pipeline_cache(self.pipeline_cache, None);
+            self.device.destroy_device(None);
+        }
     }
 
     pub fn submit<Context: AsRef<TransferContext>>(
@@ -283,7 +343,13 @@ impl Device {
         unsafe {
             self.device
                 .queue_submit(context.queue, &[submit_info], vk::Fence::null())
-                .result()?;
+                .result()
+                .map_err(|result| VulkanError::from_vk(result, "vkQueueSubmit", None))?;
+        }
+
+        // The errors of the recorded commands and of the submission are reported by now
+        if let Some(errors) = &self.validation_errors {
+            panic_on_validation_errors(errors);
         }
 
         Ok(())
@@ -308,7 +374,11 @@ impl Device {
         match res.raw {
             vk::Result::SUCCESS => Ok(false),
             vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => Ok(true),
-            _ => Err(VulkanError::from(res.raw)),
+            result => Err(VulkanError::from_vk(
+                result,
+                "vkAcquireNextImageKHR",
+                Some("swapchain"),
+            )),
         }
     }
 
@@ -333,7 +403,11 @@ impl Device {
         match res.raw {
             vk::Result::SUCCESS => Ok(false),
             vk::Result::SUBOPTIMAL_KHR | vk::Result::ERROR_OUT_OF_DATE_KHR => Ok(true),
-            _ => Err(VulkanError::from(res.raw)),
+            result => Err(VulkanError::from_vk(
+                result,
+                "vkQueuePresentKHR",
+                Some("swapchain"),
+            )),
         }
     }
 
@@ -359,7 +433,9 @@ impl Device {
     }
 
     pub fn wait_idle(&self) -> VulkanResult<()> {
-        unsafe { self.device.device_wait_idle().result()? }
+        unsafe { self.device.device_wait_idle() }
+            .result()
+            .map_err(|result| VulkanError::from_vk(result, "vkDeviceWaitIdle", None))?;
         Ok(())
     }
 
@@ -420,25 +496,35 @@ impl Device {
 
                 match i_set {
                     PER_SAMPLER => {
-                        let image_handle = bindless_set.sampler_images[*to_bind];
+                        let (image_handle, mip_level) = bindless_set.sampler_images[*to_bind];
                         let image = self.images.get(image_handle);
                         let i_info = image_infos.len();
                         image_infos.push(
                             vk::DescriptorImageInfoBuilder::new()
                                 .sampler(self.sampler)
-                                .image_view(image.full_view.vkhandle)
+                                .image_view(image.view(mip_level).vkhandle)
                                 .image_layout(image_layout),
                         );
                         writes_indirection.push((i_info, i_info + 1, true));
+
+                        // The same view without sampler, the sampler is ignored for sampled images
+                        descriptor_writes.push(
+                            vk::WriteDescriptorSetBuilder::new()
+                                .dst_set(bindless_set.vkset)
+                                .dst_binding(SAMPLED_IMAGE_BINDING)
+                                .dst_array_element(*to_bind as u32)
+                                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE),
+                        );
+                        writes_indirection.push((i_info, i_info + 1, true));
                     }
                     PER_IMAGE => {
-                        let image_handle = bindless_set.sampler_images[*to_bind];
+                        let (image_handle, mip_level) = bindless_set.storage_images[*to_bind];
                         let image = self.images.get(image_handle);
                         let i_info = image_infos.len();
                         image_infos.push(
                             vk::DescriptorImageInfoBuilder::new()
                                 .sampler(self.sampler)
-                                .image_view(image.full_view.vkhandle)
+                                .image_view(image.view(mip_level).vkhandle)
                                 .image_layout(image_layout),
                         );
                         writes_indirection.push((i_info, i_info + 1, true));
@@ -474,6 +560,18 @@ impl Device {
                         .dst_array_element(*to_unbind as u32)
                         .descriptor_count(1),
                 );
+                if i_set == PER_SAMPLER {
+                    descriptor_copies.push(
+                        vk::CopyDescriptorSetBuilder::new()
+                            .src_set(bindless_set.vkset)
+                            .src_binding(SAMPLED_IMAGE_BINDING)
+                            .src_array_element(0)
+                            .dst_set(bindless_set.vkset)
+                            .dst_binding(SAMPLED_IMAGE_BINDING)
+                            .dst_array_element(*to_unbind as u32)
+                            .descriptor_count(1),
+                    );
+                }
             }
 
             bindless_set.pending_binds[i_set].clear();
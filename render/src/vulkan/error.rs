@@ -1,4 +1,5 @@
-use erupt::vk;
+use erupt::{vk, LoaderError};
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -22,14 +23,91 @@ pub enum VulkanError {
     APIError(vk::Result),
     #[error("memory allocation failed: {0}")]
     AllocatorError(vk_alloc::AllocatorError),
+    #[error("ring buffer is full, {requested} bytes requested out of {capacity}")]
+    RingBufferFull { requested: usize, capacity: usize },
+    #[error("failed to read or write the pipeline cache: {0}")]
+    PipelineCacheIo(std::io::Error),
+    #[error("invalid SPIR-V: {0}")]
+    InvalidSpirv(String),
+    #[error("failed to compile shader {path:?}:\n{log}")]
+    ShaderCompilation { path: PathBuf, log: String },
+    #[error("shader {path:?} does not match the pipeline layout: {mismatch}")]
+    ShaderLayoutMismatch { path: PathBuf, mismatch: String },
+    #[error("missing vulkan extension {name}, needed for {purpose}")]
+    MissingExtension { name: String, purpose: &'static str },
+    #[error("missing vulkan feature {name}, needed for {purpose}")]
+    MissingFeature {
+        name: &'static str,
+        purpose: &'static str,
+    },
+    #[error("unsupported window system {0}")]
+    UnsupportedWindowSystem(String),
+    #[error("no suitable physical device: {0}")]
+    NoSuitableDevice(String),
+    #[error("failed to read shader {path:?}: {error}")]
+    ShaderIo {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    #[error("device lost during {operation}{}", on_object(.object))]
+    DeviceLost {
+        operation: &'static str,
+        object: Option<String>,
+    },
+    #[error("surface lost during {operation}{}", on_object(.object))]
+    SurfaceLost {
+        operation: &'static str,
+        object: Option<String>,
+    },
+    #[error("swapchain out of date during {operation}{}", on_object(.object))]
+    OutOfDate {
+        operation: &'static str,
+        object: Option<String>,
+    },
+    #[error("failed to load vulkan: {0}")]
+    LoaderMissing(String),
     #[error("unknown vulkan error")]
     Unknown,
 }
 
+fn on_object(object: &Option<String>) -> String {
+    object
+        .as_ref()
+        .map(|object| format!(" on {:?}", object))
+        .unwrap_or_default()
+}
+
+impl VulkanError {
+    // Losses are reported with the call that noticed them, other results stay API errors
+    pub fn from_vk(result: vk::Result, operation: &'static str, object: Option<&str>) -> Self {
+        assert!(result != vk::Result::SUCCESS);
+        let object = object.map(String::from);
+        match result {
+            vk::Result::ERROR_DEVICE_LOST => Self::DeviceLost { operation, object },
+            vk::Result::ERROR_SURFACE_LOST_KHR => Self::SurfaceLost { operation, object },
+            vk::Result::ERROR_OUT_OF_DATE_KHR => Self::OutOfDate { operation, object },
+            _ => Self::APIError(result),
+        }
+    }
+
+    // The device and everything created from it have to be recreated
+    pub fn is_device_lost(&self) -> bool {
+        matches!(self, Self::DeviceLost { .. })
+    }
+}
+
 impl From<vk::Result> for VulkanError {
     fn from(error: vk::Result) -> Self {
-        assert!(error != vk::Result::SUCCESS);
-        Self::APIError(error)
+        Self::from_vk(error, "a vulkan call", None)
+    }
+}
+
+impl From<LoaderError> for VulkanError {
+    fn from(error: LoaderError) -> Self {
+        match error {
+            LoaderError::VulkanError(result) => Self::from_vk(result, "loading vulkan", None),
+            LoaderError::SymbolNotAvailable => Self::LoaderMissing(error.to_string()),
+        }
     }
 }
 
@@ -40,3 +118,32 @@ impl From<vk_alloc::AllocatorError> for VulkanError {
 }
 
 pub type VulkanResult<T> = Result<T, VulkanError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn losses_keep_their_context() {
+        let error = VulkanError::from_vk(
+            vk::Result::ERROR_DEVICE_LOST,
+            "vkQueueSubmit",
+            Some("graphics queue"),
+        );
+        assert!(error.is_device_lost());
+        assert_eq!(
+            error.to_string(),
+            "device lost during vkQueueSubmit on \"graphics queue\""
+        );
+
+        let error = VulkanError::from(vk::Result::ERROR_SURFACE_LOST_KHR);
+        assert!(matches!(
+            error,
+            VulkanError::SurfaceLost { object: None, .. }
+        ));
+        assert!(matches!(
+            VulkanError::from(vk::Result::ERROR_OUT_OF_HOST_MEMORY),
+            VulkanError::APIError(vk::Result::ERROR_OUT_OF_HOST_MEMORY)
+        ));
+    }
+}
@@ -57,10 +57,19 @@ impl Device {
             .semaphores(&semaphores)
             .values(wait_values);
 
-        unsafe {
-            self.device.wait_semaphores(&wait_info, timeout).result()?;
-        }
+        unsafe { self.device.wait_semaphores(&wait_info, timeout) }
+            .result()
+            .map_err(|result| VulkanError::from_vk(result, "vkWaitSemaphores", None))?;
 
         Ok(())
     }
+    // Returns the last value signaled on the GPU, without waiting
+    pub fn get_fence_value(&self, fence: &Fence) -> VulkanResult<u64> {
+        let value = unsafe {
+            self.device
+                .get_semaphore_counter_value(fence.timeline_semaphore)
+                .result()?
+        };
+        Ok(value)
+    }
 }
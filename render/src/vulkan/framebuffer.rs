@@ -9,11 +9,19 @@ use erupt::{vk, DeviceLoader};
 pub const MAX_ATTACHMENTS: usize = 4;
 pub const MAX_RENDERPASS: usize = 4; // max number of renderpasses per framebuffer
 
+// Color attachments, their resolve attachments and the depth attachment
+const MAX_RENDERPASS_ATTACHMENTS: usize = 2 * MAX_ATTACHMENTS + 1;
+
 #[derive(Clone, Debug)]
 pub struct FramebufferFormat {
     pub size: [i32; 3],
     pub attachment_formats: DynamicArray<vk::Format, MAX_ATTACHMENTS>,
     pub depth_format: Option<vk::Format>,
+    // Sample count of the color and depth attachments
+    pub samples: vk::SampleCountFlagBits,
+    // Each multisampled color attachment is resolved to a single sampled image at the end of the renderpass.
+    // The depth attachment is never resolved, it would need VK_KHR_depth_stencil_resolve and renderpass2.
+    pub resolve: bool,
 }
 
 impl Default for FramebufferFormat {
@@ -22,6 +30,8 @@ impl Default for FramebufferFormat {
             size: [1, 1, 1],
             attachment_formats: DynamicArray::new(),
             depth_format: None,
+            samples: vk::SampleCountFlagBits::_1,
+            resolve: false,
         }
     }
 }
@@ -31,6 +41,7 @@ pub struct Framebuffer {
     pub vkhandle: vk::Framebuffer,
     pub format: FramebufferFormat,
     pub color_attachments: DynamicArray<Handle<Image>, MAX_ATTACHMENTS>,
+    pub resolve_attachments: DynamicArray<Handle<Image>, MAX_ATTACHMENTS>,
     pub depth_attachment: Handle<Image>,
     pub render_passes: DynamicArray<Renderpass, MAX_RENDERPASS>,
 }
@@ -44,8 +55,8 @@ pub enum ClearColorValue {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ClearDepthValue {
-    depth: f32,
-    stencil: u32,
+    pub depth: f32,
+    pub stencil: u32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -56,24 +67,35 @@ pub enum LoadOp {
     Ignore,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOp {
+    Store,
+    Ignore,
+}
+
 #[derive(Debug)]
 pub struct Renderpass {
     pub vkhandle: vk::RenderPass,
     pub load_ops: DynamicArray<LoadOp, MAX_ATTACHMENTS>,
+    pub store_ops: DynamicArray<StoreOp, MAX_ATTACHMENTS>,
 }
 
 pub fn create_renderpass(
     device: &DeviceLoader,
     format: &FramebufferFormat,
     load_ops: &[LoadOp],
+    store_ops: &[StoreOp],
 ) -> VulkanResult<Renderpass> {
     let attachment_count =
         format.attachment_formats.len() + if format.depth_format.is_some() { 1 } else { 0 };
     assert!(load_ops.len() == attachment_count);
+    assert!(store_ops.len() == attachment_count);
+    assert!(!format.resolve || format.samples != vk::SampleCountFlagBits::_1);
 
     let mut color_refs = DynamicArray::<vk::AttachmentReferenceBuilder, MAX_ATTACHMENTS>::new();
+    let mut resolve_refs = DynamicArray::<vk::AttachmentReferenceBuilder, MAX_ATTACHMENTS>::new();
     let mut attachment_descs =
-        DynamicArray::<vk::AttachmentDescriptionBuilder, MAX_ATTACHMENTS>::new();
+        DynamicArray::<vk::AttachmentDescriptionBuilder, MAX_RENDERPASS_ATTACHMENTS>::new();
 
     #[allow(clippy::needless_range_loop)]
     for i_color in 0..format.attachment_formats.len() {
@@ -86,24 +108,84 @@ pub fn create_renderpass(
         attachment_descs.push(
             vk::AttachmentDescriptionBuilder::new()
                 .format(format.attachment_formats[i_color])
-                .samples(vk::SampleCountFlagBits::_1)
+                .samples(format.samples)
                 .load_op(load_ops[i_color].to_vk())
-                .store_op(vk::AttachmentStoreOp::STORE)
+                .store_op(store_ops[i_color].to_vk())
                 .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
                 .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-                .initial_layout(if let LoadOp::ClearColor(_) = load_ops[i_color] {
-                    vk::ImageLayout::UNDEFINED
-                } else {
-                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
-                })
+                .initial_layout(
+                    load_ops[i_color].initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+                )
                 .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
         );
     }
 
-    let subpass_info = vk::SubpassDescriptionBuilder::new()
+    let depth_ref = format.depth_format.map(|depth_format| {
+        let i_depth = attachment_descs.len();
+        // The stencil aspect is loaded and stored like the depth
+        let (stencil_load_op, stencil_store_op) = if has_stencil_aspect(depth_format) {
+            (load_ops[i_depth].to_vk(), store_ops[i_depth].to_vk())
+        } else {
+            (
+                vk::AttachmentLoadOp::DONT_CARE,
+                vk::AttachmentStoreOp::DONT_CARE,
+            )
+        };
+        attachment_descs.push(
+            vk::AttachmentDescriptionBuilder::new()
+                .format(depth_format)
+                .samples(format.samples)
+                .load_op(load_ops[i_depth].to_vk())
+                .store_op(store_ops[i_depth].to_vk())
+                .stencil_load_op(stencil_load_op)
+                .stencil_store_op(stencil_store_op)
+                .initial_layout(
+                    load_ops[i_depth]
+                        .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+                )
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+        );
+
+        vk::AttachmentReferenceBuilder::new()
+            .attachment(i_depth as u32)
+            .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+    });
+
+    // Resolve attachments come last so that load ops and clear values keep the same indices
+    if format.resolve {
+        for color_format in &format.attachment_formats {
+            resolve_refs.push(
+                vk::AttachmentReferenceBuilder::new()
+                    .attachment(attachment_descs.len() as u32)
+                    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            );
+
+            attachment_descs.push(
+                vk::AttachmentDescriptionBuilder::new()
+                    .format(*color_format)
+                    .samples(vk::SampleCountFlagBits::_1)
+                    .load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            );
+        }
+    }
+
+    let mut subpass_info = vk::SubpassDescriptionBuilder::new()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(&color_refs);
 
+    if !resolve_refs.is_empty() {
+        subpass_info = subpass_info.resolve_attachments(&resolve_refs);
+    }
+
+    if let Some(depth_ref) = &depth_ref {
+        subpass_info = subpass_info.depth_stencil_attachment(depth_ref);
+    }
+
     let subpasses = [subpass_info];
 
     let renderpass_info = vk::RenderPassCreateInfoBuilder::new()
@@ -113,7 +195,12 @@ pub fn create_renderpass(
     let vkhandle = unsafe { device.create_render_pass(&renderpass_info, None).result()? };
 
     let load_ops = DynamicArray::<LoadOp, MAX_ATTACHMENTS>::from(load_ops);
-    Ok(Renderpass { vkhandle, load_ops })
+    let store_ops = DynamicArray::<StoreOp, MAX_ATTACHMENTS>::from(store_ops);
+    Ok(Renderpass {
+        vkhandle,
+        load_ops,
+        store_ops,
+    })
 }
 
 impl Device {
@@ -121,12 +208,18 @@ impl Device {
         &mut self,
         size: [i32; 3],
         color_attachments: &[Handle<Image>],
+        resolve_attachments: &[Handle<Image>],
         depth_attachment: Handle<Image>,
     ) -> VulkanResult<Handle<Framebuffer>> {
+        assert!(
+            resolve_attachments.is_empty() || resolve_attachments.len() == color_attachments.len()
+        );
+
         let mut framebuffer = Framebuffer {
             vkhandle: vk::Framebuffer::null(),
             format: FramebufferFormat::default(),
             color_attachments: DynamicArray::new(),
+            resolve_attachments: DynamicArray::new(),
             depth_attachment: Handle::invalid(),
             render_passes: DynamicArray::new(),
         };
@@ -136,10 +229,11 @@ impl Device {
         let attachment_count =
             color_attachments.len() + if depth_attachment.is_valid() { 1 } else { 0 };
 
-        let mut attachment_views = DynamicArray::<vk::ImageView, MAX_ATTACHMENTS>::new();
+        let mut attachment_views = DynamicArray::<vk::ImageView, MAX_RENDERPASS_ATTACHMENTS>::new();
         for attachment in color_attachments {
             let image = self.images.get(*attachment);
             attachment_views.push(image.full_view.vkhandle);
+            framebuffer.format.samples = image.spec.samples;
             framebuffer
                 .format
                 .attachment_formats
@@ -150,19 +244,39 @@ impl Device {
         if depth_attachment.is_valid() {
             let image = self.images.get(depth_attachment);
             attachment_views.push(image.full_view.vkhandle);
+            if !color_attachments.is_empty() {
+                assert!(image.spec.samples == framebuffer.format.samples);
+            }
+            framebuffer.format.samples = image.spec.samples;
             framebuffer.format.depth_format = Some(image.spec.format);
         }
         framebuffer.depth_attachment = depth_attachment;
 
+        for (attachment, color_attachment) in resolve_attachments.iter().zip(color_attachments) {
+            let image = self.images.get(*attachment);
+            assert!(
+                !is_depth_format(image.spec.format),
+                "depth attachments can't be resolved"
+            );
+            assert!(image.spec.samples == vk::SampleCountFlagBits::_1);
+            assert!(image.spec.format == self.images.get(*color_attachment).spec.format);
+            attachment_views.push(image.full_view.vkhandle);
+            framebuffer.resolve_attachments.push(*attachment);
+        }
+        framebuffer.format.resolve = !resolve_attachments.is_empty();
+
         let mut load_ops = DynamicArray::<LoadOp, MAX_ATTACHMENTS>::new();
+        let mut store_ops = DynamicArray::<StoreOp, MAX_ATTACHMENTS>::new();
         for _ in 0..attachment_count {
             load_ops.push(LoadOp::Ignore);
+            store_ops.push(StoreOp::Store);
         }
 
         framebuffer.render_passes.push(create_renderpass(
             &self.device,
             &framebuffer.format,
             &load_ops,
+            &store_ops,
         )?);
 
         let framebuffer_info = vk::FramebufferCreateInfoBuilder::new()
@@ -196,19 +310,21 @@ impl Device {
         &mut self,
         framebuffer_handle: Handle<Framebuffer>,
         load_ops: &[LoadOp],
+        store_ops: &[StoreOp],
     ) -> VulkanResult<(&Framebuffer, &Renderpass)> {
         let framebuffer = self.framebuffers.get_mut(framebuffer_handle);
 
-        let mut i_renderpass = framebuffer
-            .render_passes
-            .iter()
-            .position(|renderpass| renderpass.load_ops.as_slice() == load_ops);
+        let mut i_renderpass = framebuffer.render_passes.iter().position(|renderpass| {
+            renderpass.load_ops.as_slice() == load_ops
+                && renderpass.store_ops.as_slice() == store_ops
+        });
 
         if i_renderpass.is_none() {
             framebuffer.render_passes.push(create_renderpass(
                 &self.device,
                 &framebuffer.format,
                 load_ops,
+                store_ops,
             )?);
             i_renderpass = Some(framebuffer.render_passes.len() - 1);
         }
@@ -220,6 +336,22 @@ impl Device {
     }
 }
 
+impl Device {
+    // Highest sample count supported by both color and depth attachments that is not above `samples`
+    pub fn supported_sample_count(
+        &self,
+        samples: vk::SampleCountFlagBits,
+    ) -> vk::SampleCountFlagBits {
+        let mut samples = samples;
+        while samples > vk::SampleCountFlagBits::_1
+            && !self.framebuffer_sample_counts.contains(samples.bitmask())
+        {
+            samples = vk::SampleCountFlagBits(samples.0 >> 1);
+        }
+        samples
+    }
+}
+
 // --
 
 impl ClearColorValue {
@@ -250,6 +382,16 @@ impl LoadOp {
         }
     }
 
+    // Attachments that are cleared or ignored don't need their previous content
+    pub fn initial_layout(self, attachment_layout: vk::ImageLayout) -> vk::ImageLayout {
+        match self {
+            LoadOp::Load => attachment_layout,
+            LoadOp::ClearColor(_) | LoadOp::ClearDepth(_) | LoadOp::Ignore => {
+                vk::ImageLayout::UNDEFINED
+            }
+        }
+    }
+
     pub fn clear_value(&self) -> vk::ClearValue {
         match self {
             LoadOp::ClearColor(value) => vk::ClearValue {
@@ -264,3 +406,12 @@ impl LoadOp {
         }
     }
 }
+
+impl StoreOp {
+    pub fn to_vk(self) -> vk::AttachmentStoreOp {
+        match self {
+            StoreOp::Store => vk::AttachmentStoreOp::STORE,
+            StoreOp::Ignore => vk::AttachmentStoreOp::DONT_CARE,
+        }
+    }
+}
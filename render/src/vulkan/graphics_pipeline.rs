@@ -2,30 +2,63 @@ use super::device::*;
 use super::error::*;
 use super::framebuffer::*;
 use super::shader::*;
+use super::shader_variant::*;
 
 use erupt::vk;
 use exo::{dynamic_array::DynamicArray, pool::Handle};
 use std::ffi::CString;
 
 pub const MAX_RENDER_STATES: usize = 4;
+pub const MAX_VERTEX_BINDINGS: usize = 4;
+pub const MAX_VERTEX_ATTRIBUTES: usize = 8;
 
 #[derive(Copy, Clone, Debug)]
 pub enum PrimitiveTopology {
     TriangleList,
+    TriangleStrip,
+    LineList,
+    LineStrip,
     PointList,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StencilOpState {
+    pub fail_op: vk::StencilOp,
+    pub pass_op: vk::StencilOp,
+    pub depth_fail_op: vk::StencilOp,
+    pub compare_op: vk::CompareOp,
+    pub compare_mask: u32,
+    pub write_mask: u32,
+    pub reference: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StencilState {
+    pub front: StencilOpState,
+    pub back: StencilOpState,
+}
+
 #[derive(Debug)]
 pub struct DepthState {
     pub test: Option<vk::CompareOp>,
     pub enable_write: bool,
     pub bias: f32,
+    // Needs a depth format with a stencil aspect, see `has_stencil_aspect`
+    pub stencil: Option<StencilState>,
 }
 
 #[derive(Debug)]
 pub struct RasterizationState {
     pub enable_conservative_rasterization: bool,
     pub culling: bool,
+    pub front_face: FrontFace,
+    pub wireframe: bool,
 }
 
 #[derive(Debug)]
@@ -33,12 +66,48 @@ pub struct InputAssemblyState {
     pub topology: PrimitiveTopology,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct VertexBinding {
+    pub binding: u32,
+    pub stride: u32,
+    pub per_instance: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub binding: u32,
+    pub format: vk::Format,
+    pub offset: u32,
+}
+
+// Empty when the vertices are pulled from storage buffers in the vertex shader
+#[derive(Debug, Default)]
+pub struct VertexInputState {
+    pub bindings: DynamicArray<VertexBinding, MAX_VERTEX_BINDINGS>,
+    pub attributes: DynamicArray<VertexAttribute, MAX_VERTEX_ATTRIBUTES>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BlendState {
+    pub enable: bool,
+    pub src_color_factor: vk::BlendFactor,
+    pub dst_color_factor: vk::BlendFactor,
+    pub color_op: vk::BlendOp,
+    pub src_alpha_factor: vk::BlendFactor,
+    pub dst_alpha_factor: vk::BlendFactor,
+    pub alpha_op: vk::BlendOp,
+    pub write_mask: vk::ColorComponentFlags,
+}
+
 #[derive(Debug)]
 pub struct RenderState {
     pub depth: DepthState,
     pub rasterization: RasterizationState,
     pub input_assembly: InputAssemblyState,
-    pub alpha_blending: bool,
+    pub vertex_input: VertexInputState,
+    // One per color attachment, attachments without a blend state are written as is
+    pub blend_states: DynamicArray<BlendState, MAX_ATTACHMENTS>,
 }
 
 #[derive(Debug)]
@@ -46,6 +115,16 @@ pub struct GraphicsState {
     pub vertex_shader: Handle<Shader>,
     pub fragment_shader: Handle<Shader>,
     pub attachments_format: FramebufferFormat,
+    pub features: ShaderFeatures,
+}
+
+#[derive(Debug)]
+pub struct GraphicsVariant {
+    pub variant: ShaderVariant,
+    pub vertex_shader: Handle<Shader>,
+    pub fragment_shader: Handle<Shader>,
+    // One per render state of the program
+    pub pipelines: DynamicArray<vk::Pipeline, MAX_RENDER_STATES>,
 }
 
 #[derive(Debug)]
@@ -53,8 +132,11 @@ pub struct GraphicsProgram {
     pub name: String,
     pub graphics_state: GraphicsState,
     pub render_states: DynamicArray<RenderState, MAX_RENDER_STATES>,
-    pub pipelines: DynamicArray<vk::Pipeline, MAX_RENDER_STATES>,
-    pub cache: vk::PipelineCache,
+    // The first variant has no feature enabled and uses the shaders of the graphics state,
+    // the others are compiled the first time they are bound
+    pub variants: Vec<GraphicsVariant>,
+    // Variants that failed to compile, replaced by the first variant until the shaders are reloaded
+    pub failed_variants: Vec<ShaderVariant>,
     pub renderpass: vk::RenderPass,
 }
 
@@ -63,24 +145,43 @@ impl Device {
         &mut self,
         graphics_state: GraphicsState,
     ) -> VulkanResult<Handle<GraphicsProgram>> {
+        let attachments_format = &graphics_state.attachments_format;
+        let attachment_count = attachments_format.attachment_formats.len()
+            + if attachments_format.depth_format.is_some() {
+                1
+            } else {
+                0
+            };
+
+        // Load and store ops don't affect renderpass compatibility
         let mut load_ops = DynamicArray::<LoadOp, MAX_ATTACHMENTS>::new();
-        for _ in 0..graphics_state.attachments_format.attachment_formats.len() {
+        let mut store_ops = DynamicArray::<StoreOp, MAX_ATTACHMENTS>::new();
+        for _ in 0..attachment_count {
             load_ops.push(LoadOp::Ignore);
+            store_ops.push(StoreOp::Store);
         }
 
         let renderpass = super::framebuffer::create_renderpass(
             &self.device,
-            &graphics_state.attachments_format,
+            attachments_format,
             &load_ops,
+            &store_ops,
         )?
         .vkhandle;
 
+        let base_variant = GraphicsVariant {
+            variant: ShaderVariant::default(),
+            vertex_shader: graphics_state.vertex_shader,
+            fragment_shader: graphics_state.fragment_shader,
+            pipelines: DynamicArray::new(),
+        };
+
         let handle = self.graphics_programs.add(GraphicsProgram {
             name: String::new(),
             graphics_state,
             render_states: DynamicArray::new(),
-            pipelines: DynamicArray::new(),
-            cache: vk::PipelineCache::null(),
+            variants: vec![base_variant],
+            failed_variants: Vec::new(),
             renderpass,
         });
 
@@ -89,25 +190,56 @@ impl Device {
 
     pub fn destroy_program(&mut self, program_handle: Handle<GraphicsProgram>) {
         let program = self.graphics_programs.get(program_handle);
-        for pipeline in &program.pipelines {
-            unsafe {
-                self.device.destroy_pipeline(*pipeline, None);
+        for variant in &program.variants {
+            for pipeline in &variant.pipelines {
+                unsafe {
+                    self.device.destroy_pipeline(*pipeline, None);
+                }
             }
         }
         unsafe {
-            self.device.destroy_pipeline_cache(program.cache, None);
             self.device.destroy_render_pass(program.renderpass, None);
         }
+
+        // The shaders of the base variant belong to the caller
+        let variant_shaders: Vec<_> = program.variants[1..]
+            .iter()
+            .flat_map(|variant| [variant.vertex_shader, variant.fragment_shader])
+            .filter(|shader_handle| shader_handle.is_valid())
+            .collect();
+        for shader_handle in variant_shaders {
+            self.destroy_shader(shader_handle);
+        }
+
         self.graphics_programs.remove(program_handle);
     }
 
+    // Recompiles the pipeline of a render state for every variant compiled so far, returns the index of each
+    // variant that failed with its error, these keep their previous pipeline
     pub fn compile_graphics_program_pipeline(
         &mut self,
         program_handle: Handle<GraphicsProgram>,
         i_pipeline: usize,
+    ) -> Vec<(usize, VulkanError)> {
+        let variant_count = self.graphics_programs.get(program_handle).variants.len();
+        (0..variant_count)
+            .filter_map(|i_variant| {
+                self.compile_graphics_variant_pipeline(program_handle, i_variant, i_pipeline)
+                    .err()
+                    .map(|error| (i_variant, error))
+            })
+            .collect()
+    }
+
+    fn compile_graphics_variant_pipeline(
+        &mut self,
+        program_handle: Handle<GraphicsProgram>,
+        i_variant: usize,
+        i_pipeline: usize,
     ) -> VulkanResult<()> {
         let program = self.graphics_programs.get_mut(program_handle);
-        assert!(i_pipeline < program.pipelines.len());
+        let variant = &program.variants[i_variant];
+        assert!(i_pipeline < variant.pipelines.len());
         let render_state = &program.render_states[i_pipeline];
 
         let mut dynamic_states = DynamicArray::<vk::DynamicState, 4>::new();
@@ -117,7 +249,38 @@ impl Device {
         let dynamic_state_info =
             vk::PipelineDynamicStateCreateInfoBuilder::new().dynamic_states(&dynamic_states);
 
-        let vertex_input_info = vk::PipelineVertexInputStateCreateInfoBuilder::new();
+        let vertex_bindings: DynamicArray<_, MAX_VERTEX_BINDINGS> = render_state
+            .vertex_input
+            .bindings
+            .iter()
+            .map(|binding| {
+                vk::VertexInputBindingDescriptionBuilder::new()
+                    .binding(binding.binding)
+                    .stride(binding.stride)
+                    .input_rate(if binding.per_instance {
+                        vk::VertexInputRate::INSTANCE
+                    } else {
+                        vk::VertexInputRate::VERTEX
+                    })
+            })
+            .collect();
+
+        let vertex_attributes: DynamicArray<_, MAX_VERTEX_ATTRIBUTES> = render_state
+            .vertex_input
+            .attributes
+            .iter()
+            .map(|attribute| {
+                vk::VertexInputAttributeDescriptionBuilder::new()
+                    .location(attribute.location)
+                    .binding(attribute.binding)
+                    .format(attribute.format)
+                    .offset(attribute.offset)
+            })
+            .collect();
+
+        let vertex_input_info = vk::PipelineVertexInputStateCreateInfoBuilder::new()
+            .vertex_binding_descriptions(&vertex_bindings)
+            .vertex_attribute_descriptions(&vertex_attributes);
 
         let input_assembly_info = vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
             .topology(render_state.input_assembly.topology.to_vk())
@@ -126,44 +289,39 @@ impl Device {
         let rasterization_info = vk::PipelineRasterizationStateCreateInfoBuilder::new()
             .depth_clamp_enable(false)
             .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
+            .polygon_mode(if render_state.rasterization.wireframe {
+                vk::PolygonMode::LINE
+            } else {
+                vk::PolygonMode::FILL
+            })
             .cull_mode(if render_state.rasterization.culling {
                 vk::CullModeFlags::BACK
             } else {
                 vk::CullModeFlags::NONE
             })
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+            .front_face(render_state.rasterization.front_face.to_vk())
             .depth_bias_enable(render_state.depth.bias != 0.0)
             .depth_bias_constant_factor(render_state.depth.bias)
             .depth_bias_clamp(0.0)
             .depth_bias_slope_factor(0.0)
             .line_width(1.0);
 
-        let mut attachment_blend_states =
-            DynamicArray::<vk::PipelineColorBlendAttachmentStateBuilder, MAX_ATTACHMENTS>::new();
-
-        for _color_attachment in &program.graphics_state.attachments_format.attachment_formats {
-            let mut state = vk::PipelineColorBlendAttachmentStateBuilder::new()
-                .color_write_mask(
-                    vk::ColorComponentFlags::R
-                        | vk::ColorComponentFlags::G
-                        | vk::ColorComponentFlags::B
-                        | vk::ColorComponentFlags::A,
-                )
-                .blend_enable(render_state.alpha_blending);
-
-            if render_state.alpha_blending {
-                state = state
-                    .src_color_blend_factor(vk::BlendFactor::ONE)
-                    .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-                    .color_blend_op(vk::BlendOp::ADD)
-                    .src_alpha_blend_factor(vk::BlendFactor::ONE)
-                    .dst_alpha_blend_factor(vk::BlendFactor::ONE)
-                    .alpha_blend_op(vk::BlendOp::ADD);
-            }
-
-            attachment_blend_states.push(state);
-        }
+        let attachment_count = program
+            .graphics_state
+            .attachments_format
+            .attachment_formats
+            .len();
+        assert!(render_state.blend_states.len() <= attachment_count);
+        let attachment_blend_states: DynamicArray<_, MAX_ATTACHMENTS> = (0..attachment_count)
+            .map(|i_attachment| {
+                render_state
+                    .blend_states
+                    .get(i_attachment)
+                    .copied()
+                    .unwrap_or_default()
+                    .to_vk()
+            })
+            .collect();
 
         let color_blend_state_info = vk::PipelineColorBlendStateCreateInfoBuilder::new()
             .attachments(&attachment_blend_states)
@@ -175,15 +333,7 @@ impl Device {
             .viewport_count(1)
             .scissor_count(1);
 
-        let stencil_info = vk::StencilOpStateBuilder::new()
-            .fail_op(vk::StencilOp::KEEP)
-            .pass_op(vk::StencilOp::KEEP)
-            .compare_op(vk::CompareOp::ALWAYS)
-            .compare_mask(0)
-            .reference(0)
-            .depth_fail_op(vk::StencilOp::KEEP)
-            .write_mask(0);
-
+        let stencil = render_state.depth.stencil.unwrap_or_default();
         let depth_stencil_info = vk::PipelineDepthStencilStateCreateInfoBuilder::new()
             .depth_test_enable(render_state.depth.test.is_some())
             .depth_write_enable(render_state.depth.enable_write)
@@ -191,19 +341,19 @@ impl Device {
             .depth_bounds_test_enable(false)
             .min_depth_bounds(0.0)
             .max_depth_bounds(0.0)
-            .stencil_test_enable(false)
-            .back(*stencil_info)
-            .front(*stencil_info);
+            .stencil_test_enable(render_state.depth.stencil.is_some())
+            .front(stencil.front.to_vk())
+            .back(stencil.back.to_vk());
 
         let multisample_info = vk::PipelineMultisampleStateCreateInfoBuilder::new()
-            .rasterization_samples(vk::SampleCountFlagBits::_1);
+            .rasterization_samples(program.graphics_state.attachments_format.samples);
 
         let entrypoint = CString::new("main").unwrap();
         let module_name = &entrypoint;
         let mut shader_stages = DynamicArray::<vk::PipelineShaderStageCreateInfoBuilder, 3>::new();
 
-        if program.graphics_state.vertex_shader.is_valid() {
-            let shader = self.shaders.get(program.graphics_state.vertex_shader);
+        if variant.vertex_shader.is_valid() {
+            let shader = self.shaders.get(variant.vertex_shader);
             let shader_info = vk::PipelineShaderStageCreateInfoBuilder::new()
                 .stage(vk::ShaderStageFlagBits::VERTEX)
                 .module(shader.vkhandle)
@@ -211,8 +361,8 @@ impl Device {
             shader_stages.push(shader_info);
         }
 
-        if program.graphics_state.fragment_shader.is_valid() {
-            let shader = self.shaders.get(program.graphics_state.fragment_shader);
+        if variant.fragment_shader.is_valid() {
+            let shader = self.shaders.get(variant.fragment_shader);
             let shader_info = vk::PipelineShaderStageCreateInfoBuilder::new()
                 .stage(vk::ShaderStageFlagBits::FRAGMENT)
                 .module(shader.vkhandle)
@@ -220,7 +370,7 @@ impl Device {
             shader_stages.push(shader_info);
         }
 
-        let old_pipeline = program.pipelines[i_pipeline];
+        let old_pipeline = variant.pipelines[i_pipeline];
 
         let pipeline_info = vk::GraphicsPipelineCreateInfoBuilder::new()
             .layout(self.descriptors.pipeline_layout)
@@ -239,7 +389,7 @@ impl Device {
 
         let new_pipeline = unsafe {
             self.device
-                .create_graphics_pipelines(program.cache, &[pipeline_info], None)
+                .create_graphics_pipelines(self.pipeline_cache, &[pipeline_info], None)
                 .result()?[0]
         };
 
@@ -249,7 +399,7 @@ impl Device {
             }
         }
 
-        program.pipelines[i_pipeline] = new_pipeline;
+        program.variants[i_variant].pipelines[i_pipeline] = new_pipeline;
 
         Ok(())
     }
@@ -259,21 +409,274 @@ impl Device {
         program_handle: Handle<GraphicsProgram>,
         render_state: RenderState,
     ) -> VulkanResult<usize> {
+        if render_state.rasterization.wireframe && !self.fill_mode_non_solid {
+            return Err(VulkanError::MissingFeature {
+                name: "fillModeNonSolid",
+                purpose: "wireframe rasterization",
+            });
+        }
+
         let program = self.graphics_programs.get_mut(program_handle);
 
-        let index = program.pipelines.len();
-        program.pipelines.push(vk::Pipeline::null());
+        let index = program.render_states.len();
+        for variant in &mut program.variants {
+            variant.pipelines.push(vk::Pipeline::null());
+        }
         program.render_states.push(render_state);
-        self.compile_graphics_program_pipeline(program_handle, index)?;
+        if let Some((_i_variant, error)) = self
+            .compile_graphics_program_pipeline(program_handle, index)
+            .into_iter()
+            .next()
+        {
+            return Err(error);
+        }
         Ok(index)
     }
+
+    // Returns the index of the variant in the program, compiling its shaders and pipelines if needed.
+    // A variant that fails to compile is reported in `variant_errors` and the first variant is returned instead
+    pub fn get_or_compile_graphics_variant(
+        &mut self,
+        program_handle: Handle<GraphicsProgram>,
+        variant: ShaderVariant,
+    ) -> VulkanResult<usize> {
+        let program = self.graphics_programs.get(program_handle);
+        if let Some(i_variant) = program.variants.iter().position(|v| v.variant == variant) {
+            return Ok(i_variant);
+        }
+        if program.failed_variants.contains(&variant) {
+            return Ok(0);
+        }
+
+        match self.compile_graphics_variant(program_handle, variant) {
+            Ok(i_variant) => Ok(i_variant),
+            Err((_shader_handle, error)) if error.is_device_lost() => Err(error),
+            Err((shader_handle, error)) => {
+                let program = self.graphics_programs.get_mut(program_handle);
+                program.failed_variants.push(variant);
+                self.variant_errors.push((shader_handle, error));
+                Ok(0)
+            }
+        }
+    }
+
+    // Errors are returned with the base shader of the stage that failed
+    fn compile_graphics_variant(
+        &mut self,
+        program_handle: Handle<GraphicsProgram>,
+        variant: ShaderVariant,
+    ) -> Result<usize, (Handle<Shader>, VulkanError)> {
+        let program = self.graphics_programs.get(program_handle);
+        let features = program.graphics_state.features;
+        let base_vertex_shader = program.graphics_state.vertex_shader;
+        let base_fragment_shader = program.graphics_state.fragment_shader;
+        let pipeline_count = program.render_states.len();
+
+        let vertex_shader = if base_vertex_shader.is_valid() {
+            self.create_shader_variant(base_vertex_shader, features, variant)
+                .map_err(|error| (base_vertex_shader, error))?
+        } else {
+            Handle::invalid()
+        };
+        let fragment_shader = if base_fragment_shader.is_valid() {
+            match self.create_shader_variant(base_fragment_shader, features, variant) {
+                Ok(shader_handle) => shader_handle,
+                Err(error) => {
+                    if vertex_shader.is_valid() {
+                        self.destroy_shader(vertex_shader);
+                    }
+                    return Err((base_fragment_shader, error));
+                }
+            }
+        } else {
+            Handle::invalid()
+        };
+
+        let program = self.graphics_programs.get_mut(program_handle);
+        let i_variant = program.variants.len();
+        program.variants.push(GraphicsVariant {
+            variant,
+            vertex_shader,
+            fragment_shader,
+            pipelines: (0..pipeline_count).map(|_| vk::Pipeline::null()).collect(),
+        });
+
+        let compiled = (0..pipeline_count).try_for_each(|i_pipeline| {
+            self.compile_graphics_variant_pipeline(program_handle, i_variant, i_pipeline)
+        });
+
+        if let Err(error) = compiled {
+            let variant = self
+                .graphics_programs
+                .get_mut(program_handle)
+                .variants
+                .pop()
+                .unwrap();
+            for pipeline in &variant.pipelines {
+                if !pipeline.is_null() {
+                    unsafe {
+                        self.device.destroy_pipeline(*pipeline, None);
+                    }
+                }
+            }
+            for shader_handle in [variant.vertex_shader, variant.fragment_shader] {
+                if shader_handle.is_valid() {
+                    self.destroy_shader(shader_handle);
+                }
+            }
+            let stage_shader = if base_vertex_shader.is_valid() {
+                base_vertex_shader
+            } else {
+                base_fragment_shader
+            };
+            return Err((stage_shader, error));
+        }
+
+        Ok(i_variant)
+    }
+}
+
+impl Default for RenderState {
+    fn default() -> Self {
+        Self {
+            depth: DepthState {
+                test: None,
+                enable_write: false,
+                bias: 0.0,
+                stencil: None,
+            },
+            rasterization: RasterizationState {
+                enable_conservative_rasterization: false,
+                culling: false,
+                front_face: FrontFace::CounterClockwise,
+                wireframe: false,
+            },
+            input_assembly: InputAssemblyState {
+                topology: PrimitiveTopology::TriangleList,
+            },
+            vertex_input: VertexInputState::default(),
+            blend_states: DynamicArray::new(),
+        }
+    }
 }
 
 impl PrimitiveTopology {
     pub fn to_vk(self) -> vk::PrimitiveTopology {
         match self {
             PrimitiveTopology::TriangleList => vk::PrimitiveTopology::TRIANGLE_LIST,
+            PrimitiveTopology::TriangleStrip => vk::PrimitiveTopology::TRIANGLE_STRIP,
+            PrimitiveTopology::LineList => vk::PrimitiveTopology::LINE_LIST,
+            PrimitiveTopology::LineStrip => vk::PrimitiveTopology::LINE_STRIP,
             PrimitiveTopology::PointList => vk::PrimitiveTopology::POINT_LIST,
         }
     }
 }
+
+impl FrontFace {
+    pub fn to_vk(self) -> vk::FrontFace {
+        match self {
+            FrontFace::CounterClockwise => vk::FrontFace::COUNTER_CLOCKWISE,
+            FrontFace::Clockwise => vk::FrontFace::CLOCKWISE,
+        }
+    }
+}
+
+impl Default for StencilOpState {
+    fn default() -> Self {
+        Self {
+            fail_op: vk::StencilOp::KEEP,
+            pass_op: vk::StencilOp::KEEP,
+            depth_fail_op: vk::StencilOp::KEEP,
+            compare_op: vk::CompareOp::ALWAYS,
+            compare_mask: 0,
+            write_mask: 0,
+            reference: 0,
+        }
+    }
+}
+
+impl StencilOpState {
+    pub fn to_vk(self) -> vk::StencilOpState {
+        *vk::StencilOpStateBuilder::new()
+            .fail_op(self.fail_op)
+            .pass_op(self.pass_op)
+            .depth_fail_op(self.depth_fail_op)
+            .compare_op(self.compare_op)
+            .compare_mask(self.compare_mask)
+            .write_mask(self.write_mask)
+            .reference(self.reference)
+    }
+}
+
+impl BlendState {
+    // Colors are expected to be multiplied by their alpha already
+    pub fn premultiplied_alpha() -> Self {
+        Self {
+            enable: true,
+            src_color_factor: vk::BlendFactor::ONE,
+            dst_color_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_op: vk::BlendOp::ADD,
+            src_alpha_factor: vk::BlendFactor::ONE,
+            dst_alpha_factor: vk::BlendFactor::ONE,
+            alpha_op: vk::BlendOp::ADD,
+            ..Self::default()
+        }
+    }
+
+    pub fn alpha() -> Self {
+        Self {
+            enable: true,
+            src_color_factor: vk::BlendFactor::SRC_ALPHA,
+            dst_color_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            color_op: vk::BlendOp::ADD,
+            src_alpha_factor: vk::BlendFactor::ONE,
+            dst_alpha_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+            alpha_op: vk::BlendOp::ADD,
+            ..Self::default()
+        }
+    }
+
+    pub fn additive() -> Self {
+        Self {
+            enable: true,
+            src_color_factor: vk::BlendFactor::ONE,
+            dst_color_factor: vk::BlendFactor::ONE,
+            color_op: vk::BlendOp::ADD,
+            src_alpha_factor: vk::BlendFactor::ONE,
+            dst_alpha_factor: vk::BlendFactor::ONE,
+            alpha_op: vk::BlendOp::ADD,
+            ..Self::default()
+        }
+    }
+
+    pub fn to_vk(self) -> vk::PipelineColorBlendAttachmentStateBuilder<'static> {
+        vk::PipelineColorBlendAttachmentStateBuilder::new()
+            .blend_enable(self.enable)
+            .src_color_blend_factor(self.src_color_factor)
+            .dst_color_blend_factor(self.dst_color_factor)
+            .color_blend_op(self.color_op)
+            .src_alpha_blend_factor(self.src_alpha_factor)
+            .dst_alpha_blend_factor(self.dst_alpha_factor)
+            .alpha_blend_op(self.alpha_op)
+            .color_write_mask(self.write_mask)
+    }
+}
+
+// Opaque, the attachment is overwritten
+impl Default for BlendState {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            src_color_factor: vk::BlendFactor::ONE,
+            dst_color_factor: vk::BlendFactor::ZERO,
+            color_op: vk::BlendOp::ADD,
+            src_alpha_factor: vk::BlendFactor::ONE,
+            dst_alpha_factor: vk::BlendFactor::ZERO,
+            alpha_op: vk::BlendOp::ADD,
+            write_mask: vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        }
+    }
+}
@@ -6,7 +6,7 @@ use exo::pool::Handle;
 
 use erupt::vk;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ImageState {
     Null,
     GraphicsShaderRead,
@@ -32,6 +32,7 @@ pub struct ImageSpec {
     pub name: String,
     pub size: [i32; 3],
     pub mip_levels: u32,
+    pub array_layers: u32,
     pub image_type: vk::ImageType,
     pub format: vk::Format,
     pub samples: vk::SampleCountFlagBits,
@@ -44,6 +45,7 @@ impl Default for ImageSpec {
             name: String::from("unnamed"),
             size: [1, 1, 1],
             mip_levels: 1,
+            array_layers: 1,
             image_type: vk::ImageType::_2D,
             format: vk::Format::R8G8B8A8_UNORM,
             samples: vk::SampleCountFlagBits::_1,
@@ -70,10 +72,89 @@ pub struct Image {
     pub memory_block: Option<memory::Allocation>,
     pub spec: ImageSpec,
     pub full_view: ImageView,
-    pub state: ImageState,
+    // One view per level, only for images with several levels
+    pub mip_views: Vec<ImageView>,
+    pub states: SubresourceStates,
     is_proxy: bool,
 }
 
+// Range of mip levels and array layers of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubresourceRange {
+    pub base_mip_level: u32,
+    pub level_count: u32,
+    pub base_array_layer: u32,
+    pub layer_count: u32,
+}
+
+// State of every mip level of every array layer
+#[derive(Debug)]
+pub struct SubresourceStates {
+    mip_levels: u32,
+    array_layers: u32,
+    states: Vec<ImageState>,
+}
+
+impl Image {
+    // `None` is the view of all the levels
+    pub fn view(&self, mip_level: Option<u32>) -> &ImageView {
+        match mip_level {
+            Some(level) if !self.mip_views.is_empty() => &self.mip_views[level as usize],
+            Some(level) => {
+                assert!(level == 0);
+                &self.full_view
+            }
+            None => &self.full_view,
+        }
+    }
+
+    pub fn mip_size(&self, level: u32) -> [i32; 3] {
+        mip_size(self.spec.size, level)
+    }
+
+    pub fn full_range(&self) -> SubresourceRange {
+        SubresourceRange {
+            base_mip_level: 0,
+            level_count: self.spec.mip_levels,
+            base_array_layer: 0,
+            layer_count: self.spec.array_layers,
+        }
+    }
+
+    // All the layers of one level
+    pub fn mip_range(&self, level: u32) -> SubresourceRange {
+        SubresourceRange {
+            base_mip_level: level,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: self.spec.array_layers,
+        }
+    }
+
+    pub fn vk_range(&self, range: SubresourceRange) -> vk::ImageSubresourceRange {
+        *vk::ImageSubresourceRangeBuilder::new()
+            .aspect_mask(self.full_view.range.aspect_mask)
+            .base_mip_level(range.base_mip_level)
+            .level_count(range.level_count)
+            .base_array_layer(range.base_array_layer)
+            .layer_count(range.layer_count)
+    }
+}
+
+// Number of levels of a full mip chain, down to 1x1x1
+pub fn mip_count(size: [i32; 3]) -> u32 {
+    let max_side = size[0].max(size[1]).max(size[2]).max(1) as u32;
+    32 - max_side.leading_zeros()
+}
+
+pub fn mip_size(size: [i32; 3], level: u32) -> [i32; 3] {
+    [
+        (size[0] >> level).max(1),
+        (size[1] >> level).max(1),
+        (size[2] >> level).max(1),
+    ]
+}
+
 impl Device {
     fn create_image_view(
         &mut self,
@@ -101,6 +182,12 @@ impl Device {
     }
 
     pub fn create_image(&mut self, spec: ImageSpec) -> VulkanResult<Handle<Image>> {
+        // Sampled views need a single aspect, the full view of a depth stencil image has both
+        assert!(
+            !(has_stencil_aspect(spec.format)
+                && spec.usages.contains(vk::ImageUsageFlags::SAMPLED)),
+            "depth stencil images can't be sampled"
+        );
         let image_create_info = vk::ImageCreateInfoBuilder::new()
             .image_type(spec.image_type)
             .format(spec.format)
@@ -110,7 +197,7 @@ impl Device {
                 depth: spec.size[2] as u32,
             })
             .mip_levels(spec.mip_levels)
-            .array_layers(1)
+            .array_layers(spec.array_layers)
             .samples(spec.samples)
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .usage(spec.usages)
@@ -138,27 +225,32 @@ impl Device {
         }
         .result()?;
 
-        let is_depth = spec.format == vk::Format::D32_SFLOAT;
         let full_range = vk::ImageSubresourceRangeBuilder::new()
-            .aspect_mask(if is_depth {
-                vk::ImageAspectFlags::DEPTH
-            } else {
-                vk::ImageAspectFlags::COLOR
-            })
+            .aspect_mask(format_aspect(spec.format))
             .base_mip_level(0)
             .level_count(image_create_info.mip_levels)
             .base_array_layer(0)
             .layer_count(image_create_info.array_layers);
 
-        let full_view_type = match spec.image_type {
-            vk::ImageType::_1D => vk::ImageViewType::_1D,
-            vk::ImageType::_2D => vk::ImageViewType::_2D,
-            vk::ImageType::_3D => vk::ImageViewType::_3D,
-            _ => unreachable!(),
-        };
+        let full_view_type = spec.view_type();
         let full_view =
             self.create_image_view(vkimage, *full_range, spec.format, full_view_type)?;
 
+        let mut mip_views = Vec::new();
+        if spec.mip_levels > 1 {
+            for level in 0..spec.mip_levels {
+                let mip_range = full_range.base_mip_level(level).level_count(1);
+                let mip_view =
+                    self.create_image_view(vkimage, *mip_range, spec.format, full_view_type)?;
+                self.set_vk_name(
+                    mip_view.vkhandle.0,
+                    vk::ObjectType::IMAGE_VIEW,
+                    &format!("{} mip {}", spec.name, level),
+                )?;
+                mip_views.push(mip_view);
+            }
+        }
+
         self.set_vk_name(vkimage.0, vk::ObjectType::IMAGE, &spec.name)?;
         self.set_vk_name(full_view.vkhandle.0, vk::ObjectType::IMAGE_VIEW, &spec.name)?;
         self.set_vk_name(
@@ -167,26 +259,33 @@ impl Device {
             &spec.name,
         )?;
 
+        let states = SubresourceStates::new(spec.mip_levels, spec.array_layers);
         let image_handle = self.images.add(Image {
             vkhandle: vkimage,
             memory_block: Some(memory_block),
             spec,
             full_view,
-            state: ImageState::Null,
+            mip_views,
+            states,
             is_proxy: false,
         });
 
         let image_mut = self.images.get_mut(image_handle);
-        image_mut.full_view.sampled_idx = self
-            .descriptors
-            .bindless_set
-            .bind_sampler_image(image_handle) as u32;
-
-        if image_mut.spec.usages.contains(vk::ImageUsageFlags::STORAGE) {
-            image_mut.full_view.storage_idx =
-                self.descriptors
-                    .bindless_set
-                    .bind_storage_image(image_handle) as u32;
+        let bindless_set = &mut self.descriptors.bindless_set;
+        let mip_levels = image_mut.mip_views.len() as u32;
+        let views = std::iter::once((None, &mut image_mut.full_view)).chain(
+            (0..mip_levels)
+                .map(Some)
+                .zip(image_mut.mip_views.iter_mut()),
+        );
+        for (mip_level, view) in views {
+            if image_mut.spec.usages.contains(vk::ImageUsageFlags::SAMPLED) {
+                view.sampled_idx = bindless_set.bind_sampler_image(image_handle, mip_level) as u32;
+            }
+
+            if image_mut.spec.usages.contains(vk::ImageUsageFlags::STORAGE) {
+                view.storage_idx = bindless_set.bind_storage_image(image_handle, mip_level) as u32;
+            }
         }
 
         Ok(image_handle)
@@ -197,35 +296,27 @@ impl Device {
         spec: ImageSpec,
         proxy: vk::Image,
     ) -> VulkanResult<Handle<Image>> {
-        let is_depth = spec.format == vk::Format::D32_SFLOAT;
         let full_range = vk::ImageSubresourceRangeBuilder::new()
-            .aspect_mask(if is_depth {
-                vk::ImageAspectFlags::DEPTH
-            } else {
-                vk::ImageAspectFlags::COLOR
-            })
+            .aspect_mask(format_aspect(spec.format))
             .base_mip_level(0)
             .level_count(spec.mip_levels)
             .base_array_layer(0)
-            .layer_count(1);
+            .layer_count(spec.array_layers);
 
-        let full_view_type = match spec.image_type {
-            vk::ImageType::_1D => vk::ImageViewType::_1D,
-            vk::ImageType::_2D => vk::ImageViewType::_2D,
-            vk::ImageType::_3D => vk::ImageViewType::_3D,
-            _ => unreachable!(),
-        };
+        let full_view_type = spec.view_type();
         let full_view = self.create_image_view(proxy, *full_range, spec.format, full_view_type)?;
 
         self.set_vk_name(proxy.0, vk::ObjectType::IMAGE, &spec.name)?;
         self.set_vk_name(full_view.vkhandle.0, vk::ObjectType::IMAGE_VIEW, &spec.name)?;
 
+        let states = SubresourceStates::new(spec.mip_levels, spec.array_layers);
         let res = self.images.add(Image {
             vkhandle: proxy,
             memory_block: None,
             spec,
             full_view,
-            state: ImageState::Null,
+            mip_views: Vec::new(),
+            states,
             is_proxy: true,
         });
 
@@ -234,19 +325,18 @@ impl Device {
 
     pub fn unbind_image(&mut self, image_handle: Handle<Image>) {
         let image = self.images.get_mut(image_handle);
+        let bindless_set = &mut self.descriptors.bindless_set;
 
-        if image.full_view.sampled_idx > 0 {
-            self.descriptors
-                .bindless_set
-                .unbind_sampler_image(image.full_view.sampled_idx as usize);
-            image.full_view.sampled_idx = 0;
-        }
+        for view in std::iter::once(&mut image.full_view).chain(image.mip_views.iter_mut()) {
+            if view.sampled_idx > 0 {
+                bindless_set.unbind_sampler_image(view.sampled_idx as usize);
+                view.sampled_idx = 0;
+            }
 
-        if image.full_view.storage_idx > 0 {
-            self.descriptors
-                .bindless_set
-                .unbind_storage_image(image.full_view.storage_idx as usize);
-            image.full_view.storage_idx = 0;
+            if view.storage_idx > 0 {
+                bindless_set.unbind_storage_image(view.storage_idx as usize);
+                view.storage_idx = 0;
+            }
         }
     }
 
@@ -270,11 +360,73 @@ impl Device {
         unsafe {
             self.device
                 .destroy_image_view(image.full_view.vkhandle, None);
+            for mip_view in &image.mip_views {
+                self.device.destroy_image_view(mip_view.vkhandle, None);
+            }
         }
         self.images.remove(image_handle);
     }
 }
 
+impl ImageSpec {
+    pub fn view_type(&self) -> vk::ImageViewType {
+        let is_array = self.array_layers > 1;
+        match self.image_type {
+            vk::ImageType::_1D if is_array => vk::ImageViewType::_1D_ARRAY,
+            vk::ImageType::_1D => vk::ImageViewType::_1D,
+            vk::ImageType::_2D if is_array => vk::ImageViewType::_2D_ARRAY,
+            vk::ImageType::_2D => vk::ImageViewType::_2D,
+            vk::ImageType::_3D => vk::ImageViewType::_3D,
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub fn is_depth_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D16_UNORM | vk::Format::X8_D24_UNORM_PACK32 | vk::Format::D32_SFLOAT
+    ) || has_stencil_aspect(format)
+}
+
+pub fn has_stencil_aspect(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::D24_UNORM_S8_UINT | vk::Format::D32_SFLOAT_S8_UINT
+    )
+}
+
+// Aspects of the full view of an image, barriers and attachments cover both aspects of depth stencil formats
+pub fn format_aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    if has_stencil_aspect(format) {
+        vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+    } else if is_depth_format(format) {
+        vk::ImageAspectFlags::DEPTH
+    } else {
+        vk::ImageAspectFlags::COLOR
+    }
+}
+
+// Size in bytes of a texel, only uncompressed formats are supported
+pub fn format_texel_size(format: vk::Format) -> usize {
+    match format {
+        vk::Format::R8_UNORM | vk::Format::R8_SRGB => 1,
+        vk::Format::R8G8_UNORM | vk::Format::R16_SFLOAT | vk::Format::D16_UNORM => 2,
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::R32_SFLOAT
+        | vk::Format::R32_UINT
+        | vk::Format::D32_SFLOAT => 4,
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R32G32_SFLOAT => 8,
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        _ => panic!("unsupported format {:?}", format),
+    }
+}
+
 impl ImageState {
     pub fn get_src_access(self) -> ImageAccess {
         let (stage, access, layout) = match self {
@@ -322,7 +474,7 @@ impl ImageState {
             Self::DepthAttachment => (
                 vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
                 vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
             ),
 
             Self::Present => (
@@ -387,7 +539,7 @@ impl ImageState {
                     | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
                 vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
                     | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ,
-                vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
             ),
 
             Self::Present => (
@@ -404,3 +556,258 @@ impl ImageState {
         }
     }
 }
+
+impl ImageState {
+    pub fn is_write(self) -> bool {
+        let write_accesses = vk::AccessFlags::SHADER_WRITE
+            | vk::AccessFlags::TRANSFER_WRITE
+            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE;
+        self.get_dst_access().access.intersects(write_accesses)
+    }
+}
+
+impl SubresourceStates {
+    pub fn new(mip_levels: u32, array_layers: u32) -> Self {
+        Self {
+            mip_levels,
+            array_layers,
+            states: vec![ImageState::Null; (mip_levels * array_layers) as usize],
+        }
+    }
+
+    pub fn get(&self, mip_level: u32, array_layer: u32) -> ImageState {
+        self.states[self.index(mip_level, array_layer)]
+    }
+
+    // The state of the whole image, if all its subresources are in the same state
+    pub fn uniform_state(&self) -> Option<ImageState> {
+        let first = self.states[0];
+        if self.states.iter().all(|state| *state == first) {
+            Some(first)
+        } else {
+            None
+        }
+    }
+
+    // Ranges of subresources sharing a state, as large as possible
+    pub fn ranges(&self) -> Vec<(SubresourceRange, ImageState)> {
+        self.collect_ranges(self.full_range(), Some)
+    }
+
+    // Moves a range to `state_dst` and returns the ranges that need a barrier with their previous state.
+    // Subresources already in `state_dst` are skipped unless the state writes.
+    pub fn transition(
+        &mut self,
+        range: SubresourceRange,
+        state_dst: ImageState,
+    ) -> Vec<(SubresourceRange, ImageState)> {
+        assert!(range.base_mip_level + range.level_count <= self.mip_levels);
+        assert!(range.base_array_layer + range.layer_count <= self.array_layers);
+
+        let transitions = self.collect_ranges(range, |state_src| {
+            if state_src != state_dst || state_dst.is_write() {
+                Some(state_src)
+            } else {
+                None
+            }
+        });
+
+        for array_layer in range.base_array_layer..range.base_array_layer + range.layer_count {
+            for mip_level in range.base_mip_level..range.base_mip_level + range.level_count {
+                let i_state = self.index(mip_level, array_layer);
+                self.states[i_state] = state_dst;
+            }
+        }
+
+        transitions
+    }
+
+    fn full_range(&self) -> SubresourceRange {
+        SubresourceRange {
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: self.array_layers,
+        }
+    }
+
+    fn index(&self, mip_level: u32, array_layer: u32) -> usize {
+        assert!(mip_level < self.mip_levels && array_layer < self.array_layers);
+        (array_layer * self.mip_levels + mip_level) as usize
+    }
+
+    // Merges consecutive mip levels of a layer with the same key, then identical runs of consecutive layers
+    fn collect_ranges(
+        &self,
+        range: SubresourceRange,
+        key: impl Fn(ImageState) -> Option<ImageState>,
+    ) -> Vec<(SubresourceRange, ImageState)> {
+        let mut ranges: Vec<(SubresourceRange, ImageState)> = Vec::new();
+        // Ranges extended by the previous layer
+        let mut open_ranges = 0..0;
+
+        for array_layer in range.base_array_layer..range.base_array_layer + range.layer_count {
+            let mut layer_runs: Vec<(u32, u32, ImageState)> = Vec::new();
+            for mip_level in range.base_mip_level..range.base_mip_level + range.level_count {
+                let state = match key(self.get(mip_level, array_layer)) {
+                    Some(state) => state,
+                    None => continue,
+                };
+                match layer_runs.last_mut() {
+                    Some((base, count, run_state))
+                        if *base + *count == mip_level && *run_state == state =>
+                    {
+                        *count += 1
+                    }
+                    _ => layer_runs.push((mip_level, 1, state)),
+                }
+            }
+
+            let extends_previous_layer = open_ranges.len() == layer_runs.len()
+                && ranges[open_ranges.clone()].iter().zip(&layer_runs).all(
+                    |((open_range, open_state), (base, count, state))| {
+                        open_range.base_mip_level == *base
+                            && open_range.level_count == *count
+                            && open_state == state
+                    },
+                );
+
+            if extends_previous_layer {
+                for (open_range, _) in &mut ranges[open_ranges.clone()] {
+                    open_range.layer_count += 1;
+                }
+            } else {
+                let start = ranges.len();
+                ranges.extend(layer_runs.iter().map(|(base, count, state)| {
+                    (
+                        SubresourceRange {
+                            base_mip_level: *base,
+                            level_count: *count,
+                            base_array_layer: array_layer,
+                            layer_count: 1,
+                        },
+                        *state,
+                    )
+                }));
+                open_ranges = start..ranges.len();
+            }
+        }
+
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(mips: std::ops::Range<u32>, layers: std::ops::Range<u32>) -> SubresourceRange {
+        SubresourceRange {
+            base_mip_level: mips.start,
+            level_count: mips.end - mips.start,
+            base_array_layer: layers.start,
+            layer_count: layers.end - layers.start,
+        }
+    }
+
+    #[test]
+    fn depth_formats() {
+        assert!(is_depth_format(vk::Format::D32_SFLOAT));
+        assert!(is_depth_format(vk::Format::D16_UNORM));
+        assert!(!is_depth_format(vk::Format::R32_SFLOAT));
+        assert!(!is_depth_format(vk::Format::R8G8B8A8_UNORM));
+        assert!(is_depth_format(vk::Format::D24_UNORM_S8_UINT));
+
+        assert_eq!(
+            format_aspect(vk::Format::D32_SFLOAT_S8_UINT),
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        );
+        assert_eq!(
+            format_aspect(vk::Format::D32_SFLOAT),
+            vk::ImageAspectFlags::DEPTH
+        );
+        assert_eq!(
+            format_aspect(vk::Format::R8G8B8A8_UNORM),
+            vk::ImageAspectFlags::COLOR
+        );
+    }
+
+    #[test]
+    fn whole_image_transition() {
+        let mut states = SubresourceStates::new(4, 2);
+        assert_eq!(states.uniform_state(), Some(ImageState::Null));
+
+        let transitions = states.transition(range(0..4, 0..2), ImageState::TransferDst);
+        assert_eq!(transitions, vec![(range(0..4, 0..2), ImageState::Null)]);
+        assert_eq!(states.uniform_state(), Some(ImageState::TransferDst));
+    }
+
+    #[test]
+    fn read_after_read_is_skipped() {
+        let mut states = SubresourceStates::new(1, 1);
+        states.transition(range(0..1, 0..1), ImageState::GraphicsShaderRead);
+        assert!(states
+            .transition(range(0..1, 0..1), ImageState::GraphicsShaderRead)
+            .is_empty());
+    }
+
+    #[test]
+    fn write_after_write_needs_a_barrier() {
+        let mut states = SubresourceStates::new(1, 1);
+        states.transition(range(0..1, 0..1), ImageState::ComputeShaderReadWrite);
+        assert_eq!(
+            states.transition(range(0..1, 0..1), ImageState::ComputeShaderReadWrite),
+            vec![(range(0..1, 0..1), ImageState::ComputeShaderReadWrite)]
+        );
+    }
+
+    #[test]
+    fn mip_chain_downsample() {
+        // Write mip N while reading mip N-1
+        let mut states = SubresourceStates::new(4, 1);
+        states.transition(range(0..4, 0..1), ImageState::TransferDst);
+
+        for level in 1..4 {
+            let transitions =
+                states.transition(range(level - 1..level, 0..1), ImageState::TransferSrc);
+            assert_eq!(
+                transitions,
+                vec![(range(level - 1..level, 0..1), ImageState::TransferDst)]
+            );
+            assert_eq!(states.get(level - 1, 0), ImageState::TransferSrc);
+            assert_eq!(states.get(level, 0), ImageState::TransferDst);
+            assert_eq!(states.uniform_state(), None);
+        }
+
+        // Only the last level changes, the others are already in the right state
+        let transitions = states.transition(range(0..4, 0..1), ImageState::TransferSrc);
+        assert_eq!(
+            transitions,
+            vec![(range(3..4, 0..1), ImageState::TransferDst)]
+        );
+        assert_eq!(states.uniform_state(), Some(ImageState::TransferSrc));
+    }
+
+    #[test]
+    fn ranges_are_merged_across_layers() {
+        let mut states = SubresourceStates::new(3, 4);
+        states.transition(range(0..3, 0..4), ImageState::GraphicsShaderRead);
+        states.transition(range(1..3, 1..3), ImageState::ColorAttachment);
+
+        let ranges = states.ranges();
+        assert_eq!(
+            ranges,
+            vec![
+                (range(0..3, 0..1), ImageState::GraphicsShaderRead),
+                (range(0..1, 1..3), ImageState::GraphicsShaderRead),
+                (range(1..3, 1..3), ImageState::ColorAttachment),
+                (range(0..3, 3..4), ImageState::GraphicsShaderRead),
+            ]
+        );
+
+        // Every subresource changes state, one barrier per range
+        let transitions = states.transition(range(0..3, 0..4), ImageState::TransferSrc);
+        assert_eq!(transitions, ranges);
+    }
+}
@@ -2,8 +2,10 @@ use exo::dynamic_array::DynamicArray;
 
 use super::error::*;
 use super::physical_device::*;
+use super::validation::*;
 
 use erupt::{cstr, vk, EntryLoader, InstanceLoader};
+use raw_window_handle::RawWindowHandle;
 use std::{
     ffi::{c_void, CStr},
     os::raw::c_char,
@@ -12,8 +14,10 @@ use std::{
 const LAYER_KHRONOS_VALIDATION: *const c_char = cstr!("VK_LAYER_KHRONOS_validation");
 const VK_KHR_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_surface");
 const VK_KHR_WIN32_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_win32_surface");
+const VK_KHR_XLIB_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_xlib_surface");
 const VK_KHR_XCB_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_xcb_surface");
 const VK_KHR_WAYLAND_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_KHR_wayland_surface");
+const VK_EXT_HEADLESS_SURFACE_EXTENSION_NAME: *const c_char = cstr!("VK_EXT_headless_surface");
 const VK_EXT_DEBUG_UTILS_EXTENSION_NAME: *const c_char = cstr!("VK_EXT_debug_utils");
 
 pub const MAX_PHYSICAL_DEVICES: usize = 4;
@@ -21,6 +25,16 @@ pub const MAX_PHYSICAL_DEVICES: usize = 4;
 pub struct InstanceSpec {
     pub enable_validation: bool,
     pub enable_graphic_windows: bool,
+    // Selects the surface extension of the window system,
+    // every window system supported by the loader is enabled without it
+    pub window_handle: Option<RawWindowHandle>,
+    // Swapchains without a window, to run the presentation path in CI
+    pub enable_headless_surface: bool,
+    // Validation messages are printed to stderr without a logger
+    pub validation_logger: Option<ValidationLogger>,
+    pub min_validation_severity: ValidationSeverity,
+    // Makes API misuse fail tests instead of scrolling by, the errors are checked by `Device::submit`
+    pub panic_on_validation_error: bool,
 }
 
 impl Default for InstanceSpec {
@@ -28,6 +42,11 @@ impl Default for InstanceSpec {
         InstanceSpec {
             enable_validation: true,
             enable_graphic_windows: true,
+            window_handle: None,
+            enable_headless_surface: false,
+            validation_logger: None,
+            min_validation_severity: ValidationSeverity::Warning,
+            panic_on_validation_error: false,
         }
     }
 }
@@ -36,46 +55,103 @@ pub struct Instance {
     pub instance: Box<InstanceLoader>,
     pub entry: Box<EntryLoader>,
     pub messenger: vk::DebugUtilsMessengerEXT,
+    // Headless instances can't create surfaces, devices are created without swapchain support
+    pub graphic_windows_enabled: bool,
+    pub headless_surface_enabled: bool,
+    // The window systems surfaces can be created for
+    pub surface_extensions: DynamicArray<*const c_char, 4>,
+    // Has to outlive the messenger
+    validation_state: Box<ValidationState>,
 }
 
-unsafe extern "system" fn debug_callback(
-    message_severity: vk::DebugUtilsMessageSeverityFlagBitsEXT,
-    _message_types: vk::DebugUtilsMessageTypeFlagsEXT,
-    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
-) -> vk::Bool32 {
-    match message_severity {
-        vk::DebugUtilsMessageSeverityFlagBitsEXT::WARNING_EXT => {
-            eprintln!(
-                "Warning: {}",
-                CStr::from_ptr((*p_callback_data).p_message).to_string_lossy()
-            );
-        }
-        vk::DebugUtilsMessageSeverityFlagBitsEXT::ERROR_EXT => {
-            eprintln!(
-                "Error: {}",
-                CStr::from_ptr((*p_callback_data).p_message).to_string_lossy()
-            );
-        }
-        _ => {}
+// The extension needed to create a surface for a window
+fn window_surface_extension(window_handle: &RawWindowHandle) -> VulkanResult<*const c_char> {
+    match window_handle {
+        RawWindowHandle::Win32(_) => Ok(VK_KHR_WIN32_SURFACE_EXTENSION_NAME),
+        RawWindowHandle::Xlib(_) => Ok(VK_KHR_XLIB_SURFACE_EXTENSION_NAME),
+        RawWindowHandle::Xcb(_) => Ok(VK_KHR_XCB_SURFACE_EXTENSION_NAME),
+        RawWindowHandle::Wayland(_) => Ok(VK_KHR_WAYLAND_SURFACE_EXTENSION_NAME),
+        other => Err(VulkanError::UnsupportedWindowSystem(format!("{:?}", other))),
     }
+}
+
+fn extension_name(extension: *const c_char) -> String {
+    unsafe { CStr::from_ptr(extension) }
+        .to_string_lossy()
+        .into_owned()
+}
 
-    vk::FALSE
+fn contains_extension(extensions: &[*const c_char], extension: *const c_char) -> bool {
+    let extension = unsafe { CStr::from_ptr(extension) };
+    extensions
+        .iter()
+        .any(|other| unsafe { CStr::from_ptr(*other) } == extension)
 }
 
 impl Instance {
     pub fn new(spec: InstanceSpec) -> VulkanResult<Instance> {
-        let entry = Box::new(EntryLoader::new().unwrap());
+        let entry =
+            EntryLoader::new().map_err(|error| VulkanError::LoaderMissing(error.to_string()))?;
+        let entry = Box::new(entry);
 
-        let mut instance_extensions = DynamicArray::<_, 8>::new();
+        let extension_properties =
+            unsafe { entry.enumerate_instance_extension_properties(None, None) }.result()?;
+        let supported_extensions: Vec<*const c_char> = extension_properties
+            .iter()
+            .map(|properties| &properties.extension_name as *const c_char)
+            .collect();
+        let require_extension = |extension: *const c_char, purpose: &'static str| {
+            if contains_extension(&supported_extensions, extension) {
+                Ok(extension)
+            } else {
+                Err(VulkanError::MissingExtension {
+                    name: extension_name(extension),
+                    purpose,
+                })
+            }
+        };
+
+        let mut surface_extensions = DynamicArray::<_, 4>::new();
         if spec.enable_graphic_windows {
-            instance_extensions.push(VK_KHR_SURFACE_EXTENSION_NAME);
-            if cfg!(windows) {
-                instance_extensions.push(VK_KHR_WIN32_SURFACE_EXTENSION_NAME);
-            } else if cfg!(unix) {
-                instance_extensions.push(VK_KHR_WAYLAND_SURFACE_EXTENSION_NAME);
+            if let Some(window_handle) = &spec.window_handle {
+                surface_extensions.push(require_extension(
+                    window_surface_extension(window_handle)?,
+                    "creating a surface for the window",
+                )?);
+            } else {
+                let platform_extensions: &[*const c_char] = if cfg!(windows) {
+                    &[VK_KHR_WIN32_SURFACE_EXTENSION_NAME]
+                } else {
+                    &[
+                        VK_KHR_XLIB_SURFACE_EXTENSION_NAME,
+                        VK_KHR_XCB_SURFACE_EXTENSION_NAME,
+                        VK_KHR_WAYLAND_SURFACE_EXTENSION_NAME,
+                    ]
+                };
+                for extension in platform_extensions {
+                    if contains_extension(&supported_extensions, *extension) {
+                        surface_extensions.push(*extension);
+                    }
+                }
             }
         }
+
+        let mut instance_extensions = DynamicArray::<_, 8>::new();
+        if spec.enable_graphic_windows || spec.enable_headless_surface {
+            instance_extensions.push(require_extension(
+                VK_KHR_SURFACE_EXTENSION_NAME,
+                "presenting to a surface",
+            )?);
+        }
+        for extension in &surface_extensions {
+            instance_extensions.push(*extension);
+        }
+        if spec.enable_headless_surface {
+            instance_extensions.push(require_extension(
+                VK_EXT_HEADLESS_SURFACE_EXTENSION_NAME,
+                "creating a surface without a window",
+            )?);
+        }
         instance_extensions.push(VK_EXT_DEBUG_UTILS_EXTENSION_NAME);
 
         let installed_layers =
@@ -103,19 +179,25 @@ impl Instance {
             .enabled_layer_names(&instance_layers)
             .enabled_extension_names(&instance_extensions);
 
-        let instance = Box::new(unsafe { InstanceLoader::new(&entry, &instance_info).unwrap() });
+        let instance = Box::new(unsafe { InstanceLoader::new(&entry, &instance_info) }?);
+
+        let validation_state = Box::new(ValidationState {
+            logger: spec.validation_logger,
+            errors: spec
+                .panic_on_validation_error
+                .then(ValidationErrors::default),
+        });
 
         let messenger = if validation_enabled {
             let messenger_info = vk::DebugUtilsMessengerCreateInfoEXTBuilder::new()
-                .message_severity(
-                    vk::DebugUtilsMessageSeverityFlagsEXT::WARNING_EXT
-                        | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR_EXT,
-                )
+                .message_severity(spec.min_validation_severity.vk_flags_and_above())
                 .message_type(
-                    vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION_EXT
+                    vk::DebugUtilsMessageTypeFlagsEXT::GENERAL_EXT
+                        | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION_EXT
                         | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE_EXT,
                 )
-                .pfn_user_callback(Some(debug_callback));
+                .pfn_user_callback(Some(debug_callback))
+                .user_data(&*validation_state as *const ValidationState as *mut c_void);
 
             unsafe { instance.create_debug_utils_messenger_ext(&messenger_info, None) }.result()?
         } else {
@@ -126,15 +208,58 @@ impl Instance {
             entry,
             instance,
             messenger,
+            graphic_windows_enabled: spec.enable_graphic_windows,
+            headless_surface_enabled: spec.enable_headless_surface,
+            surface_extensions,
+            validation_state,
         })
     }
 
+    // Checks that the instance was created with the extension the window system needs
+    pub fn check_window_support(&self, window_handle: &RawWindowHandle) -> VulkanResult<()> {
+        let extension = window_surface_extension(window_handle)?;
+        if contains_extension(&self.surface_extensions, extension) {
+            Ok(())
+        } else {
+            Err(VulkanError::MissingExtension {
+                name: extension_name(extension),
+                purpose: "creating a surface for the window",
+            })
+        }
+    }
+
+    // Validation errors recorded since the last call, always empty without `panic_on_validation_error`
+    pub fn take_validation_errors(&self) -> Vec<ValidationMessage> {
+        match &self.validation_state.errors {
+            Some(errors) => take_validation_errors(errors),
+            None => Vec::new(),
+        }
+    }
+
+    pub(crate) fn validation_errors(&self) -> Option<ValidationErrors> {
+        self.validation_state.errors.clone()
+    }
+
+    // Headless surfaces need an instance created with `enable_headless_surface`
+    pub fn check_headless_surface_support(&self) -> VulkanResult<()> {
+        if self.headless_surface_enabled {
+            Ok(())
+        } else {
+            Err(VulkanError::MissingExtension {
+                name: extension_name(VK_EXT_HEADLESS_SURFACE_EXTENSION_NAME),
+                purpose: "creating a surface without a window",
+            })
+        }
+    }
+
     pub fn destroy(self) {
         unsafe {
             self.instance
                 .destroy_debug_utils_messenger_ext(self.messenger, None);
             self.instance.destroy_instance(None);
         }
+        // The messenger is gone, nothing points to the state anymore
+        drop(self.validation_state);
     }
 
     pub fn get_physical_devices(
@@ -152,6 +277,15 @@ impl Instance {
                     self.instance
                         .get_physical_device_properties(vkphysical_device)
                 },
+                queue_families: unsafe {
+                    self.instance
+                        .get_physical_device_queue_family_properties(vkphysical_device, None)
+                        .to_vec()
+                },
+                memory_properties: unsafe {
+                    self.instance
+                        .get_physical_device_memory_properties(vkphysical_device)
+                },
                 ..Default::default()
             });
             let physical_device = physical_devices.back_mut();
@@ -1,4 +1,10 @@
+use super::error::*;
+
 use erupt::vk;
+use std::{ffi::CStr, fmt::Write, os::raw::c_char};
+
+// Selects a device by index or by (part of) its name, bypassing the scoring
+pub const PHYSICAL_DEVICE_OVERRIDE_ENV_VAR: &str = "RENDER_DEVICE";
 
 #[derive(Default, Debug)]
 pub struct PhysicalDevice {
@@ -6,4 +12,390 @@ pub struct PhysicalDevice {
     pub properties: vk::PhysicalDeviceProperties,
     pub vulkan12_features: vk::PhysicalDeviceVulkan12Features,
     pub features: vk::PhysicalDeviceFeatures2,
+    pub queue_families: Vec<vk::QueueFamilyProperties>,
+    pub memory_properties: vk::PhysicalDeviceMemoryProperties,
+}
+
+#[derive(Clone, Debug)]
+pub struct PhysicalDeviceRequirements {
+    // The bindless set needs runtime arrays of partially bound descriptors updated after bind
+    pub descriptor_indexing: bool,
+    // Fences are timeline semaphores
+    pub timeline_semaphores: bool,
+    // A graphics family and a compute family without graphics, like `Device::new` creates
+    pub async_compute_queue: bool,
+    pub min_device_local_memory: u64,
+}
+
+impl Default for PhysicalDeviceRequirements {
+    fn default() -> Self {
+        Self {
+            descriptor_indexing: true,
+            timeline_semaphores: true,
+            async_compute_queue: true,
+            min_device_local_memory: 256 << 20,
+        }
+    }
+}
+
+impl PhysicalDevice {
+    pub fn name(&self) -> String {
+        unsafe { CStr::from_ptr(&self.properties.device_name as *const c_char) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub fn device_local_memory(&self) -> u64 {
+        self.memory_properties.memory_heaps[..self.memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum()
+    }
+
+    fn has_queue_family(&self, included: vk::QueueFlags, excluded: vk::QueueFlags) -> bool {
+        self.queue_families.iter().any(|family| {
+            family.queue_count > 0
+                && family.queue_flags.contains(included)
+                && !family.queue_flags.intersects(excluded)
+        })
+    }
+
+    // Lists what the device lacks, empty when it can be used
+    pub fn missing_requirements(&self, requirements: &PhysicalDeviceRequirements) -> Vec<String> {
+        let features = &self.vulkan12_features;
+        let mut missing = Vec::new();
+
+        if requirements.descriptor_indexing
+            && [
+                features.descriptor_indexing,
+                features.runtime_descriptor_array,
+                features.descriptor_binding_partially_bound,
+                features.descriptor_binding_update_unused_while_pending,
+                features.descriptor_binding_sampled_image_update_after_bind,
+                features.descriptor_binding_storage_image_update_after_bind,
+                features.descriptor_binding_storage_buffer_update_after_bind,
+            ]
+            .iter()
+            .any(|feature| *feature != vk::TRUE)
+        {
+            missing.push(String::from("descriptor indexing"));
+        }
+
+        if requirements.timeline_semaphores && features.timeline_semaphore != vk::TRUE {
+            missing.push(String::from("timeline semaphores"));
+        }
+
+        if !self.has_queue_family(vk::QueueFlags::GRAPHICS, vk::QueueFlags::empty()) {
+            missing.push(String::from("graphics queue"));
+        }
+
+        if requirements.async_compute_queue
+            && !self.has_queue_family(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS)
+        {
+            missing.push(String::from("async compute queue"));
+        }
+
+        let device_local_memory = self.device_local_memory();
+        if device_local_memory < requirements.min_device_local_memory {
+            missing.push(format!(
+                "{} MiB of device local memory (has {} MiB)",
+                requirements.min_device_local_memory >> 20,
+                device_local_memory >> 20
+            ));
+        }
+
+        missing
+    }
+
+    // Higher is better: discrete GPUs first, then the largest amount of VRAM
+    pub fn score(&self) -> u64 {
+        let type_score = match self.properties.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        (type_score << 48) + (self.device_local_memory() >> 20)
+    }
+
+    // Meant to be pasted in bug reports
+    pub fn capability_report(&self) -> String {
+        let properties = &self.properties;
+        let limits = &properties.limits;
+        let features = &self.vulkan12_features;
+        let mut report = String::new();
+
+        let version = |version: u32| {
+            format!(
+                "{}.{}.{}",
+                vk::api_version_major(version),
+                vk::api_version_minor(version),
+                vk::api_version_patch(version)
+            )
+        };
+
+        let _ = writeln!(report, "{} ({:?})", self.name(), properties.device_type);
+        let _ = writeln!(
+            report,
+            "  vendor {:#06x}, device {:#06x}, api {}, driver {:#x}",
+            properties.vendor_id,
+            properties.device_id,
+            version(properties.api_version),
+            properties.driver_version
+        );
+
+        let _ = writeln!(report, "  memory heaps:");
+        for heap in &self.memory_properties.memory_heaps
+            [..self.memory_properties.memory_heap_count as usize]
+        {
+            let _ = writeln!(report, "    {} MiB {:?}", heap.size >> 20, heap.flags);
+        }
+
+        let _ = writeln!(report, "  queue families:");
+        for (i_family, family) in self.queue_families.iter().enumerate() {
+            let _ = writeln!(
+                report,
+                "    #{}: {} x {:?}",
+                i_family, family.queue_count, family.queue_flags
+            );
+        }
+
+        let _ = writeln!(
+            report,
+            "  descriptor indexing: {}, timeline semaphores: {}, buffer device address: {}",
+            features.descriptor_indexing == vk::TRUE,
+            features.timeline_semaphore == vk::TRUE,
+            features.buffer_device_address == vk::TRUE
+        );
+        let _ = writeln!(
+            report,
+            "  max push constants: {} bytes, max image 2D: {}, max sampled images per stage: {}",
+            limits.max_push_constants_size,
+            limits.max_image_dimension2_d,
+            limits.max_per_stage_descriptor_sampled_images
+        );
+
+        report
+    }
+}
+
+// The override is given as an index or (part of) a device name, case insensitive
+fn find_override(physical_devices: &[PhysicalDevice], device_override: &str) -> Option<usize> {
+    if let Ok(i_device) = device_override.trim().parse::<usize>() {
+        return (i_device < physical_devices.len()).then_some(i_device);
+    }
+
+    let device_override = device_override.trim().to_lowercase();
+    physical_devices
+        .iter()
+        .position(|device| device.name().to_lowercase().contains(&device_override))
+}
+
+pub fn select_physical_device(
+    physical_devices: &[PhysicalDevice],
+    requirements: &PhysicalDeviceRequirements,
+    device_override: Option<&str>,
+) -> VulkanResult<usize> {
+    if let Some(device_override) = device_override {
+        let i_device = find_override(physical_devices, device_override).ok_or_else(|| {
+            VulkanError::NoSuitableDevice(format!(
+                "no device matches {}={:?}",
+                PHYSICAL_DEVICE_OVERRIDE_ENV_VAR, device_override
+            ))
+        })?;
+
+        let missing = physical_devices[i_device].missing_requirements(requirements);
+        if !missing.is_empty() {
+            return Err(VulkanError::NoSuitableDevice(format!(
+                "{} was selected by {} but lacks {}",
+                physical_devices[i_device].name(),
+                PHYSICAL_DEVICE_OVERRIDE_ENV_VAR,
+                missing.join(", ")
+            )));
+        }
+        return Ok(i_device);
+    }
+
+    let mut rejections = Vec::new();
+    let mut i_selected: Option<usize> = None;
+    for (i_device, physical_device) in physical_devices.iter().enumerate() {
+        let missing = physical_device.missing_requirements(requirements);
+        if !missing.is_empty() {
+            rejections.push(format!(
+                "{} lacks {}",
+                physical_device.name(),
+                missing.join(", ")
+            ));
+            continue;
+        }
+
+        if i_selected
+            .is_none_or(|i_selected| physical_device.score() > physical_devices[i_selected].score())
+        {
+            i_selected = Some(i_device);
+        }
+    }
+
+    i_selected.ok_or_else(|| {
+        if rejections.is_empty() {
+            VulkanError::NoSuitableDevice(String::from("no vulkan device found"))
+        } else {
+            VulkanError::NoSuitableDevice(rejections.join("; "))
+        }
+    })
+}
+
+// Selects the best device, or the one given in `RENDER_DEVICE`, and prints the capabilities of all of them
+pub fn select_physical_device_from_env(
+    physical_devices: &[PhysicalDevice],
+    requirements: &PhysicalDeviceRequirements,
+) -> VulkanResult<usize> {
+    for (i_device, physical_device) in physical_devices.iter().enumerate() {
+        print!(
+            "Found device #{}: {}",
+            i_device,
+            physical_device.capability_report()
+        );
+    }
+
+    let device_override = std::env::var(PHYSICAL_DEVICE_OVERRIDE_ENV_VAR).ok();
+    let i_selected =
+        select_physical_device(physical_devices, requirements, device_override.as_deref())?;
+    println!(
+        "Selected device #{}: {}",
+        i_selected,
+        physical_devices[i_selected].name()
+    );
+    Ok(i_selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_device(
+        name: &str,
+        device_type: vk::PhysicalDeviceType,
+        vram_mib: u64,
+        async_compute: bool,
+    ) -> PhysicalDevice {
+        let mut device = PhysicalDevice::default();
+        for (i_char, c) in name.bytes().enumerate() {
+            device.properties.device_name[i_char] = c as c_char;
+        }
+        device.properties.device_type = device_type;
+
+        let features = &mut device.vulkan12_features;
+        features.descriptor_indexing = vk::TRUE;
+        features.runtime_descriptor_array = vk::TRUE;
+        features.descriptor_binding_partially_bound = vk::TRUE;
+        features.descriptor_binding_update_unused_while_pending = vk::TRUE;
+        features.descriptor_binding_sampled_image_update_after_bind = vk::TRUE;
+        features.descriptor_binding_storage_image_update_after_bind = vk::TRUE;
+        features.descriptor_binding_storage_buffer_update_after_bind = vk::TRUE;
+        features.timeline_semaphore = vk::TRUE;
+
+        device.queue_families.push(vk::QueueFamilyProperties {
+            queue_flags: vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+            queue_count: 1,
+            ..Default::default()
+        });
+        if async_compute {
+            device.queue_families.push(vk::QueueFamilyProperties {
+                queue_flags: vk::QueueFlags::COMPUTE,
+                queue_count: 1,
+                ..Default::default()
+            });
+        }
+
+        device.memory_properties.memory_heap_count = 1;
+        device.memory_properties.memory_heaps[0] = vk::MemoryHeap {
+            size: vram_mib << 20,
+            flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+        };
+        device
+    }
+
+    fn test_devices() -> Vec<PhysicalDevice> {
+        vec![
+            test_device(
+                "Integrated",
+                vk::PhysicalDeviceType::INTEGRATED_GPU,
+                2048,
+                true,
+            ),
+            test_device(
+                "Small Discrete",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                4096,
+                true,
+            ),
+            test_device(
+                "Big Discrete",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                8192,
+                true,
+            ),
+        ]
+    }
+
+    #[test]
+    fn selects_best_suitable_device() {
+        let requirements = PhysicalDeviceRequirements::default();
+        let mut devices = test_devices();
+        assert_eq!(
+            select_physical_device(&devices, &requirements, None).unwrap(),
+            2
+        );
+
+        devices[2].vulkan12_features.timeline_semaphore = vk::FALSE;
+        assert_eq!(
+            select_physical_device(&devices, &requirements, None).unwrap(),
+            1
+        );
+
+        devices[1] = test_device(
+            "Small Discrete",
+            vk::PhysicalDeviceType::DISCRETE_GPU,
+            4096,
+            false,
+        );
+        assert_eq!(
+            select_physical_device(&devices, &requirements, None).unwrap(),
+            0
+        );
+
+        devices[0].memory_properties.memory_heaps[0].size = 128 << 20;
+        assert!(matches!(
+            select_physical_device(&devices, &requirements, None),
+            Err(VulkanError::NoSuitableDevice(_))
+        ));
+    }
+
+    #[test]
+    fn overrides_by_index_or_name() {
+        let requirements = PhysicalDeviceRequirements::default();
+        let devices = test_devices();
+        assert_eq!(
+            select_physical_device(&devices, &requirements, Some("0")).unwrap(),
+            0
+        );
+        assert_eq!(
+            select_physical_device(&devices, &requirements, Some("small")).unwrap(),
+            1
+        );
+        assert!(select_physical_device(&devices, &requirements, Some("3")).is_err());
+        assert!(select_physical_device(&devices, &requirements, Some("Radeon")).is_err());
+    }
+
+    #[test]
+    fn reports_missing_requirements() {
+        let mut device = test_device("Device", vk::PhysicalDeviceType::CPU, 512, false);
+        device.vulkan12_features.runtime_descriptor_array = vk::FALSE;
+        let missing = device.missing_requirements(&PhysicalDeviceRequirements::default());
+        assert_eq!(missing, vec!["descriptor indexing", "async compute queue"]);
+        assert!(device.capability_report().starts_with("Device (CPU)"));
+    }
 }
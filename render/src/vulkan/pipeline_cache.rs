@@ -0,0 +1,129 @@
+use super::device::*;
+use super::error::*;
+
+use erupt::{vk, DeviceLoader};
+use std::path::Path;
+
+// VkPipelineCacheHeaderVersionOne
+const HEADER_SIZE: usize = 32;
+const HEADER_VERSION_ONE: u32 = 1;
+
+// A cache written by another driver or device would be ignored by the driver at best
+pub(crate) fn is_cache_compatible(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+
+    let read_u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    read_u32(0) as usize >= HEADER_SIZE
+        && read_u32(4) == HEADER_VERSION_ONE
+        && read_u32(8) == properties.vendor_id
+        && read_u32(12) == properties.device_id
+        && data[16..32] == properties.pipeline_cache_uuid
+}
+
+// Starts from an empty cache when the file is missing or was written by another device
+pub(crate) fn create_pipeline_cache(
+    device: &DeviceLoader,
+    properties: &vk::PhysicalDeviceProperties,
+    path: Option<&Path>,
+) -> VulkanResult<vk::PipelineCache> {
+    profile::scope!("load pipeline cache");
+
+    let initial_data = path
+        .and_then(|path| std::fs::read(path).ok())
+        .filter(|data| is_cache_compatible(data, properties))
+        .unwrap_or_default();
+
+    let cache_info = vk::PipelineCacheCreateInfoBuilder::new()
+        .initial_data_size(initial_data.len())
+        .initial_data(initial_data.as_ptr() as _);
+    let cache = unsafe { device.create_pipeline_cache(&cache_info, None).result()? };
+
+    Ok(cache)
+}
+
+impl Device {
+    pub fn save_pipeline_cache(&self) -> VulkanResult<()> {
+        let path = match &self.spec.pipeline_cache_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        profile::scope!("save pipeline cache");
+
+        let data = unsafe {
+            let mut size = 0;
+            self.device
+                .get_pipeline_cache_data(self.pipeline_cache, &mut size, std::ptr::null_mut())
+                .result()?;
+            let mut data = vec![0u8; size];
+            self.device
+                .get_pipeline_cache_data(self.pipeline_cache, &mut size, data.as_mut_ptr() as _)
+                .result()?;
+            data.truncate(size);
+            data
+        };
+
+        std::fs::write(path, &data).map_err(VulkanError::PipelineCacheIo)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x2204,
+            pipeline_cache_uuid: [7; 16],
+            ..Default::default()
+        }
+    }
+
+    fn header(vendor_id: u32, device_id: u32, uuid: [u8; 16]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&HEADER_VERSION_ONE.to_le_bytes());
+        data.extend_from_slice(&vendor_id.to_le_bytes());
+        data.extend_from_slice(&device_id.to_le_bytes());
+        data.extend_from_slice(&uuid);
+        data
+    }
+
+    #[test]
+    fn accepts_cache_from_the_same_device() {
+        let mut data = header(0x10de, 0x2204, [7; 16]);
+        data.extend_from_slice(&[1, 2, 3]);
+        assert!(is_cache_compatible(&data, &properties()));
+    }
+
+    #[test]
+    fn rejects_cache_from_another_device_or_driver() {
+        assert!(!is_cache_compatible(
+            &header(0x1002, 0x2204, [7; 16]),
+            &properties()
+        ));
+        assert!(!is_cache_compatible(
+            &header(0x10de, 0x2206, [7; 16]),
+            &properties()
+        ));
+        assert!(!is_cache_compatible(
+            &header(0x10de, 0x2204, [8; 16]),
+            &properties()
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_cache() {
+        let data = header(0x10de, 0x2204, [7; 16]);
+        assert!(!is_cache_compatible(
+            &data[..HEADER_SIZE - 1],
+            &properties()
+        ));
+        assert!(!is_cache_compatible(&[], &properties()));
+    }
+}
@@ -0,0 +1,130 @@
+use super::descriptor_set::*;
+use super::device::*;
+use super::error::*;
+
+use erupt::vk;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SamplerSpec {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode: vk::SamplerAddressMode,
+    // Clamped to the device limit, ignored when the device doesn't support anisotropic filtering
+    pub max_anisotropy: Option<f32>,
+    pub compare_op: Option<vk::CompareOp>,
+    pub mip_lod_bias: f32,
+    pub min_lod: f32,
+    pub max_lod: f32,
+}
+
+impl Default for SamplerSpec {
+    fn default() -> Self {
+        Self::linear()
+    }
+}
+
+impl SamplerSpec {
+    pub fn linear() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: None,
+            compare_op: None,
+            mip_lod_bias: 0.0,
+            min_lod: 0.0,
+            max_lod: vk::LOD_CLAMP_NONE,
+        }
+    }
+
+    pub fn nearest() -> Self {
+        Self {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            ..Self::linear()
+        }
+    }
+
+    pub fn address_mode(mut self, address_mode: vk::SamplerAddressMode) -> Self {
+        self.address_mode = address_mode;
+        self
+    }
+
+    pub fn max_anisotropy(mut self, max_anisotropy: f32) -> Self {
+        self.max_anisotropy = Some(max_anisotropy);
+        self
+    }
+
+    pub fn compare_op(mut self, compare_op: vk::CompareOp) -> Self {
+        self.compare_op = Some(compare_op);
+        self
+    }
+
+    pub fn lod(mut self, min_lod: f32, max_lod: f32, mip_lod_bias: f32) -> Self {
+        self.min_lod = min_lod;
+        self.max_lod = max_lod;
+        self.mip_lod_bias = mip_lod_bias;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Sampler {
+    pub spec: SamplerSpec,
+    pub vkhandle: vk::Sampler,
+}
+
+impl Device {
+    // Returns the index of the sampler in the bindless sampler array, samplers live as long as the device
+    pub fn get_or_create_sampler(&mut self, spec: SamplerSpec) -> VulkanResult<u32> {
+        if let Some(i_sampler) = self
+            .samplers
+            .iter()
+            .position(|sampler| sampler.spec == spec)
+        {
+            return Ok(i_sampler as u32);
+        }
+
+        assert!(self.samplers.len() < MAX_SAMPLERS as usize);
+        let i_sampler = self.samplers.len() as u32;
+
+        let max_anisotropy = spec
+            .max_anisotropy
+            .map(|max_anisotropy| max_anisotropy.min(self.max_sampler_anisotropy))
+            .filter(|max_anisotropy| *max_anisotropy > 1.0);
+
+        let sampler_info = vk::SamplerCreateInfoBuilder::new()
+            .mag_filter(spec.mag_filter)
+            .min_filter(spec.min_filter)
+            .mipmap_mode(spec.mipmap_mode)
+            .address_mode_u(spec.address_mode)
+            .address_mode_v(spec.address_mode)
+            .address_mode_w(spec.address_mode)
+            .anisotropy_enable(max_anisotropy.is_some())
+            .max_anisotropy(max_anisotropy.unwrap_or(1.0))
+            .compare_enable(spec.compare_op.is_some())
+            .compare_op(spec.compare_op.unwrap_or(vk::CompareOp::NEVER))
+            .mip_lod_bias(spec.mip_lod_bias)
+            .min_lod(spec.min_lod)
+            .max_lod(spec.max_lod);
+
+        let vkhandle = unsafe { self.device.create_sampler(&sampler_info, None).result()? };
+
+        let sampler_infos = [vk::DescriptorImageInfoBuilder::new().sampler(vkhandle)];
+        let write = vk::WriteDescriptorSetBuilder::new()
+            .dst_set(self.descriptors.bindless_set.vkset)
+            .dst_binding(SAMPLER_BINDING)
+            .dst_array_element(i_sampler)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&sampler_infos);
+        unsafe {
+            self.device.update_descriptor_sets(&[write], &[]);
+        }
+
+        self.samplers.push(Sampler { spec, vkhandle });
+        Ok(i_sampler)
+    }
+}
@@ -1,19 +1,89 @@
+use super::compute_pipeline::*;
 use super::device::*;
 use super::error::*;
+use super::graphics_pipeline::*;
+use super::shader_reflection::*;
+use super::shader_variant::*;
+use crate::shader::{compile_shader, CompiledShader};
 use erupt::vk;
 use exo::pool::Handle;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub struct Shader {
     pub path: PathBuf,
+    pub defines: Vec<(String, String)>,
+    // Files that trigger a reload when they change
+    pub dependencies: Vec<PathBuf>,
     pub vkhandle: vk::ShaderModule,
     pub bytecode: Vec<u8>,
+    pub reflection: ShaderReflection,
+}
+
+#[derive(Debug)]
+pub struct ShaderReload {
+    pub shader: Handle<Shader>,
+    pub path: PathBuf,
+    // The previous module and pipelines are kept for everything that fails, empty when the reload succeeded
+    pub errors: Vec<VulkanError>,
+}
+
+// Precompiled SPIR-V is loaded as is, anything else is compiled from source
+fn load_shader(path: &Path, defines: &[(String, String)]) -> VulkanResult<CompiledShader> {
+    if path.extension().is_some_and(|extension| extension == "spv") {
+        Ok(CompiledShader {
+            bytecode: std::fs::read(path).map_err(|error| VulkanError::ShaderIo {
+                path: PathBuf::from(path),
+                error,
+            })?,
+            dependencies: vec![PathBuf::from(path)],
+        })
+    } else {
+        compile_shader(path, defines)
+    }
 }
 
 impl Device {
     pub fn create_shader(&mut self, path: &str) -> VulkanResult<Handle<Shader>> {
-        let bytecode = std::fs::read(&path).unwrap();
+        self.create_shader_with_defines(path, &[])
+    }
+
+    pub fn create_shader_with_defines(
+        &mut self,
+        path: &str,
+        defines: &[(&str, &str)],
+    ) -> VulkanResult<Handle<Shader>> {
+        let defines = defines
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.add_shader(PathBuf::from(path), defines)
+    }
+
+    // Compiles the same source as `base_shader` with the defines of the variant's features added
+    pub fn create_shader_variant(
+        &mut self,
+        base_shader: Handle<Shader>,
+        features: ShaderFeatures,
+        variant: ShaderVariant,
+    ) -> VulkanResult<Handle<Shader>> {
+        let base_shader = self.shaders.get(base_shader);
+        let path = base_shader.path.clone();
+        let mut defines = base_shader.defines.clone();
+        defines.extend(variant.defines(features));
+        self.add_shader(path, defines)
+    }
+
+    fn add_shader(
+        &mut self,
+        path: PathBuf,
+        defines: Vec<(String, String)>,
+    ) -> VulkanResult<Handle<Shader>> {
+        let CompiledShader {
+            bytecode,
+            dependencies,
+        } = load_shader(&path, &defines)?;
+        let reflection = self.reflect_and_validate(&path, &bytecode)?;
 
         let shader_info = vk::ShaderModuleCreateInfo {
             code_size: bytecode.len(),
@@ -28,19 +98,29 @@ impl Device {
         };
 
         let shader_handle = self.shaders.add(Shader {
-            path: PathBuf::from(path),
+            path,
+            defines,
+            dependencies,
             vkhandle,
             bytecode,
+            reflection,
         });
 
         Ok(shader_handle)
     }
 
     pub fn update_shader_from_fs(&mut self, shader_handle: Handle<Shader>) -> VulkanResult<()> {
-        let shader = self.shaders.get_mut(shader_handle);
-        println!("reloading shader {:?}", &shader.path);
+        let shader = self.shaders.get(shader_handle);
+        let path = shader.path.clone();
+
+        let CompiledShader {
+            bytecode: new_bytecode,
+            dependencies: new_dependencies,
+        } = load_shader(&path, &shader.defines)?;
+        // Keep the previous module when the new one doesn't fit the pipeline layout
+        let new_reflection = self.reflect_and_validate(&path, &new_bytecode)?;
 
-        let new_bytecode = std::fs::read(&shader.path).unwrap();
+        let shader = self.shaders.get_mut(shader_handle);
         let shader_info = vk::ShaderModuleCreateInfo {
             code_size: new_bytecode.len(),
             p_code: new_bytecode.as_ptr() as *const u32,
@@ -54,10 +134,146 @@ impl Device {
                 .create_shader_module(&shader_info, None)
                 .result()?;
         }
+        shader.bytecode = new_bytecode;
+        shader.dependencies = new_dependencies;
+        shader.reflection = new_reflection;
 
         Ok(())
     }
 
+    // Recompiles the shaders depending on one of the changed files and the pipelines using them
+    pub fn reload_shaders(&mut self, changed_paths: &[PathBuf]) -> VulkanResult<Vec<ShaderReload>> {
+        let shaders_to_reload: Vec<_> = self
+            .shaders
+            .iter()
+            .filter(|(_handle, shader)| {
+                shader
+                    .dependencies
+                    .iter()
+                    .any(|dependency| changed_paths.contains(dependency))
+            })
+            .map(|(handle, _shader)| handle)
+            .collect();
+
+        if shaders_to_reload.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.wait_idle()?;
+
+        let mut reloads: Vec<ShaderReload> = shaders_to_reload
+            .into_iter()
+            .map(|shader_handle| ShaderReload {
+                shader: shader_handle,
+                path: self.shaders.get(shader_handle).path.clone(),
+                errors: self
+                    .update_shader_from_fs(shader_handle)
+                    .err()
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+
+        // A file included by several shaders changes all of them, rebuild each program once
+        let reloaded: Vec<_> = reloads
+            .iter()
+            .filter(|reload| reload.errors.is_empty())
+            .map(|reload| reload.shader)
+            .collect();
+        for (variant_shaders, error) in self.compile_programs_using_shaders(&reloaded) {
+            let reload = reloads
+                .iter_mut()
+                .find(|reload| variant_shaders.contains(&reload.shader));
+            // A variant can depend on other files than the shaders that triggered the rebuild of its program
+            match reload {
+                Some(reload) => reload.errors.push(error),
+                None => reloads[0].errors.push(error),
+            }
+        }
+
+        Ok(reloads)
+    }
+
+    pub fn programs_using_shaders(
+        &self,
+        shader_handles: &[Handle<Shader>],
+    ) -> (Vec<Handle<GraphicsProgram>>, Vec<Handle<ComputeProgram>>) {
+        let graphics_programs = self
+            .graphics_programs
+            .iter()
+            .filter(|(_handle, program)| {
+                program.variants.iter().any(|variant| {
+                    shader_handles.contains(&variant.vertex_shader)
+                        || shader_handles.contains(&variant.fragment_shader)
+                })
+            })
+            .map(|(handle, _program)| handle)
+            .collect();
+
+        let compute_programs = self
+            .compute_programs
+            .iter()
+            .filter(|(_handle, program)| {
+                program
+                    .variants
+                    .iter()
+                    .any(|variant| shader_handles.contains(&variant.shader))
+            })
+            .map(|(handle, _program)| handle)
+            .collect();
+
+        (graphics_programs, compute_programs)
+    }
+
+    // Every pipeline is attempted, the ones that fail keep their previous version and are returned with the
+    // shaders of their variant. The variants that failed to compile while recording are attempted again at their
+    // next bind
+    fn compile_programs_using_shaders(
+        &mut self,
+        shader_handles: &[Handle<Shader>],
+    ) -> Vec<([Handle<Shader>; 2], VulkanError)> {
+        let (graphics_programs, compute_programs) = self.programs_using_shaders(shader_handles);
+        let mut errors = Vec::new();
+
+        for program_handle in graphics_programs {
+            let program = self.graphics_programs.get_mut(program_handle);
+            program.failed_variants.clear();
+            let pipeline_count = program.render_states.len();
+            for i_pipeline in 0..pipeline_count {
+                for (i_variant, error) in
+                    self.compile_graphics_program_pipeline(program_handle, i_pipeline)
+                {
+                    let variant = &self.graphics_programs.get(program_handle).variants[i_variant];
+                    errors.push(([variant.vertex_shader, variant.fragment_shader], error));
+                }
+            }
+        }
+
+        for program_handle in compute_programs {
+            self.compute_programs
+                .get_mut(program_handle)
+                .failed_variants
+                .clear();
+            for (i_variant, error) in self.compile_compute_program(program_handle) {
+                let variant = &self.compute_programs.get(program_handle).variants[i_variant];
+                errors.push(([variant.shader, Handle::invalid()], error));
+            }
+        }
+
+        errors
+    }
+
+    fn reflect_and_validate(&self, path: &Path, bytecode: &[u8]) -> VulkanResult<ShaderReflection> {
+        let reflection = reflect_spirv(bytecode)?;
+        validate_reflection(&reflection, self.spec.push_constant_size).map_err(|mismatch| {
+            VulkanError::ShaderLayoutMismatch {
+                path: PathBuf::from(path),
+                mismatch,
+            }
+        })?;
+        Ok(reflection)
+    }
+
     pub fn destroy_shader(&mut self, shader_handle: Handle<Shader>) {
         let shader = self.shaders.get(shader_handle);
         unsafe {
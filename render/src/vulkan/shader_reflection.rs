@@ -0,0 +1,725 @@
+use super::descriptor_set::*;
+use super::device::*;
+use super::error::*;
+use super::shader::*;
+
+use erupt::vk;
+use exo::{pod::PodStruct, pool::Handle};
+use std::collections::HashMap;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+// Opcodes
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+const OP_NAME: u32 = 5;
+const OP_MEMBER_NAME: u32 = 6;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+
+// Decorations
+const DECORATION_BLOCK: u32 = 2;
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+const IMAGE_DIM_BUFFER: u32 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedBinding {
+    pub name: String,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    // None for runtime arrays
+    pub count: Option<u32>,
+    // Size of the block for uniform and storage buffers, without the runtime array at the end
+    pub size: u32,
+    // Members of the block for uniform and storage buffers
+    pub members: Vec<ReflectedMember>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReflectedMember {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShaderReflection {
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constant_size: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Scalar { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+}
+
+#[derive(Default)]
+struct Module {
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    names: HashMap<u32, String>,
+    member_names: HashMap<(u32, u32), String>,
+    decorations: HashMap<(u32, u32), u32>,
+    flags: HashMap<u32, Vec<u32>>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+    // (id, pointer type, storage class)
+    variables: Vec<(u32, u32, u32)>,
+}
+
+impl Module {
+    fn parse(bytecode: &[u8]) -> VulkanResult<Self> {
+        if !bytecode.len().is_multiple_of(4) || bytecode.len() < 20 {
+            return Err(VulkanError::InvalidSpirv(String::from(
+                "the bytecode is not a sequence of words",
+            )));
+        }
+
+        let words: Vec<u32> = bytecode
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+
+        if words[0] != SPIRV_MAGIC {
+            return Err(VulkanError::InvalidSpirv(String::from("bad magic number")));
+        }
+
+        let mut module = Module::default();
+        let mut i_word = 5;
+        while i_word < words.len() {
+            let word_count = (words[i_word] >> 16) as usize;
+            let opcode = words[i_word] & 0xffff;
+            if word_count == 0 || i_word + word_count > words.len() {
+                return Err(VulkanError::InvalidSpirv(format!(
+                    "truncated instruction at word {}",
+                    i_word
+                )));
+            }
+            module.parse_instruction(opcode, &words[i_word + 1..i_word + word_count])?;
+            i_word += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, opcode: u32, operands: &[u32]) -> VulkanResult<()> {
+        let operand = |i: usize| operands.get(i).copied().unwrap_or(0);
+        let operands_from = |i: usize| {
+            operands.get(i..).ok_or_else(|| {
+                VulkanError::InvalidSpirv(format!(
+                    "opcode {} has {} operands but needs at least {}",
+                    opcode,
+                    operands.len(),
+                    i
+                ))
+            })
+        };
+        match opcode {
+            OP_NAME => {
+                let name = parse_string(operands_from(1)?);
+                self.names.insert(operand(0), name);
+            }
+            OP_MEMBER_NAME => {
+                let name = parse_string(operands_from(2)?);
+                self.member_names.insert((operand(0), operand(1)), name);
+            }
+            OP_DECORATE => match operand(1) {
+                DECORATION_BLOCK | DECORATION_BUFFER_BLOCK => {
+                    self.flags.entry(operand(0)).or_default().push(operand(1))
+                }
+                decoration => {
+                    self.decorations
+                        .insert((operand(0), decoration), operand(2));
+                }
+            },
+            OP_MEMBER_DECORATE => {
+                self.member_decorations
+                    .insert((operand(0), operand(1), operand(2)), operand(3));
+            }
+            OP_TYPE_INT | OP_TYPE_FLOAT => {
+                self.types
+                    .insert(operand(0), Type::Scalar { width: operand(1) });
+            }
+            OP_TYPE_VECTOR => {
+                self.types.insert(
+                    operand(0),
+                    Type::Vector {
+                        component: operand(1),
+                        count: operand(2),
+                    },
+                );
+            }
+            OP_TYPE_MATRIX => {
+                self.types.insert(
+                    operand(0),
+                    Type::Matrix {
+                        column: operand(1),
+                        count: operand(2),
+                    },
+                );
+            }
+            OP_TYPE_IMAGE => {
+                self.types.insert(
+                    operand(0),
+                    Type::Image {
+                        dim: operand(2),
+                        sampled: operand(6),
+                    },
+                );
+            }
+            OP_TYPE_SAMPLER => {
+                self.types.insert(operand(0), Type::Sampler);
+            }
+            OP_TYPE_SAMPLED_IMAGE => {
+                self.types.insert(operand(0), Type::SampledImage);
+            }
+            OP_TYPE_ARRAY => {
+                let length = self.constants.get(&operand(2)).copied().unwrap_or(0);
+                self.types.insert(
+                    operand(0),
+                    Type::Array {
+                        element: operand(1),
+                        length,
+                    },
+                );
+            }
+            OP_TYPE_RUNTIME_ARRAY => {
+                self.types.insert(
+                    operand(0),
+                    Type::RuntimeArray {
+                        element: operand(1),
+                    },
+                );
+            }
+            OP_TYPE_STRUCT => {
+                self.types.insert(
+                    operand(0),
+                    Type::Struct {
+                        members: operands_from(1)?.to_vec(),
+                    },
+                );
+            }
+            OP_TYPE_POINTER => {
+                self.types.insert(
+                    operand(0),
+                    Type::Pointer {
+                        pointee: operand(2),
+                    },
+                );
+            }
+            OP_CONSTANT => {
+                self.constants.insert(operand(1), operand(2));
+            }
+            OP_VARIABLE => {
+                self.variables.push((operand(1), operand(0), operand(2)));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn has_flag(&self, id: u32, flag: u32) -> bool {
+        self.flags
+            .get(&id)
+            .is_some_and(|flags| flags.contains(&flag))
+    }
+
+    // Size in bytes following the offsets and strides decorations, runtime arrays have no size
+    fn type_size(&self, type_id: u32, matrix_stride: Option<u32>) -> u32 {
+        match self.types.get(&type_id) {
+            Some(Type::Scalar { width }) => width / 8,
+            Some(Type::Vector { component, count }) => self.type_size(*component, None) * count,
+            Some(Type::Matrix { column, count }) => {
+                matrix_stride.unwrap_or_else(|| self.type_size(*column, None)) * count
+            }
+            Some(Type::Array { element, length }) => {
+                let stride = self
+                    .decorations
+                    .get(&(type_id, DECORATION_ARRAY_STRIDE))
+                    .copied()
+                    .unwrap_or_else(|| self.type_size(*element, matrix_stride));
+                stride * length
+            }
+            Some(Type::Struct { .. }) => self
+                .struct_members(type_id)
+                .iter()
+                .map(|member| member.offset + member.size)
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn struct_members(&self, type_id: u32) -> Vec<ReflectedMember> {
+        let members = match self.types.get(&type_id) {
+            Some(Type::Struct { members }) => members,
+            _ => return Vec::new(),
+        };
+
+        (0..members.len() as u32)
+            .map(|i_member| {
+                let offset = self
+                    .member_decorations
+                    .get(&(type_id, i_member, DECORATION_OFFSET))
+                    .copied()
+                    .unwrap_or(0);
+                let matrix_stride = self
+                    .member_decorations
+                    .get(&(type_id, i_member, DECORATION_MATRIX_STRIDE))
+                    .copied();
+                ReflectedMember {
+                    name: self
+                        .member_names
+                        .get(&(type_id, i_member))
+                        .cloned()
+                        .unwrap_or_default(),
+                    offset,
+                    size: self.type_size(members[i_member as usize], matrix_stride),
+                }
+            })
+            .collect()
+    }
+
+    fn reflect(&self) -> ShaderReflection {
+        let mut reflection = ShaderReflection::default();
+
+        for (id, pointer_type, storage_class) in &self.variables {
+            let mut type_id = match self.types.get(pointer_type) {
+                Some(Type::Pointer { pointee }) => *pointee,
+                _ => continue,
+            };
+
+            if *storage_class == STORAGE_CLASS_PUSH_CONSTANT {
+                reflection.push_constant_size = reflection
+                    .push_constant_size
+                    .max(self.type_size(type_id, None));
+                continue;
+            }
+
+            let is_resource = matches!(
+                *storage_class,
+                STORAGE_CLASS_UNIFORM_CONSTANT
+                    | STORAGE_CLASS_UNIFORM
+                    | STORAGE_CLASS_STORAGE_BUFFER
+            );
+            if !is_resource {
+                continue;
+            }
+
+            let mut count = Some(1);
+            match self.types.get(&type_id) {
+                Some(Type::Array { element, length }) => {
+                    count = Some(*length);
+                    type_id = *element;
+                }
+                Some(Type::RuntimeArray { element }) => {
+                    count = None;
+                    type_id = *element;
+                }
+                _ => {}
+            }
+
+            let descriptor_type = match (self.types.get(&type_id), *storage_class) {
+                (Some(Type::SampledImage), _) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                (Some(Type::Sampler), _) => vk::DescriptorType::SAMPLER,
+                (Some(Type::Image { dim, sampled }), _) => match (*dim, *sampled) {
+                    (IMAGE_DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                    (IMAGE_DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                    (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                    _ => vk::DescriptorType::SAMPLED_IMAGE,
+                },
+                (Some(Type::Struct { .. }), STORAGE_CLASS_STORAGE_BUFFER) => {
+                    vk::DescriptorType::STORAGE_BUFFER
+                }
+                (Some(Type::Struct { .. }), STORAGE_CLASS_UNIFORM) => {
+                    if self.has_flag(type_id, DECORATION_BUFFER_BLOCK) {
+                        vk::DescriptorType::STORAGE_BUFFER
+                    } else {
+                        vk::DescriptorType::UNIFORM_BUFFER
+                    }
+                }
+                _ => continue,
+            };
+
+            let (size, members) = match descriptor_type {
+                vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::STORAGE_BUFFER => {
+                    (self.type_size(type_id, None), self.struct_members(type_id))
+                }
+                _ => (0, Vec::new()),
+            };
+
+            let name = self
+                .names
+                .get(id)
+                .or_else(|| self.names.get(&type_id))
+                .cloned()
+                .unwrap_or_default();
+
+            reflection.bindings.push(ReflectedBinding {
+                name,
+                set: self
+                    .decorations
+                    .get(&(*id, DECORATION_DESCRIPTOR_SET))
+                    .copied()
+                    .unwrap_or(0),
+                binding: self
+                    .decorations
+                    .get(&(*id, DECORATION_BINDING))
+                    .copied()
+                    .unwrap_or(0),
+                descriptor_type,
+                count,
+                size,
+                members,
+            });
+        }
+
+        reflection
+    }
+}
+
+// Literal strings are nul terminated and padded to a whole word
+fn parse_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .take_while(|byte| *byte != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+pub fn reflect_spirv(bytecode: &[u8]) -> VulkanResult<ShaderReflection> {
+    Ok(Module::parse(bytecode)?.reflect())
+}
+
+// Descriptor types of the pipeline layout shared by every program, see `Device::new`
+fn expected_descriptor_type(set: u32, binding: u32) -> Option<vk::DescriptorType> {
+    match (set, binding) {
+        (GLOBAL_BINDLESS_SET, binding) => match binding as usize {
+            PER_SAMPLER => Some(vk::DescriptorType::COMBINED_IMAGE_SAMPLER),
+            PER_IMAGE => Some(vk::DescriptorType::STORAGE_IMAGE),
+            PER_BUFFER => Some(vk::DescriptorType::STORAGE_BUFFER),
+            _ if binding == SAMPLED_IMAGE_BINDING => Some(vk::DescriptorType::SAMPLED_IMAGE),
+            _ if binding == SAMPLER_BINDING => Some(vk::DescriptorType::SAMPLER),
+            _ => None,
+        },
+        // The dynamic offset doesn't change how the shader sees the buffer
+        (GLOBAL_UNIFORM_SET, 0) | (SHADER_UNIFORM_SET, 0) => {
+            Some(vk::DescriptorType::UNIFORM_BUFFER)
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn validate_reflection(
+    reflection: &ShaderReflection,
+    push_constant_size: usize,
+) -> Result<(), String> {
+    for binding in &reflection.bindings {
+        match expected_descriptor_type(binding.set, binding.binding) {
+            None => {
+                return Err(format!(
+                    "{:?} uses set {} binding {} that is not in the pipeline layout",
+                    binding.name, binding.set, binding.binding
+                ))
+            }
+            Some(expected) if expected != binding.descriptor_type => {
+                return Err(format!(
+                    "{:?} at set {} binding {} is a {:?} but the pipeline layout has a {:?}",
+                    binding.name, binding.set, binding.binding, binding.descriptor_type, expected
+                ))
+            }
+            Some(_) => {}
+        }
+
+        if binding.set == GLOBAL_BINDLESS_SET
+            && binding.binding == SAMPLER_BINDING
+            && binding.count.is_some_and(|count| count > MAX_SAMPLERS)
+        {
+            return Err(format!(
+                "{:?} has more than {} samplers",
+                binding.name, MAX_SAMPLERS
+            ));
+        }
+    }
+
+    if reflection.push_constant_size as usize > push_constant_size {
+        return Err(format!(
+            "push constants are {} bytes but the pipeline layout has {}",
+            reflection.push_constant_size, push_constant_size
+        ));
+    }
+
+    Ok(())
+}
+
+// Every member of the options block needs a field of `T` with the same offset, size and name,
+// `T` can have more fields for padding or for other shaders
+fn options_layout_mismatch<T: PodStruct>(options: &ReflectedBinding) -> Option<String> {
+    let type_name = std::any::type_name::<T>();
+    for member in &options.members {
+        let field = T::FIELDS
+            .iter()
+            .find(|field| field.offset == member.offset as usize);
+        let field = match field {
+            Some(field) => field,
+            None => {
+                return Some(format!(
+                    "{:?}.{} is at offset {} but {} has no field there",
+                    options.name, member.name, member.offset, type_name
+                ))
+            }
+        };
+
+        // Names are missing from stripped modules
+        let same_name = member.name.is_empty() || member.name == field.name;
+        if !same_name || member.size as usize != field.size {
+            return Some(format!(
+                "{:?}.{} is {} bytes at offset {} but {}::{} is {} bytes",
+                options.name,
+                member.name,
+                member.size,
+                member.offset,
+                type_name,
+                field.name,
+                field.size
+            ));
+        }
+    }
+    None
+}
+
+impl Device {
+    // Checks the options bound with `bind_shader_options` against the uniform block the shader reads,
+    // contexts call it for the bound shaders before every draw and dispatch
+    pub fn validate_shader_options<T: PodStruct>(
+        &self,
+        shader_handle: Handle<Shader>,
+    ) -> VulkanResult<()> {
+        let shader = self.shaders.get(shader_handle);
+        let options = shader
+            .reflection
+            .bindings
+            .iter()
+            .find(|binding| binding.set == SHADER_UNIFORM_SET && binding.binding == 0);
+
+        if let Some(mismatch) = options.and_then(options_layout_mismatch::<T>) {
+            return Err(VulkanError::ShaderLayoutMismatch {
+                path: shader.path.clone(),
+                mismatch,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exo::pod_struct;
+
+    // Assembles a module from instructions given as (opcode, operands)
+    fn assemble(instructions: &[(u32, Vec<u32>)]) -> Vec<u8> {
+        let mut words = vec![SPIRV_MAGIC, 0x0001_0500, 0, 100, 0];
+        for (opcode, operands) in instructions {
+            words.push(((operands.len() as u32 + 1) << 16) | opcode);
+            words.extend_from_slice(operands);
+        }
+        words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    // Operands of a member name instruction
+    fn member_name(type_id: u32, i_member: u32, name: &str) -> Vec<u32> {
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.resize(name.len() / 4 * 4 + 4, 0);
+        let mut operands = vec![type_id, i_member];
+        operands.extend(
+            bytes
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap())),
+        );
+        operands
+    }
+
+    // layout(set = 2, binding = 0) uniform Options { vec2 scale; float t; uint ids[3]; }
+    // layout(set = 0, binding = 0) uniform sampler2D textures[];
+    // layout(push_constant) uniform Constants { uint a; uint b; }
+    fn test_module() -> Vec<u8> {
+        assemble(&[
+            (OP_MEMBER_NAME, member_name(10, 0, "scale")),
+            (OP_MEMBER_NAME, member_name(10, 1, "t")),
+            (OP_MEMBER_NAME, member_name(10, 2, "ids")),
+            (OP_DECORATE, vec![10, DECORATION_BLOCK]),
+            (OP_DECORATE, vec![11, DECORATION_DESCRIPTOR_SET, 2]),
+            (OP_DECORATE, vec![11, DECORATION_BINDING, 0]),
+            (OP_DECORATE, vec![5, DECORATION_ARRAY_STRIDE, 16]),
+            (OP_MEMBER_DECORATE, vec![10, 0, DECORATION_OFFSET, 0]),
+            (OP_MEMBER_DECORATE, vec![10, 1, DECORATION_OFFSET, 8]),
+            (OP_MEMBER_DECORATE, vec![10, 2, DECORATION_OFFSET, 16]),
+            (OP_DECORATE, vec![21, DECORATION_DESCRIPTOR_SET, 0]),
+            (OP_DECORATE, vec![21, DECORATION_BINDING, 0]),
+            (OP_MEMBER_DECORATE, vec![30, 0, DECORATION_OFFSET, 0]),
+            (OP_MEMBER_DECORATE, vec![30, 1, DECORATION_OFFSET, 4]),
+            (OP_TYPE_FLOAT, vec![1, 32]),
+            (OP_TYPE_INT, vec![2, 32, 0]),
+            (OP_TYPE_VECTOR, vec![3, 1, 2]),
+            (OP_CONSTANT, vec![2, 4, 3]),
+            (OP_TYPE_ARRAY, vec![5, 2, 4]),
+            (OP_TYPE_STRUCT, vec![10, 3, 1, 5]),
+            (OP_TYPE_POINTER, vec![12, STORAGE_CLASS_UNIFORM, 10]),
+            (OP_VARIABLE, vec![12, 11, STORAGE_CLASS_UNIFORM]),
+            (OP_TYPE_IMAGE, vec![17, 1, 1, 0, 0, 0, 1, 0]),
+            (OP_TYPE_SAMPLED_IMAGE, vec![18, 17]),
+            (OP_TYPE_RUNTIME_ARRAY, vec![19, 18]),
+            (
+                OP_TYPE_POINTER,
+                vec![20, STORAGE_CLASS_UNIFORM_CONSTANT, 19],
+            ),
+            (OP_VARIABLE, vec![20, 21, STORAGE_CLASS_UNIFORM_CONSTANT]),
+            (OP_TYPE_STRUCT, vec![30, 2, 2]),
+            (OP_TYPE_POINTER, vec![31, STORAGE_CLASS_PUSH_CONSTANT, 30]),
+            (OP_VARIABLE, vec![31, 32, STORAGE_CLASS_PUSH_CONSTANT]),
+        ])
+    }
+
+    #[test]
+    fn reflects_bindings_and_push_constants() {
+        let reflection = reflect_spirv(&test_module()).unwrap();
+        assert_eq!(reflection.push_constant_size, 8);
+        assert_eq!(reflection.bindings.len(), 2);
+
+        let options = &reflection.bindings[0];
+        assert_eq!((options.set, options.binding), (2, 0));
+        assert_eq!(options.descriptor_type, vk::DescriptorType::UNIFORM_BUFFER);
+        assert_eq!(options.size, 16 + 3 * 16);
+        assert_eq!(
+            options.members[2],
+            ReflectedMember {
+                name: String::from("ids"),
+                offset: 16,
+                size: 3 * 16,
+            }
+        );
+
+        let textures = &reflection.bindings[1];
+        assert_eq!((textures.set, textures.binding), (0, 0));
+        assert_eq!(
+            textures.descriptor_type,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        );
+        assert_eq!(textures.count, None);
+    }
+
+    #[test]
+    fn validates_against_the_pipeline_layout() {
+        let mut reflection = reflect_spirv(&test_module()).unwrap();
+        assert!(validate_reflection(&reflection, 8).is_ok());
+        assert!(validate_reflection(&reflection, 4).is_err());
+
+        reflection.bindings[1].binding = PER_IMAGE as u32;
+        assert!(validate_reflection(&reflection, 8).is_err());
+
+        reflection.bindings[1].set = 3;
+        assert!(validate_reflection(&reflection, 8).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_bytecode() {
+        assert!(reflect_spirv(&[0; 20]).is_err());
+        let mut truncated = test_module();
+        truncated.truncate(truncated.len() - 4);
+        assert!(reflect_spirv(&truncated).is_err());
+
+        for opcode in [OP_NAME, OP_MEMBER_NAME, OP_TYPE_STRUCT] {
+            assert!(matches!(
+                reflect_spirv(&assemble(&[(opcode, vec![])])),
+                Err(VulkanError::InvalidSpirv(_))
+            ));
+        }
+    }
+
+    pod_struct! {
+        struct TestOptions {
+            scale: [f32; 2],
+            t: f32,
+            padding: u32,
+            ids: [[u32; 4]; 3],
+        }
+    }
+
+    pod_struct! {
+        struct ReorderedOptions {
+            t: f32,
+            padding: u32,
+            scale: [f32; 2],
+            ids: [[u32; 4]; 3],
+        }
+    }
+
+    pod_struct! {
+        struct PackedOptions {
+            scale: [f32; 2],
+            t: f32,
+            ids: [u32; 3],
+        }
+    }
+
+    pod_struct! {
+        struct RenamedOptions {
+            translation: [f32; 2],
+            t: f32,
+            padding: u32,
+            ids: [[u32; 4]; 3],
+        }
+    }
+
+    #[test]
+    fn compares_the_options_with_the_rust_layout() {
+        let reflection = reflect_spirv(&test_module()).unwrap();
+        let options = &reflection.bindings[0];
+        assert_eq!(options_layout_mismatch::<TestOptions>(options), None);
+        assert!(options_layout_mismatch::<ReorderedOptions>(options).is_some());
+        assert!(options_layout_mismatch::<PackedOptions>(options).is_some());
+        assert!(options_layout_mismatch::<RenamedOptions>(options).is_some());
+
+        // Without names only the offsets and sizes are compared
+        let mut stripped = options.clone();
+        for member in &mut stripped.members {
+            member.name.clear();
+        }
+        assert_eq!(options_layout_mismatch::<RenamedOptions>(&stripped), None);
+    }
+}
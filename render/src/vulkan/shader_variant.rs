@@ -0,0 +1,75 @@
+pub const MAX_SHADER_FEATURES: usize = 16;
+
+// Feature flags declared by a program, each enabled feature is compiled as `#define FEATURE 1`
+pub type ShaderFeatures = &'static [&'static str];
+
+// Set of features enabled in a program, indexed like the program's feature list
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ShaderVariant(u32);
+
+impl ShaderVariant {
+    pub fn with_feature(self, i_feature: usize, enabled: bool) -> Self {
+        assert!(i_feature < MAX_SHADER_FEATURES);
+        if enabled {
+            Self(self.0 | (1 << i_feature))
+        } else {
+            Self(self.0 & !(1 << i_feature))
+        }
+    }
+
+    pub fn has_feature(self, i_feature: usize) -> bool {
+        i_feature < MAX_SHADER_FEATURES && self.0 & (1 << i_feature) != 0
+    }
+
+    pub fn defines(self, features: ShaderFeatures) -> Vec<(String, String)> {
+        assert!(self.0 >> features.len() == 0);
+        features
+            .iter()
+            .enumerate()
+            .filter(|(i_feature, _name)| self.has_feature(*i_feature))
+            .map(|(_i_feature, name)| (name.to_string(), String::from("1")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEATURES: ShaderFeatures = &["DEBUG", "MSAA", "WIREFRAME"];
+
+    #[test]
+    fn toggles_features() {
+        let variant = ShaderVariant::default()
+            .with_feature(0, true)
+            .with_feature(2, true)
+            .with_feature(0, false);
+        assert!(!variant.has_feature(0));
+        assert!(!variant.has_feature(1));
+        assert!(variant.has_feature(2));
+        assert_eq!(variant, ShaderVariant::default().with_feature(2, true));
+    }
+
+    #[test]
+    fn defines_enabled_features() {
+        assert!(ShaderVariant::default().defines(FEATURES).is_empty());
+        assert_eq!(
+            ShaderVariant::default()
+                .with_feature(1, true)
+                .with_feature(2, true)
+                .defines(FEATURES),
+            vec![
+                (String::from("MSAA"), String::from("1")),
+                (String::from("WIREFRAME"), String::from("1"))
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_undeclared_features() {
+        ShaderVariant::default()
+            .with_feature(3, true)
+            .defines(FEATURES);
+    }
+}
@@ -36,19 +36,89 @@ impl Surface {
         window_handle: &WindowHandle,
         size_requested: Option<[i32; 2]>,
     ) -> VulkanResult<Surface> {
+        instance.check_window_support(&window_handle.raw_window_handle())?;
+
         let surface = unsafe {
             erupt::utils::surface::create_surface(&instance.instance, window_handle, None)
         }
         .result()?;
 
-        let _graphics_present_support = unsafe {
-            instance.instance.get_physical_device_surface_support_khr(
-                physical_device.device,
-                device.graphics_family_idx,
-                surface,
-            )
+        Self::from_vk_surface(instance, device, physical_device, surface, size_requested)
+    }
+
+    // A swapchain presenting nowhere, needs an instance created with `enable_headless_surface`
+    pub fn new_headless(
+        instance: &Instance,
+        device: &mut Device,
+        physical_device: &mut PhysicalDevice,
+        size: [i32; 2],
+    ) -> VulkanResult<Surface> {
+        instance.check_headless_surface_support()?;
+
+        let surface_info = vk::HeadlessSurfaceCreateInfoEXTBuilder::new();
+        let surface = unsafe {
+            instance
+                .instance
+                .create_headless_surface_ext(&surface_info, None)
+        }
+        .result()?;
+
+        Self::from_vk_surface(instance, device, physical_device, surface, Some(size))
+    }
+
+    fn from_vk_surface(
+        instance: &Instance,
+        device: &mut Device,
+        physical_device: &mut PhysicalDevice,
+        surface: vk::SurfaceKHR,
+        size_requested: Option<[i32; 2]>,
+    ) -> VulkanResult<Surface> {
+        let (present_mode, format) =
+            Self::query_properties(instance, device, physical_device, surface)?;
+
+        let mut surface = Surface {
+            surface,
+            swapchain: vk::SwapchainKHR::null(),
+            present_mode,
+            format,
+            size: [0, 0],
+            current_image: 0,
+            previous_image: 0,
+            images: DynamicArray::new(),
+            image_acquired_semaphores: DynamicArray::new(),
+            can_present_semaphores: DynamicArray::new(),
+            is_outdated: false,
+            size_requested,
         };
 
+        surface.create_swapchain(instance, device, physical_device)?;
+
+        Ok(surface)
+    }
+
+    // Present support, present modes and formats depend on the physical device
+    fn query_properties(
+        instance: &Instance,
+        device: &Device,
+        physical_device: &PhysicalDevice,
+        surface: vk::SurfaceKHR,
+    ) -> VulkanResult<(vk::PresentModeKHR, vk::SurfaceFormatKHR)> {
+        let graphics_present_support = unsafe {
+            instance
+                .instance
+                .get_physical_device_surface_support_khr(
+                    physical_device.device,
+                    device.graphics_family_idx,
+                    surface,
+                )
+                .result()?
+        };
+        if !graphics_present_support {
+            return Err(VulkanError::NoSuitableDevice(String::from(
+                "the graphics queue cannot present to the surface",
+            )));
+        }
+
         let present_modes = unsafe {
             instance
                 .instance
@@ -93,24 +163,7 @@ impl Surface {
             }
         }
 
-        let mut surface = Surface {
-            surface,
-            swapchain: vk::SwapchainKHR::null(),
-            present_mode,
-            format,
-            size: [0, 0],
-            current_image: 0,
-            previous_image: 0,
-            images: DynamicArray::new(),
-            image_acquired_semaphores: DynamicArray::new(),
-            can_present_semaphores: DynamicArray::new(),
-            is_outdated: false,
-            size_requested,
-        };
-
-        surface.create_swapchain(instance, device, physical_device)?;
-
-        Ok(surface)
+        Ok((present_mode, format))
     }
 
     pub fn destroy(&mut self, instance: &Instance, device: &mut Device) {
@@ -181,7 +234,10 @@ impl Surface {
             device
                 .device
                 .create_swapchain_khr(&swapchain_create_info, None)
-                .result()?
+                .result()
+                .map_err(|result| {
+                    VulkanError::from_vk(result, "vkCreateSwapchainKHR", Some("swapchain"))
+                })?
         };
 
         let swapchain_images =
@@ -276,6 +332,25 @@ impl Surface {
         self.create_swapchain(instance, device, physical_device)
     }
 
+    // The surface outlives a lost device, its swapchain is created again on the new one that can be another
+    // physical device
+    pub fn move_to_device(
+        &mut self,
+        instance: &Instance,
+        lost_device: &mut Device,
+        device: &mut Device,
+        physical_device: &mut PhysicalDevice,
+    ) -> VulkanResult<()> {
+        self.destroy_swapchain(lost_device);
+        (self.present_mode, self.format) =
+            Self::query_properties(instance, device, physical_device, self.surface)?;
+        self.size_requested = self.size_requested.or(Some(self.size));
+        self.is_outdated = false;
+        self.current_image = 0;
+        self.previous_image = 0;
+        self.create_swapchain(instance, device, physical_device)
+    }
+
     pub fn current_image(&self) -> Handle<Image> {
         self.images[self.current_image as usize]
     }
@@ -0,0 +1,240 @@
+use erupt::vk;
+use std::{
+    ffi::{c_void, CStr},
+    fmt,
+    os::raw::c_char,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValidationSeverity {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+impl ValidationSeverity {
+    fn from_vk(severity: vk::DebugUtilsMessageSeverityFlagBitsEXT) -> Self {
+        match severity {
+            vk::DebugUtilsMessageSeverityFlagBitsEXT::ERROR_EXT => Self::Error,
+            vk::DebugUtilsMessageSeverityFlagBitsEXT::WARNING_EXT => Self::Warning,
+            vk::DebugUtilsMessageSeverityFlagBitsEXT::INFO_EXT => Self::Info,
+            _ => Self::Verbose,
+        }
+    }
+
+    // The severities reported by the messenger, this one and the ones above it
+    pub(crate) fn vk_flags_and_above(self) -> vk::DebugUtilsMessageSeverityFlagsEXT {
+        let mut flags = vk::DebugUtilsMessageSeverityFlagsEXT::ERROR_EXT;
+        if self <= Self::Warning {
+            flags |= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING_EXT;
+        }
+        if self <= Self::Info {
+            flags |= vk::DebugUtilsMessageSeverityFlagsEXT::INFO_EXT;
+        }
+        if self <= Self::Verbose {
+            flags |= vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE_EXT;
+        }
+        flags
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidationObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    // The name given with `Device::set_vk_name`
+    pub name: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidationMessage {
+    pub severity: ValidationSeverity,
+    pub types: vk::DebugUtilsMessageTypeFlagsEXT,
+    pub id_name: String,
+    pub id_number: i32,
+    pub message: String,
+    pub objects: Vec<ValidationObject>,
+}
+
+impl fmt::Display for ValidationMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} [{} {:#x}]: {}",
+            self.severity, self.id_name, self.id_number, self.message
+        )?;
+        for object in &self.objects {
+            write!(f, "\n    {:?} {:#x}", object.object_type, object.handle)?;
+            if let Some(name) = &object.name {
+                write!(f, " {:?}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub type ValidationLogger = Box<dyn Fn(&ValidationMessage) + Send + Sync>;
+
+// Errors recorded by the callback, a panic can't unwind through the driver so they are checked on the Rust side
+pub(crate) type ValidationErrors = Arc<Mutex<Vec<ValidationMessage>>>;
+
+// Owned by the instance, the messenger gets a pointer to it as user data
+pub(crate) struct ValidationState {
+    pub logger: Option<ValidationLogger>,
+    // Only recorded with `InstanceSpec::panic_on_validation_error`
+    pub errors: Option<ValidationErrors>,
+}
+
+pub(crate) fn take_validation_errors(errors: &ValidationErrors) -> Vec<ValidationMessage> {
+    // A panic while the lock was held doesn't invalidate the messages
+    let mut errors = errors
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    std::mem::take(&mut *errors)
+}
+
+pub(crate) fn panic_on_validation_errors(errors: &ValidationErrors) {
+    let errors = take_validation_errors(errors);
+    if !errors.is_empty() {
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        panic!("validation errors:\n{}", messages.join("\n"));
+    }
+}
+
+fn optional_str(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(
+            unsafe { CStr::from_ptr(ptr) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+pub(crate) unsafe extern "system" fn debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagBitsEXT,
+    message_types: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    p_user_data: *mut c_void,
+) -> vk::Bool32 {
+    let state = &*(p_user_data as *const ValidationState);
+    let callback_data = &*p_callback_data;
+
+    let objects = if callback_data.p_objects.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
+    };
+
+    let message = ValidationMessage {
+        severity: ValidationSeverity::from_vk(message_severity),
+        types: message_types,
+        id_name: optional_str(callback_data.p_message_id_name).unwrap_or_default(),
+        id_number: callback_data.message_id_number,
+        message: optional_str(callback_data.p_message).unwrap_or_default(),
+        objects: objects
+            .iter()
+            .map(|object| ValidationObject {
+                object_type: object.object_type,
+                handle: object.object_handle,
+                name: optional_str(object.p_object_name),
+            })
+            .collect(),
+    };
+
+    match &state.logger {
+        Some(logger) => logger(&message),
+        None => eprintln!("{}", message),
+    }
+
+    if let Some(errors) = &state.errors {
+        if message.severity == ValidationSeverity::Error {
+            if let Ok(mut errors) = errors.lock() {
+                errors.push(message);
+            }
+        }
+    }
+
+    vk::FALSE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severity_flags_include_higher_severities() {
+        assert_eq!(
+            ValidationSeverity::Error.vk_flags_and_above(),
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR_EXT
+        );
+        assert_eq!(
+            ValidationSeverity::Info.vk_flags_and_above(),
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR_EXT
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING_EXT
+                | vk::DebugUtilsMessageSeverityFlagsEXT::INFO_EXT
+        );
+    }
+
+    #[test]
+    fn formats_message_with_objects() {
+        let message = ValidationMessage {
+            severity: ValidationSeverity::Error,
+            types: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION_EXT,
+            id_name: String::from("VUID-vkCmdDraw-None-02699"),
+            id_number: 0x1b,
+            message: String::from("descriptor not updated"),
+            objects: vec![
+                ValidationObject {
+                    object_type: vk::ObjectType::COMMAND_BUFFER,
+                    handle: 0x10,
+                    name: Some(String::from("graphics context")),
+                },
+                ValidationObject {
+                    object_type: vk::ObjectType::PIPELINE,
+                    handle: 0x20,
+                    name: None,
+                },
+            ],
+        };
+        assert_eq!(
+            message.to_string(),
+            "Error [VUID-vkCmdDraw-None-02699 0x1b]: descriptor not updated\n    COMMAND_BUFFER 0x10 \"graphics context\"\n    PIPELINE 0x20"
+        );
+    }
+
+    fn error(message: &str) -> ValidationMessage {
+        ValidationMessage {
+            severity: ValidationSeverity::Error,
+            types: vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION_EXT,
+            id_name: String::new(),
+            id_number: 0,
+            message: String::from(message),
+            objects: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn taking_errors_empties_them() {
+        let errors = ValidationErrors::default();
+        errors.lock().unwrap().push(error("first"));
+        errors.lock().unwrap().push(error("second"));
+        let taken = take_validation_errors(&errors);
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[1].message, "second");
+        assert!(take_validation_errors(&errors).is_empty());
+        panic_on_validation_errors(&errors);
+    }
+
+    #[test]
+    #[should_panic(expected = "descriptor not updated")]
+    fn recorded_errors_panic_on_the_rust_side() {
+        let errors = ValidationErrors::default();
+        errors.lock().unwrap().push(error("descriptor not updated"));
+        panic_on_validation_errors(&errors);
+    }
+}
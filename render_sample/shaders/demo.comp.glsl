@@ -273,6 +273,11 @@ void main()
 		R = pixel_size_ws * 10000.0;
 	}
 
+#ifdef DEBUG
+	// Heatmap of the steps taken by the ray march, misses included
+	R = TurboColormap(inter_steps / 200.0);
+#endif
+
 	float4 output_color = float4(1.0);
 	output_color.rgb = float3(R);
 	output_color.a = 1.0;
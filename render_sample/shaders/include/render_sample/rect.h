@@ -30,8 +30,15 @@ struct TexturedRect
 	u32 i_clip_rect;
 	f32 border_radius;
 	u32 base_color;
+	u32 texture_filter;
+	u32 padding0;
+	u32 padding1;
+	u32 padding2;
 };
-const u32 sizeof_textured_rect = 3 * sizeof_float4;
+const u32 sizeof_textured_rect = 4 * sizeof_float4;
+
+const u32 TextureFilter_Nearest = 0;
+const u32 TextureFilter_Linear = 1;
 
 const u32 RectType_Color = 0;
 const u32 RectType_Textured = 1;
@@ -28,6 +28,8 @@ mod custom_ui {
 
     pub mod widgets {
         use drawer2d::{drawer::*, rect::*};
+        use exo::pool::Handle;
+        use render::vulkan::Shader;
 
         pub struct FpsHistogram<'a> {
             pub histogram: &'a super::FpsHistogram,
@@ -115,18 +117,77 @@ mod custom_ui {
                 ColorU32::greyscale(255),
             );
         }
+
+        fn draw_left_label(
+            ui: &ui::Ui,
+            drawer: &mut Drawer,
+            label: &str,
+            rect: Rect,
+            color: ColorU32,
+        ) {
+            let (label_run, label_layout) = drawer.shape_and_layout_text(&ui.theme.face(), label);
+            let pos = [rect.pos[0], Rect::center(rect, label_layout.size()).pos[1]];
+            drawer.draw_text_run(&label_run, &label_layout, pos, 0, color);
+        }
+
+        pub struct ShaderErrors<'a> {
+            pub errors: &'a [(Handle<Shader>, String)],
+            pub rect: Rect,
+        }
+
+        // One line per line of the compiler logs, the lines that don't fit are dropped
+        pub fn shader_errors(ui: &mut ui::Ui, drawer: &mut Drawer, widget: ShaderErrors) {
+            let em = ui.em();
+            let line_height = 1.5 * em;
+
+            drawer.draw_colored_rect(
+                ColoredRect::new(widget.rect).color(ColorU32::from_f32(0.3, 0.0, 0.0, 0.8)),
+            );
+            ui.state.add_rect_to_last_container(widget.rect);
+
+            let mut lines_rect = widget.rect.inset(0.5 * em);
+            for line in widget
+                .errors
+                .iter()
+                .flat_map(|(_shader, error)| error.lines())
+            {
+                if lines_rect.size[1] < line_height {
+                    break;
+                }
+                let line_rect = lines_rect.split_top(line_height);
+                draw_left_label(ui, drawer, line, line_rect, ColorU32::greyscale(255));
+            }
+        }
     }
 }
 
 mod custom_render {
     use drawer2d::drawer::Drawer;
-    use exo::{dynamic_array::DynamicArray, pool::Handle};
+    use exo::{dynamic_array::DynamicArray, pod_struct, pool::Handle};
     use render::{bindings, render_graph::graph::*, shader_path, vk, vulkan};
-    use std::{cell::RefCell, mem::size_of, rc::Rc};
+    use std::{cell::RefCell, rc::Rc};
 
     pub struct UiPass {
         pub glyph_atlas: Handle<vulkan::Image>,
+        // Tints every primitive to show the overdraw
+        pub debug: bool,
         ui_program: Handle<vulkan::GraphicsProgram>,
+        samples: vk::SampleCountFlagBits,
+    }
+
+    const UI_FEATURES: vulkan::ShaderFeatures = &["DEBUG"];
+    const UI_FEATURE_DEBUG: usize = 0;
+
+    pod_struct! {
+        struct UiOptions {
+            pub scale: [f32; 2],
+            pub translation: [f32; 2],
+            pub vertices_descriptor_index: u32,
+            pub primitive_bytes_offset: u32,
+            pub glyph_atlas_descriptor: u32,
+            pub nearest_sampler_descriptor: u32,
+            pub linear_sampler_descriptor: u32,
+        }
     }
 
     impl UiPass {
@@ -134,32 +195,29 @@ mod custom_render {
             device: &mut vulkan::Device,
             glyph_atlas_size: [i32; 2],
         ) -> vulkan::VulkanResult<Self> {
+            // Multisampling keeps the edges of the ui shapes smooth
+            let samples = device.supported_sample_count(vk::SampleCountFlagBits::_4);
+            let vertex_shader = device.create_shader(shader_path!("ui.vert.glsl"))?;
+            let fragment_shader = device.create_shader(shader_path!("ui.frag.glsl"))?;
+
             let ui_gfx_state = vulkan::GraphicsState {
-                vertex_shader: device.create_shader(shader_path!("ui.vert.spv")).unwrap(),
-                fragment_shader: device.create_shader(shader_path!("ui.frag.spv")).unwrap(),
+                vertex_shader,
+                fragment_shader,
                 attachments_format: vulkan::FramebufferFormat {
                     attachment_formats: DynamicArray::from([vk::Format::R8G8B8A8_UNORM]),
+                    samples,
+                    resolve: samples != vk::SampleCountFlagBits::_1,
                     ..Default::default()
                 },
+                features: UI_FEATURES,
             };
 
             let ui_program = device.create_graphics_program(ui_gfx_state)?;
             device.compile_graphics_program(
                 ui_program,
                 vulkan::RenderState {
-                    depth: vulkan::DepthState {
-                        test: None,
-                        enable_write: false,
-                        bias: 0.0,
-                    },
-                    rasterization: vulkan::RasterizationState {
-                        enable_conservative_rasterization: false,
-                        culling: false,
-                    },
-                    input_assembly: vulkan::InputAssemblyState {
-                        topology: vulkan::PrimitiveTopology::TriangleList,
-                    },
-                    alpha_blending: true,
+                    blend_states: DynamicArray::from([vulkan::BlendState::premultiplied_alpha()]),
+                    ..Default::default()
                 },
             )?;
 
@@ -177,18 +235,34 @@ mod custom_render {
 
             Ok(Self {
                 glyph_atlas,
+                debug: false,
                 ui_program,
+                samples,
             })
         }
 
+        pub fn destroy(&self, device: &mut vulkan::Device) {
+            let graphics_state = &device.graphics_programs.get(self.ui_program).graphics_state;
+            let shaders = [graphics_state.vertex_shader, graphics_state.fragment_shader];
+            device.destroy_program(self.ui_program);
+            for shader in shaders {
+                device.destroy_shader(shader);
+            }
+            device.destroy_image(self.glyph_atlas);
+        }
+
         pub fn register_graph(
             &self,
             graph: &mut RenderGraph,
             output: Handle<TextureDesc>,
+            // Textures drawn by the UI, like the output of an async pass
+            sampled_textures: &[Handle<TextureDesc>],
             drawer: &Rc<Drawer<'static>>,
         ) {
             let glyph_atlas = self.glyph_atlas;
             let ui_program = self.ui_program;
+            let ui_variant =
+                vulkan::ShaderVariant::default().with_feature(UI_FEATURE_DEBUG, self.debug);
             let drawer = Rc::clone(drawer);
             let drawer2 = Rc::clone(&drawer);
 
@@ -199,47 +273,36 @@ mod custom_render {
                 use drawer2d::glyph_cache::GlyphEvent;
                 let drawer = Rc::clone(&drawer);
 
-                let mut glyphs_to_upload: Vec<vulkan::BufferImageCopy> = Vec::with_capacity(32);
+                let mut rejected_size = None;
                 drawer
                     .glyph_cache()
                     .process_events(|cache_event, glyph_image, glyph_atlas_pos| {
-                        // Copy new glyphs to the upload buffer
+                        // Stage the new glyphs, they are copied in a single batch
                         if let GlyphEvent::New(_, _) = cache_event {
                             if let Some(atlas_pos) = glyph_atlas_pos {
                                 let image = glyph_image.unwrap();
-                                let (slice, offset) =
-                                    api.upload_buffer.allocate(image.data.len(), 256);
-                                unsafe {
-                                    (*slice).copy_from_slice(&image.data);
+                                if !api.upload_manager.upload_image_region(
+                                    api.device,
+                                    glyph_atlas,
+                                    [atlas_pos[0], atlas_pos[1], 0],
+                                    [image.placement.width, image.placement.height, 1],
+                                    &image.data,
+                                ) {
+                                    rejected_size.get_or_insert(image.data.len());
                                 }
-
-                                let image_offset = [atlas_pos[0], atlas_pos[1], 0];
-
-                                glyphs_to_upload.push(vulkan::BufferImageCopy {
-                                    buffer_offset: offset as u64,
-                                    buffer_size: image.data.len() as u32,
-                                    image_offset,
-                                    image_extent: [
-                                        image.placement.width as u32,
-                                        image.placement.height as u32,
-                                        1,
-                                    ],
-                                });
                             }
                         }
                     });
-                if !glyphs_to_upload.is_empty() {
-                    ctx.base_context().barrier(
-                        api.device,
-                        glyph_atlas,
-                        vulkan::ImageState::TransferDst,
-                    );
-                    ctx.transfer_mut().copy_buffer_to_image(
-                        api.device,
-                        api.upload_buffer.buffer,
-                        glyph_atlas,
-                        &glyphs_to_upload,
-                    );
+                if let Some(requested) = rejected_size {
+                    return Err(vulkan::VulkanError::RingBufferFull {
+                        requested,
+                        capacity: api.upload_manager.capacity(),
+                    });
+                }
+
+                if api.upload_manager.has_pending_uploads() {
+                    api.upload_manager
+                        .record_uploads(api.device, ctx.transfer_mut());
                     ctx.base_context().barrier(
                         api.device,
                         glyph_atlas,
@@ -254,96 +317,128 @@ mod custom_render {
             let drawer = drawer2;
             let execute = move |graph: &mut RenderGraph,
                                 api: &mut PassApi,
-                                ctx: &mut vulkan::GraphicsContext| {
+                                ctx: &mut vulkan::GraphicsContext|
+                  -> vulkan::VulkanResult<()> {
                 let vertices = drawer.get_vertices();
-                let (slice, vertices_offset) = api
-                    .dynamic_vertex_buffer
-                    .allocate(vertices.len(), Drawer::get_primitive_alignment());
-                unsafe {
-                    (*slice).copy_from_slice(vertices);
-                }
+                let vertices_allocation = api.dynamic_vertex_buffer.allocate_slice_aligned::<u8>(
+                    api.device,
+                    vertices.len(),
+                    Drawer::get_primitive_alignment(),
+                )?;
+                vertices_allocation.data.copy_from_slice(vertices);
+                let (vertices_buffer, vertices_offset) =
+                    (vertices_allocation.buffer, vertices_allocation.offset);
+
                 let indices = drawer.get_indices();
-                let indices_byte_length = indices.len() * size_of::<u32>();
-                let (slice, indices_offset) = api
+                let indices_allocation = api
                     .dynamic_index_buffer
-                    .allocate(indices_byte_length, size_of::<u32>());
-                unsafe {
-                    let gpu_indices = std::slice::from_raw_parts_mut(
-                        (*slice).as_mut_ptr() as *mut u32,
-                        (*slice).len() / size_of::<u32>(),
-                    );
-                    gpu_indices.copy_from_slice(indices);
-                }
-                #[repr(C, packed)]
-                struct Options {
-                    pub scale: [f32; 2],
-                    pub translation: [f32; 2],
-                    pub vertices_descriptor_index: u32,
-                    pub primitive_bytes_offset: u32,
-                    pub glyph_atlas_descriptor: u32,
-                }
+                    .allocate_slice::<u32>(api.device, indices.len())?;
+                indices_allocation.data.copy_from_slice(indices);
+                let (indices_buffer, indices_offset) =
+                    (indices_allocation.buffer, indices_allocation.offset);
 
-                let options = bindings::bind_shader_options(
+                let output_size = graph.image_size(output);
+                let glyph_atlas_descriptor =
+                    api.device.images.get(glyph_atlas).full_view.sampled_idx;
+                let nearest_sampler_descriptor = api
+                    .device
+                    .get_or_create_sampler(vulkan::SamplerSpec::nearest())?;
+                let linear_sampler_descriptor = api
+                    .device
+                    .get_or_create_sampler(vulkan::SamplerSpec::linear())?;
+                let options = UiOptions {
+                    scale: [2.0 / (output_size[0] as f32), 2.0 / (output_size[1] as f32)],
+                    translation: [-1.0, -1.0],
+                    vertices_descriptor_index: api.device.buffers.get(vertices_buffer).storage_idx,
+                    primitive_bytes_offset: vertices_offset,
+                    glyph_atlas_descriptor,
+                    nearest_sampler_descriptor,
+                    linear_sampler_descriptor,
+                };
+                bindings::bind_and_copy_shader_options(
                     api.device,
                     api.uniform_buffer,
                     &ctx,
-                    size_of::<Options>(),
-                )
-                .unwrap();
+                    options,
+                )?;
 
-                let output_size = graph.image_size(output);
-                let glyph_atlas_descriptor =
-                    api.device.images.get(glyph_atlas).full_view.sampled_idx;
-                unsafe {
-                    let p_options =
-                        std::slice::from_raw_parts_mut((*options).as_ptr() as *mut Options, 1);
-                    p_options[0] = Options {
-                        scale: [2.0 / (output_size[0] as f32), 2.0 / (output_size[1] as f32)],
-                        translation: [-1.0, -1.0],
-                        vertices_descriptor_index: api
-                            .device
-                            .buffers
-                            .get(api.dynamic_vertex_buffer.buffer)
-                            .storage_idx,
-                        primitive_bytes_offset: vertices_offset,
-                        glyph_atlas_descriptor,
-                    };
-                }
                 ctx.bind_index_buffer(
                     api.device,
-                    api.dynamic_index_buffer.buffer,
+                    indices_buffer,
                     vk::IndexType::UINT32,
                     indices_offset as usize,
                 );
-                ctx.bind_graphics_pipeline(api.device, ui_program, 0);
+                ctx.bind_graphics_variant(api.device, ui_program, 0, ui_variant)?;
                 ctx.draw_indexed(
                     api.device,
                     vulkan::DrawIndexedOptions {
                         vertex_count: indices.len() as u32,
                         ..Default::default()
                     },
-                );
+                )?;
+
+                Ok(())
             };
 
-            graph.graphics_pass(output, execute);
+            for texture in sampled_textures {
+                graph.use_texture(*texture, vk::ImageUsageFlags::SAMPLED);
+            }
+            let attachment = if self.samples != vk::SampleCountFlagBits::_1 {
+                let multisampled_output = graph.output_image(
+                    TextureDesc::new(
+                        String::from("ui multisampled"),
+                        TextureSize::Absolute(graph.image_size(output)),
+                    )
+                    .samples(self.samples),
+                );
+                Attachment::new(multisampled_output).resolve(output)
+            } else {
+                Attachment::new(output)
+            };
+            graph.graphics_pass(attachment, execute);
         }
     }
 
     pub struct DemoNode {
         program: Handle<vulkan::ComputeProgram>,
         resolved_output_descriptor: u32,
+        // Shows the number of ray marching steps
+        pub debug: bool,
+    }
+
+    const DEMO_FEATURES: vulkan::ShaderFeatures = &["DEBUG"];
+    const DEMO_FEATURE_DEBUG: usize = 0;
+
+    pod_struct! {
+        struct DemoOptions {
+            pub storage_output_frame: u32,
+            pub i_frame: u32,
+            pub dt: f32,
+            pub t: f32,
+        }
     }
 
     impl DemoNode {
         pub fn new(device: &mut vulkan::Device) -> vulkan::VulkanResult<DemoNode> {
-            let shader_handle = device.create_shader(shader_path!("demo.comp.spv"))?;
+            let shader_handle = device.create_shader(shader_path!("demo.comp.glsl"))?;
             let node = DemoNode {
-                program: device.create_compute_program(String::from("demo"), shader_handle)?,
+                program: device.create_compute_program(
+                    String::from("demo"),
+                    shader_handle,
+                    DEMO_FEATURES,
+                )?,
                 resolved_output_descriptor: 1,
+                debug: false,
             };
             Ok(node)
         }
 
+        pub fn destroy(&self, device: &mut vulkan::Device) {
+            let shader = device.compute_programs.get(self.program).shader;
+            device.destroy_compute_program(self.program);
+            device.destroy_shader(shader);
+        }
+
         pub fn output_descriptor(&self) -> u32 {
             self.resolved_output_descriptor
         }
@@ -356,8 +451,15 @@ mod custom_render {
             t: f32,
         ) {
             let demo_program = pass.borrow().program;
+            let demo_variant = vulkan::ShaderVariant::default()
+                .with_feature(DEMO_FEATURE_DEBUG, pass.borrow().debug);
             let pass = Rc::clone(pass);
 
+            graph.use_texture(
+                output,
+                vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            );
+
             let execute = move |graph: &mut RenderGraph,
                                 api: &mut PassApi,
                                 ctx: &mut vulkan::ComputeContext|
@@ -371,31 +473,17 @@ mod custom_render {
                         api.device.images.get(output_image).full_view.sampled_idx;
                     pass.borrow_mut().resolved_output_descriptor = output_sampled_descriptor;
 
-                    #[repr(C, packed)]
-                    struct Options {
-                        pub storage_output_frame: u32,
-                        pub i_frame: u32,
-                        pub dt: f32,
-                        pub t: f32,
-                    }
-
-                    let options = bindings::bind_shader_options(
+                    bindings::bind_and_copy_shader_options(
                         api.device,
                         api.uniform_buffer,
                         &ctx,
-                        size_of::<Options>(),
-                    )
-                    .unwrap();
-                    unsafe {
-                        let p_options =
-                            std::slice::from_raw_parts_mut((*options).as_ptr() as *mut Options, 1);
-                        p_options[0] = Options {
+                        DemoOptions {
                             storage_output_frame: output_descriptor,
                             i_frame: graph.i_frame() as u32,
                             dt,
                             t,
-                        };
-                    }
+                        },
+                    )?;
 
                     ctx.base_context().barrier(
                         api.device,
@@ -403,7 +491,7 @@ mod custom_render {
                         vulkan::ImageState::ComputeShaderReadWrite,
                     );
 
-                    ctx.bind_compute_pipeline(api.device, demo_program);
+                    ctx.bind_compute_variant(api.device, demo_program, demo_variant)?;
 
                     let output_size = graph.resources.texture_desc_handle_size(output);
                     let size = [
@@ -411,7 +499,7 @@ mod custom_render {
                         ((output_size[1] as u32) / 16) + 1,
                         1,
                     ];
-                    ctx.dispatch(api.device, size);
+                    ctx.dispatch(api.device, size)?;
                 }
 
                 Ok(())
@@ -421,11 +509,50 @@ mod custom_render {
     }
 }
 
+mod headless {
+    use super::App;
+    use anyhow::Context;
+    use std::path::Path;
+
+    pub const HEADLESS_RESOLUTION: [i32; 2] = [1280, 720];
+    // The first frames lay out the docking and upload the glyphs
+    pub const HEADLESS_FRAME_COUNT: usize = 3;
+    const HEADLESS_DT: f32 = 1.0 / 60.0;
+
+    // Renders a few frames without a window and writes the last one to a PNG
+    pub fn run_headless(output_path: &str) -> anyhow::Result<()> {
+        let mut app = App::new_headless(HEADLESS_RESOLUTION)?;
+
+        for _ in 0..HEADLESS_FRAME_COUNT {
+            app.update(HEADLESS_DT)?;
+        }
+        let pixels = app.renderer.read_pixels()?;
+        write_png(Path::new(output_path), HEADLESS_RESOLUTION, &pixels)?;
+
+        app.renderer.destroy();
+        Ok(())
+    }
+
+    pub fn write_png(path: &Path, size: [i32; 2], rgba8_pixels: &[u8]) -> anyhow::Result<()> {
+        let file = std::io::BufWriter::new(
+            std::fs::File::create(path).with_context(|| format!("creating {:?}", path))?,
+        );
+        let mut encoder = png::Encoder::new(file, size[0] as u32, size[1] as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(rgba8_pixels)?;
+        Ok(())
+    }
+}
+
 use drawer2d::{drawer::*, font::*, rect::*};
-use exo::dynamic_array::DynamicArray;
+use exo::{dynamic_array::DynamicArray, pool::Handle};
 use raw_window_handle::HasRawWindowHandle;
-use render::{render_graph, ring_buffer::*, shader, vk, vulkan, vulkan::error::VulkanResult};
-use std::{cell::RefCell, ffi::CStr, os::raw::c_char, rc::Rc, time::Instant};
+use render::{
+    render_graph, ring_buffer::*, shader, upload::UploadManager, vk, vulkan,
+    vulkan::error::VulkanResult,
+};
+use std::{cell::RefCell, rc::Rc, time::Instant};
 use winit::{
     event::{ElementState, Event, MouseButton, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
@@ -434,10 +561,41 @@ use winit::{
 };
 
 const FRAME_QUEUE_LENGTH: usize = 2;
+const UPLOAD_STAGING_SIZE: usize = 32 << 20;
 static mut DRAWER_VERTEX_MEMORY: [u8; 64 << 10] = [0; 64 << 10];
 static mut DRAWER_INDEX_MEMORY: [u32; 8 << 10] = [0; 8 << 10];
 const GLYPH_ATLAS_RESOLUTION: i32 = 4096;
 
+// The node presenting or reading back the final image of a frame
+enum OutputNode {
+    Swapchain(Rc<RefCell<render_graph::builtins::SwapchainPass>>),
+    Offscreen(Rc<RefCell<render_graph::builtins::OffscreenPass>>),
+}
+
+impl OutputNode {
+    fn i_frame(&self) -> usize {
+        match self {
+            Self::Swapchain(node) => node.borrow().i_frame,
+            Self::Offscreen(node) => node.borrow().i_frame,
+        }
+    }
+
+    fn wait_for_frame(&self, device: &vulkan::Device, wait_value: u64) -> VulkanResult<()> {
+        let wait_values = [wait_value];
+        match self {
+            Self::Swapchain(node) => device.wait_for_fences(&[&node.borrow().fence], &wait_values),
+            Self::Offscreen(node) => device.wait_for_fences(&[&node.borrow().fence], &wait_values),
+        }
+    }
+}
+
+// Next to the executable, the working directory can be anywhere
+fn pipeline_cache_path() -> Option<std::path::PathBuf> {
+    std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("pipeline_cache.bin"))
+}
+
 struct Renderer {
     instance: vulkan::Instance,
     physical_devices: DynamicArray<vulkan::PhysicalDevice, { vulkan::MAX_PHYSICAL_DEVICES }>,
@@ -447,14 +605,16 @@ struct Renderer {
     uniform_buffer: RingBuffer,
     dynamic_vertex_buffer: RingBuffer,
     dynamic_index_buffer: RingBuffer,
-    upload_buffer: RingBuffer,
+    upload_manager: UploadManager,
     render_graph: render_graph::graph::RenderGraph,
     ui_node: custom_render::UiPass,
-    swapchain_node: Rc<RefCell<render_graph::builtins::SwapchainPass>>,
+    output_node: OutputNode,
     demo_node: Rc<RefCell<custom_render::DemoNode>>,
     frame_count: usize,
     time: f32,
     shader_watcher: shader::ShaderWatcher,
+    // Shaders whose last reload failed, they keep running their previous version
+    shader_errors: Vec<(Handle<vulkan::Shader>, String)>,
 }
 
 impl Renderer {
@@ -464,52 +624,15 @@ impl Renderer {
     ) -> vulkan::VulkanResult<Self> {
         let instance = vulkan::Instance::new(vulkan::InstanceSpec {
             enable_validation: cfg!(debug_assertions),
+            window_handle: Some(window_handle.raw_window_handle()),
             ..Default::default()
         })?;
-        let mut physical_devices = instance.get_physical_devices()?;
-
-        let mut i_selected = None;
-        for (i_device, physical_device) in (&physical_devices).into_iter().enumerate() {
-            let device_name =
-                unsafe { CStr::from_ptr(&physical_device.properties.device_name as *const c_char) };
-            println!("Found device: {:?}", device_name);
-            if i_selected.is_none()
-                && physical_device.properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
-            {
-                println!(
-                    "Prioritizing device {:?} because it is a discrete GPU.",
-                    device_name
-                );
-                i_selected = Some(i_device);
-            }
-        }
-
-        if i_selected.is_none() {
-            i_selected = Some(0);
-            let device_name = unsafe {
-                CStr::from_ptr(&physical_devices[0].properties.device_name as *const c_char)
-            };
-            println!(
-                "No discrete GPU found, defaulting to device #0 {:?}.",
-                device_name
-            )
-        }
-
-        let i_selected = i_selected.unwrap();
-        let physical_device = &mut physical_devices[i_selected];
-
-        let mut device = vulkan::Device::new(
-            &instance,
-            vulkan::DeviceSpec {
-                push_constant_size: 8,
-            },
-            physical_device,
-        )?;
+        let (mut physical_devices, i_selected, mut device) = Self::create_device(&instance)?;
 
         let surface = vulkan::Surface::new(
             &instance,
             &mut device,
-            physical_device,
+            &mut physical_devices[i_selected],
             window_handle,
             Some(window_size),
         )?;
@@ -519,6 +642,73 @@ impl Renderer {
             surface,
         }));
 
+        Self::from_device(
+            instance,
+            physical_devices,
+            i_selected,
+            device,
+            OutputNode::Swapchain(swapchain_node),
+        )
+    }
+
+    // Renders without a window, see `read_pixels` to get the result of a frame
+    pub fn new_headless(size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        let instance = vulkan::Instance::new(vulkan::InstanceSpec {
+            enable_validation: cfg!(debug_assertions),
+            enable_graphic_windows: false,
+            ..Default::default()
+        })?;
+        let (physical_devices, i_selected, mut device) = Self::create_device(&instance)?;
+
+        let offscreen_node = Rc::new(RefCell::new(render_graph::builtins::OffscreenPass::new(
+            &mut device,
+            size,
+        )?));
+
+        Self::from_device(
+            instance,
+            physical_devices,
+            i_selected,
+            device,
+            OutputNode::Offscreen(offscreen_node),
+        )
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn create_device(
+        instance: &vulkan::Instance,
+    ) -> VulkanResult<(
+        DynamicArray<vulkan::PhysicalDevice, { vulkan::MAX_PHYSICAL_DEVICES }>,
+        usize,
+        vulkan::Device,
+    )> {
+        let mut physical_devices = instance.get_physical_devices()?;
+
+        let i_selected = vulkan::select_physical_device_from_env(
+            &physical_devices,
+            &vulkan::PhysicalDeviceRequirements::default(),
+        )?;
+        let physical_device = &mut physical_devices[i_selected];
+
+        let device = vulkan::Device::new(
+            instance,
+            vulkan::DeviceSpec {
+                push_constant_size: 8,
+                pipeline_cache_path: pipeline_cache_path(),
+            },
+            physical_device,
+        )?;
+
+        Ok((physical_devices, i_selected, device))
+    }
+
+    fn from_device(
+        instance: vulkan::Instance,
+        physical_devices: DynamicArray<vulkan::PhysicalDevice, { vulkan::MAX_PHYSICAL_DEVICES }>,
+        i_device: usize,
+        mut device: vulkan::Device,
+        output_node: OutputNode,
+    ) -> VulkanResult<Self> {
         let demo_node = Rc::new(RefCell::new(custom_render::DemoNode::new(&mut device)?));
 
         let context_pools: [vulkan::ContextPool; FRAME_QUEUE_LENGTH] =
@@ -531,6 +721,7 @@ impl Renderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 1024,
+                allow_growth: true,
             },
         )?;
 
@@ -541,6 +732,7 @@ impl Renderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 128 << 10,
+                allow_growth: true,
             },
         )?;
 
@@ -551,18 +743,12 @@ impl Renderer {
                 memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
                 frame_queue_length: FRAME_QUEUE_LENGTH,
                 buffer_size: 128 << 10,
+                allow_growth: true,
             },
         )?;
 
-        let upload_buffer = RingBuffer::new(
-            &mut device,
-            RingBufferSpec {
-                usages: vk::BufferUsageFlags::TRANSFER_SRC,
-                memory_usage: vulkan::buffer::MemoryUsageFlags::CpuToGpu,
-                frame_queue_length: FRAME_QUEUE_LENGTH,
-                buffer_size: 32 << 20,
-            },
-        )?;
+        let upload_manager =
+            UploadManager::new(&mut device, UPLOAD_STAGING_SIZE, FRAME_QUEUE_LENGTH)?;
 
         let render_graph = render_graph::graph::RenderGraph::new();
         let ui_node = custom_render::UiPass::new(
@@ -577,35 +763,116 @@ impl Renderer {
             instance,
             physical_devices,
             device,
-            i_device: i_selected,
+            i_device,
             context_pools,
             uniform_buffer,
             dynamic_vertex_buffer,
             dynamic_index_buffer,
-            upload_buffer,
+            upload_manager,
             ui_node,
             render_graph,
-            swapchain_node,
+            output_node,
             demo_node,
             frame_count: 0,
             time: 0.0,
             shader_watcher,
+            shader_errors: Vec::new(),
         })
     }
 
+    // Recreates the device and everything created from it, the instance and the surface are kept
+    pub fn recover_from_device_lost(&mut self) -> vulkan::VulkanResult<()> {
+        let (mut physical_devices, i_device, device) = Self::create_device(&self.instance)?;
+
+        // Destroying objects is still valid on a lost device, waiting for it is not
+        let mut lost_device = std::mem::replace(&mut self.device, device);
+        match &self.output_node {
+            OutputNode::Swapchain(node) => {
+                let mut node = node.borrow_mut();
+                lost_device.destroy_fence(&node.fence);
+                node.fence = self.device.create_fence()?;
+                node.i_frame = 0;
+                node.surface.move_to_device(
+                    &self.instance,
+                    &mut lost_device,
+                    &mut self.device,
+                    &mut physical_devices[i_device],
+                )?;
+            }
+            OutputNode::Offscreen(node) => {
+                let size = node.borrow().size;
+                let lost_node = node.replace(render_graph::builtins::OffscreenPass::new(
+                    &mut self.device,
+                    size,
+                )?);
+                lost_node.destroy(&mut lost_device);
+            }
+        }
+
+        std::mem::replace(
+            &mut self.render_graph,
+            render_graph::graph::RenderGraph::new(),
+        )
+        .destroy(&mut lost_device);
+
+        let context_pools = [
+            self.device.create_context_pool()?,
+            self.device.create_context_pool()?,
+        ];
+        for context_pool in std::mem::replace(&mut self.context_pools, context_pools) {
+            lost_device.destroy_context_pool(context_pool);
+        }
+
+        for ring_buffer in [
+            &mut self.uniform_buffer,
+            &mut self.dynamic_vertex_buffer,
+            &mut self.dynamic_index_buffer,
+        ] {
+            let new_ring_buffer = RingBuffer::new(&mut self.device, ring_buffer.spec().clone())?;
+            std::mem::replace(ring_buffer, new_ring_buffer).destroy(&mut lost_device);
+        }
+        let upload_manager =
+            UploadManager::new(&mut self.device, UPLOAD_STAGING_SIZE, FRAME_QUEUE_LENGTH)?;
+        std::mem::replace(&mut self.upload_manager, upload_manager).destroy(&mut lost_device);
+
+        // The passes' programs and images belonged to the lost device
+        self.ui_node.destroy(&mut lost_device);
+        self.demo_node.borrow().destroy(&mut lost_device);
+        lost_device.destroy_lost();
+
+        let debug = self.ui_node.debug;
+        self.ui_node = custom_render::UiPass::new(
+            &mut self.device,
+            [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
+        )?;
+        self.ui_node.debug = debug;
+        self.demo_node = Rc::new(RefCell::new(custom_render::DemoNode::new(
+            &mut self.device,
+        )?));
+        self.demo_node.borrow_mut().debug = debug;
+
+        self.physical_devices = physical_devices;
+        self.i_device = i_device;
+        self.shader_errors.clear();
+        Ok(())
+    }
+
     pub fn destroy(mut self) {
         self.device.wait_idle().unwrap();
 
-        self.device
-            .destroy_fence(&self.swapchain_node.borrow().fence);
+        self.render_graph.destroy(&mut self.device);
         for context_pool in self.context_pools {
             self.device.destroy_context_pool(context_pool);
         }
 
-        self.swapchain_node
-            .borrow_mut()
-            .surface
-            .destroy(&self.instance, &mut self.device);
+        match &self.output_node {
+            OutputNode::Swapchain(node) => {
+                let mut node = node.borrow_mut();
+                self.device.destroy_fence(&node.fence);
+                node.surface.destroy(&self.instance, &mut self.device);
+            }
+            OutputNode::Offscreen(node) => node.borrow().destroy(&mut self.device),
+        }
 
         self.device.destroy();
         self.instance.destroy();
@@ -625,16 +892,15 @@ impl Renderer {
         profile::next_frame();
         profile::scope!("render");
 
-        let i_frame = {
-            let b = self.swapchain_node.borrow();
-            b.i_frame
-        };
+        let i_frame = self.output_node.i_frame();
+        let signal_value = (i_frame + FRAME_QUEUE_LENGTH) as u64;
 
         let intermediate_buffer = self.render_graph.output_image(TextureDesc::new(
             String::from("render buffer desc"),
             TextureSize::ScreenRelative([1.0, 1.0]),
         ));
 
+        let mut sampled_textures = Vec::new();
         if let Some(viewport_size) = demo_viewport {
             let demo_buffer = self.render_graph.output_image(TextureDesc::new(
                 String::from("demo viewport"),
@@ -648,102 +914,77 @@ impl Renderer {
                 dt,
                 self.time,
             );
+            sampled_textures.push(demo_buffer);
         }
 
         if let Some(drawer) = drawer {
-            self.ui_node
-                .register_graph(&mut self.render_graph, intermediate_buffer, drawer);
+            self.ui_node.register_graph(
+                &mut self.render_graph,
+                intermediate_buffer,
+                &sampled_textures,
+                drawer,
+            );
         }
 
-        let swapchain_output = builtins::SwapchainPass::acquire_next_image(
-            &self.swapchain_node,
-            &mut self.render_graph,
-        );
+        match &self.output_node {
+            OutputNode::Swapchain(swapchain_node) => {
+                let swapchain_output = builtins::SwapchainPass::acquire_next_image(
+                    swapchain_node,
+                    &mut self.render_graph,
+                );
 
-        builtins::blit_image(
-            &mut self.render_graph,
-            intermediate_buffer,
-            swapchain_output,
-        );
+                builtins::blit_image(
+                    &mut self.render_graph,
+                    intermediate_buffer,
+                    swapchain_output,
+                );
 
-        builtins::SwapchainPass::present(
-            &self.swapchain_node,
-            &mut self.render_graph,
-            (i_frame + FRAME_QUEUE_LENGTH) as u64,
-        );
+                builtins::SwapchainPass::present(
+                    swapchain_node,
+                    &mut self.render_graph,
+                    signal_value,
+                );
+            }
+            OutputNode::Offscreen(offscreen_node) => {
+                builtins::OffscreenPass::readback(
+                    offscreen_node,
+                    &mut self.render_graph,
+                    intermediate_buffer,
+                    signal_value,
+                );
+            }
+        }
 
         let current_frame = i_frame % FRAME_QUEUE_LENGTH;
         let context_pool = &mut self.context_pools[current_frame];
 
         let wait_value: u64 = i_frame as u64;
-        {
-            let fence = &self.swapchain_node.borrow().fence;
-            let wait_values = [wait_value];
-            self.device.wait_for_fences(&[fence], &wait_values)?;
-        }
+        self.output_node.wait_for_frame(&self.device, wait_value)?;
 
         self.device.reset_context_pool(context_pool)?;
 
-        let reloaded_shader = self.shader_watcher.update(|watch_event| {
-            if let render::shader::DebouncedEvent::Write(path) = watch_event {
-                self.device
-                    .shaders
-                    .iter()
-                    .find(|(_handle, shader)| shader.path == path)
-                    .map(|(handle, _shader)| handle)
-            } else {
-                None
-            }
-        });
-
-        if let Some(reloaded_shader) = reloaded_shader {
-            self.device.wait_idle().unwrap();
-
-            self.device.update_shader_from_fs(reloaded_shader)?;
-
-            let graphics_programs_to_reload: Vec<_> = self
-                .device
-                .graphics_programs
-                .iter()
-                .filter(|(_handle, program)| {
-                    program.graphics_state.vertex_shader == reloaded_shader
-                        || program.graphics_state.fragment_shader == reloaded_shader
-                })
-                .map(|(handle, _program)| handle)
-                .collect();
-
-            for program_handle in graphics_programs_to_reload {
-                let pipeline_count = self
-                    .device
-                    .graphics_programs
-                    .get(program_handle)
-                    .pipelines
-                    .len();
-
-                for i_pipeline in 0..pipeline_count {
-                    self.device
-                        .compile_graphics_program_pipeline(program_handle, i_pipeline)?;
-                }
-            }
-
-            let compute_programs_to_reload: Vec<_> = self
-                .device
-                .compute_programs
-                .iter()
-                .filter(|(_handle, program)| program.shader == reloaded_shader)
-                .map(|(handle, _program)| handle)
-                .collect();
-
-            for program_handle in compute_programs_to_reload {
-                self.device.compile_compute_program(program_handle)?;
+        let changed_paths = self.shader_watcher.changed_paths();
+        for reload in self.device.reload_shaders(&changed_paths)? {
+            self.shader_errors
+                .retain(|(shader_handle, _error)| *shader_handle != reload.shader);
+            for error in reload.errors {
+                eprintln!("{}", error);
+                self.shader_errors.push((reload.shader, error.to_string()));
             }
         }
+        // Variants that failed to compile during the last frame were drawn with their base variant
+        for (shader_handle, error) in self.device.variant_errors.drain(..) {
+            eprintln!("{}", error);
+            self.shader_errors.push((shader_handle, error.to_string()));
+        }
 
         self.device.update_bindless_set();
-        self.uniform_buffer.start_frame();
-        self.dynamic_vertex_buffer.start_frame();
-        self.dynamic_index_buffer.start_frame();
-        self.upload_buffer.start_frame();
+        self.uniform_buffer.start_frame(&mut self.device);
+        self.dynamic_vertex_buffer.start_frame(&mut self.device);
+        self.dynamic_index_buffer.start_frame(&mut self.device);
+        self.upload_manager.start_frame(&self.device)?;
+        // Uploads staged since the last frame, the graph waits for them in its first context
+        self.upload_manager.flush(&mut self.device, context_pool)?;
 
         let pass_api = render_graph::graph::PassApi {
             instance: &self.instance,
@@ -753,7 +994,7 @@ impl Renderer {
             uniform_buffer: &mut self.uniform_buffer,
             dynamic_vertex_buffer: &mut self.dynamic_vertex_buffer,
             dynamic_index_buffer: &mut self.dynamic_index_buffer,
-            upload_buffer: &mut self.upload_buffer,
+            upload_manager: &mut self.upload_manager,
         };
 
         self.render_graph.execute(pass_api, context_pool)?;
@@ -763,6 +1004,14 @@ impl Renderer {
         Ok(())
     }
 
+    // Waits for the last rendered frame of a headless renderer and returns its RGBA8 pixels
+    pub fn read_pixels(&mut self) -> VulkanResult<Vec<u8>> {
+        match &self.output_node {
+            OutputNode::Offscreen(node) => node.borrow_mut().read_pixels(&mut self.device),
+            OutputNode::Swapchain(_) => panic!("only headless renderers can read their pixels"),
+        }
+    }
+
     pub fn get_glyph_atlas_descriptor(&self) -> u32 {
         self.device
             .images
@@ -785,10 +1034,54 @@ struct App {
 }
 
 impl App {
+    pub fn new(renderer: Renderer, window_size: [i32; 2], scale_factor: f32) -> Self {
+        let ui_font = Font::from_file(
+            concat!(env!("OUT_DIR"), "/", "iAWriterQuattroS-Regular.ttf"),
+            0,
+        )
+        .unwrap();
+
+        let drawer = Drawer::new(
+            unsafe { &mut DRAWER_VERTEX_MEMORY },
+            unsafe { &mut DRAWER_INDEX_MEMORY },
+            [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
+            renderer.get_glyph_atlas_descriptor(),
+        );
+
+        let font_size = 18.0;
+        let ui = ui::Ui::new(Rc::new(ui_font), font_size * scale_factor);
+
+        Self {
+            renderer,
+            drawer: Rc::new(drawer),
+            ui,
+            fps_histogram: custom_ui::FpsHistogram::new(),
+            docking: ui_docking::Docking::new(),
+            show_fps: true,
+            font_size,
+            window_size: [window_size[0] as f32, window_size[1] as f32],
+            demo_viewport: None,
+        }
+    }
+
+    pub fn new_headless(size: [i32; 2]) -> vulkan::VulkanResult<Self> {
+        Ok(Self::new(Renderer::new_headless(size)?, size, 1.0))
+    }
+
     pub fn update(&mut self, dt: f32) -> vulkan::VulkanResult<()> {
         self.fps_histogram.push_time(dt);
         self.draw_ui();
-        self.draw_gpu(dt)
+        match self.draw_gpu(dt) {
+            Err(error) if error.is_device_lost() => {
+                eprintln!("{}, recreating the device", error);
+                self.renderer.recover_from_device_lost()?;
+                // The glyphs have to be uploaded again to the new atlas
+                let drawer = Rc::get_mut(&mut self.drawer).unwrap();
+                drawer.reset_glyph_atlas(self.renderer.get_glyph_atlas_descriptor());
+                Ok(())
+            }
+            result => result,
+        }
     }
 }
 
@@ -899,6 +1192,18 @@ impl App {
                 self.show_fps = !self.show_fps;
             }
             cursor[1] += 3.0 * em;
+
+            if self.ui.button(
+                drawer,
+                ui::Button::with_label("Toggle debug shaders").rect(Rect {
+                    pos: cursor,
+                    size: [20.0 * em, 1.5 * em],
+                }),
+            ) {
+                let debug = !self.renderer.ui_node.debug;
+                self.renderer.ui_node.debug = debug;
+                self.renderer.demo_node.borrow_mut().debug = debug;
+            }
         }
 
         if let Some(content2_rect) = self.docking.tabview("Content 2") {
@@ -987,6 +1292,25 @@ impl App {
                 },
             );
         }
+
+        // -- Shader errors
+        if !self.renderer.shader_errors.is_empty() {
+            let errors_rect = Rect {
+                pos: [
+                    fullscreen.pos[0] + 1.0 * em,
+                    fullscreen.pos[1] + fullscreen.size[1] - 11.0 * em,
+                ],
+                size: [fullscreen.size[0] - 2.0 * em, 10.0 * em],
+            };
+            custom_ui::widgets::shader_errors(
+                &mut self.ui,
+                drawer,
+                custom_ui::widgets::ShaderErrors {
+                    errors: &self.renderer.shader_errors,
+                    rect: errors_rect,
+                },
+            );
+        }
     }
 
     pub fn draw_gpu(&mut self, dt: f32) -> VulkanResult<()> {
@@ -999,6 +1323,17 @@ impl App {
 fn main() {
     profile::init();
 
+    // `--headless [output.png]` renders without a window, for CI with a software Vulkan driver
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(i_arg) = args.iter().position(|arg| arg == "--headless") {
+        let output_path = args.get(i_arg + 1).map_or("headless.png", String::as_str);
+        if let Err(e) = headless::run_headless(output_path) {
+            eprintln!("Headless rendering failed: {:?}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let mut event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_title("Cripsy Waffle")
@@ -1010,34 +1345,8 @@ fn main() {
         [window_size.width as i32, window_size.height as i32]
     };
 
-    let ui_font = Font::from_file(
-        concat!(env!("OUT_DIR"), "/", "iAWriterQuattroS-Regular.ttf"),
-        0,
-    )
-    .unwrap();
-
     let renderer = Renderer::new(&window, inner_size).unwrap();
-    let drawer = Drawer::new(
-        unsafe { &mut DRAWER_VERTEX_MEMORY },
-        unsafe { &mut DRAWER_INDEX_MEMORY },
-        [GLYPH_ATLAS_RESOLUTION, GLYPH_ATLAS_RESOLUTION],
-        renderer.get_glyph_atlas_descriptor(),
-    );
-
-    let font_size = 18.0;
-    let ui = ui::Ui::new(Rc::new(ui_font), font_size * (window.scale_factor() as f32));
-
-    let mut app = App {
-        renderer,
-        drawer: Rc::new(drawer),
-        ui,
-        fps_histogram: custom_ui::FpsHistogram::new(),
-        docking: ui_docking::Docking::new(),
-        show_fps: true,
-        font_size,
-        window_size: [inner_size[0] as f32, inner_size[1] as f32],
-        demo_viewport: None,
-    };
+    let mut app = App::new(renderer, inner_size, window.scale_factor() as f32);
 
     let now = Instant::now();
     let mut last_time = now.elapsed();
@@ -1059,10 +1368,12 @@ fn main() {
                 window_id,
             } if window_id == window.id() => {
                 let window_size: winit::dpi::LogicalSize<f32> = physical_size.to_logical(1.0);
-                let mut surface = &mut app.renderer.swapchain_node.borrow_mut().surface;
-                surface.is_outdated = true;
-                surface.size_requested =
-                    Some([window_size.width as i32, window_size.height as i32]);
+                if let OutputNode::Swapchain(swapchain_node) = &app.renderer.output_node {
+                    let surface = &mut swapchain_node.borrow_mut().surface;
+                    surface.is_outdated = true;
+                    surface.size_requested =
+                        Some([window_size.width as i32, window_size.height as i32]);
+                }
                 app.window_size = [window_size.width, window_size.height];
             }
 